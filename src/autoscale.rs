@@ -0,0 +1,27 @@
+//! Scale recommendation for monitors connected without a saved profile
+use crate::{edid::Edid, modes::Modes, monitor::Monitor};
+
+/// Logical DPI the recommended scale aims for
+pub const TARGET_DPI: f64 = 96.0;
+/// Narrowest physical width taken as a real size. Projectors and some TVs
+/// report an aspect ratio or nothing instead.
+const MIN_WIDTH_MM: i32 = 100;
+
+/// Supported scale of `mode`, one of the modes of `monitor`, closest to
+/// [`TARGET_DPI`], with the DPI it was derived from. `None` if the EDID has
+/// no usable physical size or the monitor is virtual.
+pub fn recommend(monitor: &Monitor, mode: &Modes, edid: &Edid) -> Option<(f64, f64)> {
+    if monitor.is_virtual() {
+        return None;
+    }
+    let width_mm = edid.width_mm.filter(|&width| width >= MIN_WIDTH_MM)?;
+    let (width, _) = mode.get_size();
+    let dpi = width as f64 / (width_mm as f64 / 25.4);
+    let ideal = dpi / TARGET_DPI;
+    let scale = mode
+        .supported_scales()
+        .iter()
+        .copied()
+        .min_by(|a, b| (a - ideal).abs().total_cmp(&(b - ideal).abs()))?;
+    Some((scale, dpi))
+}
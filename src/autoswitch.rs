@@ -0,0 +1,121 @@
+//! Switching profiles without kanshi, with `displayd.autoswitch`, or when
+//! kanshi doesn't apply them anyway (see
+//! [`kanshi_applies_profiles`](crate::kanshi_applies_profiles)).
+//!
+//! The watch loop applies the saved profile kanshi would pick whenever the
+//! connected monitors change, through the backend that already reports them,
+//! so there is no second process racing the daemon for the outputs. Profiles
+//! are picked the way kanshi picks them, the first one in the profiles
+//! directory whose outputs are exactly the connected ones, and their `exec`
+//! lines are run once the outputs are set up.
+use crate::{
+    backend::{self, Backend, DisplayBackend},
+    get_kanshi_paths, kanshi,
+    monitor::Monitor,
+    DisplayManager,
+};
+use log::{debug, error, info, warn};
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tokio::process::Command;
+
+/// The profiles changed since the watch loop last applied one
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+/// How long kanshi started by the session gets to come up before it is stopped
+const KANSHI_GRACE: Duration = Duration::from_secs(5);
+
+/// Have the watch loop apply the profile for the connected monitors again, as
+/// kanshi does when it reloads
+pub fn profiles_changed() {
+    PENDING.store(true, Ordering::Relaxed);
+}
+
+/// Whether the profiles changed since the last call
+pub fn take_pending() -> bool {
+    PENDING.swap(false, Ordering::Relaxed)
+}
+
+/// Stop the kanshi the session starts, which would fight over the outputs.
+/// It is stopped again after [`KANSHI_GRACE`], as sessions often start it
+/// after the daemon.
+pub async fn stop_kanshi() {
+    for delay in [Duration::ZERO, KANSHI_GRACE] {
+        tokio::time::sleep(delay).await;
+        match Command::new("killall").arg("-q").arg("kanshi").status().await {
+            Ok(status) if status.success() => info!("Stopped kanshi, profiles are switched by the daemon"),
+            Ok(_) => {}
+            Err(e) => debug!("Cannot stop kanshi: {e}"),
+        }
+    }
+}
+
+/// Apply the saved profile for `monitors` through `backend`, reporting the
+/// outcome with `ProfileApplied`. Returns whether a profile was applied.
+pub async fn apply(monitors: &[Monitor], backend: &impl DisplayBackend) -> bool {
+    let profiles_dir = match get_kanshi_paths().await {
+        Ok(paths) => paths.profiles,
+        Err(e) => {
+            warn!("Cannot find the kanshi profiles: {e}");
+            return false;
+        }
+    };
+    let outputs: Vec<(&str, String)> = monitors
+        .iter()
+        .map(|monitor| (monitor.get_connector(), monitor.get_dpy_name()))
+        .collect();
+    let (path, profile, selected) = match kanshi::matching_profile(&profiles_dir, &outputs) {
+        Ok(Some(found)) => found,
+        Ok(None) => {
+            debug!("No saved profile for the connected monitors");
+            return false;
+        }
+        Err(e) => {
+            error!("Cannot read the profiles in {}: {e}", profiles_dir.display());
+            return false;
+        }
+    };
+    let name = profile
+        .name
+        .clone()
+        .unwrap_or_else(|| path.file_name().unwrap_or_default().to_string_lossy().into_owned());
+    // Connectors rather than the criteria, which may be `*`
+    let mut commands: Vec<String> = profile
+        .outputs
+        .iter()
+        .zip(&selected)
+        .filter(|(directive, _)| !directive.options.is_empty())
+        .map(|(directive, &index)| format!("output \"{}\" {}", outputs[index].0, directive.options.join(" ")))
+        .collect();
+    // The `exec swaymsg output` lines of profiles saved on sway set what kanshi
+    // can't, and go through the backend with the rest. Other swaymsg commands
+    // run like any exec, but only on sway, where they reach the right compositor.
+    let on_sway = matches!(backend::current(), Some(Backend::Sway(_)));
+    let mut execs = Vec::new();
+    for exec in &profile.execs {
+        match exec.strip_prefix("swaymsg ") {
+            Some(command) if on_sway && command.starts_with("output ") => commands.push(command.to_string()),
+            Some(_) if !on_sway => debug!("Skipping exec of profile {name} outside sway: {exec}"),
+            _ => execs.push(exec),
+        }
+    }
+    info!("Applying profile {name} from {}", path.display());
+    if let Err(e) = backend.apply(&commands).await {
+        if let Err(e) = DisplayManager::emit_profile_applied(&name, false, &e.to_string()).await {
+            warn!("Cannot emit ProfileApplied: {e}");
+        }
+        return false;
+    }
+    for exec in execs {
+        debug!("Running exec of profile {name}: {exec}");
+        if let Err(e) = Command::new("sh").arg("-c").arg(exec).spawn() {
+            warn!("Cannot run '{exec}': {e}");
+        }
+    }
+    if let Err(e) = DisplayManager::emit_profile_applied(&name, true, "").await {
+        warn!("Cannot emit ProfileApplied: {e}");
+    }
+    true
+}
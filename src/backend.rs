@@ -0,0 +1,842 @@
+//! The compositor the daemon drives and what it can do. Everything that
+//! depends on the compositor goes through [`DisplayBackend`], which
+//! [`SwayBackend`] implements over sway's IPC,
+//! [`NiriBackend`](crate::niri::NiriBackend) over niri's,
+//! [`WlrBackend`](crate::wlr::WlrBackend) over the wlr output protocols and
+//! [`X11Backend`](crate::xrandr::X11Backend) with `xrandr`.
+use crate::{
+    config::{self, BackendKind},
+    kanshi::TRANSFORMS,
+    layout::Rect,
+    lock::{Mutex, Rank, Ranked},
+    niri::NiriBackend,
+    wlr::WlrBackend,
+    xrandr::X11Backend,
+};
+use async_io::Async;
+use futures_util::{stream::BoxStream, StreamExt};
+use log::{debug, error, info, warn};
+use std::{
+    collections::HashMap,
+    env, fmt, fs,
+    future::Future,
+    io,
+    os::unix::net::UnixStream as StdUnixStream,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+use swayipc_async::{Connection, EnabledOrDisabled, Event, EventType, WorkspaceChange};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+};
+use zbus::fdo;
+
+/// Delay before the first try to reconnect to the compositor, doubled after
+/// every failed attempt up to [`MAX_RECONNECT_DELAY`]
+pub const RECONNECT_DELAY: Duration = Duration::from_millis(250);
+pub const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+static BACKEND: OnceLock<Backend> = OnceLock::new();
+
+/// Output options only sway has, which profiles pass on to it with `exec
+/// swaymsg`. Backends translating the commands skip them.
+const SWAY_ONLY_OPTIONS: [&str; 6] = [
+    "allow_tearing",
+    "hdr",
+    "max_render_time",
+    "render_bit_depth",
+    "scale_filter",
+    "subpixel",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Backend {
+    /// sway, with its (major, minor) version if it could be queried
+    Sway(Option<(i32, i32)>),
+    /// A compositor speaking wlr-output-management, with the desktop name
+    /// from `$XDG_CURRENT_DESKTOP`
+    Wlr(Option<String>),
+    /// niri, with its version if it could be queried
+    Niri(Option<String>),
+    /// An X server, with its RandR version if it could be queried
+    X11(Option<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Positions can be reported and applied in either layout mode (see
+    /// [`layout`](crate::layout))
+    pub layout_mode_change: bool,
+    /// Every output must use the same scale
+    pub global_scale_required: bool,
+    /// Outputs can be scaled, otherwise only a scale of 1 is supported
+    pub scaling: bool,
+    /// Outputs accept `allow_tearing`
+    pub tearing: bool,
+    /// Outputs accept `render_bit_depth`
+    pub render_bit_depth: bool,
+    /// Outputs accept `hdr`
+    pub hdr: bool,
+}
+
+impl Backend {
+    pub fn name(&self) -> String {
+        match self {
+            Backend::Sway(Some((major, minor))) => format!("sway {major}.{minor}"),
+            Backend::Sway(None) => String::from("sway"),
+            Backend::Wlr(Some(desktop)) => format!("{desktop} (wlr-output-management)"),
+            Backend::Wlr(None) => String::from("wlr-output-management"),
+            Backend::Niri(Some(version)) => format!("niri {version}"),
+            Backend::Niri(None) => String::from("niri"),
+            Backend::X11(Some(version)) => format!("X11 (RandR {version})"),
+            Backend::X11(None) => String::from("X11"),
+        }
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        match self {
+            Backend::Sway(version) => {
+                let at_least = |wanted: (i32, i32)| version.is_some_and(|version| version >= wanted);
+                Capabilities {
+                    // Physical positions are converted to sway's logical ones
+                    layout_mode_change: true,
+                    global_scale_required: false,
+                    scaling: true,
+                    tearing: at_least((1, 9)),
+                    render_bit_depth: at_least((1, 7)),
+                    hdr: at_least((1, 11)),
+                }
+            }
+            Backend::Wlr(_) | Backend::Niri(_) => Capabilities {
+                layout_mode_change: true,
+                global_scale_required: false,
+                scaling: true,
+                tearing: false,
+                render_bit_depth: false,
+                hdr: false,
+            },
+            Backend::X11(_) => Capabilities {
+                layout_mode_change: true,
+                // There is no scale per output, only 1 is accepted
+                global_scale_required: true,
+                scaling: false,
+                tearing: false,
+                render_bit_depth: false,
+                hdr: false,
+            },
+        }
+    }
+}
+
+/// Remember the backend found at startup. Only the first call has an effect.
+pub fn set(backend: Backend) {
+    let _ = BACKEND.set(backend);
+}
+
+/// The backend found at startup, `None` before the server started
+pub fn current() -> Option<&'static Backend> {
+    BACKEND.get()
+}
+
+/// An output as the backends report it, enabled or not
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Output {
+    /// The connector, e.g. `DP-1`
+    pub name: String,
+    pub make: String,
+    pub model: String,
+    pub serial: String,
+    /// The output is enabled, part of the layout
+    pub active: bool,
+    /// The output is powered on
+    pub power: bool,
+    pub primary: bool,
+    /// The output isn't for the desktop, such as a VR headset
+    pub non_desktop: bool,
+    pub modes: Vec<OutputMode>,
+    /// `None` if the output is disabled
+    pub current_mode: Option<OutputMode>,
+    /// Position and size in the layout, in logical pixels, all 0 if the
+    /// output is disabled
+    pub rect: Rect,
+    /// `None` if the output is disabled
+    pub scale: Option<f64>,
+    /// One of sway's transforms, such as `flipped-90`, `None` if the output
+    /// is disabled
+    pub transform: Option<String>,
+    /// `None` if unknown
+    pub adaptive_sync: Option<bool>,
+    /// sway's `max_render_time` in milliseconds, 0 for off, `None` if the
+    /// compositor doesn't report it
+    pub max_render_time: Option<u32>,
+    /// Whether tearing page flips are allowed, `None` if the compositor
+    /// doesn't report it
+    pub allow_tearing: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutputMode {
+    pub width: i32,
+    pub height: i32,
+    /// mHz, 0 if unknown
+    pub refresh: i32,
+    /// The mode the output asks for, usually its native resolution
+    pub preferred: bool,
+}
+
+/// A sway `output` command, for the backends that translate them
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputCommand {
+    /// `*`, a connector or an output description
+    pub criteria: String,
+    pub change: OutputChange,
+}
+
+/// What an output command asks of the outputs it selects
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OutputChange {
+    pub enabled: Option<bool>,
+    pub mode: Option<ModeRequest>,
+    pub position: Option<(i32, i32)>,
+    pub scale: Option<f64>,
+    /// One of [`TRANSFORMS`]
+    pub transform: Option<&'static str>,
+    pub adaptive_sync: Option<bool>,
+    pub power: Option<bool>,
+    /// Make the output X11's primary one, which sway has no notion of, so
+    /// only sent on X11 (see [`primary_command`])
+    pub primary: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModeRequest {
+    pub width: i32,
+    pub height: i32,
+    /// mHz
+    pub refresh: Option<i32>,
+    /// Asked for with `--custom`, rather than one of the output's modes
+    pub custom: bool,
+}
+
+impl OutputCommand {
+    /// Parse `command`, `None` if it is empty. Commands other than `output`
+    /// are an error.
+    pub fn parse(command: &str) -> Result<Option<OutputCommand>, String> {
+        let words = split_command(command);
+        match words.first().map(String::as_str) {
+            Some("output") => {}
+            Some(other) => return Err(format!("{other} isn't an output command")),
+            None => return Ok(None),
+        }
+        let criteria = words.get(1).ok_or("output expects a name")?.clone();
+        let change = OutputChange::parse(&words[2..])?;
+        Ok(Some(OutputCommand { criteria, change }))
+    }
+
+    /// Whether the command is for the output on `connector` described as
+    /// `description`, its make, model and serial
+    pub fn selects(&self, connector: &str, description: &str) -> bool {
+        self.criteria == "*" || self.criteria == connector || self.criteria == description
+    }
+}
+
+impl OutputChange {
+    /// Parse the options of an output command, such as `mode 1920x1080 scale 2`.
+    /// Options only sway has are skipped, see [`SWAY_ONLY_OPTIONS`].
+    pub fn parse(options: &[String]) -> Result<OutputChange, String> {
+        let mut change = OutputChange::default();
+        let mut args = options.iter();
+        while let Some(option) = args.next() {
+            match option.as_str() {
+                "enable" => change.enabled = Some(true),
+                "disable" => change.enabled = Some(false),
+                "mode" | "resolution" | "res" => {
+                    let mut spec = args.next().ok_or("mode expects a size")?;
+                    let custom = spec == "--custom";
+                    if custom {
+                        spec = args.next().ok_or("mode expects a size")?;
+                    }
+                    change.mode = Some(parse_mode(spec, custom).ok_or(format!("Invalid mode {spec}"))?);
+                }
+                "position" | "pos" => {
+                    let x = args.next().ok_or("position expects x and y")?;
+                    let (x, y) = match x.split_once(',') {
+                        Some((x, y)) => (x.to_string(), y.to_string()),
+                        None => (x.clone(), args.next().ok_or("position expects x and y")?.clone()),
+                    };
+                    let parse = |value: &str| value.parse::<i32>().map_err(|_| format!("Invalid position {x},{y}"));
+                    change.position = Some((parse(&x)?, parse(&y)?));
+                }
+                "scale" => {
+                    let scale = args.next().ok_or("scale expects a number")?;
+                    change.scale = Some(
+                        scale
+                            .parse::<f64>()
+                            .ok()
+                            .filter(|scale| scale.is_finite() && *scale > 0.0)
+                            .ok_or(format!("Invalid scale {scale}"))?,
+                    );
+                }
+                "transform" => {
+                    let transform = args.next().ok_or("transform expects a rotation")?;
+                    change.transform = Some(
+                        TRANSFORMS
+                            .iter()
+                            .find(|name| *name == transform)
+                            .ok_or(format!("Invalid transform {transform}"))?,
+                    );
+                }
+                "adaptive_sync" => change.adaptive_sync = Some(parse_switch(args.next(), option)?),
+                "power" | "dpms" => change.power = Some(parse_switch(args.next(), option)?),
+                "primary" => change.primary = true,
+                // kanshi's name for the output in profiles
+                "alias" => {
+                    args.next();
+                }
+                option if SWAY_ONLY_OPTIONS.contains(&option) => {
+                    let value = args.next().map_or("", String::as_str);
+                    debug!("Skipping output {option} {value}, which only sway supports");
+                }
+                other => return Err(format!("output {other} isn't supported")),
+            }
+        }
+        Ok(change)
+    }
+
+    /// Add the changes of `other`, which win over these
+    pub fn merge(&mut self, other: &OutputChange) {
+        self.enabled = other.enabled.or(self.enabled);
+        self.mode = other.mode.or(self.mode);
+        self.position = other.position.or(self.position);
+        self.scale = other.scale.or(self.scale);
+        self.transform = other.transform.or(self.transform);
+        self.adaptive_sync = other.adaptive_sync.or(self.adaptive_sync);
+        self.power = other.power.or(self.power);
+        self.primary |= other.primary;
+    }
+}
+
+/// The command making `connector` the primary output, for the backends that
+/// have one: only X11
+pub fn primary_command(connector: &str) -> Option<String> {
+    matches!(current(), Some(Backend::X11(_))).then(|| format!("output \"{connector}\" primary"))
+}
+
+/// Split a sway command into words, keeping double-quoted ones together
+fn split_command(command: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+    let mut started = false;
+    for c in command.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                started = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if started {
+                    words.push(std::mem::take(&mut word));
+                    started = false;
+                }
+            }
+            c => {
+                word.push(c);
+                started = true;
+            }
+        }
+    }
+    if started {
+        words.push(word);
+    }
+    words
+}
+
+/// `WxH`, `WxH@R` or `WxH@RHz`, with R in Hz
+fn parse_mode(spec: &str, custom: bool) -> Option<ModeRequest> {
+    let (size, refresh) = match spec.split_once('@') {
+        Some((size, refresh)) => (size, Some(refresh.trim_end_matches("Hz"))),
+        None => (spec, None),
+    };
+    let (width, height) = size.split_once('x')?;
+    let refresh = match refresh {
+        Some(refresh) => Some((refresh.parse::<f64>().ok()? * 1000.0).round() as i32),
+        None => None,
+    };
+    Some(ModeRequest {
+        width: width.parse().ok()?,
+        height: height.parse().ok()?,
+        refresh,
+        custom,
+    })
+}
+
+fn parse_switch(value: Option<&String>, option: &str) -> Result<bool, String> {
+    match value.map(String::as_str) {
+        Some("on" | "enable" | "yes") => Ok(true),
+        Some("off" | "disable" | "no") => Ok(false),
+        _ => Err(format!("{option} expects on or off")),
+    }
+}
+
+/// A change reported by the compositor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendEvent {
+    /// An output was connected, disconnected or reconfigured
+    OutputsChanged,
+    /// The compositor reloaded its config, which may have reset the outputs
+    ConfigReloaded,
+}
+
+pub type BackendEvents = BoxStream<'static, Result<BackendEvent, BackendError>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendError(String);
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+impl From<swayipc_async::Error> for BackendError {
+    fn from(error: swayipc_async::Error) -> Self {
+        BackendError(error.to_string())
+    }
+}
+
+impl From<String> for BackendError {
+    fn from(error: String) -> Self {
+        BackendError(error)
+    }
+}
+
+/// What the daemon needs from the compositor. Outputs are described with
+/// [`Output`] and changed with sway's output commands, such as
+/// `output DP-1 mode 1920x1080`, the syntax kanshi profiles use as well; a
+/// backend for another compositor translates them.
+pub trait DisplayBackend {
+    /// The compositor and its version
+    fn detect(&self) -> impl Future<Output = Backend> + Send;
+
+    /// All outputs, enabled or not
+    fn outputs(&self) -> impl Future<Output = Result<Vec<Output>, BackendError>> + Send;
+
+    /// Run `commands` in order, failing on the first one the compositor rejects
+    fn apply(&self, commands: &[String]) -> impl Future<Output = fdo::Result<()>> + Send;
+
+    /// Output changes, on a connection of their own. `None` if the compositor
+    /// won't send them, in which case the outputs are polled.
+    fn subscribe(&self) -> impl Future<Output = Option<BackendEvents>> + Send;
+
+    /// Wait for the compositor to come back after the connection was lost,
+    /// trying with a growing delay between attempts
+    fn reconnect(&self) -> impl Future<Output = ()> + Send;
+}
+
+/// sway, through its IPC socket. A lost connection is reopened once before a
+/// request fails, so a restarted sway doesn't fail every later call.
+pub struct SwayBackend {
+    connection: Mutex<Connection>,
+}
+
+impl Ranked for Connection {
+    const RANK: Rank = Rank::Sway;
+}
+
+impl SwayBackend {
+    /// Connect to the sway at `$SWAYSOCK`
+    pub async fn connect() -> Result<SwayBackend, BackendError> {
+        Ok(SwayBackend {
+            connection: Mutex::new(connect_sway().await?),
+        })
+    }
+}
+
+/// Connect to sway's IPC socket, looked up again every time (see
+/// [`sway_socket`]) rather than through swayipc, which trusts `$SWAYSOCK`
+async fn connect_sway() -> Result<Connection, swayipc_async::Error> {
+    let socket = sway_socket()?;
+    Ok(Async::<StdUnixStream>::connect(socket).await?.into())
+}
+
+/// sway's IPC socket: `$SWAYSOCK` unless the sway it names has exited, as
+/// after sway restarted with a new socket, otherwise the newest socket of a
+/// running sway in `$XDG_RUNTIME_DIR`
+fn sway_socket() -> io::Result<PathBuf> {
+    let from_env = env::var_os("SWAYSOCK").map(PathBuf::from);
+    if let Some(socket) = from_env.as_ref().filter(|socket| is_live_socket(socket)) {
+        return Ok(socket.clone());
+    }
+    let runtime_dir = env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from);
+    let found = runtime_dir
+        .and_then(|dir| fs::read_dir(dir).ok())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| sway_socket_pid(path).is_some() && is_live_socket(path))
+        .max_by_key(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok());
+    match found {
+        Some(socket) => {
+            if from_env.as_ref() != Some(&socket) {
+                info!("Found sway's IPC socket at {}", socket.display());
+            }
+            Ok(socket)
+        }
+        None => Err(io::Error::new(io::ErrorKind::NotFound, "found no IPC socket of a running sway")),
+    }
+}
+
+/// The pid in the name sway gives its IPC sockets, `sway-ipc.UID.PID.sock`
+fn sway_socket_pid(path: &Path) -> Option<u32> {
+    let name = path.file_name()?.to_str()?;
+    let (_uid, pid) = name.strip_prefix("sway-ipc.")?.strip_suffix(".sock")?.split_once('.')?;
+    pid.parse().ok()
+}
+
+/// Whether `socket` exists and, if it is named like sway's, its sway runs
+fn is_live_socket(socket: &Path) -> bool {
+    if !socket.exists() {
+        return false;
+    }
+    match sway_socket_pid(socket) {
+        Some(pid) => Path::new(&format!("/proc/{pid}")).exists(),
+        None => true,
+    }
+}
+
+/// `output` as sway describes it
+fn from_sway(output: swayipc_async::Output) -> Output {
+    // sway doesn't report the preferred mode, Monitor::new finds it in the EDID
+    let mode = |mode: &swayipc_async::Mode| OutputMode {
+        width: mode.width,
+        height: mode.height,
+        refresh: mode.refresh,
+        preferred: false,
+    };
+    Output {
+        modes: output.modes.iter().map(mode).collect(),
+        current_mode: output.current_mode.as_ref().map(mode),
+        rect: (output.rect.x, output.rect.y, output.rect.width, output.rect.height),
+        // Older sways only report dpms, newer ones report power as well
+        power: output.power || output.dpms,
+        adaptive_sync: output
+            .adaptive_sync_status
+            .map(|status| status == EnabledOrDisabled::Enabled),
+        name: output.name,
+        make: output.make,
+        model: output.model,
+        serial: output.serial,
+        active: output.active,
+        primary: output.primary,
+        non_desktop: output.non_desktop,
+        // sway reports -1 for disabled outputs
+        scale: output.scale.filter(|_| output.active),
+        transform: output.transform,
+        max_render_time: None,
+        allow_tearing: None,
+    }
+}
+
+/// Type of sway's `GET_OUTPUTS` IPC message
+const IPC_GET_OUTPUTS: u32 = 3;
+
+/// `max_render_time` and `allow_tearing` of sway's outputs by connector,
+/// which swayipc leaves out of its outputs, from a raw `GET_OUTPUTS` request
+/// on a connection of its own. sway 1.9 and later report `allow_tearing`.
+async fn output_settings() -> io::Result<HashMap<String, (Option<u32>, Option<bool>)>> {
+    let mut stream = UnixStream::connect(sway_socket()?).await?;
+    let mut request = b"i3-ipc".to_vec();
+    request.extend_from_slice(&0u32.to_ne_bytes());
+    request.extend_from_slice(&IPC_GET_OUTPUTS.to_ne_bytes());
+    stream.write_all(&request).await?;
+    // The magic string, the payload length and the message type
+    let mut header = [0; 14];
+    stream.read_exact(&mut header).await?;
+    let length = u32::from_ne_bytes(header[6..10].try_into().unwrap()) as usize;
+    let mut payload = vec![0; length];
+    stream.read_exact(&mut payload).await?;
+    let outputs: Vec<serde_json::Value> = serde_json::from_slice(&payload)?;
+    Ok(outputs
+        .iter()
+        .filter_map(|output| {
+            let name = output.get("name")?.as_str()?.to_string();
+            let max_render_time = output
+                .get("max_render_time")
+                .and_then(serde_json::Value::as_u64)
+                .and_then(|milliseconds| u32::try_from(milliseconds).ok());
+            let allow_tearing = output.get("allow_tearing").and_then(serde_json::Value::as_bool);
+            Some((name, (max_render_time, allow_tearing)))
+        })
+        .collect())
+}
+
+/// Whether `error` means the IPC socket is gone rather than sway refusing a
+/// request
+fn is_disconnect(error: &swayipc_async::Error) -> bool {
+    matches!(error, swayipc_async::Error::Io(_))
+}
+
+impl DisplayBackend for SwayBackend {
+    async fn detect(&self) -> Backend {
+        match self.connection.lock().await.get_version().await {
+            Ok(version) => Backend::Sway(Some((version.major, version.minor))),
+            Err(e) => {
+                warn!("Could not query the sway version: {e}");
+                Backend::Sway(None)
+            }
+        }
+    }
+
+    async fn outputs(&self) -> Result<Vec<Output>, BackendError> {
+        let mut connection = self.connection.lock().await;
+        let outputs = match connection.get_outputs().await {
+            Err(e) if is_disconnect(&e) => {
+                warn!("Lost the connection to sway ({e}), reconnecting");
+                *connection = connect_sway().await?;
+                connection.get_outputs().await?
+            }
+            outputs => outputs?,
+        };
+        drop(connection);
+        let settings = output_settings().await.unwrap_or_else(|e| {
+            debug!("Cannot read the render settings of sway's outputs: {e}");
+            HashMap::new()
+        });
+        Ok(outputs
+            .into_iter()
+            .map(|output| {
+                let (max_render_time, allow_tearing) = settings.get(&output.name).copied().unwrap_or_default();
+                Output {
+                    max_render_time,
+                    allow_tearing,
+                    ..from_sway(output)
+                }
+            })
+            .collect())
+    }
+
+    async fn apply(&self, commands: &[String]) -> fdo::Result<()> {
+        let mut connection = self.connection.lock().await;
+        for command in commands {
+            debug!("Running sway command: {command}");
+            let outcomes = match connection.run_command(command).await {
+                Err(e) if is_disconnect(&e) => {
+                    warn!("Lost the connection to sway ({e}), reconnecting");
+                    *connection = connect_sway()
+                        .await
+                        .map_err(|e| fdo::Error::Failed(format!("Cannot reconnect to sway: {e}")))?;
+                    connection.run_command(command).await
+                }
+                outcomes => outcomes,
+            }
+            .map_err(|e| fdo::Error::Failed(format!("sway IPC error: {e}")))?;
+            if let Some(Err(e)) = outcomes.into_iter().find(Result::is_err) {
+                error!("sway rejected '{command}': {e}");
+                return Err(fdo::Error::Failed(format!("sway rejected '{command}': {e}")));
+            }
+        }
+        Ok(())
+    }
+
+    /// Output events, and workspace events for config reloads
+    async fn subscribe(&self) -> Option<BackendEvents> {
+        let subscription = match connect_sway().await {
+            Ok(connection) => connection.subscribe([EventType::Output, EventType::Workspace]).await,
+            Err(e) => Err(e),
+        };
+        match subscription {
+            Ok(events) => Some(
+                events
+                    .filter_map(|event| async move {
+                        match event {
+                            Ok(Event::Workspace(event)) if event.change == WorkspaceChange::Reload => {
+                                Some(Ok(BackendEvent::ConfigReloaded))
+                            }
+                            // Other workspace events just come with the subscription
+                            Ok(Event::Workspace(_)) => None,
+                            Ok(_) => Some(Ok(BackendEvent::OutputsChanged)),
+                            Err(e) => Some(Err(e.into())),
+                        }
+                    })
+                    .boxed(),
+            ),
+            Err(e) => {
+                warn!("Cannot subscribe to sway's output events, polling instead: {e}");
+                None
+            }
+        }
+    }
+
+    async fn reconnect(&self) {
+        let mut delay = RECONNECT_DELAY;
+        loop {
+            tokio::time::sleep(delay).await;
+            match connect_sway().await {
+                Ok(connection) => {
+                    info!("Reconnected to sway");
+                    *self.connection.lock().await = connection;
+                    return;
+                }
+                Err(e) => {
+                    debug!("Cannot reconnect to sway yet: {e}");
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                }
+            }
+        }
+    }
+}
+
+/// The backend chosen with `displayd.backend`
+pub enum Compositor {
+    Sway(SwayBackend),
+    Wlr(WlrBackend),
+    Niri(NiriBackend),
+    X11(X11Backend),
+}
+
+impl Compositor {
+    /// Connect to the first of the backends the config allows that answers,
+    /// see [`Config::backend_candidates`](config::Config::backend_candidates)
+    pub async fn connect() -> Result<Compositor, BackendError> {
+        let candidates = config::current().backend_candidates();
+        if candidates.is_empty() {
+            return Err(BackendError(String::from(
+                "Found no compositor: none of SWAYSOCK, NIRI_SOCKET, WAYLAND_DISPLAY or DISPLAY is set",
+            )));
+        }
+        let mut errors = Vec::new();
+        for kind in candidates {
+            match Compositor::connect_to(kind).await {
+                Ok(compositor) => {
+                    info!("Using the {kind} backend");
+                    return Ok(compositor);
+                }
+                Err(e) => {
+                    debug!("Cannot use the {kind} backend: {e}");
+                    errors.push(format!("{kind}: {e}"));
+                }
+            }
+        }
+        Err(BackendError(errors.join(", ")))
+    }
+
+    async fn connect_to(kind: BackendKind) -> Result<Compositor, BackendError> {
+        match kind {
+            BackendKind::Sway => Ok(Compositor::Sway(SwayBackend::connect().await?)),
+            BackendKind::Wlr => Ok(Compositor::Wlr(WlrBackend::connect().await?)),
+            BackendKind::Niri => Ok(Compositor::Niri(NiriBackend::connect().await?)),
+            BackendKind::X11 => Ok(Compositor::X11(X11Backend::connect().await?)),
+        }
+    }
+}
+
+impl DisplayBackend for Compositor {
+    async fn detect(&self) -> Backend {
+        match self {
+            Compositor::Sway(backend) => backend.detect().await,
+            Compositor::Wlr(backend) => backend.detect().await,
+            Compositor::Niri(backend) => backend.detect().await,
+            Compositor::X11(backend) => backend.detect().await,
+        }
+    }
+
+    async fn outputs(&self) -> Result<Vec<Output>, BackendError> {
+        match self {
+            Compositor::Sway(backend) => backend.outputs().await,
+            Compositor::Wlr(backend) => backend.outputs().await,
+            Compositor::Niri(backend) => backend.outputs().await,
+            Compositor::X11(backend) => backend.outputs().await,
+        }
+    }
+
+    async fn apply(&self, commands: &[String]) -> fdo::Result<()> {
+        match self {
+            Compositor::Sway(backend) => backend.apply(commands).await,
+            Compositor::Wlr(backend) => backend.apply(commands).await,
+            Compositor::Niri(backend) => backend.apply(commands).await,
+            Compositor::X11(backend) => backend.apply(commands).await,
+        }
+    }
+
+    async fn subscribe(&self) -> Option<BackendEvents> {
+        match self {
+            Compositor::Sway(backend) => backend.subscribe().await,
+            Compositor::Wlr(backend) => backend.subscribe().await,
+            Compositor::Niri(backend) => backend.subscribe().await,
+            Compositor::X11(backend) => backend.subscribe().await,
+        }
+    }
+
+    async fn reconnect(&self) {
+        match self {
+            Compositor::Sway(backend) => backend.reconnect().await,
+            Compositor::Wlr(backend) => backend.reconnect().await,
+            Compositor::Niri(backend) => backend.reconnect().await,
+            Compositor::X11(backend) => backend.reconnect().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_output_commands() {
+        let command = OutputCommand::parse(
+            r#"output "Dell Inc. DELL U2720Q ABC123" mode 3840x2160@59.997Hz position 0,0 scale 1.5 transform 90 enable"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(command.criteria, "Dell Inc. DELL U2720Q ABC123");
+        assert!(command.selects("DP-1", "Dell Inc. DELL U2720Q ABC123"));
+        assert!(!command.selects("DP-2", "Dell Inc. DELL U2720Q XYZ789"));
+        assert!(OutputCommand::parse("output DP-1 primary").unwrap().unwrap().change.primary);
+        assert_eq!(
+            command.change,
+            OutputChange {
+                enabled: Some(true),
+                mode: Some(ModeRequest {
+                    width: 3840,
+                    height: 2160,
+                    refresh: Some(59997),
+                    custom: false,
+                }),
+                position: Some((0, 0)),
+                scale: Some(1.5),
+                transform: Some("90"),
+                ..OutputChange::default()
+            }
+        );
+    }
+
+    #[test]
+    fn skips_sway_only_options() {
+        let command = OutputCommand::parse("output DP-1 scale_filter nearest allow_tearing yes max_render_time 5 power off")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            command.change,
+            OutputChange {
+                power: Some(false),
+                ..OutputChange::default()
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_what_cannot_be_translated() {
+        assert!(OutputCommand::parse("create_output").is_err());
+        assert!(OutputCommand::parse("output DP-1 colour purple").is_err());
+        assert!(OutputCommand::parse("output DP-1 transform 45").is_err());
+        assert!(OutputCommand::parse("output DP-1 scale 0").is_err());
+        assert!(OutputCommand::parse("output DP-1 primary on").is_err());
+        assert_eq!(OutputCommand::parse("").unwrap(), None);
+    }
+}
@@ -0,0 +1,121 @@
+//! Output brightness, through a `/sys/class/backlight` device when the output
+//! has one and through software dimming (see [`crate::gamma`]) otherwise.
+//!
+//! Backlight devices are only writable by root, so their brightness is set
+//! through logind's `SetBrightness`, which lets the session's user do it.
+use log::debug;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+const SYSFS_BACKLIGHT: &str = "/sys/class/backlight";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Backlight {
+    /// Backlight device directory under `/sys/class/backlight`
+    Sysfs(PathBuf),
+    /// Gamma based dimming for outputs without a backlight device
+    Software,
+}
+
+impl Backlight {
+    /// Backlight backing the output. Only builtin panels are mapped to a
+    /// sysfs device, external displays always use software dimming.
+    pub fn for_output(builtin: bool) -> Backlight {
+        if builtin {
+            if let Some(device) = sysfs_device() {
+                return Backlight::Sysfs(device);
+            }
+        }
+        Backlight::Software
+    }
+
+    /// Current brightness in percent. Only sysfs devices can be read back.
+    pub fn percent(&self) -> Option<i32> {
+        let Backlight::Sysfs(device) = self else {
+            return None;
+        };
+        let max = read_value(&device.join("max_brightness")).ok()?;
+        let value = read_value(&device.join("brightness")).ok()?;
+        (max > 0).then(|| (value * 100 / max) as i32)
+    }
+
+    /// Set the brightness of `connector` in percent, returning the value
+    /// actually applied
+    pub async fn set(&self, connector: &str, percent: i32) -> Result<i32, String> {
+        let percent = percent.clamp(0, 100);
+        match self {
+            Backlight::Sysfs(device) => {
+                let max: i64 = read_value(&device.join("max_brightness"))?;
+                let value = max * percent as i64 / 100;
+                if let Err(e) = set_through_logind(device, value).await {
+                    // Writable without logind if a udev rule opened it up
+                    debug!("Cannot set the brightness through logind ({e}), writing to {}", device.display());
+                    fs::write(device.join("brightness"), value.to_string())
+                        .map_err(|write_error| format!("Cannot set the brightness of {}: {e}, {write_error}", device.display()))?;
+                }
+                Ok(percent)
+            }
+            Backlight::Software => {
+                let connector = connector.to_string();
+                let level = percent as f64 / 100.0;
+                let applied = tokio::task::spawn_blocking(move || {
+                    crate::gamma::set_brightness(&connector, level)
+                })
+                .await
+                .map_err(|e| e.to_string())??;
+                Ok((applied * 100.0).round() as i32)
+            }
+        }
+    }
+}
+
+/// Preferred backlight device, following the firmware > platform > raw
+/// order used by systemd-backlight
+fn sysfs_device() -> Option<PathBuf> {
+    let mut devices: Vec<(usize, PathBuf)> = fs::read_dir(SYSFS_BACKLIGHT)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let path = entry.path();
+            let kind = fs::read_to_string(path.join("type")).unwrap_or_default();
+            let rank = match kind.trim() {
+                "firmware" => 0,
+                "platform" => 1,
+                _ => 2,
+            };
+            (rank, path)
+        })
+        .collect();
+    devices.sort();
+    let device = devices.into_iter().next().map(|(_, path)| path);
+    debug!("Backlight device: {device:?}");
+    device
+}
+
+/// Set the brightness of the backlight `device` to `value` with logind's
+/// `SetBrightness` on the session of the daemon
+async fn set_through_logind(device: &Path, value: i64) -> zbus::Result<()> {
+    let name = device.file_name().unwrap_or_default().to_string_lossy();
+    let value = value.clamp(0, u32::MAX.into()) as u32;
+    let connection = zbus::Connection::system().await?;
+    connection
+        .call_method(
+            Some("org.freedesktop.login1"),
+            "/org/freedesktop/login1/session/auto",
+            Some("org.freedesktop.login1.Session"),
+            "SetBrightness",
+            &("backlight", name.as_ref(), value),
+        )
+        .await?;
+    Ok(())
+}
+
+fn read_value(path: &Path) -> Result<i64, String> {
+    fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read {}: {e}", path.display()))?
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid value in {}: {e}", path.display()))
+}
@@ -0,0 +1,123 @@
+//! Exporting the daemon's saved state to a single JSON bundle and importing
+//! it back, for backups and moving a configuration to another machine.
+//!
+//! A bundle holds the layout mode, the kanshi profiles and the JSON layouts
+//! (see [`restore`](crate::restore)). Everything else the daemon knows is
+//! rebuilt from sway when it starts.
+use crate::{config, get_kanshi_paths, kanshi, layout::LayoutMode, restore};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, error::Error, fs, path::Path};
+
+/// Version written to new bundles. Bundles with another version are refused.
+pub const BUNDLE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Bundle {
+    pub version: u32,
+    /// See [`LayoutMode`], `None` if it was never changed
+    pub layout_mode: Option<u32>,
+    /// kanshi profiles by file name
+    pub profiles: BTreeMap<String, String>,
+    /// JSON layouts by file name
+    pub layouts: BTreeMap<String, String>,
+}
+
+/// Contents of the regular files in `dir` by file name, empty if `dir`
+/// doesn't exist
+fn read_dir(dir: &Path, extension: Option<&str>) -> Result<BTreeMap<String, String>, Box<dyn Error>> {
+    let mut files = BTreeMap::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(files),
+        Err(e) => return Err(format!("{}: {e}", dir.display()).into()),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if !path.is_file() || extension.is_some_and(|ext| path.extension().and_then(|e| e.to_str()) != Some(ext)) {
+            continue;
+        }
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        files.insert(name.to_string(), fs::read_to_string(&path)?);
+    }
+    Ok(files)
+}
+
+/// Gather the saved state into a bundle
+pub async fn export() -> Result<Bundle, Box<dyn Error>> {
+    let profiles = read_dir(&get_kanshi_paths().await?.profiles, None)?;
+    let layouts = match config::current().layouts_dir() {
+        Some(dir) => read_dir(&dir, Some("json"))?,
+        None => BTreeMap::new(),
+    };
+    Ok(Bundle {
+        version: BUNDLE_VERSION,
+        layout_mode: LayoutMode::load().map(|mode| mode as u32),
+        profiles,
+        layouts,
+    })
+}
+
+/// Parse a bundle, checking its version first so that bundles from other
+/// versions are reported as such rather than as malformed
+pub fn parse(text: &str) -> Result<Bundle, String> {
+    let value: serde_json::Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    match value.get("version").and_then(serde_json::Value::as_u64) {
+        Some(version) if version == u64::from(BUNDLE_VERSION) => {}
+        Some(version) => {
+            return Err(format!(
+                "unsupported bundle version {version}, expected {BUNDLE_VERSION}"
+            ))
+        }
+        None => return Err(String::from("not a regolith-displayd bundle, no version")),
+    }
+    let bundle: Bundle = serde_json::from_value(value).map_err(|e| e.to_string())?;
+    bundle.validate()?;
+    Ok(bundle)
+}
+
+impl Bundle {
+    /// Check every file before anything is written
+    fn validate(&self) -> Result<(), String> {
+        if let Some(mode) = self.layout_mode {
+            LayoutMode::from_u32(mode).ok_or_else(|| format!("invalid layout mode {mode}"))?;
+        }
+        for name in self.profiles.keys().chain(self.layouts.keys()) {
+            if name.is_empty() || name.contains('/') || name.starts_with('.') {
+                return Err(format!("invalid file name {name:?}"));
+            }
+        }
+        for (name, text) in &self.profiles {
+            kanshi::parse(text).map_err(|e| format!("profile {name}: {e}"))?;
+        }
+        for (name, text) in &self.layouts {
+            restore::parse(text).map_err(|e| format!("layout {name}: {e}"))?;
+        }
+        Ok(())
+    }
+
+    /// Write the bundle's state, replacing files of the same name. Files the
+    /// bundle doesn't have are kept.
+    pub async fn import(&self) -> Result<(), Box<dyn Error>> {
+        let profiles_dir = get_kanshi_paths().await?.profiles;
+        fs::create_dir_all(&profiles_dir)?;
+        for (name, text) in &self.profiles {
+            fs::write(profiles_dir.join(name), text)?;
+        }
+        if !self.layouts.is_empty() {
+            let dir = config::current()
+                .layouts_dir()
+                .ok_or("no directory for JSON layouts, set displayd.layouts_dir")?;
+            fs::create_dir_all(&dir)?;
+            for (name, text) in &self.layouts {
+                fs::write(dir.join(name), text)?;
+            }
+        }
+        if let Some(mode) = self.layout_mode.and_then(LayoutMode::from_u32) {
+            mode.save()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,802 @@
+//! Daemon settings, read from the Regolith resource database (trawl).
+//!
+//! Every setting is optional and falls back to the previous hard-coded
+//! behaviour when its resource is unset or can't be parsed.
+//!
+//! The settings can be reloaded while the daemon runs (see `Reload`), and
+//! every one of them but those in [`STARTUP_SETTINGS`] takes effect without
+//! a restart: the watch loop, applies and `GetCurrentState` all read
+//! [`current`] each time they need a value.
+use crate::kanshi;
+use crate::monitor::SCALE_FILTERS;
+use crate::restore;
+use crate::{POLL_INTERVAL, STARTUP_QUIET_PERIOD};
+use lazy_static::lazy_static;
+use log::warn;
+use std::{
+    collections::BTreeSet,
+    fmt, fs, io,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use zbus::names::WellKnownName;
+use zvariant::ObjectPath;
+
+const MIN_POLL_INTERVAL_MS: u64 = 100;
+
+/// Settings read at startup only, which a reload keeps as they were (see
+/// [`Config::keep_startup_settings`])
+pub const STARTUP_SETTINGS: [&str; 5] = [
+    "displayd.bus_name",
+    "displayd.object_path",
+    "displayd.backend",
+    "displayd.supervise_kanshi",
+    "displayd.autoswitch",
+];
+
+/// Environment variable overriding `displayd.backend`, e.g. from a session's
+/// systemd unit
+pub const BACKEND_ENV: &str = "REGOLITH_DISPLAYD_BACKEND";
+
+lazy_static! {
+    static ref CONFIG: RwLock<Arc<Config>> = RwLock::new(Arc::new(Config::default()));
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    /// `displayd.min_mode_size`: modes smaller than `WIDTHxHEIGHT` are hidden
+    /// from `GetCurrentState`, except the current and preferred ones
+    pub min_mode_size: Option<(i32, i32)>,
+    /// `displayd.snap_refresh`: report refresh rates within a few mHz of an
+    /// integer (e.g. 59.999Hz) as that integer
+    pub snap_refresh: bool,
+    /// `displayd.managed_outputs`: comma separated connectors or output
+    /// descriptions (`Make Model Serial`) the daemon may change. Empty means
+    /// every output is managed.
+    pub managed_outputs: Vec<String>,
+    /// `displayd.excluded_outputs`: comma separated connectors or output
+    /// descriptions hidden from clients and profiles, like the outputs sway
+    /// flags as non-desktop
+    pub excluded_outputs: Vec<String>,
+    /// `displayd.reapply_on_wake`: reapply the saved profile when outputs
+    /// come back from DPMS off
+    pub reapply_on_wake: bool,
+    /// `displayd.profile_exec`: command added as an `exec` line to every
+    /// profile written, with `{outputs}` replaced by the profile's outputs
+    pub profile_exec: Option<String>,
+    /// `displayd.primary_exec`: like `profile_exec`, added only when the
+    /// apply marks a monitor primary, with `{primary}` replaced by its
+    /// connector
+    pub primary_exec: Option<String>,
+    /// `displayd.profile_exec_file`: file of more commands added like
+    /// `profile_exec`, one per line, [`default_exec_file`] when unset
+    pub profile_exec_file: Option<PathBuf>,
+    /// `displayd.kanshi_reload`: shell command making kanshi reread its
+    /// config, with `{config}` replaced by the config path. Unset restarts
+    /// kanshi.
+    pub kanshi_reload: Option<String>,
+    /// `displayd.mutter_compat`: report the extra mutter properties as a
+    /// working mutter would, for clients that check them before continuing
+    pub mutter_compat: bool,
+    /// `displayd.adaptive_sync`: adaptive sync for outputs whose apply
+    /// doesn't set it. Unset leaves it to sway.
+    pub adaptive_sync: Option<bool>,
+    /// `displayd.scale_filter`: scale filter for outputs whose apply doesn't
+    /// set it. Unset leaves it to sway.
+    pub scale_filter: Option<String>,
+    /// `displayd.builtin_output`: connector of the built-in panel, overriding
+    /// the detection by connector type
+    pub builtin_output: Option<String>,
+    /// `displayd.allow_overlap`: accept layouts where outputs overlap or have
+    /// negative positions
+    pub allow_overlap: bool,
+    /// `displayd.auto_scale`: scale monitors connected without a saved
+    /// profile according to their DPI
+    pub auto_scale: bool,
+    /// `displayd.poll_interval_ms`: how often the watch loop polls sway when
+    /// it doesn't get output events and during startup, [`POLL_INTERVAL`]
+    /// when unset
+    pub poll_interval: Option<Duration>,
+    /// `displayd.startup_quiet_ms`: how long the outputs must stay unchanged
+    /// after the daemon starts before the first `MonitorsChanged`,
+    /// [`STARTUP_QUIET_PERIOD`] when unset
+    pub startup_quiet_period: Option<Duration>,
+    /// `displayd.min_apply_interval_ms`: applies coming sooner than this
+    /// after the previous one are refused. Unset doesn't limit them.
+    pub min_apply_interval: Option<Duration>,
+    /// `displayd.layouts_dir`: directory of JSON layouts (see [`restore`]),
+    /// [`restore::default_dir`] when unset
+    pub layouts_dir: Option<PathBuf>,
+    /// `displayd.hotplug_fallback`: what to do with monitors connected
+    /// without a saved profile or JSON layout for the new set of outputs
+    pub hotplug_fallback: HotplugFallback,
+    /// `displayd.night_light`: follow gnome-settings-daemon's night light
+    /// settings, tinting the outputs through the gamma control
+    pub night_light: bool,
+    /// `displayd.xft_dpi`: publish `Xft.dpi` for the XWayland scale (see
+    /// [`xwayland`](crate::xwayland)) when a configuration is applied. Off
+    /// by default, as it replaces the `Xft.dpi` the user set.
+    pub xft_dpi: bool,
+    /// `displayd.bus_name`: well-known name to own instead of
+    /// [`BUS_NAME`](crate::BUS_NAME), read at startup only
+    pub bus_name: Option<String>,
+    /// `displayd.object_path`: path to serve the interfaces at instead of
+    /// [`OBJECT_PATH`](crate::OBJECT_PATH), read at startup only
+    pub object_path: Option<String>,
+    /// `displayd.profile_retention_days`: profiles whose outputs haven't been
+    /// connected for this many days are removed at startup, 0 never removes
+    /// them. Unset only removes them on `PruneProfiles`, after
+    /// [`DEFAULT_PROFILE_RETENTION_DAYS`](crate::profiles::DEFAULT_PROFILE_RETENTION_DAYS).
+    pub profile_retention_days: Option<u32>,
+    /// `displayd.manage_kanshi_include`: add the include of the profiles
+    /// directory to kanshi's config at startup if it is missing
+    pub manage_kanshi_include: bool,
+    /// `displayd.supervise_kanshi`: run kanshi as a child of the daemon (see
+    /// [`supervisor`](crate::supervisor)), read at startup only
+    pub supervise_kanshi: bool,
+    /// `displayd.single_kanshi_config`: copy every profile into a section of
+    /// kanshi's config instead of having it include the profiles directory
+    pub single_kanshi_config: bool,
+    /// `displayd.backend`: the compositor to drive, read at startup only.
+    /// Overridden by [`BACKEND_ENV`]. Unset detects it, see
+    /// [`backend_candidates`](Config::backend_candidates).
+    pub backend: Option<BackendKind>,
+    /// `displayd.autoswitch`: apply the saved profiles when the monitors
+    /// change from the daemon itself (see [`autoswitch`](crate::autoswitch))
+    /// instead of kanshi, which is stopped. Read at startup only.
+    pub autoswitch: bool,
+    /// `displayd.niri_output_config`: on niri, save the profile for the
+    /// connected outputs to niri's config instead of leaving it to kanshi
+    pub niri_output_config: bool,
+    /// Resources that were set, to tell configured values from defaults
+    resolved: BTreeSet<&'static str>,
+    /// Settings taken from the environment instead
+    overridden: BTreeSet<&'static str>,
+    /// Settings given on the command line, see [`Config::set_from_cli`]
+    from_cli: BTreeSet<&'static str>,
+}
+
+/// Setting `displayd.hotplug_fallback`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum HotplugFallback {
+    /// `none`: leave the new monitors to sway
+    #[default]
+    None,
+    /// `arrange`: place the new monitors right of the enabled ones, at their
+    /// preferred mode
+    Arrange,
+    /// `profile:NAME`: apply the kanshi profile `NAME` from the profiles
+    /// directory, whatever outputs it lists
+    Profile(String),
+}
+
+/// Setting `displayd.backend`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// `sway`: sway's IPC
+    Sway,
+    /// `wlr`: any wlroots compositor, through the wlr output protocols.
+    /// `river` is accepted for it as well.
+    Wlr,
+    /// `niri`: niri's IPC
+    Niri,
+    /// `x11`: an X server, such as the i3 session's, through `xrandr`
+    X11,
+}
+
+/// Where the value of a setting comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    Config,
+    Env,
+    /// A command line option such as `--backend`
+    Cli,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setting {
+    pub name: String,
+    pub value: String,
+    pub source: Source,
+}
+
+#[derive(Default)]
+struct Loader {
+    resolved: BTreeSet<&'static str>,
+}
+
+impl Config {
+    pub async fn load() -> Config {
+        let mut loader = Loader::default();
+        let mut config = Config {
+            min_mode_size: loader.get("displayd.min_mode_size", parse_size).await,
+            snap_refresh: loader
+                .get("displayd.snap_refresh", parse_bool)
+                .await
+                .unwrap_or(false),
+            managed_outputs: loader
+                .get("displayd.managed_outputs", parse_list)
+                .await
+                .unwrap_or_default(),
+            excluded_outputs: loader
+                .get("displayd.excluded_outputs", parse_list)
+                .await
+                .unwrap_or_default(),
+            reapply_on_wake: loader
+                .get("displayd.reapply_on_wake", parse_bool)
+                .await
+                .unwrap_or(false),
+            profile_exec: loader.get("displayd.profile_exec", parse_command).await,
+            primary_exec: loader
+                .get("displayd.primary_exec", parse_primary_command)
+                .await,
+            profile_exec_file: loader
+                .get("displayd.profile_exec_file", parse_string)
+                .await
+                .map(PathBuf::from),
+            kanshi_reload: loader
+                .get("displayd.kanshi_reload", parse_reload_command)
+                .await,
+            mutter_compat: loader
+                .get("displayd.mutter_compat", parse_bool)
+                .await
+                .unwrap_or(false),
+            adaptive_sync: loader.get("displayd.adaptive_sync", parse_bool).await,
+            scale_filter: loader
+                .get("displayd.scale_filter", parse_scale_filter)
+                .await,
+            builtin_output: loader.get("displayd.builtin_output", parse_string).await,
+            allow_overlap: loader
+                .get("displayd.allow_overlap", parse_bool)
+                .await
+                .unwrap_or(false),
+            auto_scale: loader
+                .get("displayd.auto_scale", parse_bool)
+                .await
+                .unwrap_or(false),
+            poll_interval: loader
+                .get("displayd.poll_interval_ms", parse_interval)
+                .await,
+            startup_quiet_period: loader
+                .get("displayd.startup_quiet_ms", parse_millis)
+                .await,
+            min_apply_interval: loader
+                .get("displayd.min_apply_interval_ms", parse_millis)
+                .await
+                .filter(|interval| !interval.is_zero()),
+            layouts_dir: loader
+                .get("displayd.layouts_dir", parse_string)
+                .await
+                .map(PathBuf::from),
+            hotplug_fallback: loader
+                .get("displayd.hotplug_fallback", parse_fallback)
+                .await
+                .unwrap_or_default(),
+            night_light: loader
+                .get("displayd.night_light", parse_bool)
+                .await
+                .unwrap_or(true),
+            xft_dpi: loader
+                .get("displayd.xft_dpi", parse_bool)
+                .await
+                .unwrap_or(false),
+            bus_name: loader.get("displayd.bus_name", parse_bus_name).await,
+            object_path: loader.get("displayd.object_path", parse_object_path).await,
+            profile_retention_days: loader
+                .get("displayd.profile_retention_days", parse_days)
+                .await,
+            manage_kanshi_include: loader
+                .get("displayd.manage_kanshi_include", parse_bool)
+                .await
+                .unwrap_or(true),
+            supervise_kanshi: loader
+                .get("displayd.supervise_kanshi", parse_bool)
+                .await
+                .unwrap_or(false),
+            single_kanshi_config: loader
+                .get("displayd.single_kanshi_config", parse_bool)
+                .await
+                .unwrap_or(false),
+            backend: loader.get("displayd.backend", parse_backend).await,
+            niri_output_config: loader
+                .get("displayd.niri_output_config", parse_bool)
+                .await
+                .unwrap_or(false),
+            autoswitch: loader
+                .get("displayd.autoswitch", parse_bool)
+                .await
+                .unwrap_or(false),
+            resolved: BTreeSet::new(),
+            overridden: BTreeSet::new(),
+            from_cli: BTreeSet::new(),
+        };
+        config.resolved = loader.resolved;
+        if let Ok(value) = std::env::var(BACKEND_ENV) {
+            match parse_backend(value.trim()) {
+                Some(backend) => {
+                    config.backend = Some(backend);
+                    config.overridden.insert("displayd.backend");
+                }
+                None => warn!("Ignoring invalid value for {BACKEND_ENV}: {value}"),
+            }
+        }
+        config
+    }
+
+    /// Every setting with its effective value and where it comes from
+    pub fn settings(&self) -> Vec<Setting> {
+        let min_mode_size = match self.min_mode_size {
+            Some((width, height)) => format!("{width}x{height}"),
+            None => String::new(),
+        };
+        [
+            ("displayd.min_mode_size", min_mode_size),
+            ("displayd.snap_refresh", self.snap_refresh.to_string()),
+            ("displayd.managed_outputs", self.managed_outputs.join(",")),
+            ("displayd.excluded_outputs", self.excluded_outputs.join(",")),
+            ("displayd.reapply_on_wake", self.reapply_on_wake.to_string()),
+            (
+                "displayd.profile_exec",
+                self.profile_exec.clone().unwrap_or_default(),
+            ),
+            (
+                "displayd.primary_exec",
+                self.primary_exec.clone().unwrap_or_default(),
+            ),
+            (
+                "displayd.profile_exec_file",
+                self.profile_exec_file()
+                    .map(|path| path.display().to_string())
+                    .unwrap_or_default(),
+            ),
+            (
+                "displayd.kanshi_reload",
+                self.kanshi_reload.clone().unwrap_or_default(),
+            ),
+            ("displayd.mutter_compat", self.mutter_compat.to_string()),
+            (
+                "displayd.adaptive_sync",
+                self.adaptive_sync
+                    .map(|v| v.to_string())
+                    .unwrap_or_default(),
+            ),
+            (
+                "displayd.scale_filter",
+                self.scale_filter.clone().unwrap_or_default(),
+            ),
+            (
+                "displayd.builtin_output",
+                self.builtin_output.clone().unwrap_or_default(),
+            ),
+            ("displayd.allow_overlap", self.allow_overlap.to_string()),
+            ("displayd.auto_scale", self.auto_scale.to_string()),
+            (
+                "displayd.poll_interval_ms",
+                self.poll_interval().as_millis().to_string(),
+            ),
+            (
+                "displayd.startup_quiet_ms",
+                self.startup_quiet_period().as_millis().to_string(),
+            ),
+            (
+                "displayd.min_apply_interval_ms",
+                self.min_apply_interval
+                    .map(|interval| interval.as_millis().to_string())
+                    .unwrap_or_default(),
+            ),
+            (
+                "displayd.layouts_dir",
+                self.layouts_dir()
+                    .map(|dir| dir.display().to_string())
+                    .unwrap_or_default(),
+            ),
+            (
+                "displayd.hotplug_fallback",
+                self.hotplug_fallback.to_string(),
+            ),
+            ("displayd.night_light", self.night_light.to_string()),
+            ("displayd.xft_dpi", self.xft_dpi.to_string()),
+            ("displayd.bus_name", self.bus_name().to_string()),
+            ("displayd.object_path", self.object_path().to_string()),
+            (
+                "displayd.profile_retention_days",
+                self.profile_retention_days
+                    .map(|days| days.to_string())
+                    .unwrap_or_default(),
+            ),
+            (
+                "displayd.manage_kanshi_include",
+                self.manage_kanshi_include.to_string(),
+            ),
+            ("displayd.supervise_kanshi", self.supervise_kanshi.to_string()),
+            (
+                "displayd.single_kanshi_config",
+                self.single_kanshi_config.to_string(),
+            ),
+            ("displayd.backend", self.backend().to_string()),
+            (
+                "displayd.niri_output_config",
+                self.niri_output_config.to_string(),
+            ),
+            ("displayd.autoswitch", self.autoswitch.to_string()),
+        ]
+        .into_iter()
+        .map(|(name, value)| Setting {
+            name: name.to_string(),
+            value,
+            source: self.source(name),
+        })
+        .collect()
+    }
+
+    /// Record that `name` was set by a command line option, which takes
+    /// precedence over the resource and the environment
+    pub fn set_from_cli(&mut self, name: &'static str) {
+        self.from_cli.insert(name);
+    }
+
+    /// Keep the [`STARTUP_SETTINGS`] of `previous`, the config the daemon
+    /// started with, when the resources are read again for a reload. Changes
+    /// to them are only logged, they need a restart.
+    pub fn keep_startup_settings(&mut self, previous: &Config) {
+        for name in STARTUP_SETTINGS {
+            let changed = match name {
+                "displayd.bus_name" => {
+                    let changed = self.bus_name != previous.bus_name;
+                    self.bus_name = previous.bus_name.clone();
+                    changed
+                }
+                "displayd.object_path" => {
+                    let changed = self.object_path != previous.object_path;
+                    self.object_path = previous.object_path.clone();
+                    changed
+                }
+                "displayd.backend" => {
+                    let changed = self.backend != previous.backend;
+                    self.backend = previous.backend;
+                    changed
+                }
+                "displayd.supervise_kanshi" => {
+                    let changed = self.supervise_kanshi != previous.supervise_kanshi;
+                    self.supervise_kanshi = previous.supervise_kanshi;
+                    changed
+                }
+                "displayd.autoswitch" => {
+                    let changed = self.autoswitch != previous.autoswitch;
+                    self.autoswitch = previous.autoswitch;
+                    changed
+                }
+                _ => unreachable!(),
+            };
+            if changed && !previous.from_cli.contains(name) {
+                warn!("{name} changed, restart regolith-displayd for it to take effect");
+            }
+            for (sources, previous_sources) in [
+                (&mut self.resolved, &previous.resolved),
+                (&mut self.overridden, &previous.overridden),
+                (&mut self.from_cli, &previous.from_cli),
+            ] {
+                sources.remove(name);
+                if let Some(&name) = previous_sources.get(name) {
+                    sources.insert(name);
+                }
+            }
+        }
+    }
+
+    fn source(&self, name: &str) -> Source {
+        if self.from_cli.contains(name) {
+            Source::Cli
+        } else if self.overridden.contains(name) {
+            Source::Env
+        } else if self.resolved.contains(name) {
+            Source::Config
+        } else {
+            Source::Default
+        }
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        self.poll_interval.unwrap_or(POLL_INTERVAL)
+    }
+
+    pub fn layouts_dir(&self) -> Option<PathBuf> {
+        self.layouts_dir.clone().or_else(restore::default_dir)
+    }
+
+    pub fn profile_exec_file(&self) -> Option<PathBuf> {
+        self.profile_exec_file.clone().or_else(default_exec_file)
+    }
+
+    /// `exec` commands for a profile of `outputs`, from `displayd.profile_exec`
+    /// and `displayd.profile_exec_file`, and why the others were left out
+    pub fn exec_hooks(&self, outputs: &[String]) -> (Vec<String>, Vec<String>) {
+        let mut templates: Vec<String> = self.profile_exec.iter().cloned().collect();
+        if let Some(path) = self.profile_exec_file() {
+            match fs::read_to_string(&path) {
+                Ok(text) => templates.extend(
+                    text.lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                        .map(String::from),
+                ),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => warn!("Cannot read {}: {e}", path.display()),
+            }
+        }
+        let mut commands = Vec::new();
+        let mut errors = Vec::new();
+        for template in templates {
+            match kanshi::render_exec(&template, outputs) {
+                Ok(command) => commands.push(command),
+                Err(e) => errors.push(e),
+            }
+        }
+        (commands, errors)
+    }
+
+    pub fn startup_quiet_period(&self) -> Duration {
+        self.startup_quiet_period.unwrap_or(STARTUP_QUIET_PERIOD)
+    }
+
+    pub fn bus_name(&self) -> &str {
+        self.bus_name.as_deref().unwrap_or(crate::BUS_NAME)
+    }
+
+    pub fn object_path(&self) -> &str {
+        self.object_path.as_deref().unwrap_or(crate::OBJECT_PATH)
+    }
+
+    /// The backend tried first, see [`backend_candidates`](Config::backend_candidates)
+    pub fn backend(&self) -> BackendKind {
+        self.backend_candidates()
+            .first()
+            .copied()
+            .unwrap_or(BackendKind::Sway)
+    }
+
+    /// The backends to try in order: the one set, or those the session's
+    /// environment points to. `$SWAYSOCK` means sway, `$NIRI_SOCKET` niri,
+    /// `$HYPRLAND_INSTANCE_SIGNATURE` or any other `$WAYLAND_DISPLAY` a
+    /// compositor with the wlr protocols and `$DISPLAY` an X server.
+    pub fn backend_candidates(&self) -> Vec<BackendKind> {
+        if let Some(backend) = self.backend {
+            return vec![backend];
+        }
+        let set = |name: &str| std::env::var_os(name).is_some_and(|value| !value.is_empty());
+        let mut candidates = Vec::new();
+        if set("SWAYSOCK") {
+            candidates.push(BackendKind::Sway);
+        }
+        if set("NIRI_SOCKET") {
+            candidates.push(BackendKind::Niri);
+        }
+        if set("HYPRLAND_INSTANCE_SIGNATURE") || set("WAYLAND_DISPLAY") {
+            candidates.push(BackendKind::Wlr);
+        }
+        if set("DISPLAY") && !set("WAYLAND_DISPLAY") {
+            candidates.push(BackendKind::X11);
+        }
+        candidates
+    }
+
+    /// Whether the output with `connector` and `description` may be changed
+    pub fn manages(&self, connector: &str, description: &str) -> bool {
+        self.managed_outputs.is_empty()
+            || self
+                .managed_outputs
+                .iter()
+                .any(|output| output == connector || output == description)
+    }
+
+    /// Whether the output with `connector` and `description` is listed in
+    /// `displayd.excluded_outputs`
+    pub fn excludes(&self, connector: &str, description: &str) -> bool {
+        self.excluded_outputs
+            .iter()
+            .any(|output| output == connector || output == description)
+    }
+}
+
+/// Settings currently in effect
+pub fn current() -> Arc<Config> {
+    Arc::clone(&CONFIG.read().unwrap())
+}
+
+/// Replace the settings in effect
+pub fn set(config: Config) {
+    *CONFIG.write().unwrap() = Arc::new(config);
+}
+
+pub(crate) async fn resource(name: &str) -> Option<String> {
+    trawlcat::rescat(name, None).await.ok()
+}
+
+impl Loader {
+    async fn get<T>(&mut self, name: &'static str, parse: fn(&str) -> Option<T>) -> Option<T> {
+        let value = resource(name).await?;
+        let parsed = parse(value.trim());
+        match parsed {
+            Some(_) => {
+                self.resolved.insert(name);
+            }
+            None => warn!("Ignoring invalid value for {name}: {value}"),
+        }
+        parsed
+    }
+}
+
+impl fmt::Display for HotplugFallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HotplugFallback::None => write!(f, "none"),
+            HotplugFallback::Arrange => write!(f, "arrange"),
+            HotplugFallback::Profile(name) => write!(f, "profile:{name}"),
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendKind::Sway => write!(f, "sway"),
+            BackendKind::Wlr => write!(f, "wlr"),
+            BackendKind::Niri => write!(f, "niri"),
+            BackendKind::X11 => write!(f, "x11"),
+        }
+    }
+}
+
+impl Source {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Source::Default => "default",
+            Source::Config => "config",
+            Source::Env => "env",
+            Source::Cli => "cli",
+        }
+    }
+}
+
+/// Environment variables the daemon depends on, unset ones included
+pub fn environment() -> Vec<Setting> {
+    [
+        BACKEND_ENV,
+        "SWAYSOCK",
+        "NIRI_SOCKET",
+        "HYPRLAND_INSTANCE_SIGNATURE",
+        "WAYLAND_DISPLAY",
+        "DISPLAY",
+        "XDG_CONFIG_HOME",
+        "XDG_RUNTIME_DIR",
+        "HOME",
+    ]
+    .into_iter()
+    .map(|name| Setting {
+        name: name.to_string(),
+        value: std::env::var(name).unwrap_or_else(|_| String::from("(unset)")),
+        source: Source::Env,
+    })
+    .collect()
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_string(value: &str) -> Option<String> {
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Parse a comma separated list, dropping empty items
+fn parse_list(value: &str) -> Option<Vec<String>> {
+    Some(
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(String::from)
+            .collect(),
+    )
+}
+
+/// File of `exec` commands read when `displayd.profile_exec_file` is unset
+pub fn default_exec_file() -> Option<PathBuf> {
+    Some(PathBuf::from(std::env::var("HOME").ok()?).join(".config/regolith3/displayd/profile-exec"))
+}
+
+/// Parse an `exec` command template, rejecting ones kanshi couldn't read
+fn parse_command(value: &str) -> Option<String> {
+    match kanshi::render_exec(value, &[]) {
+        Ok(_) => Some(value.to_string()),
+        Err(e) => {
+            warn!("{e}");
+            None
+        }
+    }
+}
+
+/// [`parse_command`] for `displayd.primary_exec`, which may use `{primary}`
+fn parse_primary_command(value: &str) -> Option<String> {
+    match kanshi::render_primary_exec(value, "primary", &[]) {
+        Ok(_) => Some(value.to_string()),
+        Err(e) => {
+            warn!("{e}");
+            None
+        }
+    }
+}
+
+/// Parse a reload command, rejecting placeholders other than `{config}`
+fn parse_reload_command(value: &str) -> Option<String> {
+    if value.replace("{config}", "").contains(['{', '}']) {
+        warn!("Only {{config}} can be substituted in displayd.kanshi_reload: {value}");
+        return None;
+    }
+    parse_string(value)
+}
+
+/// A valid well-known bus name, for `displayd.bus_name` and `--bus-name`
+pub fn parse_bus_name(value: &str) -> Option<String> {
+    WellKnownName::try_from(value).ok().map(|name| name.to_string())
+}
+
+/// A valid object path, for `displayd.object_path` and `--object-path`
+pub fn parse_object_path(value: &str) -> Option<String> {
+    ObjectPath::try_from(value).ok().map(|path| path.to_string())
+}
+
+fn parse_scale_filter(value: &str) -> Option<String> {
+    let value = value.to_lowercase();
+    SCALE_FILTERS.contains(&value.as_str()).then_some(value)
+}
+
+/// Parse a poll interval in milliseconds, refusing intervals short enough to
+/// keep sway busy
+fn parse_interval(value: &str) -> Option<Duration> {
+    parse_millis(value).filter(|interval| interval.as_millis() >= MIN_POLL_INTERVAL_MS.into())
+}
+
+/// Parse a duration in milliseconds
+fn parse_millis(value: &str) -> Option<Duration> {
+    Some(Duration::from_millis(value.parse().ok()?))
+}
+
+/// Parse a number of days
+fn parse_days(value: &str) -> Option<u32> {
+    value.parse().ok()
+}
+
+fn parse_fallback(value: &str) -> Option<HotplugFallback> {
+    match value {
+        "none" => Some(HotplugFallback::None),
+        "arrange" => Some(HotplugFallback::Arrange),
+        _ => {
+            let name = value.strip_prefix("profile:")?.trim();
+            // Profiles are read from the profiles directory only
+            (!name.is_empty() && !name.contains('/')).then(|| HotplugFallback::Profile(name.to_string()))
+        }
+    }
+}
+
+pub fn parse_backend(value: &str) -> Option<BackendKind> {
+    match value {
+        "sway" => Some(BackendKind::Sway),
+        "wlr" | "river" => Some(BackendKind::Wlr),
+        "niri" => Some(BackendKind::Niri),
+        "x11" => Some(BackendKind::X11),
+        _ => None,
+    }
+}
+
+/// Parse `WIDTHxHEIGHT`
+fn parse_size(value: &str) -> Option<(i32, i32)> {
+    let (width, height) = value.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
@@ -0,0 +1,299 @@
+//! Reading and parsing of the EDID blobs exposed under `/sys/class/drm`
+use std::{fmt, fs, path::PathBuf};
+
+const SYSFS_DRM: &str = "/sys/class/drm";
+const BLOCK_SIZE: usize = 128;
+const HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edid {
+    /// Three letter PNP id, e.g. `DEL`
+    pub manufacturer: String,
+    pub product: u16,
+    pub serial: u32,
+    /// Name from the monitor name descriptor
+    pub name: Option<String>,
+    /// Serial from the serial number descriptor
+    pub serial_string: Option<String>,
+    pub width_mm: Option<i32>,
+    pub height_mm: Option<i32>,
+    /// Width, height and refresh rate in mHz of the first detailed timing,
+    /// the monitor's preferred mode
+    pub preferred_mode: Option<(i32, i32, i32)>,
+    /// The CTA-861 extension advertises the SMPTE ST 2084 (PQ) transfer
+    /// function in its HDR static metadata block
+    pub hdr: bool,
+    /// The monitor supports continuous frequencies and its range limits span
+    /// more than 10Hz of vertical refresh rates, which is what amdgpu takes
+    /// as capable of adaptive sync
+    pub adaptive_sync: bool,
+    pub raw: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdidError(String);
+
+impl fmt::Display for EdidError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for EdidError {}
+
+impl Edid {
+    /// Read the EDID of the DRM connector sway calls `connector` (e.g. `DP-1`)
+    pub fn read(connector: &str) -> Result<Edid, EdidError> {
+        let path = sysfs_path(connector)
+            .ok_or_else(|| EdidError(format!("no DRM connector named {connector}")))?;
+        let data = fs::read(&path)
+            .map_err(|e| EdidError(format!("cannot read {}: {e}", path.display())))?;
+        Self::parse(&data)
+    }
+
+    /// Parse the base EDID block. Empty, truncated or corrupt data is an error,
+    /// never a panic.
+    pub fn parse(data: &[u8]) -> Result<Edid, EdidError> {
+        if data.is_empty() {
+            return Err(EdidError(String::from("EDID is empty")));
+        }
+        if data.len() < BLOCK_SIZE {
+            return Err(EdidError(format!(
+                "EDID is truncated ({} bytes)",
+                data.len()
+            )));
+        }
+        let block = &data[..BLOCK_SIZE];
+        if block[..8] != HEADER {
+            return Err(EdidError(String::from("EDID header is invalid")));
+        }
+        if block.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err(EdidError(String::from("EDID checksum mismatch")));
+        }
+
+        let id = u16::from_be_bytes([block[8], block[9]]);
+        let manufacturer = [(id >> 10) & 0x1f, (id >> 5) & 0x1f, id & 0x1f]
+            .iter()
+            .map(|&c| (b'A' - 1 + c as u8) as char)
+            .collect();
+        let product = u16::from_le_bytes([block[10], block[11]]);
+        let serial = u32::from_le_bytes([block[12], block[13], block[14], block[15]]);
+
+        const CONTINUOUS_FREQUENCY: u8 = 1 << 0;
+        let continuous_frequency = block[24] & CONTINUOUS_FREQUENCY != 0;
+
+        let mut edid = Edid {
+            manufacturer,
+            product,
+            serial,
+            name: None,
+            serial_string: None,
+            // Basic display parameters only give the size in cm, 0 if unknown
+            width_mm: (block[21] != 0).then(|| block[21] as i32 * 10),
+            height_mm: (block[22] != 0).then(|| block[22] as i32 * 10),
+            preferred_mode: None,
+            hdr: data[BLOCK_SIZE..].chunks_exact(BLOCK_SIZE).any(cta_supports_pq),
+            adaptive_sync: false,
+            raw: data.to_vec(),
+        };
+
+        for (index, descriptor) in block[54..126].chunks(18).enumerate() {
+            if descriptor[0] != 0 || descriptor[1] != 0 {
+                // The first detailed timing descriptor is the preferred mode, and
+                // has the size in mm
+                if index == 0 {
+                    edid.preferred_mode = detailed_timing(descriptor);
+                    let width = descriptor[12] as i32 | ((descriptor[14] as i32 & 0xf0) << 4);
+                    let height = descriptor[13] as i32 | ((descriptor[14] as i32 & 0x0f) << 8);
+                    if width > 0 && height > 0 {
+                        edid.width_mm = Some(width);
+                        edid.height_mm = Some(height);
+                    }
+                }
+                continue;
+            }
+            match descriptor[3] {
+                0xfc => edid.name = descriptor_text(descriptor),
+                0xff => edid.serial_string = descriptor_text(descriptor),
+                0xfd => edid.adaptive_sync = continuous_frequency && vertical_range(descriptor) > 10,
+                _ => {}
+            }
+        }
+        Ok(edid)
+    }
+}
+
+/// Width, height and refresh rate in mHz of a detailed timing descriptor
+fn detailed_timing(descriptor: &[u8]) -> Option<(i32, i32, i32)> {
+    // In units of 10kHz
+    let pixel_clock = u16::from_le_bytes([descriptor[0], descriptor[1]]) as i64 * 10_000;
+    let width = descriptor[2] as i32 | ((descriptor[4] as i32 & 0xf0) << 4);
+    let h_blank = descriptor[3] as i32 | ((descriptor[4] as i32 & 0x0f) << 8);
+    let height = descriptor[5] as i32 | ((descriptor[7] as i32 & 0xf0) << 4);
+    let v_blank = descriptor[6] as i32 | ((descriptor[7] as i32 & 0x0f) << 8);
+    let total = (width + h_blank) as i64 * (height + v_blank) as i64;
+    if width == 0 || height == 0 || total == 0 {
+        return None;
+    }
+    Some((width, height, (pixel_clock * 1000 / total) as i32))
+}
+
+/// Whether the extension `block` is a CTA-861 block whose HDR static metadata
+/// data block lists the PQ transfer function
+fn cta_supports_pq(block: &[u8]) -> bool {
+    const CTA_TAG: u8 = 0x02;
+    const EXTENDED_TAG: u8 = 7;
+    const HDR_STATIC_METADATA: u8 = 0x06;
+    const EOTF_PQ: u8 = 1 << 2;
+    if block[0] != CTA_TAG {
+        return false;
+    }
+    // Data blocks run from byte 4 to the first detailed timing descriptor
+    let end = (block[2] as usize).clamp(4, BLOCK_SIZE - 1);
+    let mut offset = 4;
+    while offset < end {
+        let tag = block[offset] >> 5;
+        let length = (block[offset] & 0x1f) as usize;
+        if tag == EXTENDED_TAG
+            && length >= 2
+            && offset + 2 < end
+            && block[offset + 1] == HDR_STATIC_METADATA
+        {
+            return block[offset + 2] & EOTF_PQ != 0;
+        }
+        offset += length + 1;
+    }
+    false
+}
+
+/// Width in Hz of the vertical refresh rates of a range limits descriptor
+fn vertical_range(descriptor: &[u8]) -> i32 {
+    // Rates above 255Hz are flagged in byte 4 and stored less 255
+    let offset = |flag: u8| if descriptor[4] & flag != 0 { 255 } else { 0 };
+    let min = descriptor[5] as i32 + offset(1 << 0);
+    let max = descriptor[6] as i32 + offset(1 << 1);
+    max - min
+}
+
+/// Text of a display descriptor, terminated by a newline and padded with spaces
+fn descriptor_text(descriptor: &[u8]) -> Option<String> {
+    let text: String = descriptor[5..18]
+        .iter()
+        .take_while(|&&byte| byte != b'\n')
+        .map(|&byte| byte as char)
+        .collect();
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// FNV-1a hash of the raw EDID `data`, telling monitors apart in profile names
+/// when sway has no usable description for them. Unlike the std hashers it is
+/// stable across builds, as the names are saved.
+pub fn fingerprint(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Whether a DRM connector named `connector` exists
+pub fn has_connector(connector: &str) -> bool {
+    sysfs_path(connector).is_some()
+}
+
+/// `/sys/class/drm/cardN-<connector>/edid`
+fn sysfs_path(connector: &str) -> Option<PathBuf> {
+    let suffix = format!("-{connector}");
+    fs::read_dir(SYSFS_DRM)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .find(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            name.starts_with("card") && name.ends_with(&suffix)
+        })
+        .map(|entry| entry.path().join("edid"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A base block for a 527x296mm monitor whose preferred mode is
+    /// 1920x1080@60Hz, named `name`
+    fn base_block(name: &str) -> Vec<u8> {
+        let mut block = vec![0u8; BLOCK_SIZE];
+        block[..8].copy_from_slice(&HEADER);
+        // DEL
+        block[8..10].copy_from_slice(&[0x10, 0xac]);
+        block[10..12].copy_from_slice(&0xa0f3u16.to_le_bytes());
+        block[12..16].copy_from_slice(&0x3031_4c4cu32.to_le_bytes());
+        block[21] = 53;
+        block[22] = 30;
+        // 148.5MHz, 1920+280 by 1080+45
+        block[54..72].copy_from_slice(&[
+            0x02, 0x3a, 0x80, 0x18, 0x71, 0x38, 0x2d, 0x40, 0, 0, 0, 0, 0x0f, 0x28, 0x21, 0, 0, 0x1e,
+        ]);
+        block[72..77].copy_from_slice(&[0, 0, 0, 0xfc, 0]);
+        let mut text = format!("{name}\n").into_bytes();
+        text.resize(13, b' ');
+        block[77..90].copy_from_slice(&text);
+        block[127] = 0u8.wrapping_sub(block.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)));
+        block
+    }
+
+    #[test]
+    fn parses_the_preferred_timing() {
+        let edid = Edid::parse(&base_block("DELL U2419H")).unwrap();
+        assert_eq!(edid.manufacturer, "DEL");
+        assert_eq!(edid.name.as_deref(), Some("DELL U2419H"));
+        assert_eq!((edid.width_mm, edid.height_mm), (Some(527), Some(296)));
+        assert_eq!(edid.preferred_mode, Some((1920, 1080, 60000)));
+    }
+
+    #[test]
+    fn rejects_short_and_corrupt_edid() {
+        let block = base_block("DELL U2419H");
+        assert!(Edid::parse(&[]).is_err());
+        assert!(Edid::parse(&block[..100]).is_err());
+        let mut corrupt = block.clone();
+        corrupt[20] ^= 0xff;
+        assert!(Edid::parse(&corrupt).is_err());
+        // Extension blocks that were cut off are left out
+        let mut extended = block;
+        extended[126] = 1;
+        extended[127] = extended[127].wrapping_sub(1);
+        extended.extend_from_slice(&[0x02, 0x03]);
+        assert!(Edid::parse(&extended).is_ok());
+    }
+
+    /// A CTA-861 extension block holding `data_blocks`
+    fn cta_block(data_blocks: &[u8]) -> Vec<u8> {
+        let mut block = vec![0u8; BLOCK_SIZE];
+        block[..3].copy_from_slice(&[0x02, 0x03, 4 + data_blocks.len() as u8]);
+        block[4..4 + data_blocks.len()].copy_from_slice(data_blocks);
+        block
+    }
+
+    #[test]
+    fn finds_pq_in_the_hdr_static_metadata() {
+        // A video data block with two VICs comes first
+        let video = [0x42, 0x10, 0x04];
+        let hdr = |eotfs: u8| [0xe3, 0x06, eotfs, 0x01];
+        let cases = [
+            // Traditional SDR and PQ
+            (cta_block(&[video.as_slice(), &hdr(0x05)].concat()), true),
+            // Traditional SDR and HLG only
+            (cta_block(&[video.as_slice(), &hdr(0x09)].concat()), false),
+            (cta_block(&video), false),
+            (cta_block(&[]), false),
+            // Not a CTA block
+            ([vec![0xf0], cta_block(&hdr(0x05))[1..].to_vec()].concat(), false),
+        ];
+        for (block, pq) in cases {
+            assert_eq!(cta_supports_pq(&block), pq, "{:02x?}", &block[..12]);
+        }
+        // Data blocks end where the detailed timings start
+        let mut cut = cta_block(&[video.as_slice(), &hdr(0x05)].concat());
+        cut[2] = 4 + video.len() as u8;
+        assert!(!cta_supports_pq(&cut));
+    }
+}
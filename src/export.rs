@@ -0,0 +1,122 @@
+//! Exporting the current layout in the syntax of other output tools
+use crate::{
+    backend,
+    monitor::{self, LogicalMonitor, Monitor, MonitorTransform},
+};
+
+/// Build a `wlr-randr` invocation that recreates the given layout.
+///
+/// Active outputs get their mode, position, scale and transform; outputs
+/// without a logical monitor are turned off.
+pub fn wlr_randr_command(monitors: &[Monitor], logical_monitors: &[LogicalMonitor]) -> String {
+    let mut args = vec![String::from("wlr-randr")];
+    for monitor in monitors {
+        let connector = monitor.get_connector();
+        args.push(format!("--output {connector}"));
+        let Some(logical_monitor) = logical_monitors
+            .iter()
+            .find(|mon| mon.get_connector() == connector)
+        else {
+            args.push(String::from("--off"));
+            continue;
+        };
+        let (x_pos, y_pos) = logical_monitor.position();
+        let transform = MonitorTransform::from_u32(logical_monitor.transform())
+            .unwrap_or(MonitorTransform::Normal);
+        if monitor.get_current_size().is_some() {
+            args.push(format!("--mode {}", monitor.get_current_mode()));
+        }
+        args.push(format!("--pos {x_pos},{y_pos}"));
+        args.push(format!("--scale {}", logical_monitor.scale()));
+        args.push(format!("--transform {}", transform.to_sway()));
+    }
+    args.join(" ")
+}
+
+/// sway `output` commands that recreate the given layout. Outputs are enabled
+/// before any is disabled, as sway refuses to disable its last output. On
+/// X11, the primary output is made primary again.
+pub fn sway_commands(monitors: &[Monitor], logical_monitors: &[LogicalMonitor]) -> Vec<String> {
+    let mut enable = Vec::new();
+    let mut disable = Vec::new();
+    let mut primary = None;
+    for monitor in monitors {
+        let connector = monitor.get_connector();
+        let Some(logical_monitor) = logical_monitors
+            .iter()
+            .find(|mon| mon.get_connector() == connector)
+        else {
+            disable.push(format!("output {connector} disable"));
+            continue;
+        };
+        let mut command = format!("output {connector} enable");
+        if let Some(mode) = monitor.current_mode() {
+            command.push_str(&format!(" mode {}", mode.sway_id().unwrap_or(mode.get_modestr())));
+        }
+        let (x_pos, y_pos) = logical_monitor.position();
+        let transform = MonitorTransform::from_u32(logical_monitor.transform())
+            .unwrap_or(MonitorTransform::Normal);
+        command.push_str(&format!(
+            " position {x_pos} {y_pos} scale {} transform {}",
+            logical_monitor.scale(),
+            transform.to_sway()
+        ));
+        enable.push(command);
+        if logical_monitor.is_primary() {
+            primary = backend::primary_command(connector);
+        }
+    }
+    enable.extend(disable);
+    enable.extend(primary);
+    enable
+}
+
+/// kanshi profile for the built-in panel and any single other output, which
+/// kanshi enables at its preferred mode. `placement` puts it to the `right`
+/// of or `below` the panel, which keeps its current configuration, or makes
+/// it the `only` active output.
+pub fn any_external_profile(
+    monitors: &[Monitor],
+    logical_monitors: &[LogicalMonitor],
+    placement: &str,
+) -> Result<String, String> {
+    let builtin = monitors
+        .iter()
+        .find(|monitor| monitor::is_builtin_connector(monitor.get_connector()) && !monitor.is_virtual())
+        .ok_or("No built-in panel is connected")?;
+    let name = builtin.kanshi_name(monitors);
+    let panel = logical_monitors
+        .iter()
+        .find(|mon| mon.get_connector() == builtin.get_connector());
+    let mut lines = vec![String::from("profile \"Any external monitor\" {")];
+    let external_position = match (placement, panel) {
+        ("only", _) => {
+            lines.push(format!("\toutput \"{name}\" disable"));
+            (0, 0)
+        }
+        ("right" | "below", Some(panel)) => {
+            let (width, height) = panel
+                .logical_size(monitors)
+                .ok_or("The size of the built-in panel is unknown")?;
+            let transform = MonitorTransform::from_u32(panel.transform())
+                .unwrap_or(MonitorTransform::Normal);
+            lines.push(format!(
+                "\toutput \"{name}\" mode {} position 0,0 transform {} scale {} enable",
+                builtin.get_current_mode(),
+                transform.to_sway(),
+                panel.scale()
+            ));
+            if placement == "right" {
+                (width, 0)
+            } else {
+                (0, height)
+            }
+        }
+        ("right" | "below", None) => return Err(String::from("The built-in panel is disabled")),
+        _ => return Err(format!("Unknown placement {placement}, expected right, below or only")),
+    };
+    let (x_pos, y_pos) = external_position;
+    lines.push(format!("\toutput * position {x_pos},{y_pos} enable"));
+    lines.push(String::from("}"));
+    Ok(lines.join("\n") + "\n")
+}
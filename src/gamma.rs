@@ -0,0 +1,381 @@
+//! Software dimming and night light through the wlr gamma-control protocol.
+//!
+//! A gamma table only stays applied while the client that set it keeps its
+//! gamma control alive, so a dedicated thread owns the Wayland connection for
+//! the lifetime of the daemon and applies the requests sent to it. An output
+//! has a single table, which combines its brightness with the color
+//! temperature.
+use lazy_static::lazy_static;
+use log::{debug, error};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Seek, SeekFrom, Write},
+    os::fd::{AsFd, FromRawFd},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Mutex,
+    },
+    thread,
+};
+use wayland_client::{
+    delegate_noop,
+    protocol::{wl_output::WlOutput, wl_registry::WlRegistry},
+    Connection, Dispatch, EventQueue, QueueHandle,
+};
+use wayland_protocols_wlr::gamma_control::v1::client::{
+    zwlr_gamma_control_manager_v1::ZwlrGammaControlManagerV1,
+    zwlr_gamma_control_v1::{self, ZwlrGammaControlV1},
+};
+
+/// Lowest software brightness, so an output can't be dimmed to black by accident
+pub const MIN_BRIGHTNESS: f64 = 0.2;
+
+lazy_static! {
+    static ref WORKER: Mutex<Option<Sender<Request>>> = Mutex::new(None);
+}
+
+/// Neutral color temperature, in Kelvin, which leaves the colors alone
+pub const NEUTRAL_TEMPERATURE: u32 = 6500;
+
+enum Change {
+    Brightness { connector: String, brightness: f64 },
+    /// Color temperature of every output, `None` for neutral
+    Temperature(Option<u32>),
+}
+
+struct Request {
+    change: Change,
+    reply: Sender<Result<(), String>>,
+}
+
+#[derive(Default)]
+struct OutputState {
+    output: Option<WlOutput>,
+    name: Option<String>,
+    control: Option<ZwlrGammaControlV1>,
+    gamma_size: Option<u32>,
+    failed: bool,
+    /// Software brightness, `None` for full
+    brightness: Option<f64>,
+}
+
+#[derive(Default)]
+struct State {
+    manager: Option<ZwlrGammaControlManagerV1>,
+    /// Outputs keyed by their registry name
+    outputs: HashMap<u32, OutputState>,
+    temperature: Option<u32>,
+}
+
+/// Dim `connector` to `brightness` (0.0 - 1.0), clamped to [`MIN_BRIGHTNESS`].
+/// Full brightness drops the gamma table, restoring the compositor's own.
+///
+/// Blocks until the gamma worker has applied the table.
+pub fn set_brightness(connector: &str, brightness: f64) -> Result<f64, String> {
+    let brightness = brightness.clamp(MIN_BRIGHTNESS, 1.0);
+    send(Change::Brightness {
+        connector: connector.to_string(),
+        brightness,
+    })?;
+    Ok(brightness)
+}
+
+/// Tint every output to the color temperature `kelvin`, or back to neutral
+/// for `None`. Outputs connected later get it the next time this is called.
+///
+/// Blocks until the gamma worker has applied the tables.
+pub fn set_temperature(kelvin: Option<u32>) -> Result<(), String> {
+    send(Change::Temperature(kelvin))
+}
+
+fn send(change: Change) -> Result<(), String> {
+    let (reply, response) = mpsc::channel();
+    let request = Request { change, reply };
+    {
+        let mut worker = WORKER.lock().unwrap();
+        let sender = worker.get_or_insert_with(spawn_worker);
+        if sender.send(request).is_err() {
+            *worker = None;
+            return Err(String::from("Gamma worker is not running"));
+        }
+    }
+    response
+        .recv()
+        .map_err(|_| String::from("Gamma worker stopped"))?
+}
+
+fn spawn_worker() -> Sender<Request> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || run(receiver));
+    sender
+}
+
+fn run(receiver: Receiver<Request>) {
+    let connection = match Connection::connect_to_env() {
+        Ok(connection) => connection,
+        Err(e) => {
+            error!("Cannot connect to the wayland display for gamma control: {e}");
+            for request in receiver {
+                let _ = request.reply.send(Err(e.to_string()));
+            }
+            return;
+        }
+    };
+    let mut queue = connection.new_event_queue();
+    let qh = queue.handle();
+    connection.display().get_registry(&qh, ());
+    let mut state = State::default();
+    for request in receiver {
+        let result = apply(&mut queue, &qh, &mut state, &request);
+        let _ = request.reply.send(result);
+    }
+}
+
+fn apply(
+    queue: &mut EventQueue<State>,
+    qh: &QueueHandle<State>,
+    state: &mut State,
+    request: &Request,
+) -> Result<(), String> {
+    // Catch up on output hotplugs and names
+    queue.roundtrip(state).map_err(|e| e.to_string())?;
+    match &request.change {
+        Change::Brightness {
+            connector,
+            brightness,
+        } => {
+            let (&id, output) = state
+                .outputs
+                .iter_mut()
+                .find(|(_, output)| output.name.as_deref() == Some(connector.as_str()))
+                .ok_or(format!("No wayland output named {connector}"))?;
+            output.brightness = (*brightness < 1.0).then_some(*brightness);
+            update(queue, qh, state, id)?;
+            debug!("Software brightness of {connector} set to {brightness}");
+            Ok(())
+        }
+        Change::Temperature(kelvin) => {
+            state.temperature = kelvin.filter(|&kelvin| kelvin != NEUTRAL_TEMPERATURE);
+            let ids: Vec<u32> = state.outputs.keys().copied().collect();
+            let mut result = Ok(());
+            for id in ids {
+                if let Err(e) = update(queue, qh, state, id) {
+                    error!("Cannot set the color temperature: {e}");
+                    result = Err(e);
+                }
+            }
+            debug!("Color temperature set to {kelvin:?}");
+            result
+        }
+    }
+}
+
+/// Apply the brightness and color temperature of output `id`, dropping its
+/// gamma table, and so restoring the compositor's own, when both are neutral
+fn update(
+    queue: &mut EventQueue<State>,
+    qh: &QueueHandle<State>,
+    state: &mut State,
+    id: u32,
+) -> Result<(), String> {
+    let manager = state
+        .manager
+        .clone()
+        .ok_or("Compositor doesn't support wlr-gamma-control")?;
+    let temperature = state.temperature;
+    let output = state.outputs.get_mut(&id).ok_or("Output disappeared")?;
+    let connector = output.name.clone().unwrap_or_else(|| format!("output {id}"));
+
+    if output.brightness.is_none() && temperature.is_none() {
+        if let Some(control) = output.control.take() {
+            control.destroy();
+            output.gamma_size = None;
+        }
+        queue.flush().map_err(|e| e.to_string())?;
+        return Ok(());
+    }
+
+    if output.control.is_none() {
+        let wl_output = output.output.as_ref().ok_or("Output not bound")?;
+        output.failed = false;
+        output.control = Some(manager.get_gamma_control(wl_output, qh, id));
+        queue.roundtrip(state).map_err(|e| e.to_string())?;
+    }
+    let output = state.outputs.get_mut(&id).ok_or("Output disappeared")?;
+    if output.failed {
+        if let Some(control) = output.control.take() {
+            control.destroy();
+        }
+        return Err(format!(
+            "Gamma control for {connector} is unavailable, another client may be holding it"
+        ));
+    }
+    let size = output
+        .gamma_size
+        .ok_or("Compositor didn't report a gamma size")?;
+    let brightness = output.brightness.unwrap_or(1.0);
+    let factors = whitepoint(temperature.unwrap_or(NEUTRAL_TEMPERATURE)).map(|f| f * brightness);
+    let ramp = ramp_file(size, factors).map_err(|e| e.to_string())?;
+    if let Some(control) = &output.control {
+        control.set_gamma(ramp.as_fd());
+    }
+    queue.flush().map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Red, green and blue factors (0.0 - 1.0) of a black body at `kelvin`,
+/// after Tanner Helland's approximation of the Planckian locus
+fn whitepoint(kelvin: u32) -> [f64; 3] {
+    if kelvin == NEUTRAL_TEMPERATURE {
+        return [1.0; 3];
+    }
+    let t = kelvin.clamp(1000, 40000) as f64 / 100.0;
+    let red = if t <= 66.0 {
+        255.0
+    } else {
+        329.698727446 * (t - 60.0).powf(-0.1332047592)
+    };
+    let green = if t <= 66.0 {
+        99.4708025861 * t.ln() - 161.1195681661
+    } else {
+        288.1221695283 * (t - 60.0).powf(-0.0755148492)
+    };
+    let blue = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.5177312231 * (t - 10.0).ln() - 305.0447927307
+    };
+    [red, green, blue].map(|c| (c / 255.0).clamp(0.0, 1.0))
+}
+
+/// Anonymous memory file holding the red, green and blue ramps, each scaled
+/// by its factor, as expected by `set_gamma`
+fn ramp_file(size: u32, factors: [f64; 3]) -> io::Result<File> {
+    // SAFETY: the name is a valid C string, and the descriptor returned is
+    // owned by nothing else
+    let mut file = unsafe {
+        let fd = libc::memfd_create(c"regolith-displayd-gamma".as_ptr(), libc::MFD_CLOEXEC);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        File::from_raw_fd(fd)
+    };
+    let steps = size.max(2) - 1;
+    for factor in factors {
+        let channel: Vec<u8> = (0..size)
+            .flat_map(|i| {
+                let value = (i as f64 / steps as f64) * factor * u16::MAX as f64;
+                (value as u16).to_ne_bytes()
+            })
+            .collect();
+        file.write_all(&channel)?;
+    }
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+impl Dispatch<WlRegistry, ()> for State {
+    fn event(
+        state: &mut Self,
+        registry: &WlRegistry,
+        event: <WlRegistry as wayland_client::Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        use wayland_client::protocol::wl_registry::Event;
+        match event {
+            Event::Global {
+                name,
+                interface,
+                version,
+            } => match interface.as_str() {
+                "wl_output" if version >= 4 => {
+                    let output = registry.bind::<WlOutput, _, _>(name, 4, qh, name);
+                    state.outputs.entry(name).or_default().output = Some(output);
+                }
+                "zwlr_gamma_control_manager_v1" => {
+                    state.manager = Some(registry.bind(name, 1, qh, ()));
+                }
+                _ => {}
+            },
+            Event::GlobalRemove { name } => {
+                if let Some(output) = state.outputs.remove(&name) {
+                    if let Some(control) = output.control {
+                        control.destroy();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<WlOutput, u32> for State {
+    fn event(
+        state: &mut Self,
+        _: &WlOutput,
+        event: <WlOutput as wayland_client::Proxy>::Event,
+        id: &u32,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let wayland_client::protocol::wl_output::Event::Name { name } = event {
+            state.outputs.entry(*id).or_default().name = Some(name);
+        }
+    }
+}
+
+impl Dispatch<ZwlrGammaControlV1, u32> for State {
+    fn event(
+        state: &mut Self,
+        _: &ZwlrGammaControlV1,
+        event: zwlr_gamma_control_v1::Event,
+        id: &u32,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        let Some(output) = state.outputs.get_mut(id) else {
+            return;
+        };
+        match event {
+            zwlr_gamma_control_v1::Event::GammaSize { size } => output.gamma_size = Some(size),
+            zwlr_gamma_control_v1::Event::Failed => output.failed = true,
+            _ => {}
+        }
+    }
+}
+
+delegate_noop!(State: ignore ZwlrGammaControlManagerV1);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_the_neutral_temperature_alone() {
+        assert_eq!(whitepoint(NEUTRAL_TEMPERATURE), [1.0; 3]);
+    }
+
+    #[test]
+    fn warms_lower_temperatures() {
+        let [red, green, blue] = whitepoint(2700);
+        assert_eq!(red, 1.0);
+        assert!(blue < green && green < 1.0, "{green} {blue}");
+        assert_eq!(whitepoint(1000)[2], 0.0);
+        // Past the clamp, every temperature is the coldest one
+        assert_eq!(whitepoint(100_000), whitepoint(40000));
+        let [red, _, blue] = whitepoint(10000);
+        assert!(red < 1.0);
+        assert_eq!(blue, 1.0);
+    }
+
+    #[test]
+    fn gets_bluer_as_the_temperature_rises() {
+        let blues: Vec<f64> = (2000..=6000).step_by(500).map(|kelvin| whitepoint(kelvin)[2]).collect();
+        assert!(blues.windows(2).all(|pair| pair[0] < pair[1]), "{blues:?}");
+    }
+}
@@ -0,0 +1,78 @@
+//! Record of the configurations applied through the daemon
+use crate::lock::{Mutex, Rank, Ranked};
+use std::{
+    collections::VecDeque,
+    fs,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use zbus::{fdo::DBusProxy, names::BusName, Connection, MessageHeader};
+
+/// Number of entries kept, older ones are dropped first
+const MAX_ENTRIES: usize = 50;
+
+#[derive(Debug, Clone)]
+pub struct ChangeRecord {
+    /// Seconds since the unix epoch
+    pub time: u64,
+    /// Who made the change, see [`describe_caller`]
+    pub caller: String,
+    pub method: u32,
+    pub profile: String,
+}
+
+#[derive(Clone, Default)]
+pub struct ChangeHistory {
+    entries: Arc<Mutex<VecDeque<ChangeRecord>>>,
+}
+
+impl Ranked for VecDeque<ChangeRecord> {
+    const RANK: Rank = Rank::History;
+}
+
+impl ChangeHistory {
+    pub async fn push(&self, caller: String, method: u32, profile: String) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs())
+            .unwrap_or(0);
+        let mut entries = self.entries.lock().await;
+        if entries.len() == MAX_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back(ChangeRecord {
+            time,
+            caller,
+            method,
+            profile,
+        });
+    }
+
+    /// Entries from oldest to newest
+    pub async fn entries(&self) -> Vec<ChangeRecord> {
+        self.entries.lock().await.iter().cloned().collect()
+    }
+}
+
+/// Unique bus name of the sender of `header`, followed by its process name
+/// and pid when the bus can tell them, e.g. `:1.42 (gnome-control-c, pid 1234)`
+pub async fn describe_caller(connection: &Connection, header: &MessageHeader<'_>) -> String {
+    let Ok(Some(sender)) = header.sender() else {
+        return String::from("unknown");
+    };
+    let sender = sender.to_string();
+    let Ok(bus_name) = BusName::try_from(sender.as_str()) else {
+        return sender;
+    };
+    let pid = match DBusProxy::new(connection).await {
+        Ok(proxy) => proxy.get_connection_unix_process_id(bus_name).await.ok(),
+        Err(_) => None,
+    };
+    match pid {
+        Some(pid) => {
+            let comm = fs::read_to_string(format!("/proc/{pid}/comm")).unwrap_or_default();
+            format!("{sender} ({}, pid {pid})", comm.trim())
+        }
+        None => sender,
+    }
+}
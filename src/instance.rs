@@ -0,0 +1,51 @@
+//! Single instance lock, so two daemons never fight over kanshi
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    os::fd::AsRawFd,
+    path::PathBuf,
+};
+
+/// Outcome of [`lock`]
+pub enum Instance {
+    /// This is the only instance for as long as the file is kept open
+    Acquired(File),
+    /// Another instance holds the lock, with its pid if it could be read
+    Running(Option<u32>),
+}
+
+/// `$XDG_RUNTIME_DIR/regolith-displayd.lock`
+pub fn lock_path() -> PathBuf {
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir).join("regolith-displayd.lock"),
+        _ => {
+            let user = std::env::var("USER").unwrap_or_default();
+            PathBuf::from(format!("/tmp/regolith-displayd-{user}.lock"))
+        }
+    }
+}
+
+/// Take the instance lock, writing our pid to the lock file
+pub fn lock() -> io::Result<Instance> {
+    let path = lock_path();
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+    // SAFETY: the descriptor belongs to `file`, which outlives the call
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        return Ok(Instance::Acquired(file));
+    }
+    let error = io::Error::last_os_error();
+    if error.kind() != io::ErrorKind::WouldBlock {
+        return Err(error);
+    }
+    let pid = fs::read_to_string(&path)
+        .ok()
+        .and_then(|pid| pid.trim().parse().ok());
+    Ok(Instance::Running(pid))
+}
@@ -0,0 +1,878 @@
+//! Parsing and checking of kanshi configuration files
+use crate::{monitor::Monitor, watcher};
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Options accepted by kanshi's `output` directive
+const OUTPUT_OPTIONS: [&str; 8] = [
+    "enable",
+    "disable",
+    "mode",
+    "position",
+    "scale",
+    "transform",
+    "adaptive_sync",
+    "alias",
+];
+
+/// Values of the `transform` output option
+pub(crate) const TRANSFORMS: [&str; 8] = [
+    "normal",
+    "90",
+    "180",
+    "270",
+    "flipped",
+    "flipped-90",
+    "flipped-180",
+    "flipped-270",
+];
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct KanshiConfig {
+    pub includes: Vec<String>,
+    pub profiles: Vec<Profile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Profile {
+    pub name: Option<String>,
+    pub outputs: Vec<OutputDirective>,
+    pub execs: Vec<String>,
+    /// Lines of the `profile` keyword and of the closing `}`, from 1
+    pub lines: (usize, usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputDirective {
+    /// Connector name, output description or `*`
+    pub criteria: String,
+    pub options: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Open,
+    Close,
+    Newline,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Profile {
+    /// The outputs the profile lists, sorted
+    pub fn criteria(&self) -> Vec<&str> {
+        let mut criteria: Vec<&str> = self.outputs.iter().map(|output| output.criteria.as_str()).collect();
+        criteria.sort_unstable();
+        criteria
+    }
+}
+
+impl OutputDirective {
+    /// Whether the criteria selects `monitor`
+    pub fn matches(&self, monitor: &Monitor) -> bool {
+        self.criteria == "*"
+            || self.criteria == monitor.get_connector()
+            || self.criteria == monitor.get_dpy_name()
+    }
+}
+
+/// The first profile in `profiles_dir` kanshi would pick for the connected
+/// `outputs`, given by connector and description, with the file it is in and
+/// the index of the output each of its directives selects
+pub fn matching_profile(
+    profiles_dir: &Path,
+    outputs: &[(&str, String)],
+) -> io::Result<Option<(PathBuf, Profile, Vec<usize>)>> {
+    let mut paths: Vec<PathBuf> = match fs::read_dir(profiles_dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    paths.sort();
+    for path in paths {
+        let Ok(config) = parse(&fs::read_to_string(&path)?) else {
+            continue;
+        };
+        for profile in config.profiles {
+            if let Some(selected) = select_outputs(&profile, outputs) {
+                return Ok(Some((path, profile, selected)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The index of the output of `outputs`, given by connector and description,
+/// each directive of `profile` selects, `None` unless kanshi would pick the
+/// profile for them
+pub fn select_outputs(profile: &Profile, outputs: &[(&str, String)]) -> Option<Vec<usize>> {
+    if profile.outputs.len() != outputs.len() {
+        return None;
+    }
+    let mut selected: Vec<usize> = Vec::new();
+    for directive in &profile.outputs {
+        let index = outputs.iter().enumerate().position(|(index, (connector, description))| {
+            let criteria = directive.criteria.as_str();
+            (criteria == "*" || criteria == *connector || criteria == description) && !selected.contains(&index)
+        })?;
+        selected.push(index);
+    }
+    Some(selected)
+}
+
+/// Fill in the `exec` command `template`, replacing `{outputs}` with the
+/// space separated `outputs`. The result is rejected if kanshi couldn't read
+/// it back as a single `exec` directive.
+pub fn render_exec(template: &str, outputs: &[String]) -> Result<String, String> {
+    let command = template.replace("{outputs}", &outputs.join(" "));
+    let command = command.trim();
+    if command.is_empty() {
+        return Err(String::from("exec command is empty"));
+    }
+    if command.contains(['\n', '\r']) {
+        return Err(String::from("exec command spans several lines"));
+    }
+    if command.contains(['{', '}']) {
+        return Err(format!("exec command contains a brace: {command}"));
+    }
+    if command.matches('"').count() % 2 == 1 {
+        return Err(format!("exec command has an unterminated quote: {command}"));
+    }
+    Ok(command.to_string())
+}
+
+/// [`render_exec`], also replacing `{primary}` with the `primary` connector
+pub fn render_primary_exec(template: &str, primary: &str, outputs: &[String]) -> Result<String, String> {
+    render_exec(&template.replace("{primary}", primary), outputs)
+}
+
+/// Sway commands with the same effect as applying the profiles in `text`.
+/// `exec` lines are only kept if they run `swaymsg`. Profiles for the same
+/// outputs as an earlier one are variants kanshi wouldn't pick, and skipped.
+pub fn sway_commands(text: &str) -> Result<Vec<String>, ParseError> {
+    let mut commands = Vec::new();
+    let config = parse(text)?;
+    let mut seen = Vec::new();
+    for profile in &config.profiles {
+        let criteria = profile.criteria();
+        if seen.contains(&criteria) {
+            continue;
+        }
+        seen.push(criteria);
+        for output in profile.outputs.iter().filter(|o| !o.options.is_empty()) {
+            commands.push(format!(
+                "output \"{}\" {}",
+                output.criteria,
+                output.options.join(" ")
+            ));
+        }
+        for exec in &profile.execs {
+            if let Some(command) = exec.strip_prefix("swaymsg ") {
+                commands.push(command.to_string());
+            }
+        }
+    }
+    Ok(commands)
+}
+
+/// Parse the contents of a kanshi config or profile file
+pub fn parse(text: &str) -> Result<KanshiConfig, ParseError> {
+    let tokens = tokenize(text)?;
+    let mut config = KanshiConfig::default();
+    let mut tokens = tokens.into_iter();
+    while let Some((line, token)) = tokens.next() {
+        match token {
+            Token::Newline => continue,
+            Token::Word(word) if word == "include" => match tokens.next() {
+                Some((_, Token::Word(path))) => config.includes.push(path),
+                _ => return Err(error(line, "include expects a path")),
+            },
+            Token::Word(word) if word == "profile" => {
+                let mut profile = Profile::default();
+                match tokens.next() {
+                    Some((_, Token::Word(name))) => {
+                        profile.name = Some(name);
+                        if !matches!(tokens.next(), Some((_, Token::Open))) {
+                            return Err(error(line, "expected '{' after profile name"));
+                        }
+                    }
+                    Some((_, Token::Open)) => {}
+                    _ => return Err(error(line, "expected '{' after profile")),
+                }
+                parse_profile(&mut tokens, &mut profile, line)?;
+                config.profiles.push(profile);
+            }
+            Token::Word(word) => {
+                return Err(error(line, &format!("unknown directive '{word}'")));
+            }
+            Token::Open | Token::Close => return Err(error(line, "unexpected brace")),
+        }
+    }
+    Ok(config)
+}
+
+fn parse_profile<I>(tokens: &mut I, profile: &mut Profile, start: usize) -> Result<(), ParseError>
+where
+    I: Iterator<Item = (usize, Token)>,
+{
+    let mut directive: Vec<String> = Vec::new();
+    let mut directive_line = start;
+    for (line, token) in tokens.by_ref() {
+        match token {
+            Token::Word(word) => {
+                if directive.is_empty() {
+                    directive_line = line;
+                }
+                directive.push(word);
+            }
+            Token::Newline => {
+                finish_directive(profile, &mut directive, directive_line)?;
+            }
+            Token::Close => {
+                finish_directive(profile, &mut directive, directive_line)?;
+                profile.lines = (start, line);
+                return Ok(());
+            }
+            Token::Open => return Err(error(line, "unexpected '{' inside profile")),
+        }
+    }
+    Err(error(start, "profile is missing its closing '}'"))
+}
+
+fn finish_directive(
+    profile: &mut Profile,
+    directive: &mut Vec<String>,
+    line: usize,
+) -> Result<(), ParseError> {
+    if directive.is_empty() {
+        return Ok(());
+    }
+    let words = std::mem::take(directive);
+    match words[0].as_str() {
+        "output" => {
+            let criteria = words
+                .get(1)
+                .cloned()
+                .ok_or(error(line, "output expects a name"))?;
+            let options = words[2..].to_vec();
+            validate_output_options(&options, line)?;
+            profile.outputs.push(OutputDirective { criteria, options });
+        }
+        "exec" => {
+            if words.len() < 2 {
+                return Err(error(line, "exec expects a command"));
+            }
+            // The tokenizer drops the quotes, which the shell needs back
+            let command: Vec<String> = words[1..]
+                .iter()
+                .map(|word| {
+                    if word.is_empty() || word.contains(char::is_whitespace) {
+                        format!("\"{word}\"")
+                    } else {
+                        word.clone()
+                    }
+                })
+                .collect();
+            profile.execs.push(command.join(" "));
+        }
+        other => return Err(error(line, &format!("unknown profile directive '{other}'"))),
+    }
+    Ok(())
+}
+
+fn validate_output_options(options: &[String], line: usize) -> Result<(), ParseError> {
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        if !OUTPUT_OPTIONS.contains(&option.as_str()) {
+            return Err(error(line, &format!("unknown output option '{option}'")));
+        }
+        if option == "enable" || option == "disable" {
+            continue;
+        }
+        let mut value = options.next();
+        if option == "mode" && value.map(String::as_str) == Some("--custom") {
+            value = options.next();
+        }
+        let Some(value) = value else {
+            return Err(error(line, &format!("{option} expects a value")));
+        };
+        if !valid_value(option, value) {
+            return Err(error(line, &format!("invalid {option} '{value}'")));
+        }
+    }
+    Ok(())
+}
+
+/// Whether kanshi accepts `value` for `option`, as `1920x1080@60Hz` for
+/// `mode`, `-1920,0` for `position`, etc
+fn valid_value(option: &str, value: &str) -> bool {
+    match option {
+        "mode" => valid_mode(value),
+        "position" => value
+            .split_once(',')
+            .is_some_and(|(x, y)| x.parse::<i32>().is_ok() && y.parse::<i32>().is_ok()),
+        "scale" => value.parse::<f64>().is_ok_and(|scale| scale.is_finite() && scale > 0.0),
+        "transform" => TRANSFORMS.contains(&value),
+        "adaptive_sync" => value == "on" || value == "off",
+        _ => true,
+    }
+}
+
+/// `WIDTHxHEIGHT`, optionally followed by `@REFRESH` or `@REFRESHHz`
+pub(crate) fn valid_mode(mode: &str) -> bool {
+    let (size, refresh) = match mode.split_once('@') {
+        Some((size, refresh)) => (size, Some(refresh.strip_suffix("Hz").unwrap_or(refresh))),
+        None => (mode, None),
+    };
+    let valid_size = size
+        .split_once('x')
+        .is_some_and(|(width, height)| width.parse::<u32>().is_ok() && height.parse::<u32>().is_ok());
+    let valid_refresh = match refresh {
+        Some(refresh) => refresh.parse::<f64>().is_ok_and(|r| r > 0.0),
+        None => true,
+    };
+    valid_size && valid_refresh
+}
+
+/// Check that `text` is a profile file kanshi can load, so writing it can't
+/// make kanshi reject the whole config including it
+pub fn validate_profile(text: &str) -> Result<(), ParseError> {
+    let config = parse(text)?;
+    if config.profiles.is_empty() {
+        return Err(error(1, "no profile"));
+    }
+    Ok(())
+}
+
+/// The profiles in `text` with their names, each as the lines it spans.
+/// Lines outside the profiles, such as comments before them, are left out.
+pub fn split_profiles(text: &str) -> Result<Vec<(Option<String>, String)>, ParseError> {
+    let lines: Vec<&str> = text.lines().collect();
+    Ok(parse(text)?
+        .profiles
+        .into_iter()
+        .map(|profile| {
+            let (first, last) = profile.lines;
+            let text = lines[first - 1..last].iter().map(|line| format!("{line}\n")).collect();
+            (profile.name, text)
+        })
+        .collect())
+}
+
+/// `text`, holding a single profile, with that profile named `name`, or
+/// left unnamed for `None`. The name is written quoted.
+pub fn rename_profile(text: &str, name: Option<&str>) -> Result<String, ParseError> {
+    if parse(text)?.profiles.len() != 1 {
+        return Err(error(1, "expected a single profile"));
+    }
+    let header = match name {
+        Some(name) => format!("profile \"{name}\" {{"),
+        None => String::from("profile {"),
+    };
+    let mut renamed = String::with_capacity(text.len() + header.len());
+    let mut done = false;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        match trimmed.strip_prefix("profile").and_then(header_end) {
+            Some(end) if !done => {
+                renamed.push_str(&line[..line.len() - trimmed.len()]);
+                renamed.push_str(&header);
+                renamed.push_str(&trimmed["profile".len() + end..]);
+                done = true;
+            }
+            _ => renamed.push_str(line),
+        }
+    }
+    Ok(renamed)
+}
+
+/// Offset just past the `{` opening a profile in `rest`, what follows
+/// `profile` on its line
+fn header_end(rest: &str) -> Option<usize> {
+    if !rest.starts_with(|c: char| c.is_whitespace() || c == '{') {
+        return None;
+    }
+    let name_start = rest.len() - rest.trim_start().len();
+    // A quoted name may hold braces
+    let search_from = match rest[name_start..].strip_prefix('"') {
+        Some(quoted) => name_start + 1 + quoted.find('"')? + 1,
+        None => name_start,
+    };
+    Some(search_from + rest[search_from..].find('{')? + 1)
+}
+
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let mut tokens = Vec::new();
+    for (index, raw_line) in text.lines().enumerate() {
+        let line = index + 1;
+        let mut chars = raw_line.chars().peekable();
+        while let Some(&c) = chars.peek() {
+            match c {
+                '#' => break,
+                '{' => {
+                    chars.next();
+                    tokens.push((line, Token::Open));
+                }
+                '}' => {
+                    chars.next();
+                    tokens.push((line, Token::Close));
+                }
+                '"' => {
+                    chars.next();
+                    let mut word = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some(c) => word.push(c),
+                            None => return Err(error(line, "unterminated quote")),
+                        }
+                    }
+                    tokens.push((line, Token::Word(word)));
+                }
+                c if c.is_whitespace() => {
+                    chars.next();
+                }
+                _ => {
+                    let mut word = String::new();
+                    while let Some(&c) = chars.peek() {
+                        if c.is_whitespace() || c == '{' || c == '}' || c == '"' || c == '#' {
+                            break;
+                        }
+                        word.push(c);
+                        chars.next();
+                    }
+                    tokens.push((line, Token::Word(word)));
+                }
+            }
+        }
+        tokens.push((line, Token::Newline));
+    }
+    Ok(tokens)
+}
+
+fn error(line: usize, message: &str) -> ParseError {
+    ParseError {
+        line,
+        message: message.to_string(),
+    }
+}
+
+/// Expand a leading `~` and `$HOME` in an include path
+pub fn expand_path(path: &str) -> PathBuf {
+    let home = std::env::var("HOME").unwrap_or_default();
+    let path = path.replace("$HOME", &home);
+    match path.strip_prefix('~') {
+        Some(rest) => PathBuf::from(format!("{home}{rest}")),
+        None => PathBuf::from(path),
+    }
+}
+
+/// Whether the include pattern `include` selects `file`. Only wildcards in
+/// the last path component are supported, which is what kanshi configs use
+/// in practice.
+pub fn include_matches(include: &str, file: &Path) -> bool {
+    let pattern = expand_path(include);
+    let (Some(dir), Some(name_pattern)) = (pattern.parent(), pattern.file_name()) else {
+        return false;
+    };
+    let (Some(file_dir), Some(file_name)) = (file.parent(), file.file_name()) else {
+        return false;
+    };
+    dir == file_dir
+        && wildcard_match(
+            &name_pattern.to_string_lossy(),
+            &file_name.to_string_lossy(),
+        )
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(remaining) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=remaining.len())
+                .filter(|&i| remaining.is_char_boundary(i))
+                .any(|i| wildcard_match(rest, &remaining[i..]))
+        }
+    }
+}
+
+/// Comment [`ensure_include`] puts above the include it adds
+const INCLUDE_COMMENT: &str = "# Added by regolith-displayd";
+
+/// Make sure `config` includes every profile in `profiles_dir`, creating the
+/// config if it doesn't exist and adding the include at its top if it is
+/// missing. The profiles [`write_single_config`] copied into it are removed,
+/// as kanshi would read them twice otherwise. Returns whether the file was
+/// changed. A config that doesn't parse is left alone.
+pub fn ensure_include(config: &Path, profiles_dir: &Path) -> io::Result<bool> {
+    let text = match fs::read_to_string(config) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let parsed = parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let included = parsed
+        .includes
+        .iter()
+        .any(|include| expand_path(include).starts_with(profiles_dir));
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let kept = match find_section(&lines, PROFILES_BEGIN, PROFILES_END) {
+        Some((first, last)) => [&lines[..first], &lines[last + 1..]].concat().concat(),
+        None if included => return Ok(false),
+        None => text.clone(),
+    };
+    if included {
+        fs::write(config, kept)?;
+        return Ok(true);
+    }
+    let pattern = profiles_dir.join("*").display().to_string();
+    let pattern = if pattern.contains(char::is_whitespace) {
+        format!("\"{pattern}\"")
+    } else {
+        pattern
+    };
+    if let Some(dir) = config.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(
+        config,
+        format!("{INCLUDE_COMMENT}\ninclude {pattern}\n{kept}"),
+    )?;
+    Ok(true)
+}
+
+/// Lines around the profiles copied into kanshi's config by
+/// [`write_single_config`]
+const PROFILES_BEGIN: &str = "# BEGIN regolith-displayd profiles";
+const PROFILES_END: &str = "# END regolith-displayd profiles";
+
+/// Indices of the `begin` and `end` lines of a section of `lines`
+pub(crate) fn find_section(lines: &[&str], begin: &str, end: &str) -> Option<(usize, usize)> {
+    let first = lines.iter().position(|line| line.trim() == begin)?;
+    let last = first + lines[first..].iter().position(|line| line.trim() == end)?;
+    Some((first, last))
+}
+
+/// Copy the profiles in `profiles_dir` into a section of `config`, replacing
+/// the section written before, and drop includes of `profiles_dir`, so kanshi
+/// reads everything from `config`. Lines outside the section are kept.
+/// Returns whether `config` changed.
+pub fn write_single_config(config: &Path, profiles_dir: &Path) -> io::Result<bool> {
+    let text = match fs::read_to_string(config) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let mut names: Vec<PathBuf> = match fs::read_dir(profiles_dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .filter(|path| {
+                path.file_name()
+                    .is_some_and(|name| !watcher::is_temporary(&name.to_string_lossy()))
+            })
+            .collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    names.sort();
+    let mut section = format!("{PROFILES_BEGIN}\n");
+    for path in &names {
+        let profile = fs::read_to_string(path)?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        section.push_str(&format!("# {name}\n{}", profile.trim_end()));
+        section.push('\n');
+    }
+    section.push_str(PROFILES_END);
+    section.push('\n');
+
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    // The section stays where it was, or goes at the end
+    let (before, after) = match find_section(&lines, PROFILES_BEGIN, PROFILES_END) {
+        Some((first, last)) => (&lines[..first], &lines[last + 1..]),
+        None => (&lines[..], &[][..]),
+    };
+    let mut updated = without_include(before, profiles_dir).concat();
+    if !updated.is_empty() && !updated.ends_with('\n') {
+        updated.push('\n');
+    }
+    updated.push_str(&section);
+    updated.push_str(&without_include(after, profiles_dir).concat());
+    if updated == text {
+        return Ok(false);
+    }
+    parse(&updated).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    if let Some(dir) = config.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(config, updated)?;
+    Ok(true)
+}
+
+/// `lines` without the includes of `profiles_dir`, and the comment
+/// [`ensure_include`] adds above them
+fn without_include<'a>(lines: &[&'a str], profiles_dir: &Path) -> Vec<&'a str> {
+    let mut kept: Vec<&str> = Vec::new();
+    for &line in lines {
+        let include = line
+            .trim()
+            .strip_prefix("include ")
+            .map(|path| path.trim().trim_matches('"'));
+        if include.is_some_and(|path| expand_path(path).starts_with(profiles_dir)) {
+            if kept.last().is_some_and(|last| last.trim() == INCLUDE_COMMENT) {
+                kept.pop();
+            }
+            continue;
+        }
+        kept.push(line);
+    }
+    kept
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{label}: {}", self.message)
+    }
+}
+
+/// Check that `config` includes the profiles in `profiles_dir` and that each
+/// of them parses. With `monitors`, output names used by the profiles that
+/// don't match any of them are reported as warnings. With `single_config`,
+/// the profiles are copied into `config` instead of included.
+pub fn check(
+    config: &Path,
+    profiles_dir: &Path,
+    monitors: Option<&[Monitor]>,
+    single_config: bool,
+) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut report = |severity, message: String| problems.push(Problem { severity, message });
+
+    let includes = match fs::read_to_string(config) {
+        Ok(text) => match parse(&text) {
+            Ok(parsed) => parsed.includes,
+            Err(e) => {
+                report(Severity::Error, format!("{}: {e}", config.display()));
+                Vec::new()
+            }
+        },
+        Err(e) => {
+            report(
+                Severity::Error,
+                format!("cannot read {}: {e}", config.display()),
+            );
+            Vec::new()
+        }
+    };
+
+    let mut entries: Vec<PathBuf> = match fs::read_dir(profiles_dir) {
+        Ok(dir) => dir
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .collect(),
+        Err(e) => {
+            report(
+                Severity::Error,
+                format!("cannot read {}: {e}", profiles_dir.display()),
+            );
+            Vec::new()
+        }
+    };
+    entries.sort();
+
+    if !entries.is_empty()
+        && !single_config
+        && !includes
+            .iter()
+            .any(|inc| expand_path(inc).starts_with(profiles_dir))
+    {
+        report(
+            Severity::Error,
+            format!(
+                "{} doesn't include {}",
+                config.display(),
+                profiles_dir.display()
+            ),
+        );
+    }
+
+    for path in &entries {
+        let display = path.display();
+        if !single_config && !includes.iter().any(|inc| include_matches(inc, path)) {
+            report(
+                Severity::Error,
+                format!("{display} is not included by the config"),
+            );
+        }
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => {
+                report(Severity::Error, format!("cannot read {display}: {e}"));
+                continue;
+            }
+        };
+        let parsed = match parse(&text) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                report(Severity::Error, format!("{display}: {e}"));
+                continue;
+            }
+        };
+        let Some(monitors) = monitors else {
+            continue;
+        };
+        for output in parsed.profiles.iter().flat_map(|p| &p.outputs) {
+            if !monitors.iter().any(|monitor| output.matches(monitor)) {
+                report(
+                    Severity::Warning,
+                    format!("{display}: output \"{}\" is not connected", output.criteria),
+                );
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A profile as the daemon writes it
+    const PROFILE: &str = "\
+profile \"Desk\" {
+\toutput \"Dell Inc. DELL U2720Q ABC123\" enable mode 3840x2160@59.997Hz position 0,0 scale 1.5 transform normal adaptive_sync off
+\toutput eDP-1 disable
+\texec swaymsg output \"Dell Inc. DELL U2720Q ABC123\" allow_tearing yes
+\texec notify-send \"Outputs: DP-1 eDP-1\"
+}
+";
+
+    #[test]
+    fn accepts_profiles_kanshi_loads() {
+        assert_eq!(validate_profile(PROFILE), Ok(()));
+        assert_eq!(validate_profile("# A comment\nprofile {\n\toutput * mode --custom 1280x720@60Hz\n}\n"), Ok(()));
+    }
+
+    #[test]
+    fn rejects_profiles_kanshi_would_reject() {
+        let cases = [
+            ("", 1),
+            ("include ~/profiles/*\n", 1),
+            ("profile {\n\toutput DP-1 colour purple\n}\n", 2),
+            ("profile {\n\toutput DP-1 mode\n}\n", 2),
+            ("profile {\n\toutput DP-1 enable\n", 1),
+            ("profile {\n\toutput \"DP-1 enable\n}\n", 2),
+            ("profile {\n\tworkspace 1\n}\n", 2),
+            ("profile {\n\texec\n}\n", 2),
+            ("profile {\n}\n}\n", 3),
+            ("output DP-1 enable\n", 1),
+        ];
+        for (text, line) in cases {
+            assert_eq!(validate_profile(text).map_err(|e| e.line), Err(line), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn checks_option_values() {
+        let cases = [
+            ("mode", "1920x1080", true),
+            ("mode", "1920x1080@60", true),
+            ("mode", "1920x1080@59.94Hz", true),
+            ("mode", "1920x1080@0Hz", false),
+            ("mode", "1920x", false),
+            ("mode", "preferred", false),
+            ("position", "-1920,0", true),
+            ("position", "0 0", false),
+            ("position", "0x0", false),
+            ("scale", "1.25", true),
+            ("scale", "0", false),
+            ("scale", "inf", false),
+            ("transform", "flipped-90", true),
+            ("transform", "45", false),
+            ("adaptive_sync", "on", true),
+            ("adaptive_sync", "yes", false),
+            ("alias", "$left", true),
+        ];
+        for (option, value, valid) in cases {
+            assert_eq!(valid_value(option, value), valid, "{option} {value}");
+        }
+    }
+
+    #[test]
+    fn splits_profiles_with_their_names() {
+        let text = format!("# Saved by hand\n{PROFILE}\nprofile {{\n\toutput eDP-1 enable\n}}\n");
+        let profiles = split_profiles(&text).unwrap();
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(profiles[0], (Some(String::from("Desk")), PROFILE.to_string()));
+        assert_eq!(profiles[1], (None, String::from("profile {\n\toutput eDP-1 enable\n}\n")));
+    }
+
+    #[test]
+    fn renames_profiles() {
+        let unnamed = PROFILE.replacen("profile \"Desk\" {", "profile {", 1);
+        assert_eq!(rename_profile(PROFILE, None).unwrap(), unnamed);
+        assert_eq!(rename_profile(&unnamed, Some("Desk")).unwrap(), PROFILE);
+        // A brace in a quoted name doesn't end the header
+        let braced = rename_profile(&unnamed, Some("{Desk}")).unwrap();
+        assert_eq!(split_profiles(&braced).unwrap()[0].0.as_deref(), Some("{Desk}"));
+        assert_eq!(rename_profile(&braced, Some("Desk")).unwrap(), PROFILE);
+        let two = format!("{PROFILE}{unnamed}");
+        assert!(rename_profile(&two, Some("Desk")).is_err());
+        assert!(rename_profile("profile {\n", Some("Desk")).is_err());
+    }
+
+    #[test]
+    fn turns_profiles_into_sway_commands() {
+        // The second profile is a variant for the same outputs, which kanshi wouldn't pick
+        let variant = PROFILE.replace("Desk", "Desk (2)").replace("scale 1.5", "scale 2");
+        let text = format!("{PROFILE}{variant}profile {{\n\toutput eDP-1\n\toutput HDMI-A-1 enable\n}}\n");
+        assert_eq!(
+            sway_commands(&text).unwrap(),
+            [
+                "output \"Dell Inc. DELL U2720Q ABC123\" enable mode 3840x2160@59.997Hz position 0,0 scale 1.5 transform normal adaptive_sync off",
+                "output \"eDP-1\" disable",
+                "output \"Dell Inc. DELL U2720Q ABC123\" allow_tearing yes",
+                "output \"HDMI-A-1\" enable",
+            ]
+        );
+        assert!(sway_commands("profile {\n\toutput DP-1 scale 0\n}\n").is_err());
+    }
+}
@@ -0,0 +1,234 @@
+//! Coordinate spaces for logical monitor positions.
+//!
+//! Sway reports output positions in its layout space, where every output
+//! occupies its mode size divided by its scale. The `layout-mode` property
+//! tells the client how to read the positions in `GetCurrentState`:
+//!
+//! * [`LayoutMode::Logical`] (1): positions are logical pixels. This is what
+//!   sway reports, so they are passed through unchanged.
+//! * [`LayoutMode::Physical`] (2): positions are physical pixels and every
+//!   monitor occupies its full mode size regardless of scale.
+//!
+//! A monitor's offset depends on the scale of the monitors before it, so the
+//! conversion can't be a single multiplication. Instead each axis is walked in
+//! order, keeping monitors that touch in one space touching in the other.
+use crate::monitor::{LogicalMonitor, Monitor, MonitorApply};
+use num_derive::FromPrimitive;
+use std::{fs, io, path::PathBuf};
+
+#[derive(Debug, Clone, Copy, FromPrimitive, PartialEq, Eq)]
+pub enum LayoutMode {
+    Logical = 1,
+    Physical = 2,
+}
+
+/// Position and size of a monitor as (x, y, width, height)
+pub type Rect = (i32, i32, i32, i32);
+
+/// Position and length of a monitor along one axis
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    pos: i32,
+    src_len: i32,
+    dst_len: i32,
+}
+
+impl LayoutMode {
+    pub fn from_u32(mode: u32) -> Option<LayoutMode> {
+        num::FromPrimitive::from_u32(mode)
+    }
+
+    /// Layout mode stored in the properties, falling back to logical
+    pub fn from_property(mode: Option<u32>) -> LayoutMode {
+        mode.and_then(Self::from_u32).unwrap_or(LayoutMode::Logical)
+    }
+
+    /// Layout mode chosen by the user in a previous session
+    pub fn load() -> Option<LayoutMode> {
+        let mode = fs::read_to_string(state_path()?).ok()?;
+        Self::from_u32(mode.trim().parse().ok()?)
+    }
+
+    /// Remember the layout mode across restarts
+    pub fn save(self) -> io::Result<()> {
+        let path = state_path()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "$HOME not defined"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, format!("{}\n", self as u32))
+    }
+}
+
+/// Check that no monitor has a negative position and that no two overlap.
+/// `rects` are (connector, (x, y, width, height)).
+pub fn check_layout(rects: &[(String, Rect)]) -> Result<(), String> {
+    for (connector, (x, y, _, _)) in rects {
+        if *x < 0 || *y < 0 {
+            return Err(format!("{connector} has a negative position {x},{y}"));
+        }
+    }
+    for (i, (connector, a)) in rects.iter().enumerate() {
+        for (other, b) in &rects[i + 1..] {
+            let overlaps_x = a.0 < b.0 + b.2 && b.0 < a.0 + a.2;
+            let overlaps_y = a.1 < b.1 + b.3 && b.1 < a.1 + a.3;
+            if overlaps_x && overlaps_y {
+                return Err(format!("{connector} overlaps {other}"));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `$XDG_STATE_HOME/regolith-displayd`, where state kept across restarts goes
+pub(crate) fn state_dir() -> Option<PathBuf> {
+    let state_home = match std::env::var("XDG_STATE_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var("HOME").ok()?).join(".local/state"),
+    };
+    Some(state_home.join("regolith-displayd"))
+}
+
+/// `$XDG_STATE_HOME/regolith-displayd/layout-mode`
+fn state_path() -> Option<PathBuf> {
+    Some(state_dir()?.join("layout-mode"))
+}
+
+/// Rewrite sway's logical positions into the coordinate space of `mode`
+pub fn to_layout_space(
+    mode: LayoutMode,
+    monitors: &[Monitor],
+    logical_monitors: &mut [LogicalMonitor],
+) {
+    if mode == LayoutMode::Logical {
+        return;
+    }
+    let items: Vec<_> = logical_monitors
+        .iter()
+        .map(|logical_monitor| Item {
+            position: logical_monitor.position(),
+            src: logical_monitor.logical_size(monitors).unwrap_or((0, 0)),
+            dst: logical_monitor.physical_size(monitors).unwrap_or((0, 0)),
+        })
+        .collect();
+    for (logical_monitor, (x, y)) in logical_monitors.iter_mut().zip(convert(&items)) {
+        logical_monitor.set_position(x, y);
+    }
+}
+
+/// Rewrite the positions of `requests`, given in the coordinate space of
+/// `mode`, into sway's logical positions
+pub fn from_layout_space(mode: LayoutMode, monitors: &[Monitor], requests: &mut [MonitorApply]) {
+    if mode == LayoutMode::Logical {
+        return;
+    }
+    let size = |request: &MonitorApply, mode| {
+        request
+            .rect(monitors, mode)
+            .map(|(_, _, width, height)| (width, height))
+            .unwrap_or((0, 0))
+    };
+    let items: Vec<_> = requests
+        .iter()
+        .map(|request| Item {
+            position: request.position(),
+            src: size(request, LayoutMode::Physical),
+            dst: size(request, LayoutMode::Logical),
+        })
+        .collect();
+    for (request, (x, y)) in requests.iter_mut().zip(convert(&items)) {
+        request.set_position(x, y);
+    }
+}
+
+/// A monitor's position with its size in the source and destination spaces
+struct Item {
+    position: (i32, i32),
+    src: (i32, i32),
+    dst: (i32, i32),
+}
+
+/// Positions of `items` in the destination space
+fn convert(items: &[Item]) -> Vec<(i32, i32)> {
+    let x_spans: Vec<_> = items
+        .iter()
+        .map(|item| Span {
+            pos: item.position.0,
+            src_len: item.src.0,
+            dst_len: item.dst.0,
+        })
+        .collect();
+    let y_spans: Vec<_> = items
+        .iter()
+        .map(|item| Span {
+            pos: item.position.1,
+            src_len: item.src.1,
+            dst_len: item.dst.1,
+        })
+        .collect();
+    convert_axis(&x_spans)
+        .into_iter()
+        .zip(convert_axis(&y_spans))
+        .collect()
+}
+
+/// Place every span right after its nearest predecessor in the destination
+/// space, carrying over any gap between them unchanged. Spans without a
+/// predecessor keep their position.
+fn convert_axis(spans: &[Span]) -> Vec<i32> {
+    let mut order: Vec<usize> = (0..spans.len()).collect();
+    order.sort_by_key(|&i| spans[i].pos);
+    let mut converted = vec![0; spans.len()];
+    for (n, &i) in order.iter().enumerate() {
+        let span = spans[i];
+        let predecessor = order[..n]
+            .iter()
+            .filter(|&&j| spans[j].pos + spans[j].src_len <= span.pos)
+            .max_by_key(|&&j| {
+                (
+                    spans[j].pos + spans[j].src_len,
+                    converted[j] + spans[j].dst_len,
+                )
+            });
+        converted[i] = match predecessor {
+            Some(&j) => {
+                let gap = span.pos - (spans[j].pos + spans[j].src_len);
+                converted[j] + spans[j].dst_len + gap
+            }
+            None => span.pos,
+        };
+    }
+    converted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Output;
+    use crate::testing::{self, mode};
+
+    fn output(name: &str, (width, height): (i32, i32), scale: f64, x: i32) -> Output {
+        Output {
+            rect: (x, 0, (width as f64 / scale) as i32, (height as f64 / scale) as i32),
+            scale: Some(scale),
+            ..testing::output(name, vec![mode(width, height, 60000, true)], x)
+        }
+    }
+
+    #[test]
+    fn reports_positions_after_a_fractional_scale() {
+        let outputs = [
+            output("DP-2", (2880, 1800), 1.5, 0),
+            output("DP-1", (2560, 1440), 1.0, 1920),
+        ];
+        let monitors: Vec<Monitor> = outputs.iter().map(Monitor::new).collect();
+        let logical_monitors: Vec<LogicalMonitor> = outputs.iter().map(LogicalMonitor::new).collect();
+        let positions = |mode| {
+            let mut logical_monitors = logical_monitors.clone();
+            to_layout_space(mode, &monitors, &mut logical_monitors);
+            logical_monitors.iter().map(LogicalMonitor::position).collect::<Vec<_>>()
+        };
+        assert_eq!(positions(LayoutMode::Logical), [(0, 0), (1920, 0)]);
+        assert_eq!(positions(LayoutMode::Physical), [(0, 0), (2880, 0)]);
+    }
+}
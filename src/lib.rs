@@ -1,22 +1,93 @@
+pub mod autoscale;
+pub mod autoswitch;
+pub mod backend;
+pub mod backup;
+pub mod backlight;
+pub mod config;
+pub mod edid;
+pub mod export;
+pub mod gamma;
+pub mod history;
+pub mod instance;
+pub mod kanshi;
+pub mod layout;
+pub mod lock;
 pub mod modes;
 pub mod monitor;
+pub mod nightlight;
+pub mod niri;
+pub mod previous;
+pub mod privacy;
+pub mod profiles;
+pub mod restore;
+pub mod revert;
+pub mod supervisor;
+pub mod toggle;
+pub mod wake;
+pub mod watcher;
+pub mod wlr;
+pub mod xrandr;
+pub mod xwayland;
 
+#[cfg(test)]
+mod testing;
+
+use backend::{ Backend, BackendError, BackendEvent, BackendEvents, Capabilities, DisplayBackend, Compositor, Output };
+use backlight::Backlight;
+use config::HotplugFallback;
 use core::fmt;
+use edid::Edid;
+use history::ChangeHistory;
+use layout::LayoutMode;
 use lazy_static::lazy_static;
 use log::{ debug, error, info, warn };
 use monitor::{ LogicalMonitor, Monitor, MonitorApply };
+use num_derive::FromPrimitive;
+use profiles::ProfileServer;
+use revert::{ PendingConfig, RevertState };
+use toggle::OutputMemory;
+use wake::WakeTracker;
 use serde::{ Deserialize, Serialize };
 use std::collections::{ HashMap, HashSet };
 use std::io::Write;
-use std::process::Command;
-use std::{ error::Error, fs::{ self, File }, path::PathBuf, sync::Arc, thread, time::Duration };
-use swayipc_async::Connection;
-use tokio::sync::Mutex;
-use zbus::{ dbus_interface, ConnectionBuilder, SignalContext };
+use std::{ error::Error, fs::{ self, File }, hash::Hash, path::PathBuf, sync::{ atomic::{ AtomicBool, AtomicU64, Ordering }, Arc, OnceLock }, time::{ Duration, Instant } };
+use tokio::{ process::Command, sync::Notify };
+use lock::{ Mutex, Rank, Ranked };
+use futures_util::StreamExt;
+use zbus::{ dbus_interface, fdo, names::WellKnownName, ConnectionBuilder, MessageHeader, SignalContext };
 use zvariant::{ DeserializeDict, SerializeDict, Type };
 
+/// Bus name of the emulated interface, see `displayd.bus_name`
+pub const BUS_NAME: &str = "org.gnome.Mutter.DisplayConfig";
+/// Path the interfaces are served at, see `displayd.object_path`
+pub const OBJECT_PATH: &str = "/org/gnome/Mutter/DisplayConfig";
+/// Default interval at which the watch loop polls sway for output changes
+/// when it doesn't get sway's output events, and while outputs settle at startup
+pub const POLL_INTERVAL: Duration = Duration::from_millis(700);
+/// Interval of the safety poll while sway's output events are received, in
+/// case an event is missed
+pub const FALLBACK_POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// Default time the outputs must stay unchanged at startup before the first
+/// `MonitorsChanged` is emitted
+pub const STARTUP_QUIET_PERIOD: Duration = Duration::from_millis(1500);
+/// Longest a profile write may take before `ApplyMonitorsConfig` gives up on it
+pub const PROFILE_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest width and height of outputs made with `CreateVirtualOutput`
+pub const MAX_VIRTUAL_OUTPUT_SIZE: i32 = 16384;
+
+/// Path the interfaces were served at, for emitting signals
+static SERVED_PATH: OnceLock<String> = OnceLock::new();
+
 lazy_static! {
     static ref ZBUS_CONNECTION: Arc<Mutex<Option<zbus::Connection>>> = Arc::new(Mutex::new(None));
+    /// Connectors sway last reported more than once, so the warning is only
+    /// logged when they change instead of on every poll
+    static ref DUPLICATE_CONNECTORS: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+    /// Connected outputs left out by `displayd.excluded_outputs`. kanshi still
+    /// sees them, unlike non-desktop outputs, so profiles list them as well.
+    static ref EXCLUDED_CONNECTORS: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+    /// Wakes the watch loop before its next poll is due, see `Rescan`
+    static ref RESCAN: Notify = Notify::new();
 }
 
 /// Stores configrations, interacts with sway IPC and monitors hardware changes
@@ -31,8 +102,12 @@ pub struct DisplayManager {
 /// DBus Interface for providing bindings
 pub struct DisplayServer {
     manager: Arc<Mutex<DisplayManager>>,
-    // TODO: Make independent of sway
-    sway_connection: Arc<Mutex<Connection>>,
+    backend: Arc<Compositor>,
+    revert: RevertState,
+    history: ChangeHistory,
+    outputs: OutputMemory,
+    /// When a configuration was last applied, see [`DisplayServer::check_apply_interval`]
+    last_apply: Arc<Mutex<Option<Instant>>>,
 }
 
 #[derive(Debug, Clone, SerializeDict, DeserializeDict, Type, PartialEq)]
@@ -48,14 +123,47 @@ pub struct DisplayManagerProperties {
     legacy_scale_factor: Option<i32>,
 }
 
+/// Outcome of an apply, see `ApplyMonitorsConfigWithResult`
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Type)]
+pub struct ApplyResult {
+    /// Name of the kanshi profile for the monitors
+    pub profile: String,
+    /// Connectors enabled by the configuration
+    pub enabled: Vec<String>,
+    /// Connectors disabled by the configuration
+    pub disabled: Vec<String>,
+    /// Connector of the monitor the configuration makes primary
+    pub primary: Option<String>,
+    pub kanshi_reloaded: bool,
+    /// Parts of the request that were ignored or adjusted
+    pub warnings: Vec<String>,
+}
+
+/// `method` argument of `ApplyMonitorsConfig`
+#[derive(Debug, Clone, Copy, FromPrimitive, PartialEq, Eq)]
+pub enum ApplyMethod {
+    /// Only check that the configuration could be applied
+    Verify = 0,
+    /// Apply without saving, reverting unless confirmed in time
+    Temporary = 1,
+    /// Apply and save to the kanshi profile
+    Persistent = 2,
+}
+
+impl ApplyMethod {
+    pub fn from_u32(method: u32) -> Option<ApplyMethod> {
+        num::FromPrimitive::from_u32(method)
+    }
+}
+
 #[derive(Debug)]
 pub struct ServerError {
     description: String,
 }
 
 pub struct KanshiPaths {
-    profiles: PathBuf,
-    config: PathBuf,
+    pub profiles: PathBuf,
+    pub config: PathBuf,
 }
 
 #[dbus_interface(name = "org.gnome.Mutter.DisplayConfig")]
@@ -63,7 +171,7 @@ impl DisplayServer {
     pub async fn get_current_state(&mut self) -> DisplayManager {
         info!("Recieved 'GetCurrentState' request from control-center");
         let manager_ref = self.manager.lock().await;
-        manager_ref.clone()
+        manager_ref.client_state()
     }
 
     pub async fn apply_monitors_config(
@@ -71,129 +179,626 @@ impl DisplayServer {
         serial: u32,
         method: u32,
         mutter_logical_monitors: Vec<MonitorApply>,
-        properties: DisplayManagerProperties
+        properties: DisplayManagerProperties,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>
     ) -> zbus::fdo::Result<()> {
-        debug!("Configuration Method: {method}");
-        let mut manager_obj = self.manager.lock().await;
-        debug!("Serial: {} {}", manager_obj.serial, serial);
-        if serial != manager_obj.serial {
-            error!("Invalid configuration recieved for method apply_monitors_config: Wrong serial");
-            return Err(zbus::fdo::Error::InvalidArgs(String::from("Wrong serial")));
-        }
-        let get_dpy_name = |mon: &MonitorApply| {
-            let monitor = mon.search_monitor(&manager_obj.monitors).unwrap();
-            monitor.get_dpy_name().replace(" ", "_")
-        };
+        let caller = history::describe_caller(ctxt.connection(), &header).await;
+        info!("ApplyMonitorsConfig (method {method}) from {caller}");
+        self.apply(caller, serial, method, &mutter_logical_monitors, properties).await?;
+        Ok(())
+    }
 
-        let mut monitors_sorted = manager_obj.monitors.clone();
+    /// `ApplyMonitorsConfig`, returning what was done as (profile, enabled
+    /// connectors, disabled connectors, whether kanshi was reloaded, warnings)
+    pub async fn apply_monitors_config_with_result(
+        &mut self,
+        serial: u32,
+        method: u32,
+        mutter_logical_monitors: Vec<MonitorApply>,
+        properties: DisplayManagerProperties,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>
+    ) -> zbus::fdo::Result<ApplyResult> {
+        let caller = history::describe_caller(ctxt.connection(), &header).await;
+        info!("ApplyMonitorsConfigWithResult (method {method}) from {caller}");
+        self.apply(caller, serial, method, &mutter_logical_monitors, properties).await
+    }
 
-        monitors_sorted.sort_by_key(|monitor| monitor.get_dpy_name());
+    /// The monitor on `connector` as in `GetCurrentState`, with its logical
+    /// monitor if it is enabled (an empty array otherwise)
+    pub async fn get_monitor(&self, connector: &str) -> zbus::fdo::Result<(Monitor, Vec<LogicalMonitor>)> {
+        let state = self.manager.lock().await.client_state();
+        let Some(monitor) = state.monitors.into_iter().find(|monitor| monitor.get_connector() == connector) else {
+            return Err(zbus::fdo::Error::InvalidArgs(format!("No monitor connected to {connector}")));
+        };
+        let logical_monitors = state.logical_monitors
+            .into_iter()
+            .filter(|logical_monitor| logical_monitor.get_connector() == connector)
+            .collect();
+        Ok((monitor, logical_monitors))
+    }
 
-        let profile_name = monitors_sorted
+    /// Turn the output on `connector` off, or back on with the settings it had
+    /// when it was turned off (see [`toggle`])
+    pub async fn set_output_enabled(&self, connector: &str, enabled: bool) -> zbus::fdo::Result<()> {
+        info!("SetOutputEnabled {connector} {enabled}");
+        check_unique_connectors()?;
+        let mut manager_obj = self.manager.lock().await;
+        let Some(monitor) = manager_obj.monitors.iter().find(|monitor| monitor.get_connector() == connector) else {
+            return Err(zbus::fdo::Error::InvalidArgs(format!("No monitor connected to {connector}")));
+        };
+        if !config::current().manages(connector, &monitor.get_dpy_name()) {
+            return Err(zbus::fdo::Error::AccessDenied(format!("{connector} is not managed")));
+        }
+        let logical_monitor = manager_obj.logical_monitors
             .iter()
-            .map(|mon| mon.get_dpy_name().replace(" ", "_"))
-            .collect::<Vec<String>>()
-            .join("__");
-        info!("Profile FileName: {profile_name}");
-
-        let kanshi_paths = get_kanshi_paths().await?;
+            .find(|logical_monitor| logical_monitor.get_connector() == connector);
+        let command = match (enabled, logical_monitor) {
+            (true, None) => self.outputs.enable(monitor, &manager_obj.monitors).await,
+            (false, Some(logical_monitor)) => {
+                if manager_obj.logical_monitors.len() == 1 {
+                    return Err(zbus::fdo::Error::Failed(format!("{connector} is the only enabled output")));
+                }
+                self.outputs.disable(monitor, &manager_obj.monitors, logical_monitor).await
+            }
+            _ => {
+                debug!("{connector} is already {}", if enabled { "enabled" } else { "disabled" });
+                return Ok(());
+            }
+        };
+        self.backend.apply(&[command]).await?;
+        if enabled {
+            // The output comes back with a neutral gamma ramp
+            nightlight::refresh().await;
+        }
+        match DisplayManager::get_monitor_info(self.backend.as_ref()).await {
+            Ok(display_info) => manager_obj.set_monitor_info(display_info),
+            Err(e) => error!("Error getting output information from sway: {e}"),
+        }
+        manager_obj.emit_monitors_changed().await?;
+        Ok(())
+    }
 
-        fs::create_dir_all(&kanshi_paths.profiles).unwrap();
-        let mut profile_file = File::options()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(kanshi_paths.profiles.join(&profile_name))
-            .expect("Error while opening profile file for writing");
+    /// Set the brightness of `output` to `value` percent, returning the value
+    /// applied. See [`backlight`] and [`DisplayManager::output`].
+    pub async fn change_backlight(&self, serial: u32, output: u32, value: i32) -> zbus::fdo::Result<i32> {
+        info!("ChangeBacklight {output} {value}");
+        check_unique_connectors()?;
+        let manager_obj = self.manager.lock().await;
+        manager_obj.check_serial(serial)?;
+        let monitor = manager_obj.output(output)?;
+        let connector = monitor.get_connector();
+        if !config::current().manages(connector, &monitor.get_dpy_name()) {
+            return Err(zbus::fdo::Error::AccessDenied(format!("{connector} is not managed")));
+        }
+        let builtin = monitor::is_builtin_connector(connector) && !monitor.is_virtual();
+        Backlight::for_output(builtin)
+            .set(connector, value).await
+            .map_err(zbus::fdo::Error::Failed)
+    }
 
-        // Profile Write buffer (Only written if no errors occur)
-        let mut profile_buf = Vec::new();
+    /// Create a headless output of `width` by `height` pixels with sway's
+    /// `create_output`, e.g. for a VNC server to show, and return its connector.
+    /// It is then reported and configured like any other monitor.
+    pub async fn create_virtual_output(&self, width: i32, height: i32) -> zbus::fdo::Result<String> {
+        info!("CreateVirtualOutput {width}x{height}");
+        let valid = 1..=MAX_VIRTUAL_OUTPUT_SIZE;
+        if !valid.contains(&width) || !valid.contains(&height) {
+            return Err(zbus::fdo::Error::InvalidArgs(format!("Invalid size {width}x{height}")));
+        }
+        let mut manager_obj = self.manager.lock().await;
+        let read_connectors = || async {
+            self.backend.outputs().await
+                .map(|outputs| outputs.into_iter().map(|output| output.name).collect::<HashSet<_>>())
+                .map_err(|e| zbus::fdo::Error::Failed(format!("sway IPC error: {e}")))
+        };
+        let before = read_connectors().await?;
+        self.backend.apply(&[String::from("create_output")]).await?;
+        let Some(connector) = read_connectors().await?.into_iter().find(|connector| !before.contains(connector)) else {
+            return Err(zbus::fdo::Error::Failed(String::from("sway didn't create an output")));
+        };
+        self.backend.apply(&[format!("output {connector} mode {width}x{height}")]).await?;
+        match DisplayManager::get_monitor_info(self.backend.as_ref()).await {
+            Ok(display_info) => manager_obj.set_monitor_info(display_info),
+            Err(e) => error!("Error getting output information from sway: {e}"),
+        }
+        manager_obj.emit_monitors_changed().await?;
+        Ok(connector)
+    }
 
-        let mut active_mons = Vec::new();
+    /// Remove the virtual output on `connector` with sway's `output unplug`
+    pub async fn destroy_virtual_output(&self, connector: &str) -> zbus::fdo::Result<()> {
+        info!("DestroyVirtualOutput {connector}");
+        check_unique_connectors()?;
+        let mut manager_obj = self.manager.lock().await;
+        let Some(monitor) = manager_obj.monitors.iter().find(|monitor| monitor.get_connector() == connector) else {
+            return Err(zbus::fdo::Error::InvalidArgs(format!("No monitor connected to {connector}")));
+        };
+        if !monitor.is_virtual() {
+            return Err(zbus::fdo::Error::InvalidArgs(format!("{connector} is not a virtual output")));
+        }
+        if !config::current().manages(connector, &monitor.get_dpy_name()) {
+            return Err(zbus::fdo::Error::AccessDenied(format!("{connector} is not managed")));
+        }
+        self.backend.apply(&[format!("output {connector} unplug")]).await?;
+        match DisplayManager::get_monitor_info(self.backend.as_ref()).await {
+            Ok(display_info) => manager_obj.set_monitor_info(display_info),
+            Err(e) => error!("Error getting output information from sway: {e}"),
+        }
+        manager_obj.emit_monitors_changed().await?;
+        Ok(())
+    }
 
-        writeln!(&mut profile_buf, "profile {{").unwrap();
+    /// Apply `mutter_logical_monitors` to sway, read back what sway made of
+    /// it and go back to the previous layout, without writing a profile.
+    /// Returns whether sway applied the configuration as requested, and the
+    /// differences otherwise.
+    pub async fn test_apply(
+        &self,
+        serial: u32,
+        mutter_logical_monitors: Vec<MonitorApply>,
+        properties: DisplayManagerProperties
+    ) -> zbus::fdo::Result<(bool, Vec<String>)> {
+        info!("TestApply");
+        check_unique_connectors()?;
+        let manager_obj = self.manager.lock().await;
+        manager_obj.check_serial(serial)?;
+        manager_obj.check_layout(&mutter_logical_monitors, &properties)?;
         for mutter_logical_mointor in &mutter_logical_monitors {
-            // If apply_monitors_config called with method == 0 (Verify configuration)
-            if method == 0 {
-                match mutter_logical_mointor.verify(&self.sway_connection, &manager_obj.monitors) {
-                    Ok(_) => {
-                        continue;
+            mutter_logical_mointor.verify(self.backend.as_ref(), &manager_obj.monitors)?;
+        }
+        let mutter_logical_monitors = manager_obj.to_sway_space(&mutter_logical_monitors, &properties);
+        let mut result = ApplyResult::default();
+        let profile_buf = manager_obj.generate_profile(&mutter_logical_monitors, &manager_obj.profile_name(), &mut result)?;
+        let mut commands = kanshi::sway_commands(&String::from_utf8_lossy(&profile_buf))
+            .map_err(|e| zbus::fdo::Error::Failed(format!("Invalid generated profile: {e}")))?;
+        commands.extend(result.primary.as_deref().and_then(backend::primary_command));
+        let restore_commands = export::sway_commands(&manager_obj.monitors, &manager_obj.logical_monitors);
+
+        let mut problems = Vec::new();
+        match self.backend.apply(&commands).await {
+            Ok(()) => match DisplayManager::get_monitor_info(self.backend.as_ref()).await {
+                Ok((monitors, logical_monitors)) => {
+                    for mutter_logical_mointor in &mutter_logical_monitors {
+                        let connector = &mutter_logical_mointor.monitors[0].0;
+                        if result.enabled.contains(connector) {
+                            problems.extend(mutter_logical_mointor.check_applied(&monitors, &logical_monitors));
+                        }
                     }
-                    Err(e) => {
-                        return Err(e);
+                    for connector in &result.disabled {
+                        if logical_monitors.iter().any(|logical_monitor| logical_monitor.get_connector() == connector) {
+                            problems.push(format!("{connector} was not disabled"));
+                        }
                     }
                 }
-            }
-            if let Some(sway_logical_monitor) = mutter_logical_mointor.search_logical_monitor(&manager_obj.logical_monitors) {
-                active_mons.push(sway_logical_monitor);
-            }            
-            let Some(sway_physical_monitor) = mutter_logical_mointor.search_monitor(&manager_obj.monitors) else {
-                continue;
-            };
-            mutter_logical_mointor.save_kanshi(&mut profile_buf, &sway_physical_monitor);
+                Err(e) => problems.push(format!("Cannot read the outputs back from sway: {e}")),
+            },
+            Err(e) => problems.push(e.to_string()),
         }
-        if method == 0 {
-            return Ok(());
+        if let Err(e) = self.backend.apply(&restore_commands).await {
+            error!("Error restoring the layout after TestApply: {e}");
+            return Err(zbus::fdo::Error::Failed(format!("Cannot restore the previous layout: {e}")));
         }
+        Ok((problems.is_empty(), problems))
+    }
 
-        for disabled_mon in manager_obj.get_disabled_monitors(&active_mons) {
-            writeln!(&mut profile_buf, "\toutput \"{}\" disable", disabled_mon.get_dpy_name()).expect(
-                "Failed to write to file"
-            );
-        }
-        writeln!(&mut profile_buf, "}}").unwrap();
-        manager_obj.properties = properties;
-        
-        if let Err(e) = profile_file.write(&profile_buf) {
-            error!("Error writing data to kanshi config file: {e}");
-            return Err(zbus::fdo::Error::IOError(e.to_string()));
+    /// Keep the pending temporary configuration, saving it as if it had been
+    /// applied persistently. Does nothing if no configuration is pending.
+    pub async fn confirm_configuration(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>
+    ) -> zbus::fdo::Result<()> {
+        let caller = history::describe_caller(ctxt.connection(), &header).await;
+        info!("ConfirmConfiguration from {caller}");
+        check_unique_connectors()?;
+        let Some((profile_name, profile_path, profile)) = self.revert.confirm().await else {
+            debug!("No temporary configuration to confirm");
+            return Ok(());
+        };
+        info!("Temporary configuration confirmed");
+        let mut result = ApplyResult::default();
+        self.save_profile(caller, &profile_name, profile_path, profile, &mut result).await?;
+        if !result.kanshi_reloaded {
+            return Err(zbus::fdo::Error::Failed(result.warnings.join("; ")));
         }
+        Ok(())
+    }
 
-        // reload kanshi config
-        if let Err(e) = reload_kanshi().await {
-            error!("Error reloading kanshi configuration: {e}");
+    /// Connectors of the connected outputs without a saved configuration,
+    /// neither in the kanshi profile nor in a JSON layout for the connected
+    /// outputs
+    pub async fn get_unconfigured_outputs(&self) -> Vec<String> {
+        self.manager.lock().await.unconfigured_outputs().await
+    }
+
+    /// Applied configurations, oldest first, as (unix time, caller, method, profile)
+    pub async fn get_change_history(&self) -> Vec<(u64, String, u32, String)> {
+        self.history
+            .entries().await
+            .into_iter()
+            .map(|entry| (entry.time, entry.caller, entry.method, entry.profile))
+            .collect()
+    }
+
+    /// Effective daemon settings and relevant environment for bug reports, as
+    /// (name, value, source) where source is "default", "config", "env" or
+    /// "cli"
+    pub async fn get_effective_config(&self) -> zbus::fdo::Result<Vec<(String, String, String)>> {
+        let kanshi_source = match config::resource("kanshi.path").await {
+            Some(_) => config::Source::Config,
+            None => config::Source::Default,
+        };
+        let kanshi_paths = get_kanshi_paths().await?;
+        let mut settings = vec![
+            config::Setting {
+                name: String::from("kanshi.config"),
+                value: kanshi_paths.config.display().to_string(),
+                source: kanshi_source,
+            },
+            config::Setting {
+                name: String::from("kanshi.profiles"),
+                value: kanshi_paths.profiles.display().to_string(),
+                source: kanshi_source,
+            }
+        ];
+        settings.extend(config::current().settings());
+        settings.extend(config::environment());
+        Ok(
+            settings
+                .into_iter()
+                .map(|setting| (setting.name, setting.value, setting.source.as_str().to_string()))
+                .collect()
+        )
+    }
+
+    /// Read the settings again and apply them without restarting the daemon
+    pub async fn reload(&self) -> zbus::fdo::Result<()> {
+        info!("Reloading settings");
+        let mut config = config::Config::load().await;
+        config.keep_startup_settings(&config::current());
+        config::set(config);
+        profiles::load_existing().await;
+        let mut manager = self.manager.lock().await;
+        match DisplayManager::get_monitor_info(self.backend.as_ref()).await {
+            Ok(display_info) => manager.set_monitor_info(display_info),
+            Err(e) => error!("Error getting output information from sway: {e}"),
         }
-        if let Err(e) = manager_obj.get_monitor_info(&self.sway_connection).await {
-            error!("Error getting output information from sway: {e}");
+        warn_about_config(&manager.monitors);
+        // Settings such as min_mode_size and snap_refresh change the reported state
+        manager.emit_monitors_changed().await?;
+        Ok(())
+    }
+
+    /// Change the `layout-mode` property without applying a configuration
+    pub async fn set_layout_mode(
+        &self,
+        mode: u32,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>
+    ) -> zbus::fdo::Result<()> {
+        info!("SetLayoutMode {mode}");
+        let mut manager = self.manager.lock().await;
+        if !manager.set_layout_mode(mode)? {
+            return Ok(());
         }
-        DisplayManager::emit_monitors_changed().await?;
+        self.layout_mode_changed(&ctxt).await?;
+        manager.emit_monitors_changed().await?;
         Ok(())
     }
 
+    #[dbus_interface(property)]
+    pub async fn layout_mode(&self) -> u32 {
+        let manager = self.manager.lock().await;
+        LayoutMode::from_property(manager.properties.layout) as u32
+    }
+
     #[dbus_interface(property)]
     pub async fn apply_monitors_config_allowed(&self) -> bool {
         info!("Call to apply_monitors_config");
-        return true;
+        true
+    }
+
+    /// 0 (on) if any enabled output is powered, 3 (off) if none is. If sway
+    /// can't be queried, 0 with `displayd.mutter_compat` and -1 (unknown)
+    /// without it.
+    #[dbus_interface(property)]
+    pub async fn power_save_mode(&self) -> i32 {
+        match self.backend.outputs().await {
+            Ok(outputs) => {
+                if outputs.iter().any(|output| output.active && output.power) { 0 } else { 3 }
+            }
+            Err(e) => {
+                warn!("Cannot read the power state of the outputs: {e}");
+                if config::current().mutter_compat { 0 } else { -1 }
+            }
+        }
+    }
+
+    /// Power the outputs on with 0, off with 1 (standby), 2 (suspend) or 3 (off)
+    #[dbus_interface(property)]
+    pub async fn set_power_save_mode(&self, mode: i32) -> zbus::Result<()> {
+        let power = match mode {
+            0 => "on",
+            1..=3 => "off",
+            _ => {
+                return Err(zbus::fdo::Error::InvalidArgs(format!("Invalid PowerSaveMode {mode}")).into());
+            }
+        };
+        info!("Setting PowerSaveMode to {mode}, turning outputs {power}");
+        self.backend.apply(&[format!("output * power {power}")]).await.map_err(Into::into)
+    }
+
+    // The properties below complete the property set of the mutter revision in
+    // org.gnome.Mutter.DisplayConfig.xml.
+
+    #[dbus_interface(property)]
+    pub async fn panel_orientation_managed(&self) -> bool {
+        false
+    }
+
+    /// True once the night light settings are being followed, see [`nightlight`]
+    #[dbus_interface(property)]
+    pub async fn night_light_supported(&self) -> bool {
+        nightlight::supported()
     }
 
     #[dbus_interface(signal)]
     pub async fn monitors_changed(&self, ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+
+    /// Emitted when an unconfirmed temporary configuration is rolled back.
+    /// `serial` is the serial to use for the next apply.
+    #[dbus_interface(signal)]
+    pub async fn configuration_reverted(
+        &self,
+        ctxt: &SignalContext<'_>,
+        serial: u32,
+        profile: &str
+    ) -> zbus::Result<()>;
+}
+
+impl DisplayServer {
+    /// Write `profile_buf`, generated for the profile file `profile_name`, to
+    /// `profile_path`, keeping a backup for `RevertLastApply`, note it in the
+    /// history and reload kanshi, telling clients with `ProfileApplied`. A
+    /// failed reload is only a warning in `result`.
+    async fn save_profile(
+        &self,
+        caller: String,
+        profile_name: &str,
+        profile_path: PathBuf,
+        profile_buf: Vec<u8>,
+        result: &mut ApplyResult
+    ) -> zbus::fdo::Result<()> {
+        let profile_buf = profiles::merge_generated(&profile_path, profile_buf);
+        let previous = previous_profile(profile_name, &profile_path, &profile_buf);
+        if let Err(e) = write_profile(profile_path.clone(), profile_buf).await {
+            DisplayManager::emit_profile_applied(profile_name, false, &e.to_string()).await?;
+            return Err(e);
+        }
+        // Only kept once the profile was replaced, a failed write leaves the backup alone
+        if let Some(previous) = previous {
+            if let Err(e) = previous::save(&previous) {
+                warn!("Cannot keep a backup of {}: {e}", profile_path.display());
+            }
+        }
+        self.history.push(caller, ApplyMethod::Persistent as u32, profile_name.to_string()).await;
+        match reload_kanshi().await {
+            Ok(()) => {
+                result.kanshi_reloaded = true;
+                DisplayManager::emit_profile_applied(profile_name, true, "").await?;
+            }
+            Err(e) => {
+                error!("Error reloading kanshi configuration: {e}");
+                result.warnings.push(format!("kanshi was not reloaded: {e}"));
+                DisplayManager::emit_profile_applied(profile_name, false, &format!("kanshi was not reloaded: {e}")).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Refuse an apply coming sooner than `displayd.min_apply_interval_ms`
+    /// after the previous one
+    async fn check_apply_interval(&self) -> zbus::fdo::Result<()> {
+        let Some(min_interval) = config::current().min_apply_interval else {
+            return Ok(());
+        };
+        if let Some(last_apply) = *self.last_apply.lock().await {
+            let elapsed = last_apply.elapsed();
+            if elapsed < min_interval {
+                warn!("Refusing an apply {}ms after the previous one", elapsed.as_millis());
+                return Err(
+                    zbus::fdo::Error::LimitsExceeded(
+                        format!("Configurations applied too frequently, retry in {}ms", (min_interval - elapsed).as_millis())
+                    )
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Verify or apply `mutter_logical_monitors` for `caller`
+    async fn apply(
+        &self,
+        caller: String,
+        serial: u32,
+        method: u32,
+        mutter_logical_monitors: &[MonitorApply],
+        properties: DisplayManagerProperties
+    ) -> zbus::fdo::Result<ApplyResult> {
+        let Some(apply_method) = ApplyMethod::from_u32(method) else {
+            return Err(zbus::fdo::Error::InvalidArgs(format!("Invalid method {method}")));
+        };
+        let mut manager_obj = self.manager.lock().await;
+        if apply_method != ApplyMethod::Verify {
+            check_unique_connectors()?;
+            self.check_apply_interval().await?;
+        }
+        let mut result = ApplyResult::default();
+        manager_obj.check_serial(serial)?;
+        let profile_name = manager_obj.profile_name();
+        info!("Profile FileName: {profile_name}");
+        result.profile = profile_name.clone();
+
+        let kanshi_paths = get_kanshi_paths().await?;
+
+        manager_obj.check_layout(mutter_logical_monitors, &properties)?;
+        if apply_method == ApplyMethod::Verify {
+            for mutter_logical_mointor in mutter_logical_monitors {
+                mutter_logical_mointor.verify(self.backend.as_ref(), &manager_obj.monitors)?;
+            }
+            return Ok(result);
+        }
+        let mutter_logical_monitors = manager_obj.to_sway_space(mutter_logical_monitors, &properties);
+        let profile_buf = manager_obj.generate_profile(&mutter_logical_monitors, &profile_name, &mut result)?;
+        if let Some(layout) = properties.layout {
+            manager_obj.check_layout_mode(layout)?;
+        }
+
+        *self.last_apply.lock().await = Some(Instant::now());
+        match apply_method {
+            ApplyMethod::Temporary => {
+                // Temporary configurations go to sway directly and are reverted unless a
+                // persistent apply confirms them
+                let profile = String::from_utf8_lossy(&profile_buf);
+                let mut commands = kanshi::sway_commands(&profile)
+                    .map_err(|e| zbus::fdo::Error::Failed(format!("Invalid generated profile: {e}")))?;
+                commands.extend(result.primary.as_deref().and_then(backend::primary_command));
+                let previous = export::sway_commands(&manager_obj.monitors, &manager_obj.logical_monitors);
+                self.backend.apply(&commands).await?;
+                self.history.push(caller, method, profile_name.clone()).await;
+                let profile_path = kanshi_paths.profiles.join(&profile_name);
+                let pending = PendingConfig {
+                    profile_name,
+                    profile_path,
+                    profile: profile_buf,
+                    previous,
+                    previous_layout: manager_obj.properties.layout,
+                };
+                self.revert.arm(pending, Arc::clone(&self.manager), Arc::clone(&self.backend)).await;
+            }
+            ApplyMethod::Persistent => {
+                // Applied live first, so the change doesn't wait for kanshi to restart and
+                // still happens if kanshi is misbehaving
+                let mut commands = kanshi::sway_commands(&String::from_utf8_lossy(&profile_buf))
+                    .map_err(|e| zbus::fdo::Error::Failed(format!("Invalid generated profile: {e}")))?;
+                commands.extend(result.primary.as_deref().and_then(backend::primary_command));
+                self.backend.apply(&commands).await?;
+                let profile_path = kanshi_paths.profiles.join(&profile_name);
+                self.save_profile(caller, &profile_name, profile_path, profile_buf, &mut result).await?;
+                if self.revert.cancel().await {
+                    info!("Temporary configuration confirmed");
+                }
+            }
+            ApplyMethod::Verify => unreachable!("verified configurations return before applying"),
+        }
+        // Only switched once the configuration in that layout mode was applied
+        if let Some(layout) = properties.layout {
+            manager_obj.set_layout_mode(layout)?;
+        }
+        for mutter_logical_monitor in &mutter_logical_monitors {
+            mutter_logical_monitor.remember_applied();
+        }
+        match DisplayManager::get_monitor_info(self.backend.as_ref()).await {
+            Ok(display_info) => manager_obj.set_monitor_info(display_info),
+            Err(e) => error!("Error getting output information from sway: {e}"),
+        }
+        manager_obj.publish_xwayland_scale().await;
+        manager_obj.emit_monitors_changed().await?;
+        Ok(result)
+    }
 }
 
 impl DisplayServer {
     pub async fn new(
         manager: Arc<Mutex<DisplayManager>>,
-        sway_connection: Arc<Mutex<Connection>>
+        backend: Arc<Compositor>
     ) -> DisplayServer {
         DisplayServer {
             manager,
-            sway_connection,
+            backend,
+            revert: RevertState::default(),
+            history: ChangeHistory::default(),
+            outputs: OutputMemory::default(),
+            last_apply: Arc::new(Mutex::new(None)),
         }
     }
-    pub async fn run_server(self) -> Result<(), Box<dyn Error>> {
+    /// Serve the interfaces and take the bus name. With `replace`, an instance
+    /// already owning the name is asked to hand it over, otherwise starting
+    /// fails. This instance exits when it is replaced in turn.
+    pub async fn run_server(self, replace: bool) -> Result<(), Box<dyn Error>> {
         info!("Starting display daemon");
-        self.manager.lock().await.get_monitor_info(&self.sway_connection).await?;
-
-        let mut connection = ZBUS_CONNECTION.lock().await;
-        *connection = Some(
-            ConnectionBuilder::session()?
-                .name("org.gnome.Mutter.DisplayConfig")?
-                .serve_at("/org/gnome/Mutter/DisplayConfig", self)?
-                .build().await?
-        );
+        let backend = self.backend.detect().await;
+        if config::current().single_kanshi_config {
+            if write_single_kanshi_config().await {
+                if let Err(e) = reload_kanshi().await {
+                    warn!("Cannot reload kanshi: {e}");
+                }
+            }
+        } else if config::current().manage_kanshi_include {
+            ensure_kanshi_include().await;
+        }
+        profiles::load_existing().await;
+        let capabilities = backend.capabilities();
+        info!("Backend: {}, {capabilities:?}", backend.name());
+        monitor::set_tearing_supported(capabilities.tearing);
+        monitor::set_render_bit_depth_supported(capabilities.render_bit_depth);
+        monitor::set_hdr_supported(capabilities.hdr);
+        modes::set_scaling_supported(capabilities.scaling);
+        self.manager.lock().await.properties.set_capabilities(&capabilities);
+        backend::set(backend);
+        let display_info = DisplayManager::get_monitor_info(self.backend.as_ref()).await?;
+        warn_about_config(&display_info.0);
+        self.manager.lock().await.set_monitor_info(display_info);
+        if let Some(max_age) = config::current().profile_retention_days.and_then(profiles::retention) {
+            {
+                let manager = self.manager.lock().await;
+                profiles::mark_seen(&manager.monitors, &manager.profile_name());
+            }
+            match profiles::prune(max_age).await {
+                Ok(removed) if !removed.is_empty() => info!("Removed stale kanshi profiles: {}", removed.join(", ")),
+                Ok(_) => {}
+                Err(e) => warn!("Cannot prune the kanshi profiles: {e}"),
+            }
+        }
+
+        let profile_server = ProfileServer::new(Arc::clone(&self.manager), Arc::clone(&self.backend));
+        let config = config::current();
+        let bus_name = config.bus_name().to_string();
+        let object_path = config.object_path().to_string();
+        let connection = ConnectionBuilder::session()?
+            .serve_at(object_path.as_str(), self)?
+            .serve_at(object_path.as_str(), profile_server)?
+            .build().await?;
+        let _ = SERVED_PATH.set(object_path.clone());
+        let dbus = fdo::DBusProxy::new(&connection).await?;
+        // Subscribed before the request so a replacement right after it isn't missed
+        let mut name_lost = dbus.receive_name_lost().await?;
+        let mut flags = fdo::RequestNameFlags::AllowReplacement | fdo::RequestNameFlags::DoNotQueue;
+        if replace {
+            flags |= fdo::RequestNameFlags::ReplaceExisting;
+        }
+        let name = WellKnownName::try_from(bus_name.as_str())?;
+        if dbus.request_name(name, flags).await? == fdo::RequestNameReply::Exists {
+            return Err(format!("{bus_name} is owned by another process, use --replace to take it over").into());
+        }
+        info!("Serving {object_path} as {bus_name}");
+        tokio::spawn(async move {
+            while let Some(signal) = name_lost.next().await {
+                if signal.args().is_ok_and(|args| args.name() == bus_name.as_str()) {
+                    info!("{bus_name} was taken over by another instance, exiting");
+                    std::process::exit(0);
+                }
+            }
+        });
+        *ZBUS_CONNECTION.lock().await = Some(connection);
         Ok(())
     }
 }
+impl Ranked for DisplayManager {
+    const RANK: Rank = Rank::Manager;
+}
+
 impl DisplayManager {
     pub async fn new() -> DisplayManager {
         DisplayManager {
@@ -204,41 +809,143 @@ impl DisplayManager {
         }
     }
 
+    /// Query the compositor's outputs whenever it sends an output event, and emit
+    /// MonitorsChanged when they changed. When the compositor reloads its config, which
+    /// may reset the outputs, MonitorsChanged is emitted in any case. Outputs are also polled every
+    /// [`FALLBACK_POLL_INTERVAL`], or every `displayd.poll_interval_ms` if
+    /// its events can't be received.
+    ///
+    /// `backend` should be dedicated to the watch loop so polling never
+    /// waits on, or holds up, the connection used for applying configurations.
+    /// If the compositor goes away, the loop reconnects once it is back, subscribes to
+    /// its events again and emits MonitorsChanged.
     pub async fn watch_changes(
         manager_obj: Arc<Mutex<DisplayManager>>,
-        sway_connection: Arc<Mutex<Connection>>
+        backend: impl DisplayBackend
     ) -> Result<(), Box<dyn Error>> {
-        let mut prev_monitor_set = HashSet::new();
-        let mut prev_logical_monitor_set = HashSet::new();
+        // The outputs are compared with the state last published with
+        // MonitorsChanged, also by applies, so a change is only announced
+        // once. Set to announce the outputs in any case, as on the first poll.
+        let mut resync = true;
+        let mut wake_tracker = WakeTracker::default();
+        // Connectors seen in the previous poll, `None` before the first one
+        let mut known_connectors: Option<HashSet<String>> = None;
+        // Outputs often come up one at a time when the session starts. Until they
+        // have been stable for the quiet period, changes are only recorded and a
+        // single MonitorsChanged is emitted once the period is over.
+        let mut starting_up = true;
+        let mut last_change = Instant::now();
+        let mut pending_emit = false;
+        let mut events = backend.subscribe().await;
+        let mut last_poll = Instant::now();
         loop {
-            thread::sleep(Duration::from_millis(700));
-            let mut manager_obj_lock = manager_obj.lock().await;
-            let display_info = manager_obj_lock.get_monitor_info(&sway_connection).await.unwrap();
-            let mut monitor_set = HashSet::new();
-            let mut logical_monitor_set = HashSet::new();
-            let mut monitors_changed = false;
-            for monitor in &display_info.0 {
-                if !prev_monitor_set.contains(monitor) {
-                    monitors_changed = true;
+            // Polling stays frequent until the outputs have settled at startup
+            let interval = match (&events, starting_up) {
+                (Some(_), false) => FALLBACK_POLL_INTERVAL,
+                _ => config::current().poll_interval(),
+            };
+            // Workspace events that aren't reloads don't put the next poll off
+            let next_poll = tokio::time::Instant::from_std(last_poll + interval);
+            tokio::select! {
+                _ = tokio::time::sleep_until(next_poll) => {}
+                _ = RESCAN.notified() => debug!("Rescanning outputs"),
+                event = next_event(&mut events) => match event {
+                    Some(Ok(BackendEvent::ConfigReloaded)) => {
+                        info!("The compositor reloaded its config, resyncing");
+                        monitor::forget_applied();
+                        resync = true;
+                    }
+                    Some(Ok(BackendEvent::OutputsChanged)) => debug!("Output event from the compositor"),
+                    Some(Err(e)) => {
+                        warn!("Lost the compositor's output events ({e}), polling instead");
+                        events = None;
+                    }
+                    None => {
+                        warn!("The compositor stopped sending output events, polling instead");
+                        events = None;
+                    }
+                },
+            }
+            last_poll = Instant::now();
+            let outputs = match backend.outputs().await {
+                Ok(outputs) => outputs,
+                Err(e) => {
+                    warn!("Lost the connection to the compositor ({e}), reconnecting");
+                    backend.reconnect().await;
+                    events = backend.subscribe().await;
+                    // It may have restarted with another layout, so the next
+                    // poll reports every output as changed
+                    resync = true;
+                    continue;
                 }
-                monitor_set.insert(monitor.clone());
+            };
+            let woken = wake_tracker.update(&outputs);
+            if !woken.is_empty() && config::current().reapply_on_wake {
+                Self::reapply_after_wake(&manager_obj, &woken, &mut wake_tracker).await;
+            }
+            let display_info = Self::monitor_info_from_outputs(&outputs);
+            let connectors: HashSet<String> = display_info.0.iter().map(|monitor| monitor.get_connector().to_string()).collect();
+            let connectors_changed = known_connectors.as_ref() != Some(&connectors);
+            let connected: Vec<String> = match &known_connectors {
+                Some(known) => connectors.difference(known).cloned().collect(),
+                None => Vec::new(),
+            };
+            if connectors_changed {
+                nightlight::refresh().await;
             }
-            for logical_monitor in &display_info.1 {
-                if !prev_logical_monitor_set.contains(logical_monitor) {
-                    monitors_changed = true;
+            known_connectors = Some(connectors);
+            let monitors_changed = std::mem::take(&mut resync) || !manager_obj.lock().await.is_published(&display_info);
+            if starting_up && last_change.elapsed() >= config::current().startup_quiet_period() && !monitors_changed {
+                starting_up = false;
+                info!("Outputs settled after startup");
+                supervisor::outputs_settled();
+                if pending_emit {
+                    manager_obj.lock().await.emit_monitors_changed().await?;
                 }
-                logical_monitor_set.insert(logical_monitor.clone());
             }
             if monitors_changed {
-                prev_monitor_set = monitor_set;
-                prev_logical_monitor_set = logical_monitor_set;
-                manager_obj_lock.monitors = display_info.0.clone();
-                manager_obj_lock.logical_monitors = display_info.1.clone();
+                let mut manager_obj_lock = manager_obj.lock().await;
+                manager_obj_lock.set_monitor_info(display_info);
+                if connectors_changed {
+                    profiles::mark_seen(&manager_obj_lock.monitors, &manager_obj_lock.profile_name());
+                }
                 debug!("monitors info: {:#?}", manager_obj_lock.monitors);
                 debug!("logical monitors: {:#?}", manager_obj_lock.logical_monitors);
-                Self::emit_monitors_changed().await?;
+                if starting_up {
+                    last_change = Instant::now();
+                    pending_emit = true;
+                } else {
+                    manager_obj_lock.emit_monitors_changed().await?;
+                }
+                // Outputs sharing a connector can't be told apart in commands
+                let unique = check_unique_connectors().is_ok();
+                let restored = connectors_changed && unique && (
+                    manager_obj_lock.restore_layout(&backend).await
+                        || !kanshi_applies_profiles() && manager_obj_lock.apply_saved_profile(&backend).await
+                );
+                if !restored && unique && !connected.is_empty() {
+                    manager_obj_lock.configure_unmatched(&connected, &backend).await;
+                }
+            }
+            if autoswitch::take_pending() && check_unique_connectors().is_ok() {
+                manager_obj.lock().await.apply_saved_profile(&backend).await;
+            }
+        }
+    }
+
+    /// Copy of the state as reported to clients: logical monitor positions
+    /// are in the coordinate space of the current layout-mode (see
+    /// [`layout`]) and modes hidden by the config are left out
+    pub fn client_state(&self) -> DisplayManager {
+        let mut manager = self.clone();
+        let mode = LayoutMode::from_property(self.properties.layout);
+        layout::to_layout_space(mode, &manager.monitors, &mut manager.logical_monitors);
+        if let Some(min_size) = config::current().min_mode_size {
+            for monitor in &mut manager.monitors {
+                monitor.hide_small_modes(min_size);
             }
         }
+        manager
     }
 
     /// Get list of all the monitors that are not active
@@ -249,13 +956,17 @@ impl DisplayManager {
             .collect()
     }
 
-    pub async fn emit_monitors_changed() -> zbus::Result<()> {
+    /// Start a new serial and tell clients to read the state again. Applies
+    /// carrying an older serial are refused from then on.
+    pub async fn emit_monitors_changed(&mut self) -> zbus::Result<()> {
+        self.serial = self.serial.wrapping_add(1);
+        debug!("New serial {}", self.serial);
         let connection = ZBUS_CONNECTION.lock().await;
         info!("Emiting monitor changed");
         if let Some(con) = &*connection {
             con.emit_signal(
                 Option::<&str>::None,
-                "/org/gnome/Mutter/DisplayConfig",
+                served_path(),
                 "org.gnome.Mutter.DisplayConfig",
                 "MonitorsChanged",
                 &()
@@ -264,34 +975,577 @@ impl DisplayManager {
         Ok(())
     }
 
+    pub async fn emit_configuration_reverted(serial: u32, profile: &str) -> zbus::Result<()> {
+        let connection = ZBUS_CONNECTION.lock().await;
+        info!("Emiting configuration reverted");
+        if let Some(con) = &*connection {
+            con.emit_signal(
+                Option::<&str>::None,
+                served_path(),
+                "org.gnome.Mutter.DisplayConfig",
+                "ConfigurationReverted",
+                &(serial, profile)
+            ).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn emit_profile_applied(name: &str, success: bool, message: &str) -> zbus::Result<()> {
+        let connection = ZBUS_CONNECTION.lock().await;
+        if success {
+            info!("Profile {name} applied");
+        } else {
+            warn!("Profile {name} failed to apply: {message}");
+        }
+        if let Some(con) = &*connection {
+            con.emit_signal(
+                Option::<&str>::None,
+                served_path(),
+                "org.regolith.DisplayConfig",
+                "ProfileApplied",
+                &(name, success, message)
+            ).await?;
+        }
+        Ok(())
+    }
+
+    /// Switch to layout `mode` and remember it, returning whether it changed.
+    /// Fails if the mode is unknown or the backend can't change layouts.
+    pub fn set_layout_mode(&mut self, mode: u32) -> zbus::fdo::Result<bool> {
+        let Some(mode) = self.check_layout_mode(mode)? else {
+            return Ok(false);
+        };
+        self.properties.layout = Some(mode as u32);
+        if let Err(e) = mode.save() {
+            warn!("Could not save layout mode: {e}");
+        }
+        info!("Layout mode set to {mode:?}");
+        Ok(true)
+    }
+
+    /// Layout `mode`, `None` if it is the current one. Fails if the mode is
+    /// unknown or the backend can't change layouts.
+    fn check_layout_mode(&self, mode: u32) -> zbus::fdo::Result<Option<LayoutMode>> {
+        let Some(mode) = LayoutMode::from_u32(mode) else {
+            return Err(zbus::fdo::Error::InvalidArgs(format!("Invalid layout mode {mode}")));
+        };
+        if self.properties.layout == Some(mode as u32) {
+            return Ok(None);
+        }
+        if self.properties.support_layout_change != Some(true) {
+            return Err(zbus::fdo::Error::NotSupported(String::from("Changing the layout mode is not supported")));
+        }
+        Ok(Some(mode))
+    }
+
+    pub fn get_serial(&self) -> u32 {
+        self.serial
+    }
+
+    /// Refuse requests made against an earlier state
+    fn check_serial(&self, serial: u32) -> zbus::fdo::Result<()> {
+        if serial != self.serial {
+            warn!("Refusing a request with serial {serial}, the current serial is {}", self.serial);
+            return Err(
+                zbus::fdo::Error::InvalidArgs(
+                    format!("Stale serial {serial}, the current one is {}: call GetCurrentState again", self.serial)
+                )
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether `display_info` is the state already stored, which was published
+    /// with MonitorsChanged unless the outputs are still settling at startup
+    fn is_published(&self, (monitors, logical_monitors): &(Vec<Monitor>, Vec<LogicalMonitor>)) -> bool {
+        fn same<T: Hash + Eq>(a: &[T], b: &[T]) -> bool {
+            a.len() == b.len() && a.iter().collect::<HashSet<_>>() == b.iter().collect::<HashSet<_>>()
+        }
+        same(monitors, &self.monitors) && same(logical_monitors, &self.logical_monitors)
+    }
+
+    /// The monitor with the output id `id`, as taken by `ChangeBacklight`:
+    /// its index in the monitors of `GetCurrentState`, as mutter numbers
+    /// outputs in the same order
+    fn output(&self, id: u32) -> zbus::fdo::Result<&Monitor> {
+        usize::try_from(id)
+            .ok()
+            .and_then(|index| self.monitors.get(index))
+            .ok_or_else(|| zbus::fdo::Error::InvalidArgs(format!("Invalid output id {id}")))
+    }
+
+    /// Replace the stored monitors and logical monitors
+    pub fn set_monitor_info(&mut self, (monitors, logical_monitors): (Vec<Monitor>, Vec<LogicalMonitor>)) {
+        self.properties.legacy_scale_factor = Some(xwayland::scale_factor(logical_monitors.iter().map(LogicalMonitor::scale)));
+        self.monitors = monitors;
+        self.logical_monitors = logical_monitors;
+    }
+
+    /// Publish the XWayland scale of the current layout, see [`xwayland`]
+    pub async fn publish_xwayland_scale(&self) {
+        xwayland::publish(self.properties.legacy_scale_factor.unwrap_or(1)).await;
+    }
+
     /// Returns list of all monitors and logical monitors
-    pub async fn get_monitor_info<'a>(
-        &mut self,
-        sway_connection: &Mutex<Connection>
+    pub async fn get_monitor_info(
+        backend: &impl DisplayBackend
     ) -> Result<(Vec<Monitor>, Vec<LogicalMonitor>), Box<dyn Error>> {
-        let outputs = sway_connection.lock().await.get_outputs().await?;
+        let outputs = backend.outputs().await?;
+        Ok(Self::monitor_info_from_outputs(&outputs))
+    }
+
+    /// Monitors and logical monitors of `outputs`, leaving out non-desktop
+    /// outputs such as VR headsets and those in `displayd.excluded_outputs`
+    fn monitor_info_from_outputs(outputs: &[Output]) -> (Vec<Monitor>, Vec<LogicalMonitor>) {
+        let config = config::current();
+        for output in outputs {
+            monitor::remember_reported(output);
+        }
+        let (excluded, outputs): (Vec<_>, Vec<_>) = Self::disambiguate_connectors(outputs)
+            .into_iter()
+            .filter(|(_, output)| !output.non_desktop)
+            .partition(|(name, output)| {
+                let description = format!("{} {} {}", output.make, output.model, output.serial);
+                config.excludes(name, &description)
+            });
+        *EXCLUDED_CONNECTORS.lock().unwrap() = excluded.into_iter().map(|(name, _)| name).collect();
+        let outputs = &outputs;
+        // The EDID and backlight are looked up under the real connector
         let monitors = outputs
             .iter()
-            .map(|o| Monitor::new(o))
+            .map(|(name, output)| {
+                let mut monitor = Monitor::new(output);
+                monitor.set_connector(name);
+                monitor
+            })
             .collect();
         let logical_monitors = outputs
             .iter()
-            .filter(|o| o.active)
-            .map(|o| LogicalMonitor::new(o))
+            .filter(|(_, output)| output.active)
+            .map(|(name, output)| {
+                let mut logical_monitor = LogicalMonitor::new(output);
+                logical_monitor.set_connector(name);
+                logical_monitor
+            })
+            .collect();
+        (monitors, logical_monitors)
+    }
+
+    /// Outputs with the name each is reported under on DBus: outputs sharing a
+    /// connector name are numbered `NAME-2`, `NAME-3`, ... so clients can tell
+    /// them apart. sway and kanshi don't know these names, so nothing is sent
+    /// to them while there are duplicates, see [`check_unique_connectors`].
+    fn disambiguate_connectors(outputs: &[Output]) -> Vec<(String, Output)> {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        let mut duplicates = Vec::new();
+        let outputs = outputs
+            .iter()
+            .map(|output| {
+                let count = counts.entry(output.name.clone()).or_insert(0);
+                *count += 1;
+                let name = match *count {
+                    1 => output.name.clone(),
+                    count => {
+                        if count == 2 {
+                            duplicates.push(output.name.clone());
+                        }
+                        format!("{}-{count}", output.name)
+                    }
+                };
+                (name, output.clone())
+            })
+            .collect();
+        let mut reported = DUPLICATE_CONNECTORS.lock().unwrap();
+        if *reported != duplicates {
+            if !duplicates.is_empty() {
+                warn!(
+                    "sway reports several outputs named {}, numbering them and refusing changes until they are told apart",
+                    duplicates.join(", ")
+                );
+            }
+            *reported = duplicates;
+        }
+        outputs
+    }
+
+    /// Name of the kanshi profile for the connected set of monitors: one made
+    /// up of their names, or an existing profile for exactly those monitors
+    /// found by [`profiles::load_existing`], preferring the one earlier
+    /// versions named after [`Monitor::legacy_profile_key`]
+    pub fn profile_name(&self) -> String {
+        let generated = Self::join_profile_keys(
+            self.monitors.iter().map(|mon| mon.kanshi_name(&self.monitors)).collect()
+        );
+        let legacy = Self::join_profile_keys(
+            self.monitors
+                .iter()
+                .map(|mon| mon.legacy_profile_key().unwrap_or_else(|| mon.kanshi_name(&self.monitors)))
+                .collect()
+        );
+        profiles::existing_name(&self.monitors, &generated, &legacy).unwrap_or(generated)
+    }
+
+    /// Profile file name made up of the monitors' `names`
+    fn join_profile_keys(mut names: Vec<String>) -> String {
+        names.sort();
+        names
+            .iter()
+            .map(|name| name.replace(' ', "_"))
+            .collect::<Vec<String>>()
+            .join("__")
+    }
+
+    /// Apply the JSON layout (see [`restore`]) for the connected monitors, if
+    /// there is one. Returns whether a layout was applied.
+    async fn restore_layout(&self, backend: &impl DisplayBackend) -> bool {
+        let Some(dir) = config::current().layouts_dir() else {
+            return false;
+        };
+        let Some((path, layout)) = restore::find(&dir, &self.monitors) else {
+            return false;
+        };
+        let commands = layout.sway_commands(&self.monitors);
+        info!("Restoring layout {}", path.display());
+        match backend.apply(&commands).await {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Error restoring layout {}: {e}", path.display());
+                false
+            }
+        }
+    }
+
+    /// Apply the saved profile for the connected monitors through the backend, as
+    /// kanshi would, for when kanshi doesn't apply them (see
+    /// [`kanshi_applies_profiles`]). Returns whether a profile was applied.
+    async fn apply_saved_profile(&self, backend: &impl DisplayBackend) -> bool {
+        autoswitch::apply(&self.monitors, backend).await
+    }
+
+    /// Refuse layouts where monitors overlap or have negative positions,
+    /// unless `displayd.allow_overlap` is set
+    fn check_layout(&self, mutter_logical_monitors: &[MonitorApply], properties: &DisplayManagerProperties) -> zbus::fdo::Result<()> {
+        if config::current().allow_overlap {
+            return Ok(());
+        }
+        let layout = LayoutMode::from_property(properties.layout.or(self.properties.layout));
+        let rects: Vec<_> = mutter_logical_monitors
+            .iter()
+            .filter_map(|mon| Some((mon.monitors[0].0.clone(), mon.rect(&self.monitors, layout)?)))
+            .collect();
+        layout::check_layout(&rects).map_err(zbus::fdo::Error::InvalidArgs)
+    }
+
+    /// `mutter_logical_monitors` with their positions moved from the layout
+    /// mode of the request into sway's logical positions
+    fn to_sway_space(&self, mutter_logical_monitors: &[MonitorApply], properties: &DisplayManagerProperties) -> Vec<MonitorApply> {
+        let layout = LayoutMode::from_property(properties.layout.or(self.properties.layout));
+        let mut requests = mutter_logical_monitors.to_vec();
+        layout::from_layout_space(layout, &self.monitors, &mut requests);
+        requests
+    }
+
+    /// kanshi profile named `profile_name` for `mutter_logical_monitors`,
+    /// noting the enabled and disabled outputs and any warnings in `result`
+    fn generate_profile(
+        &self,
+        mutter_logical_monitors: &[MonitorApply],
+        profile_name: &str,
+        result: &mut ApplyResult
+    ) -> zbus::fdo::Result<Vec<u8>> {
+        let config = config::current();
+        // Profile Write buffer (Only written if no errors occur)
+        let mut profile_buf = Vec::new();
+
+        let mut active_mons = Vec::new();
+
+        writeln!(&mut profile_buf, "profile {{").unwrap();
+        for mutter_logical_mointor in mutter_logical_monitors {
+            let Some(sway_physical_monitor) = mutter_logical_mointor.search_monitor(&self.monitors) else {
+                continue;
+            };
+            let connector = sway_physical_monitor.get_connector();
+            if !config.manages(connector, &sway_physical_monitor.get_dpy_name()) {
+                debug!("Ignoring changes to unmanaged output {connector}");
+                result.warnings.push(format!("{connector} is not managed, its changes were ignored"));
+                continue;
+            }
+            if let Some(sway_logical_monitor) = mutter_logical_mointor.search_logical_monitor(&self.logical_monitors) {
+                active_mons.push(sway_logical_monitor);
+            }
+            mutter_logical_mointor.save_kanshi(&mut profile_buf, sway_physical_monitor, &self.monitors)?;
+            result.enabled.push(connector.to_string());
+            if mutter_logical_mointor.is_primary() {
+                result.primary = Some(connector.to_string());
+            }
+            let mode_id = &mutter_logical_mointor.monitors[0].1;
+            if let Some(mode) = sway_physical_monitor.search_modes(mode_id).filter(|mode| mode.is_snapped()) {
+                if let Some(sway_id) = mode.sway_id().filter(|&sway_id| sway_id != mode_id) {
+                    result.warnings.push(format!("{connector}: mode {mode_id} is {sway_id} in sway"));
+                }
+            }
+        }
+        for disabled_mon in self.get_disabled_monitors(&active_mons) {
+            if !config.manages(disabled_mon.get_connector(), &disabled_mon.get_dpy_name()) {
+                continue;
+            }
+            writeln!(&mut profile_buf, "\toutput \"{}\" disable", disabled_mon.kanshi_name(&self.monitors)).expect(
+                "Failed to write to file"
+            );
+            result.disabled.push(disabled_mon.get_connector().to_string());
+        }
+        // kanshi only matches a profile if it lists every connected output, so unmanaged
+        // outputs are listed without any directive, leaving their state to sway
+        for unmanaged_mon in &self.monitors {
+            if !config.manages(unmanaged_mon.get_connector(), &unmanaged_mon.get_dpy_name()) {
+                writeln!(&mut profile_buf, "\toutput \"{}\"", unmanaged_mon.kanshi_name(&self.monitors)).unwrap();
+            }
+        }
+        for excluded in EXCLUDED_CONNECTORS.lock().unwrap().iter() {
+            writeln!(&mut profile_buf, "\toutput {excluded}").unwrap();
+        }
+        let outputs: Vec<String> = self.monitors
+            .iter()
+            .map(|monitor| monitor.get_connector().to_string())
+            .collect();
+        let (commands, errors) = config.exec_hooks(&outputs);
+        for command in commands {
+            writeln!(&mut profile_buf, "\texec {command}").unwrap();
+        }
+        for e in errors {
+            warn!("Not adding exec hook to profile {profile_name}: {e}");
+            result.warnings.push(format!("exec hook not added: {e}"));
+        }
+        if let (Some(template), Some(primary)) = (&config.primary_exec, result.primary.clone()) {
+            match kanshi::render_primary_exec(template, &primary, &outputs) {
+                Ok(command) => writeln!(&mut profile_buf, "\texec {command}").unwrap(),
+                Err(e) => {
+                    warn!("Not adding primary output hook to profile {profile_name}: {e}");
+                    result.warnings.push(format!("primary output hook not added: {e}"));
+                }
+            }
+        }
+        writeln!(&mut profile_buf, "}}").unwrap();
+        Ok(profile_buf)
+    }
+
+    /// kanshi profile keeping the current layout, written like those of
+    /// `ApplyMonitorsConfig` to the profile file `profile_name`
+    pub(crate) fn current_profile(&self, profile_name: &str) -> zbus::fdo::Result<String> {
+        let requests: Vec<MonitorApply> = self.logical_monitors
+            .iter()
+            .filter_map(|logical_monitor| {
+                let monitor = self.monitors
+                    .iter()
+                    .find(|monitor| monitor.get_connector() == logical_monitor.get_connector())?;
+                MonitorApply::current(logical_monitor, monitor)
+            })
+            .collect();
+        let profile = self.generate_profile(&requests, profile_name, &mut ApplyResult::default())?;
+        Ok(String::from_utf8_lossy(&profile).into_owned())
+    }
+
+    /// Connectors of the monitors that neither the saved kanshi profile, the
+    /// profile of `SaveAnyExternalProfile` nor a JSON layout (see [`restore`])
+    /// for the connected set of monitors configure
+    pub async fn unconfigured_outputs(&self) -> Vec<String> {
+        if let Some(dir) = config::current().layouts_dir() {
+            if restore::find(&dir, &self.monitors).is_some() {
+                return Vec::new();
+            }
+        }
+        if profiles::any_external_matches(&self.monitors) {
+            return Vec::new();
+        }
+        let outputs: Vec<kanshi::OutputDirective> = match get_kanshi_paths().await {
+            Ok(paths) => {
+                fs::read_to_string(paths.profiles.join(self.profile_name()))
+                    .ok()
+                    .and_then(|text| kanshi::parse(&text).ok())
+                    .map(|config| config.profiles.into_iter().flat_map(|profile| profile.outputs).collect())
+                    .unwrap_or_default()
+            }
+            Err(_) => Vec::new(),
+        };
+        self.monitors
+            .iter()
+            .filter(|monitor| !outputs.iter().any(|output| output.matches(monitor)))
+            .map(|monitor| monitor.get_connector().to_string())
+            .collect()
+    }
+
+    /// Apply `displayd.hotplug_fallback` (and `displayd.auto_scale`) to the
+    /// `connected` monitors that aren't configured yet (see
+    /// [`DisplayManager::unconfigured_outputs`])
+    async fn configure_unmatched(&self, connected: &[String], backend: &impl DisplayBackend) {
+        let Ok(kanshi_paths) = get_kanshi_paths().await else {
+            return;
+        };
+        let unconfigured = self.unconfigured_outputs().await;
+        let connected: Vec<String> = connected
+            .iter()
+            .filter(|connector| unconfigured.contains(connector))
+            .cloned()
             .collect();
-        Ok((monitors, logical_monitors))
+        if connected.is_empty() {
+            return;
+        }
+        let connected = &connected[..];
+        let config = config::current();
+        let commands = match &config.hotplug_fallback {
+            HotplugFallback::None => {
+                if config.auto_scale {
+                    self.auto_scale(connected, backend).await;
+                }
+                return;
+            }
+            HotplugFallback::Arrange => {
+                info!("No profile for the connected outputs, arranging {}", connected.join(", "));
+                self.arrange_commands(connected)
+            }
+            HotplugFallback::Profile(name) => {
+                info!("No profile for the connected outputs, applying fallback profile {name}");
+                let path = kanshi_paths.profiles.join(name);
+                let commands = fs::read_to_string(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|text| kanshi::sway_commands(&text).map_err(|e| e.to_string()));
+                match commands {
+                    Ok(commands) => commands,
+                    Err(e) => {
+                        error!("Cannot read fallback profile {}: {e}", path.display());
+                        return;
+                    }
+                }
+            }
+        };
+        if let Err(e) = backend.apply(&commands).await {
+            error!("Error configuring new outputs: {e}");
+        }
+    }
+
+    /// sway commands placing the `connected` monitors in a row right of the
+    /// other enabled monitors, at their preferred mode and recommended scale
+    /// (see [`autoscale`]), or scale 1 without one
+    fn arrange_commands(&self, connected: &[String]) -> Vec<String> {
+        let config = config::current();
+        let existing: Vec<&LogicalMonitor> = self.logical_monitors
+            .iter()
+            .filter(|logical_monitor| !connected.iter().any(|c| c == logical_monitor.get_connector()))
+            .collect();
+        let mut x = existing
+            .iter()
+            .filter_map(|logical_monitor| Some(logical_monitor.position().0 + logical_monitor.logical_size(&self.monitors)?.0))
+            .max()
+            .unwrap_or(0);
+        let y = existing
+            .iter()
+            .map(|logical_monitor| logical_monitor.position().1)
+            .min()
+            .unwrap_or(0);
+        let mut commands = Vec::new();
+        for monitor in self.monitors.iter().filter(|monitor| connected.iter().any(|c| c == monitor.get_connector())) {
+            let connector = monitor.get_connector();
+            if !config.manages(connector, &monitor.get_dpy_name()) {
+                continue;
+            }
+            let Some(mode) = monitor.preferred_mode().or(monitor.current_mode()) else {
+                continue;
+            };
+            let scale = Edid::read(connector)
+                .ok()
+                .and_then(|edid| autoscale::recommend(monitor, mode, &edid))
+                .map_or(1.0, |(scale, _)| scale);
+            commands.push(format!(
+                "output {connector} enable mode {} position {x} {y} scale {scale}",
+                mode.sway_id().unwrap_or(mode.get_modestr())
+            ));
+            x += (mode.get_size().0 as f64 / scale).round() as i32;
+        }
+        commands
+    }
+
+    /// Set the recommended scale (see [`autoscale`]) on the `connected`
+    /// monitors
+    async fn auto_scale(&self, connected: &[String], backend: &impl DisplayBackend) {
+        let mut commands = Vec::new();
+        let config = config::current();
+        for monitor in self.monitors.iter().filter(|monitor| connected.iter().any(|c| c == monitor.get_connector())) {
+            let connector = monitor.get_connector();
+            if !config.manages(connector, &monitor.get_dpy_name()) {
+                continue;
+            }
+            let edid = match Edid::read(connector) {
+                Ok(edid) => edid,
+                Err(e) => {
+                    debug!("Not scaling {connector} automatically: {e}");
+                    continue;
+                }
+            };
+            match monitor.current_mode().and_then(|mode| autoscale::recommend(monitor, mode, &edid)) {
+                Some((scale, dpi)) => {
+                    info!("Scaling new monitor {connector} to {scale} ({dpi:.0} DPI)");
+                    commands.push(format!("output {connector} scale {scale}"));
+                }
+                None => debug!("Not scaling {connector} automatically: unknown physical size"),
+            }
+        }
+        if let Err(e) = backend.apply(&commands).await {
+            error!("Error applying automatic scale: {e}");
+        }
+    }
+
+    /// Reload kanshi so it reapplies the saved profile after `woken` outputs
+    /// came back from DPMS, at most once per [`wake::MIN_REAPPLY_INTERVAL`]
+    async fn reapply_after_wake(
+        manager_obj: &Mutex<DisplayManager>,
+        woken: &[String],
+        wake_tracker: &mut WakeTracker
+    ) {
+        let profile_name = manager_obj.lock().await.profile_name();
+        let Ok(kanshi_paths) = get_kanshi_paths().await else {
+            return;
+        };
+        if !kanshi_paths.profiles.join(&profile_name).exists() {
+            debug!("Outputs {woken:?} woke from DPMS, no saved profile to reapply");
+            return;
+        }
+        if !wake_tracker.should_reapply() {
+            debug!("Outputs {woken:?} woke from DPMS, skipping reapply (rate limited)");
+            return;
+        }
+        info!("Outputs {woken:?} woke from DPMS, reapplying profile {profile_name} (automatic, not user initiated)");
+        if let Err(e) = reload_kanshi().await {
+            error!("Error reloading kanshi configuration: {e}");
+        }
     }
 }
 
 impl DisplayManagerProperties {
     pub fn new() -> DisplayManagerProperties {
         DisplayManagerProperties {
-            layout: Some(1),
-            support_layout_change: Some(true),
+            layout: Some(LayoutMode::load().unwrap_or(LayoutMode::Logical) as u32),
+            // Until the backend is known, see `set_capabilities`
+            support_layout_change: Some(false),
             global_scale: Some(false),
             legacy_scale_factor: Some(1),
         }
     }
+
+    /// Report what the backend supports, falling back to the logical layout
+    /// mode if it can't change layout modes
+    pub fn set_capabilities(&mut self, capabilities: &Capabilities) {
+        self.support_layout_change = Some(capabilities.layout_mode_change);
+        self.global_scale = Some(capabilities.global_scale_required);
+        if !capabilities.layout_mode_change {
+            self.layout = Some(LayoutMode::Logical as u32);
+        }
+    }
+}
+
+impl Default for DisplayManagerProperties {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ServerError {
@@ -314,19 +1568,28 @@ impl Error for ServerError {
     }
 }
 
+/// Log settings that are likely mistakes or easily mistaken for bugs
+fn warn_about_config(monitors: &[Monitor]) {
+    let config = config::current();
+    if config.allow_overlap {
+        warn!(
+            "displayd.allow_overlap is enabled: overlapping and negative-position layouts are applied without checks"
+        );
+    }
+    if let Some(builtin) = &config.builtin_output {
+        if !monitors.iter().any(|monitor| monitor.get_connector() == builtin) {
+            warn!("displayd.builtin_output is {builtin}, but no output with that connector is connected");
+        }
+    }
+}
+
 pub async fn get_kanshi_paths() -> zbus::Result<KanshiPaths> {
     let env_vars: HashMap<String, String> = std::env::vars().collect();
     let home_dir = env_vars.get("HOME").expect("$HOME not defined");
     let default_path = format!("{home_dir}/.config/regolith3/kanshi");
     let base: PathBuf = match trawlcat::rescat("kanshi.path", Some(default_path.clone())).await {
         Ok(path) => {
-            match path.try_into() {
-                Ok(path_buf) => path_buf,
-                Err(e) => {
-                    warn!("Error: {e}");
-                    default_path.into()
-                }
-            }
+            path.into()
         }
         Err(e) => {
             warn!("Error: {e}");
@@ -335,14 +1598,380 @@ pub async fn get_kanshi_paths() -> zbus::Result<KanshiPaths> {
     };
     let profiles = base.join("profiles");
     let config = base.join("config");
-    return Ok(KanshiPaths { profiles, config });
+    Ok(KanshiPaths { profiles, config })
+}
+
+/// Write `contents` to the profile at `path`.
+///
+/// Profiles kanshi would reject are refused, as a single bad profile stops kanshi
+/// from loading its whole config.
+///
+/// The write runs on a blocking task and gives up after [`PROFILE_WRITE_TIMEOUT`],
+/// so a slow or unresponsive filesystem can't hang the DBus method call. The
+/// profile is replaced atomically (see [`write_atomic`]), and a write that
+/// timed out leaves it as it was even if it finishes later.
+///
+/// On X11, the profile is saved for autorandr as well, see
+/// [`xrandr::write_autorandr_profile`].
+pub async fn write_profile(path: PathBuf, contents: Vec<u8>) -> zbus::fdo::Result<()> {
+    let display = path.display().to_string();
+    let text = String::from_utf8_lossy(&contents).into_owned();
+    if let Err(e) = kanshi::validate_profile(&text) {
+        error!("Not writing invalid kanshi profile {display}: {e}");
+        return Err(zbus::fdo::Error::Failed(format!("Invalid profile for {display}: {e}")));
+    }
+    let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let abandoned = Arc::new(AtomicBool::new(false));
+    let write_abandoned = Arc::clone(&abandoned);
+    let write = tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        write_atomic_unless(&path, &contents, &write_abandoned)
+    });
+    match tokio::time::timeout(PROFILE_WRITE_TIMEOUT, write).await {
+        Ok(Ok(Ok(()))) => {
+            if matches!(backend::current(), Some(Backend::X11(_))) {
+                match xrandr::write_autorandr_profile(&name, &text).await {
+                    Ok(true) => info!("Saved {name} to {}", xrandr::autorandr_dir().display()),
+                    Ok(false) => {}
+                    Err(e) => error!("Cannot save {name} for autorandr: {e}"),
+                }
+            }
+            Ok(())
+        }
+        Ok(Ok(Err(e))) => {
+            error!("Error writing kanshi profile {display}: {e}");
+            Err(zbus::fdo::Error::IOError(format!("Cannot write {display}: {e}")))
+        }
+        Ok(Err(e)) => Err(zbus::fdo::Error::Failed(format!("Profile write failed: {e}"))),
+        Err(_) => {
+            abandoned.store(true, Ordering::Relaxed);
+            error!("Writing kanshi profile {display} timed out");
+            Err(
+                zbus::fdo::Error::IOError(
+                    format!("Writing {display} timed out after {}s", PROFILE_WRITE_TIMEOUT.as_secs())
+                )
+            )
+        }
+    }
+}
+
+/// Replace `path` with `contents` atomically: they are written to a temporary
+/// file next to it, which is then renamed over it, so readers such as kanshi
+/// and niri never see a half-written file. The temporary file starts with a
+/// `.`, which the profiles watcher and kanshi's include glob skip.
+pub fn write_atomic(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    write_atomic_unless(path, contents, &AtomicBool::new(false))
+}
+
+/// [`write_atomic`], leaving `path` alone if `abandoned` is set by the time
+/// the contents are on disk
+fn write_atomic_unless(path: &std::path::Path, contents: &[u8], abandoned: &AtomicBool) -> std::io::Result<()> {
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+    // A symlink, e.g. into a dotfiles repository, is kept and its target replaced
+    let path = &fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let count = WRITES.fetch_add(1, Ordering::Relaxed);
+    let temporary = path.with_file_name(format!(".{name}.{}-{count}.tmp", std::process::id()));
+    let written = (|| {
+        let mut file = File::options().create_new(true).write(true).open(&temporary)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        if abandoned.load(Ordering::Relaxed) {
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "write abandoned"));
+        }
+        fs::rename(&temporary, path)
+    })();
+    if written.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    written
+}
+
+/// Next event of `events`, never resolving without a subscription
+async fn next_event(events: &mut Option<BackendEvents>) -> Option<Result<BackendEvent, BackendError>> {
+    match events {
+        Some(events) => events.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Refuse changes while sway reports several outputs under the same
+/// connector name, see [`DisplayManager::disambiguate_connectors`]. The
+/// numbered names only exist on DBus, and the connector alone can't tell
+/// sway or kanshi which of the outputs is meant.
+pub(crate) fn check_unique_connectors() -> zbus::fdo::Result<()> {
+    let duplicates = DUPLICATE_CONNECTORS.lock().unwrap();
+    if duplicates.is_empty() {
+        return Ok(());
+    }
+    Err(
+        zbus::fdo::Error::Failed(
+            format!("Several outputs are named {}, the outputs can't be changed until sway names them apart", duplicates.join(", "))
+        )
+    )
+}
+
+/// Path the interfaces are served at
+fn served_path() -> &'static str {
+    SERVED_PATH.get().map(String::as_str).unwrap_or(OBJECT_PATH)
+}
+
+/// What the profile `name` at `path` holds before `contents` replace it, to
+/// keep for `RevertLastApply`. `None` for applies that
+/// don't change the profile, which leave the earlier backup alone.
+fn previous_profile(name: &str, path: &std::path::Path, contents: &[u8]) -> Option<previous::Previous> {
+    let previous = match fs::read(path) {
+        Ok(old) if old == contents => return None,
+        Ok(old) => Some(String::from_utf8_lossy(&old).into_owned()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            warn!("Not keeping a backup of {}: {e}", path.display());
+            return None;
+        }
+    };
+    Some(previous::Previous { name: name.to_string(), contents: previous })
+}
+
+/// Add the include of the profiles directory to kanshi's config if it is
+/// missing, without which kanshi never applies the profiles the daemon writes,
+/// and drop the copies `displayd.single_kanshi_config` left there
+async fn ensure_kanshi_include() {
+    let paths = match get_kanshi_paths().await {
+        Ok(paths) => paths,
+        Err(e) => {
+            warn!("Cannot find the kanshi config: {e}");
+            return;
+        }
+    };
+    match kanshi::ensure_include(&paths.config, &paths.profiles) {
+        Ok(true) => {
+            info!("Updated {} to include {}", paths.config.display(), paths.profiles.display());
+            if let Err(e) = reload_kanshi().await {
+                warn!("Cannot reload kanshi: {e}");
+            }
+        }
+        Ok(false) => {}
+        Err(e) => warn!("Not checking the includes of {}: {e}", paths.config.display()),
+    }
 }
 
+/// Copy the profiles into kanshi's config for `displayd.single_kanshi_config`,
+/// returning whether it changed
+async fn write_single_kanshi_config() -> bool {
+    let paths = match get_kanshi_paths().await {
+        Ok(paths) => paths,
+        Err(e) => {
+            warn!("Cannot find the kanshi config: {e}");
+            return false;
+        }
+    };
+    match kanshi::write_single_config(&paths.config, &paths.profiles) {
+        Ok(changed) => changed,
+        Err(e) => {
+            error!("Cannot copy the profiles into {}: {e}", paths.config.display());
+            false
+        }
+    }
+}
+
+/// Make kanshi reread its config: the kanshi run by [`supervisor`] if
+/// `displayd.supervise_kanshi` is on, otherwise with `displayd.kanshi_reload`
+/// if set, with `kanshictl reload` if kanshi's IPC socket exists, or by
+/// restarting it. With `displayd.niri_output_config` on niri, the profiles
+/// go to niri's config instead. On X11 there is nothing to reload. With
+/// `displayd.autoswitch`, the watch loop applies the profile itself.
 pub async fn reload_kanshi() -> zbus::Result<()> {
-    let KanshiPaths { config, .. } = get_kanshi_paths().await?;
+    watcher::reloaded();
+    if config::current().autoswitch {
+        autoswitch::profiles_changed();
+        RESCAN.notify_one();
+    }
+    // autorandr's profiles are written along with the kanshi profiles, see
+    // [`write_profile`]
+    if matches!(backend::current(), Some(Backend::X11(_))) {
+        return Ok(());
+    }
+    if config::current().niri_output_config && matches!(backend::current(), Some(Backend::Niri(_))) {
+        match niri::write_config().await {
+            Ok(true) => info!("Saved the outputs to {}", niri::config_path().display()),
+            Ok(false) => {}
+            Err(e) => error!("Cannot save the outputs to niri's config: {e}"),
+        }
+        return Ok(());
+    }
+    if config::current().autoswitch {
+        return Ok(());
+    }
+    if config::current().single_kanshi_config {
+        write_single_kanshi_config().await;
+    }
+    if config::current().supervise_kanshi {
+        if !supervisor::reload() {
+            info!("kanshi isn't running yet, it will read the config when it starts");
+        }
+        return Ok(());
+    }
+    let KanshiPaths { config: config_file, .. } = get_kanshi_paths().await?;
     let default_config_path = String::from("~/.config/regolith3/kanshi/config");
-    let config_path: String = config.into_os_string().into_string().unwrap_or(default_config_path);
-    Command::new("killall").arg("kanshi").spawn()?;
-    Command::new("kanshi").arg("-c").arg(&config_path).spawn()?;
+    let config_path: String = config_file.into_os_string().into_string().unwrap_or(default_config_path);
+    match &config::current().kanshi_reload {
+        Some(template) => {
+            let command = template.replace("{config}", &shell_quote(&config_path));
+            info!("Reloading kanshi: {command}");
+            Command::new("sh").arg("-c").arg(&command).spawn()?;
+        }
+        None if kanshi_socket().is_some_and(|socket| socket.exists()) => {
+            info!("Reloading kanshi: kanshictl reload");
+            match Command::new("kanshictl").arg("reload").status().await {
+                Ok(status) if status.success() => {}
+                outcome => {
+                    match outcome {
+                        Ok(status) => warn!("kanshictl reload failed ({status}), restarting kanshi"),
+                        Err(e) => warn!("Cannot run kanshictl ({e}), restarting kanshi"),
+                    }
+                    restart_kanshi(&config_path).await?;
+                }
+            }
+        }
+        // Applies already went to sway directly, and the watch loop applies the
+        // saved profiles when the outputs change
+        None if !kanshi_installed() => debug!("Not starting kanshi, it isn't installed"),
+        None => restart_kanshi(&config_path).await?,
+    }
     Ok(())
 }
+
+/// Whether kanshi applies the saved profiles when the outputs change: it
+/// is installed, `displayd.autoswitch` is off and the profiles don't go to
+/// niri's config or autorandr
+pub fn kanshi_applies_profiles() -> bool {
+    if config::current().autoswitch {
+        return false;
+    }
+    let native = match backend::current() {
+        Some(Backend::X11(_)) => true,
+        Some(Backend::Niri(_)) => config::current().niri_output_config,
+        _ => false,
+    };
+    !native && kanshi_installed()
+}
+
+/// Whether kanshi is on `PATH`. Its absence is logged the first time only.
+pub fn kanshi_installed() -> bool {
+    static REPORTED: AtomicBool = AtomicBool::new(false);
+    let installed = std::env::var_os("PATH")
+        .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join("kanshi").is_file()));
+    if !installed && !REPORTED.swap(true, Ordering::Relaxed) {
+        warn!("kanshi isn't installed, saved profiles are applied through sway instead");
+    }
+    installed
+}
+
+/// IPC socket of the kanshi instance of this Wayland display, as listened on
+/// by kanshi 1.4 and later
+fn kanshi_socket() -> Option<PathBuf> {
+    let runtime_dir = std::env::var("XDG_RUNTIME_DIR").ok()?;
+    let display = std::env::var("WAYLAND_DISPLAY").ok()?;
+    Some(PathBuf::from(runtime_dir).join(format!("fr.emersion.kanshi.{display}")))
+}
+
+/// Replace every running kanshi with one reading `config_path`
+async fn restart_kanshi(config_path: &str) -> std::io::Result<()> {
+    info!("Reloading kanshi: killall kanshi; kanshi -c {config_path}");
+    // Waited for, so the old instance can't be the one left running. killall
+    // fails when no kanshi runs, which is fine.
+    Command::new("killall").arg("kanshi").status().await?;
+    Command::new("kanshi").arg("-c").arg(config_path).spawn()?;
+    Ok(())
+}
+
+/// Quote `value` as a single word for `sh`
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{mode, output};
+
+    fn manager(outputs: &[Output]) -> DisplayManager {
+        let (monitors, logical_monitors) = DisplayManager::monitor_info_from_outputs(outputs);
+        DisplayManager {
+            serial: 0,
+            monitors,
+            logical_monitors,
+            properties: DisplayManagerProperties::new(),
+        }
+    }
+
+    #[test]
+    fn arranges_new_monitors_in_their_preferred_mode() {
+        let manager = manager(&[
+            output("HDMI-A-1", vec![mode(1920, 1080, 60000, true)], 0),
+            output("DP-1", vec![mode(2560, 1440, 59951, true), mode(1920, 1080, 60000, false)], 0),
+        ]);
+        assert_eq!(
+            manager.arrange_commands(&[String::from("DP-1")]),
+            ["output DP-1 enable mode 2560x1440@59.951Hz position 1920 0 scale 1"]
+        );
+    }
+
+    #[test]
+    fn knows_the_state_an_apply_already_published() {
+        let outputs = [
+            output("HDMI-A-1", vec![mode(1920, 1080, 60000, true)], 0),
+            output("DP-1", vec![mode(2560, 1440, 59951, true)], 1920),
+        ];
+        let manager = manager(&outputs);
+        let reordered = [outputs[1].clone(), outputs[0].clone()];
+        assert!(manager.is_published(&DisplayManager::monitor_info_from_outputs(&reordered)));
+        assert!(!manager.is_published(&DisplayManager::monitor_info_from_outputs(&outputs[..1])));
+        let moved = [outputs[0].clone(), Output { rect: (0, 1080, 2560, 1440), ..outputs[1].clone() }];
+        assert!(!manager.is_published(&DisplayManager::monitor_info_from_outputs(&moved)));
+    }
+
+    #[test]
+    fn finds_outputs_by_id() {
+        let manager = manager(&[
+            output("HDMI-A-1", vec![mode(1920, 1080, 60000, true)], 0),
+            output("DP-1", vec![mode(2560, 1440, 59951, true)], 1920),
+        ]);
+        assert_eq!(manager.output(0).unwrap().get_connector(), "HDMI-A-1");
+        assert_eq!(manager.output(1).unwrap().get_connector(), "DP-1");
+        assert!(manager.output(2).is_err());
+    }
+
+    #[test]
+    fn numbers_outputs_sharing_a_connector() {
+        let outputs = [
+            output("DP-1", vec![mode(1920, 1080, 60000, true)], 0),
+            output("DP-1", vec![mode(2560, 1440, 59951, true)], 1920),
+            output("DP-1", vec![mode(1280, 1024, 60020, true)], 4480),
+            output("HDMI-A-1", vec![mode(1920, 1080, 60000, true)], 5760),
+        ];
+        let (names, connectors): (Vec<String>, Vec<String>) = DisplayManager::disambiguate_connectors(&outputs)
+            .into_iter()
+            .map(|(name, output)| (name, output.name))
+            .unzip();
+        assert_eq!(names, ["DP-1", "DP-1-2", "DP-1-3", "HDMI-A-1"]);
+        // What is sent to sway still uses the connector sway knows
+        assert_eq!(connectors, ["DP-1", "DP-1", "DP-1", "HDMI-A-1"]);
+        let manager = manager(&outputs);
+        assert_eq!(manager.monitors.len(), 4);
+        // Looking them up by connector finds each one rather than the first DP-1
+        let modes: Vec<&str> = manager.logical_monitors
+            .iter()
+            .map(|logical_monitor| {
+                manager.monitors
+                    .iter()
+                    .find(|monitor| monitor.get_connector() == logical_monitor.get_connector())
+                    .unwrap()
+                    .get_current_mode()
+            })
+            .collect();
+        assert_eq!(modes, ["1920x1080@60Hz", "2560x1440@59.951Hz", "1280x1024@60.02Hz", "1920x1080@60Hz"]);
+    }
+}
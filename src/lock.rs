@@ -0,0 +1,139 @@
+//! Mutexes that are always locked in the same order.
+//!
+//! Every lock shared between the DBus handlers, the watch loop and the revert
+//! timer has a [`Rank`], and a task only ever waits for a lock ranked after
+//! every lock it already holds:
+//!
+//! 1. the [`DisplayManager`](crate::DisplayManager)
+//! 2. the time of the last apply
+//! 3. a sway connection, either the one used for applies or the watch loop's
+//! 4. the pending revert
+//! 5. the change history
+//! 6. the settings of outputs turned off with `SetOutputEnabled`
+//! 7. the DBus connection used to emit signals
+//!
+//! Debug builds panic as soon as a task breaks the order, so a mistake shows up
+//! the first time the code path runs instead of as a rare deadlock.
+use std::ops::{Deref, DerefMut};
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Rank {
+    Manager,
+    LastApply,
+    Sway,
+    Revert,
+    History,
+    Outputs,
+    Bus,
+}
+
+/// Types shared behind a [`Mutex`], with their place in the lock order
+pub trait Ranked {
+    const RANK: Rank;
+}
+
+impl Ranked for Option<Instant> {
+    const RANK: Rank = Rank::LastApply;
+}
+
+impl Ranked for Option<zbus::Connection> {
+    const RANK: Rank = Rank::Bus;
+}
+
+/// [`tokio::sync::Mutex`] checking the lock order in debug builds
+#[derive(Debug, Default)]
+pub struct Mutex<T> {
+    inner: tokio::sync::Mutex<T>,
+}
+
+pub struct MutexGuard<'a, T> {
+    inner: tokio::sync::MutexGuard<'a, T>,
+    #[cfg(debug_assertions)]
+    _held: Option<order::Held>,
+}
+
+impl<T: Ranked> Mutex<T> {
+    pub fn new(value: T) -> Mutex<T> {
+        Mutex {
+            inner: tokio::sync::Mutex::new(value),
+        }
+    }
+
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        #[cfg(debug_assertions)]
+        order::check(T::RANK);
+        let inner = self.inner.lock().await;
+        MutexGuard {
+            inner,
+            #[cfg(debug_assertions)]
+            _held: order::hold(T::RANK),
+        }
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+/// Locks held by each task. Locks taken outside of a task aren't tracked.
+#[cfg(debug_assertions)]
+mod order {
+    use super::Rank;
+    use lazy_static::lazy_static;
+    use std::{collections::HashMap, sync::Mutex};
+    use tokio::task::{self, Id};
+
+    lazy_static! {
+        static ref HELD: Mutex<HashMap<Id, Vec<Rank>>> = Mutex::new(HashMap::new());
+    }
+
+    pub struct Held {
+        task: Id,
+        rank: Rank,
+    }
+
+    /// Panic if the current task holds a lock ranked at or after `rank`
+    pub fn check(rank: Rank) {
+        let Some(task) = task::try_id() else {
+            return;
+        };
+        let held = HELD.lock().unwrap();
+        if let Some(&last) = held.get(&task).and_then(|ranks| ranks.iter().max()) {
+            assert!(
+                last < rank,
+                "lock order violation: locking {rank:?} while holding {last:?}"
+            );
+        }
+    }
+
+    pub fn hold(rank: Rank) -> Option<Held> {
+        let task = task::try_id()?;
+        HELD.lock().unwrap().entry(task).or_default().push(rank);
+        Some(Held { task, rank })
+    }
+
+    impl Drop for Held {
+        fn drop(&mut self) {
+            let mut held = HELD.lock().unwrap();
+            if let Some(ranks) = held.get_mut(&self.task) {
+                if let Some(index) = ranks.iter().position(|&rank| rank == self.rank) {
+                    ranks.swap_remove(index);
+                }
+                if ranks.is_empty() {
+                    held.remove(&self.task);
+                }
+            }
+        }
+    }
+}
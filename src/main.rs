@@ -1,27 +1,201 @@
-use log::error;
-use regolith_displayd::{ DisplayManager, DisplayServer };
-use std::{ error::Error, future::pending, sync::Arc };
-use swayipc_async::Connection as SwayConection;
-use tokio::{ sync::Mutex, try_join };
+use log::{ error, info, warn };
+use regolith_displayd::{
+    autoswitch,
+    backend::Compositor,
+    backup,
+    config::{ self, BackendKind, Config, BACKEND_ENV },
+    export,
+    get_kanshi_paths,
+    instance::{ self, Instance },
+    kanshi::{ self, Severity },
+    lock::Mutex,
+    nightlight,
+    privacy,
+    profiles,
+    supervisor,
+    watcher,
+    DisplayManager,
+    DisplayServer,
+};
+use std::{ error::Error, fs, future::pending, process, sync::Arc, time::Duration };
+use tokio::try_join;
+
+/// How often a replacing instance tries to take the instance lock
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(200);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     pretty_env_logger::init();
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        None => run_daemon(DaemonOptions::default()).await,
+        Some(flag) if flag.starts_with("--") => match DaemonOptions::parse(&args[1..]) {
+            Ok(options) => run_daemon(options).await,
+            Err(e) => {
+                error!("{e}");
+                error!("Usage: regolith-displayd [--replace] [--bus-name NAME] [--object-path PATH] [--backend NAME]");
+                process::exit(1);
+            }
+        },
+        Some("export-wlr-randr") => export_wlr_randr().await,
+        Some("check-kanshi") => check_kanshi().await,
+        Some("show-profile") => show_profile().await,
+        Some("list-profiles") => list_profiles().await,
+        Some("set-profile-name") => match (args.get(2), args.get(3)) {
+            (Some(profile), Some(name)) => set_profile_name(profile, name).await,
+            _ => {
+                error!("Usage: regolith-displayd set-profile-name PROFILE NAME");
+                process::exit(1);
+            }
+        },
+        Some("export-state") => export_state(args.get(2).map(String::as_str)).await,
+        Some("import-state") => match args.get(2) {
+            Some(path) => import_state(path).await,
+            None => {
+                error!("Usage: regolith-displayd import-state FILE");
+                process::exit(1);
+            }
+        },
+        Some(command) => {
+            error!("Unknown command: {command}");
+            process::exit(1);
+        }
+    }
+}
+
+/// Flags of the daemon
+#[derive(Default)]
+struct DaemonOptions {
+    /// Take over from a running instance
+    replace: bool,
+    /// Overrides `displayd.bus_name`
+    bus_name: Option<String>,
+    /// Overrides `displayd.object_path`
+    object_path: Option<String>,
+    /// Overrides `displayd.backend` and its environment variable
+    backend: Option<BackendKind>,
+}
+
+impl DaemonOptions {
+    fn parse(args: &[String]) -> Result<DaemonOptions, String> {
+        let mut options = DaemonOptions::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--replace" => options.replace = true,
+                "--bus-name" => {
+                    let name = args.next().ok_or("--bus-name expects a name")?;
+                    options.bus_name = Some(config::parse_bus_name(name).ok_or(format!("Invalid bus name {name}"))?);
+                }
+                "--object-path" => {
+                    let path = args.next().ok_or("--object-path expects a path")?;
+                    options.object_path = Some(config::parse_object_path(path).ok_or(format!("Invalid object path {path}"))?);
+                }
+                "--backend" => {
+                    let name = args.next().ok_or("--backend expects a name")?;
+                    options.backend = Some(config::parse_backend(name).ok_or(format!("Unknown backend {name}"))?);
+                }
+                _ => return Err(format!("Unknown option: {arg}")),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Connect to the compositor, exiting if there is none the daemon can drive
+async fn connect_compositor() -> Compositor {
+    match Compositor::connect().await {
+        Ok(compositor) => compositor,
+        Err(e) => {
+            error!("Unable to connect to the compositor ({e}). Set displayd.backend, {BACKEND_ENV} or --backend to pick one");
+            process::exit(1);
+        }
+    }
+}
+
+/// Run the daemon. With `--replace`, a running instance is taken over
+/// instead of left alone.
+async fn run_daemon(options: DaemonOptions) -> Result<(), Box<dyn Error>> {
+    let replace = options.replace;
+    // Held until the daemon exits
+    let _instance_lock = match instance::lock() {
+        Ok(Instance::Acquired(file)) => Some(file),
+        Ok(Instance::Running(pid)) => {
+            let pid = pid.map(|pid| format!(" (pid {pid})")).unwrap_or_default();
+            if !replace {
+                info!("regolith-displayd is already running{pid}, exiting");
+                return Ok(());
+            }
+            info!("Replacing the running regolith-displayd{pid}");
+            // Taken once the other instance has handed over the bus name and exited
+            tokio::spawn(async {
+                loop {
+                    tokio::time::sleep(LOCK_RETRY_INTERVAL).await;
+                    if let Ok(Instance::Acquired(file)) = instance::lock() {
+                        let _file = file;
+                        pending::<()>().await;
+                    }
+                }
+            });
+            None
+        }
+        Err(e) => {
+            warn!("Cannot take the instance lock {}: {e}", instance::lock_path().display());
+            None
+        }
+    };
+    let mut config = Config::load().await;
+    if options.bus_name.is_some() {
+        config.bus_name = options.bus_name;
+        config.set_from_cli("displayd.bus_name");
+    }
+    if options.object_path.is_some() {
+        config.object_path = options.object_path;
+        config.set_from_cli("displayd.object_path");
+    }
+    if options.backend.is_some() {
+        config.backend = options.backend;
+        config.set_from_cli("displayd.backend");
+    }
+    config::set(config);
     // New pointer to Display Manager Object
     let manager = DisplayManager::new().await;
     let manager_ref = Arc::new(Mutex::new(manager));
-    let sway_connection = SwayConection::new().await.expect(
-        "Unable to connect to sway ipc interface. Make sure sway is running and SWAYSOCK is set"
-    );
-    let sway_connection_ref = Arc::new(Mutex::new(sway_connection));
+    let backend = Arc::new(connect_compositor().await);
     let server = DisplayServer::new(
         Arc::clone(&manager_ref),
-        Arc::clone(&sway_connection_ref)
+        Arc::clone(&backend)
     ).await;
-    server.run_server().await.unwrap();
+    if config::current().autoswitch {
+        tokio::spawn(autoswitch::stop_kanshi());
+    } else if config::current().supervise_kanshi {
+        tokio::spawn(supervisor::supervise());
+    }
+    tokio::spawn(async {
+        if let Err(e) = nightlight::watch().await {
+            warn!("Night light unavailable: {e}");
+        }
+    });
+    tokio::spawn(async {
+        if let Err(e) = privacy::watch().await {
+            warn!("Not following the privacy screen setting: {e}");
+        }
+    });
+    server
+        .run_server(replace)
+        .await
+        .map_err(|e| format!("Cannot serve the display configuration on the session bus: {e}"))?;
+    let watched_manager = Arc::clone(&manager_ref);
+    tokio::spawn(async move {
+        if let Err(e) = watcher::watch(watched_manager).await {
+            warn!("Not watching the kanshi profiles for changes: {e}");
+        }
+    });
 
+    // The watch loop gets its own connection so polling doesn't contend with applies
+    let watch_backend = connect_compositor().await;
     let watch_handle = tokio::spawn(async move {
-        DisplayManager::watch_changes(manager_ref, sway_connection_ref).await.unwrap();
+        DisplayManager::watch_changes(manager_ref, watch_backend).await.unwrap();
     });
 
     if let Err(e) = try_join!(watch_handle) {
@@ -29,4 +203,109 @@ async fn main() -> Result<(), Box<dyn Error>> {
     }
     pending::<()>().await;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Print the current layout as a `wlr-randr` command line
+async fn export_wlr_randr() -> Result<(), Box<dyn Error>> {
+    config::set(Config::load().await);
+    let backend = connect_compositor().await;
+    let (monitors, logical_monitors) = DisplayManager::get_monitor_info(&backend).await?;
+    println!("{}", export::wlr_randr_command(&monitors, &logical_monitors));
+    Ok(())
+}
+
+/// Report problems with the kanshi config and profiles, exiting with a
+/// nonzero status if any of them is an error
+async fn check_kanshi() -> Result<(), Box<dyn Error>> {
+    config::set(Config::load().await);
+    let paths = get_kanshi_paths().await?;
+    // Without the compositor the profiles are still checked, just not against the connected outputs
+    let monitors = match Compositor::connect().await {
+        Ok(backend) => Some(DisplayManager::get_monitor_info(&backend).await?.0),
+        Err(e) => {
+            warn!("Not checking output names, cannot connect to the compositor: {e}");
+            None
+        }
+    };
+    let single_config = config::current().single_kanshi_config;
+    let problems = kanshi::check(&paths.config, &paths.profiles, monitors.as_deref(), single_config);
+    for problem in &problems {
+        println!("{problem}");
+    }
+    if problems.iter().any(|problem| problem.severity == Severity::Error) {
+        process::exit(1);
+    }
+    println!("kanshi configuration OK");
+    Ok(())
+}
+
+/// Print the saved kanshi profile for the connected outputs, exiting with a
+/// nonzero status if there is none
+async fn show_profile() -> Result<(), Box<dyn Error>> {
+    config::set(Config::load().await);
+    let backend = connect_compositor().await;
+    let mut manager = DisplayManager::new().await;
+    manager.set_monitor_info(DisplayManager::get_monitor_info(&backend).await?);
+    profiles::load_existing().await;
+    let profile_name = manager.profile_name();
+    let profile_path = get_kanshi_paths().await?.profiles.join(&profile_name);
+    match fs::read_to_string(&profile_path) {
+        Ok(contents) => {
+            println!("# {}", profile_path.display());
+            print!("{contents}");
+            Ok(())
+        }
+        Err(e) => {
+            println!("No saved profile for the connected outputs ({}: {e})", profile_path.display());
+            process::exit(1);
+        }
+    }
+}
+
+/// Print the file names of the saved kanshi profiles, followed by their
+/// names after a tab if they have one
+async fn list_profiles() -> Result<(), Box<dyn Error>> {
+    profiles::load_existing().await;
+    for file in profiles::list(&get_kanshi_paths().await?.profiles)? {
+        match profiles::display_name(&file) {
+            Some(name) => println!("{file}\t{name}"),
+            None => println!("{file}"),
+        }
+    }
+    Ok(())
+}
+
+/// Name the saved profile `profile` `name`, or remove its name if `name` is
+/// empty, as `SetProfileName` does
+async fn set_profile_name(profile: &str, name: &str) -> Result<(), Box<dyn Error>> {
+    config::set(Config::load().await);
+    profiles::load_existing().await;
+    profiles::set_name(profile, name).await?;
+    Ok(())
+}
+
+/// Write the saved state (see [`backup`]) as JSON to `path`, or to stdout
+async fn export_state(path: Option<&str>) -> Result<(), Box<dyn Error>> {
+    config::set(Config::load().await);
+    let json = serde_json::to_string_pretty(&backup::export().await?)?;
+    match path {
+        Some(path) => fs::write(path, json + "\n")?,
+        None => println!("{json}"),
+    }
+    Ok(())
+}
+
+/// Restore the state exported to `path` by `export-state`
+async fn import_state(path: &str) -> Result<(), Box<dyn Error>> {
+    config::set(Config::load().await);
+    let bundle = match backup::parse(&fs::read_to_string(path)?) {
+        Ok(bundle) => bundle,
+        Err(e) => {
+            error!("Cannot import {path}: {e}");
+            process::exit(1);
+        }
+    };
+    bundle.import().await?;
+    println!("Imported {path}, restart regolith-displayd and kanshi to use it");
+    Ok(())
+}
@@ -1,7 +1,27 @@
+use crate::backend::{Output, OutputMode};
+use crate::config;
 use serde::{Deserialize, Serialize};
-use swayipc_async::{Mode as SwayMode, Output};
+use std::sync::atomic::{AtomicBool, Ordering};
 use zvariant::{DeserializeDict, SerializeDict, Type};
 
+/// Largest distance from an integer, in Hz, for a refresh rate to be snapped
+const REFRESH_SNAP_EPSILON: f64 = 0.01;
+/// Largest difference, in mHz, between sway's current refresh rate and a
+/// listed mode for that mode to still count as the current one
+const CURRENT_REFRESH_TOLERANCE: i32 = 20;
+/// Largest difference, in Hz, between two refresh rates written in a mode
+/// id for them to be the same, sway writing them to the mHz
+const SAME_REFRESH_TOLERANCE: f64 = 0.0005;
+
+/// Whether the compositor scales outputs, see [`set_scaling_supported`]
+static SCALING_SUPPORTED: AtomicBool = AtomicBool::new(true);
+
+/// Record whether the compositor scales outputs. X11 doesn't, so only a
+/// scale of 1 is offered there.
+pub fn set_scaling_supported(supported: bool) {
+    SCALING_SUPPORTED.store(supported, Ordering::Relaxed);
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, Type, PartialEq)]
 pub struct Modes {
     id: String,
@@ -22,6 +42,10 @@ pub struct ModeProperties {
     preferred: Option<bool>,
     #[zvariant(rename = "is-interlaced")]
     interlaced: Option<bool>,
+    /// The mode as sway writes it, e.g. `1920x1080@59.951Hz`, which can
+    /// differ from `id` when refresh rates are snapped
+    #[zvariant(rename = "sway-mode-id")]
+    sway_id: Option<String>,
 }
 
 impl Modes {
@@ -29,55 +53,197 @@ impl Modes {
         &self.id
     }
 
-    pub fn new(output: &Output, mode_info: &SwayMode) -> Modes {
-        let SwayMode {
+    /// The mode as sway writes it, see [`ModeProperties`]
+    pub fn sway_id(&self) -> Option<&str> {
+        self.properties.sway_id.as_deref()
+    }
+
+    /// Whether the refresh rate reported to clients was snapped (see
+    /// [`Modes::new`]) rather than just written differently from sway's,
+    /// e.g. `60Hz` for `59.951Hz` but not for `60.000Hz`
+    pub fn is_snapped(&self) -> bool {
+        match self.sway_id().and_then(refresh_of) {
+            Some(refresh) => (refresh - self.refresh_rate).abs() > SAME_REFRESH_TOLERANCE,
+            None => false,
+        }
+    }
+
+    /// Whether `id` is either the id reported to clients or sway's own
+    pub fn has_id(&self, id: &str) -> bool {
+        self.id == id || self.properties.sway_id.as_deref() == Some(id)
+    }
+
+    pub fn new(output: &Output, mode_info: &OutputMode) -> Modes {
+        let OutputMode {
             height,
             width,
             refresh,
-            ..
+            preferred,
         } = *mode_info;
         let is_current = match &output.current_mode {
-            Some(x) => Self::is_current_mode(x, mode_info),
+            Some(x) => Self::is_current_mode(x, mode_info, &output.modes),
             _ => false,
         };
 
         let properties = ModeProperties {
             current: Some(is_current),
             interlaced: Some(false),
-            preferred: Some(false),
+            preferred: Some(preferred),
+            sway_id: Some(format!(
+                "{width}x{height}@{}.{:03}Hz",
+                refresh / 1000,
+                refresh % 1000
+            )),
         };
-        let supported_scales = if width >= 1920 && height >= 1080 {
+        let supported_scales = if !SCALING_SUPPORTED.load(Ordering::Relaxed) {
+            [1.0].to_vec()
+        } else if width >= 1920 && height >= 1080 {
             [1.0, 1.25, 1.5, 1.75, 2.0].to_vec()
         } else {
             [1.0, 2.0].to_vec()
         };
+        let refresh_rate = Self::normalize_refresh(refresh);
         Modes {
             width,
             height,
             supported_scales,
             id: format!(
                 "{}x{}@{}Hz",
-                mode_info.width,
-                mode_info.height,
-                (mode_info.refresh as f64) / 1000f64
+                mode_info.width, mode_info.height, refresh_rate
             ),
             preferred_scale: 1f64,
-            refresh_rate: (refresh as f64) / 1000f64,
+            refresh_rate,
             properties,
         }
     }
+    /// Refresh rate in Hz from sway's mHz, snapped to the nearest integer when
+    /// enabled in the config and within [`REFRESH_SNAP_EPSILON`] of it.
+    /// Genuinely fractional rates such as 59.94Hz are left alone.
+    fn normalize_refresh(refresh: i32) -> f64 {
+        let rate = (refresh as f64) / 1000f64;
+        if config::current().snap_refresh && (rate - rate.round()).abs() < REFRESH_SNAP_EPSILON {
+            rate.round()
+        } else {
+            rate
+        }
+    }
+    pub fn get_size(&self) -> (i32, i32) {
+        (self.width, self.height)
+    }
     pub fn get_modestr(&self) -> &str {
         &self.id
     }
+    pub fn supported_scales(&self) -> &[f64] {
+        &self.supported_scales
+    }
     pub fn is_valid_scale(&self, scale: f64) -> bool {
         self.supported_scales.contains(&scale)
     }
-    pub fn is_current_mode(actual: &SwayMode, current: &SwayMode) -> bool {
-        current.height == actual.height
-            && current.width == actual.width
-            && current.refresh == actual.refresh
+    /// Whether `mode`, one of the output's `modes`, is the `current` one.
+    /// sway's current refresh rate sometimes differs by a few mHz from every
+    /// mode it lists, so the modes of the same size closest to it, within
+    /// [`CURRENT_REFRESH_TOLERANCE`], count as current.
+    pub fn is_current_mode(current: &OutputMode, mode: &OutputMode, modes: &[OutputMode]) -> bool {
+        let distance_to_current = |mode: &OutputMode| {
+            (mode.width == current.width && mode.height == current.height)
+                .then(|| (mode.refresh - current.refresh).abs())
+                .filter(|&distance| distance <= CURRENT_REFRESH_TOLERANCE)
+        };
+        match distance_to_current(mode) {
+            Some(distance) => modes.iter().filter_map(distance_to_current).min() == Some(distance),
+            None => false,
+        }
     }
     pub fn current(&self) -> bool {
         self.properties.current == Some(true)
     }
+    pub fn preferred(&self) -> bool {
+        self.properties.preferred == Some(true)
+    }
+    /// Mark the mode of `modes` closest to `timing`, the width, height and
+    /// refresh rate in mHz of the monitor's preferred timing, as preferred
+    pub fn mark_preferred(modes: &mut [Modes], (width, height, refresh): (i32, i32, i32)) {
+        let closest = modes
+            .iter_mut()
+            .filter(|mode| mode.width == width && mode.height == height)
+            .min_by_key(|mode| ((mode.refresh_rate * 1000.0).round() as i32 - refresh).abs());
+        if let Some(mode) = closest {
+            mode.properties.preferred = Some(true);
+        }
+    }
+    /// Whether the mode is at least `width`x`height`
+    pub fn is_at_least(&self, (width, height): (i32, i32)) -> bool {
+        self.width >= width && self.height >= height
+    }
+}
+
+/// Refresh rate in Hz of mode id `id`, e.g. 59.951 for `1920x1080@59.951Hz`
+fn refresh_of(id: &str) -> Option<f64> {
+    let (_, refresh) = id.split_once('@')?;
+    refresh.trim_end_matches("Hz").parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, mode};
+
+    fn output(modes: Vec<OutputMode>, current_mode: Option<OutputMode>) -> Output {
+        Output {
+            active: current_mode.is_some(),
+            current_mode,
+            ..testing::output("DP-1", modes, 0)
+        }
+    }
+
+    fn modes(output: &Output) -> Vec<Modes> {
+        output.modes.iter().map(|mode| Modes::new(output, mode)).collect()
+    }
+
+    #[test]
+    fn reports_the_preferred_mode_of_the_backend() {
+        let preferred = mode(2560, 1440, 59951, true);
+        let output = output(vec![preferred, mode(1920, 1080, 60000, false)], Some(mode(1920, 1080, 60000, false)));
+        let modes = modes(&output);
+        assert!(modes[0].preferred());
+        assert!(!modes[1].preferred());
+    }
+
+    #[test]
+    fn marks_the_mode_closest_to_the_preferred_timing() {
+        let output = output(
+            vec![mode(2560, 1440, 143912, false), mode(2560, 1440, 59951, false), mode(1920, 1080, 60000, false)],
+            None,
+        );
+        let mut modes = modes(&output);
+        Modes::mark_preferred(&mut modes, (2560, 1440, 59950));
+        let preferred: Vec<bool> = modes.iter().map(Modes::preferred).collect();
+        assert_eq!(preferred, [false, true, false]);
+    }
+
+    #[test]
+    fn takes_the_closest_mode_as_current() {
+        let listed = vec![mode(2560, 1440, 59951, false), mode(2560, 1440, 59940, false), mode(1920, 1080, 59954, false)];
+        let close = output(listed.clone(), Some(mode(2560, 1440, 59954, false)));
+        let current: Vec<bool> = modes(&close).iter().map(Modes::current).collect();
+        assert_eq!(current, [true, false, false]);
+        // Too far from every listed refresh rate
+        let far = output(listed, Some(mode(2560, 1440, 59990, false)));
+        assert!(!modes(&far).iter().any(Modes::current));
+    }
+
+    #[test]
+    fn is_found_by_either_id() {
+        let output = output(vec![mode(1920, 1080, 60000, false)], None);
+        let mode = &modes(&output)[0];
+        assert_eq!(mode.get_id(), "1920x1080@60Hz");
+        assert_eq!(mode.sway_id(), Some("1920x1080@60.000Hz"));
+        assert!(mode.has_id("1920x1080@60Hz"));
+        assert!(mode.has_id("1920x1080@60.000Hz"));
+        assert!(!mode.has_id("1920x1080@59.940Hz"));
+        // 60Hz and 60.000Hz are the same rate written differently
+        assert!(!mode.is_snapped());
+        assert_eq!(refresh_of("1920x1080@59.951Hz"), Some(59.951));
+        assert_eq!(refresh_of("1920x1080@60Hz"), Some(60.0));
+    }
 }
@@ -1,16 +1,47 @@
+use crate::backend::{self, Backend, DisplayBackend, Output};
+use crate::backlight::Backlight;
+use crate::config;
+use crate::edid::{self, Edid, EdidError};
+use crate::layout::{LayoutMode, Rect};
 use crate::modes::Modes;
-use log::{error, warn};
+use crate::privacy;
+use log::{debug, warn};
 use num;
 use num_derive::FromPrimitive;
 use serde::{Deserialize, Serialize};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
 use std::hash::Hash;
 use std::io::Write;
-use std::sync::Arc;
-use swayipc_async::{Connection, Output};
-use tokio::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex as StdMutex;
 use zbus::fdo::Error::{self as ZError, Failed};
 use zvariant::{DeserializeDict, SerializeDict, Type};
 
+/// Whether the compositor accepts `allow_tearing`, see [`set_tearing_supported`]
+static TEARING_SUPPORTED: AtomicBool = AtomicBool::new(false);
+/// Whether the compositor accepts `render_bit_depth`, see
+/// [`set_render_bit_depth_supported`]
+static RENDER_BIT_DEPTH_SUPPORTED: AtomicBool = AtomicBool::new(false);
+/// Whether the compositor accepts `hdr`, see [`set_hdr_supported`]
+static HDR_SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// Color mode last applied to each connector. sway doesn't report it, so
+    /// outputs the daemon hasn't changed are reported in the default mode.
+    static ref COLOR_MODES: StdMutex<HashMap<String, ColorMode>> = StdMutex::new(HashMap::new());
+    /// Whether tearing was last allowed on each connector, as sway 1.9 and
+    /// later report it (see [`remember_reported`]) or else as the daemon last
+    /// applied it. Outputs the daemon hasn't changed use sway's default, off.
+    static ref TEARING: StdMutex<HashMap<String, bool>> = StdMutex::new(HashMap::new());
+    /// Render bit depth last applied to each connector, also unreported.
+    /// Outputs the daemon hasn't changed use sway's default, 8.
+    static ref BIT_DEPTHS: StdMutex<HashMap<String, u32>> = StdMutex::new(HashMap::new());
+    /// `max_render_time` of each connector in milliseconds, 0 for off, as
+    /// sway reports it (see [`remember_reported`]) or else as last set.
+    static ref RENDER_TIMES: StdMutex<HashMap<String, u32>> = StdMutex::new(HashMap::new());
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Type)]
 pub struct Monitor {
     description: (String, String, String, String),
@@ -29,7 +60,7 @@ pub struct LogicalMonitor {
     properties: LogicalMonitorProperties,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, DeserializeDict, SerializeDict, Type, Hash)]
+#[derive(Debug, Default, PartialEq, Eq, Clone, DeserializeDict, SerializeDict, Type, Hash)]
 #[zvariant(signature = "dict")]
 pub struct MonitorProperties {
     #[zvariant(rename = "width-mm")]
@@ -44,6 +75,78 @@ pub struct MonitorProperties {
     max_size: Option<(i32, i32)>,
     #[zvariant(rename = "display-name")]
     name: Option<String>,
+    /// Reported: whether the output can use immediate (tearing) page flips
+    #[zvariant(rename = "supports-tearing")]
+    supports_tearing: Option<bool>,
+    /// Reported: whether tearing page flips were last allowed by the daemon.
+    /// Requested in `ApplyMonitorsConfig`: allow tearing page flips on the
+    /// output. Unset keeps the last setting.
+    #[zvariant(rename = "allow-tearing")]
+    allow_tearing: Option<bool>,
+    /// Reported: whether the EDID advertises adaptive sync (VRR) support, see
+    /// [`Edid::adaptive_sync`]
+    #[zvariant(rename = "supports-adaptive-sync")]
+    supports_adaptive_sync: Option<bool>,
+    /// Reported: whether sway has adaptive sync on. Requested: adaptive sync,
+    /// defaulting to `displayd.adaptive_sync`
+    #[zvariant(rename = "adaptive-sync")]
+    adaptive_sync: Option<bool>,
+    /// Requested: `linear`, `nearest` or `smart`, defaulting to `displayd.scale_filter`
+    #[zvariant(rename = "scale-filter")]
+    scale_filter: Option<String>,
+    /// Reported: the bits per color channel sway last rendered the output with
+    /// at the daemon's request. Requested: one of [`RENDER_BIT_DEPTHS`],
+    /// unset keeps the last setting.
+    #[zvariant(rename = "render-bit-depth")]
+    render_bit_depth: Option<u32>,
+    /// Reported: the `max_render_time` last set with `SetMaxRenderTime`, in
+    /// milliseconds, 0 when off as it is by default
+    #[zvariant(rename = "max-render-time")]
+    max_render_time: Option<u32>,
+    /// Reported: the output is virtual (see [`is_virtual_connector`]), its
+    /// physical size is unknown
+    #[zvariant(rename = "is-virtual")]
+    virtual_output: Option<bool>,
+    /// Reported: the raw EDID as read from `/sys/class/drm`, unset when it
+    /// couldn't be read or parsed
+    edid: Option<Vec<u8>>,
+    /// Reported for built-in panels with a privacy screen (see [`privacy`]):
+    /// whether it is on, and whether it is locked, which it never is here
+    #[zvariant(rename = "privacy-screen-state")]
+    privacy_screen: Option<(bool, bool)>,
+    /// Reported: the color mode last applied, see [`ColorMode`]. Requested:
+    /// the color mode to switch to, unset leaves it alone.
+    #[zvariant(rename = "color-mode")]
+    color_mode: Option<u32>,
+    /// Reported: HDR is listed when both the output (from its EDID) and the
+    /// compositor support it
+    #[zvariant(rename = "supported-color-modes")]
+    supported_color_modes: Option<Vec<u32>>,
+    /// Reported for built-in panels with a `/sys/class/backlight` device: the
+    /// brightness range and value, in percent as taken by `ChangeBacklight`
+    #[zvariant(rename = "backlight-min")]
+    backlight_min: Option<i32>,
+    #[zvariant(rename = "backlight-max")]
+    backlight_max: Option<i32>,
+    backlight: Option<i32>,
+}
+
+/// Scale filters accepted by sway's `output scale_filter`
+pub const SCALE_FILTERS: [&str; 3] = ["linear", "nearest", "smart"];
+
+/// Bit depths accepted by sway's `output render_bit_depth`
+pub const RENDER_BIT_DEPTHS: [u32; 2] = [8, 10];
+
+/// Prefixes of the outputs of sway's headless, nested Wayland and X11
+/// backends, including those made with `swaymsg create_output`
+const VIRTUAL_PREFIXES: [&str; 4] = ["HEADLESS-", "NOOP-", "WL-", "X11-"];
+
+/// Color modes of the `color-mode` monitor property, as in mutter
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    Default = 0,
+    /// HDR: BT.2100 primaries with the PQ transfer function
+    Bt2100 = 1,
 }
 
 #[derive(FromPrimitive, PartialEq, Eq)]
@@ -79,7 +182,20 @@ pub struct MonitorApply {
 
 impl Monitor {
     pub fn new(output: &Output) -> Monitor {
-        let output_modes = output.modes.iter().map(|m| Modes::new(output, m)).collect();
+        let mut output_modes: Vec<Modes> = output.modes.iter().map(|m| Modes::new(output, m)).collect();
+        let properties = MonitorProperties::new(output);
+        // sway doesn't report the preferred mode, which is the EDID's first
+        // detailed timing
+        if !output_modes.iter().any(Modes::preferred) {
+            let timing = properties
+                .edid
+                .as_deref()
+                .and_then(|raw| Edid::parse(raw).ok())
+                .and_then(|edid| edid.preferred_mode);
+            if let Some(timing) = timing {
+                Modes::mark_preferred(&mut output_modes, timing);
+            }
+        }
         let description = (
             output.name.clone(),   // connector
             output.make.clone(),   // vendor
@@ -89,12 +205,12 @@ impl Monitor {
         Monitor {
             description,
             modes: output_modes,
-            properties: MonitorProperties::new(output),
+            properties,
         }
     }
 
     pub fn search_modes(&self, mode_id: &str) -> Option<&Modes> {
-        self.modes.iter().find(|&m| m.get_id() == mode_id)
+        self.modes.iter().find(|&m| m.has_id(mode_id))
     }
 
     pub fn get_dpy_name(&self) -> String {
@@ -102,12 +218,101 @@ impl Monitor {
         format!("{} {} {}", desc.1, desc.2, desc.3)
     }
 
+    /// Criteria identifying the monitor in kanshi profiles. This is the output
+    /// description unless another of `monitors` shares it (identical models
+    /// reporting the same serial) or it has neither make nor model, in which
+    /// case the connector is used so kanshi can tell them apart.
+    pub fn kanshi_name(&self, monitors: &[Monitor]) -> String {
+        let dpy_name = self.get_dpy_name();
+        let shared = monitors.iter().any(|other| {
+            other.get_connector() != self.get_connector() && other.get_dpy_name() == dpy_name
+        });
+        let (_, make, model, _) = &self.description;
+        if shared || make.trim().is_empty() && model.trim().is_empty() {
+            self.get_connector().to_string()
+        } else {
+            dpy_name
+        }
+    }
+
+    /// The name earlier versions gave the monitor in profile file names when
+    /// its description is incomplete (see [`Monitor::has_full_description`]):
+    /// the connector followed by a fingerprint of its EDID. `None` for
+    /// monitors those versions named like [`kanshi_name`] does.
+    ///
+    /// [`kanshi_name`]: Monitor::kanshi_name
+    pub fn legacy_profile_key(&self) -> Option<String> {
+        if self.has_full_description() {
+            return None;
+        }
+        let connector = self.get_connector();
+        Some(match &self.properties.edid {
+            Some(raw) => format!("{connector}-{:08x}", edid::fingerprint(raw)),
+            None => connector.to_string(),
+        })
+    }
+
+    /// Whether sway reports a make, model and serial for the monitor. Cheap
+    /// displays and KVMs often leave the serial empty or `Unknown`, so their
+    /// descriptions collide.
+    pub fn has_full_description(&self) -> bool {
+        let (_, make, model, serial) = &self.description;
+        [make, model, serial]
+            .iter()
+            .all(|field| !field.trim().is_empty() && field.trim() != "Unknown")
+    }
+
     pub fn get_current_mode(&self) -> &str {
         match self.modes.iter().find(|&mode| mode.current()) {
             Some(m) => m.get_modestr(),
             None => "Unknown",
         }
     }
+
+    pub fn current_mode(&self) -> Option<&Modes> {
+        self.modes.iter().find(|&mode| mode.current())
+    }
+
+    pub fn supports_color_mode(&self, mode: ColorMode) -> bool {
+        self.properties
+            .supported_color_modes
+            .as_ref()
+            .is_some_and(|modes| modes.contains(&(mode as u32)))
+    }
+
+    pub fn is_virtual(&self) -> bool {
+        self.properties.virtual_output == Some(true)
+    }
+
+    pub fn preferred_mode(&self) -> Option<&Modes> {
+        self.modes.iter().find(|&mode| mode.preferred())
+    }
+
+    /// Width and height of the current mode in physical pixels
+    pub fn get_current_size(&self) -> Option<(i32, i32)> {
+        self.modes
+            .iter()
+            .find(|&mode| mode.current())
+            .map(|mode| mode.get_size())
+    }
+
+    pub fn get_connector(&self) -> &str {
+        &self.description.0
+    }
+
+    /// Report the monitor under `connector` instead of the connector sway
+    /// names, for outputs sharing one
+    pub(crate) fn set_connector(&mut self, connector: &str) {
+        self.description.0 = connector.to_string();
+    }
+
+    /// Drop modes smaller than `min_size`, keeping the current and preferred
+    /// ones. Only meant for the copy handed to clients, applies still look up
+    /// modes in the full list.
+    pub fn hide_small_modes(&mut self, min_size: (i32, i32)) {
+        self.modes
+            .retain(|mode| mode.current() || mode.preferred() || mode.is_at_least(min_size));
+    }
 }
 
 impl PartialEq for Monitor {
@@ -149,22 +354,212 @@ impl Hash for LogicalMonitor {
 
 impl MonitorProperties {
     pub fn new(output: &Output) -> MonitorProperties {
+        if is_virtual_connector(&output.name) {
+            return Self::virtual_output(output);
+        }
+        Self::with_edid(output, Edid::read(&output.name))
+    }
+
+    /// Properties of a virtual output, which has neither an EDID nor a
+    /// physical size and is never built in
+    fn virtual_output(output: &Output) -> MonitorProperties {
+        MonitorProperties {
+            width: Some(0),
+            height: Some(0),
+            builtin: Some(false),
+            virtual_output: Some(true),
+            privacy_screen: None,
+            backlight_min: None,
+            backlight_max: None,
+            backlight: None,
+            ..Self::from_output(output, None)
+        }
+    }
+
+    /// Properties from `edid` where available. A missing or unparsable EDID
+    /// falls back to the fields sway provides.
+    pub fn with_edid(output: &Output, edid: Result<Edid, EdidError>) -> MonitorProperties {
+        let edid = match edid {
+            Ok(edid) => Some(edid),
+            Err(e) => {
+                debug!(
+                    "No usable EDID for {} ({e}), using sway's output info",
+                    output.name
+                );
+                None
+            }
+        };
+        Self::from_output(output, edid)
+    }
+
+    fn from_output(output: &Output, edid: Option<Edid>) -> MonitorProperties {
         let name = Some(format!(
             "{} {} {}",
             &output.make, &output.model, &output.serial
         ));
-        let builtin = output.name.starts_with("eDP");
+        let builtin = is_builtin_connector(&output.name);
+        let backlight = builtin.then(|| Backlight::for_output(true).percent()).flatten();
+        let supported_color_modes = supported_color_modes(&edid);
+        let (width, height) = match edid.as_ref().and_then(|e| e.width_mm.zip(e.height_mm)) {
+            Some((width, height)) => (width, height),
+            None => (output.rect.2, output.rect.3),
+        };
         MonitorProperties {
-            width: Some(output.rect.width),
-            height: Some(output.rect.height),
+            width: Some(width),
+            height: Some(height),
             name,
             builtin: Some(builtin),
             max_size: None,
             underscanning: None,
+            supports_tearing: Some(tearing_supported()),
+            allow_tearing: tearing_supported().then(|| allow_tearing(&output.name)),
+            supports_adaptive_sync: edid.as_ref().map(|edid| edid.adaptive_sync),
+            adaptive_sync: output.adaptive_sync,
+            scale_filter: None,
+            render_bit_depth: render_bit_depth_supported()
+                .then(|| render_bit_depth(&output.name).unwrap_or(RENDER_BIT_DEPTHS[0])),
+            max_render_time: Some(max_render_time(&output.name)),
+            virtual_output: Some(false),
+            edid: edid.map(|edid| edid.raw),
+            privacy_screen: builtin
+                .then(privacy::state)
+                .flatten()
+                .map(|enabled| (enabled, false)),
+            color_mode: Some(color_mode(&output.name) as u32),
+            supported_color_modes: Some(supported_color_modes),
+            backlight_min: backlight.map(|_| 0),
+            backlight_max: backlight.map(|_| 100),
+            backlight,
         }
     }
 }
 
+/// Whether `connector` is the built-in panel: the one named by
+/// `displayd.builtin_output`, or any eDP connector if that is unset
+pub fn is_builtin_connector(connector: &str) -> bool {
+    match &config::current().builtin_output {
+        Some(builtin) => builtin == connector,
+        None => connector.starts_with("eDP"),
+    }
+}
+
+/// Whether the output on `connector` is virtual: made by a headless or
+/// nested backend, or, on sway, without a DRM connector to read an EDID
+/// from. Other compositors name their outputs differently from the DRM
+/// connectors, e.g. X11 and nested sessions, so only the prefixes are
+/// trusted there.
+pub fn is_virtual_connector(connector: &str) -> bool {
+    VIRTUAL_PREFIXES.iter().any(|prefix| connector.starts_with(prefix))
+        || matches!(backend::current(), Some(Backend::Sway(_))) && !edid::has_connector(connector)
+}
+
+/// Record whether the compositor supports `allow_tearing` (sway 1.9 and later)
+pub fn set_tearing_supported(supported: bool) {
+    TEARING_SUPPORTED.store(supported, Ordering::Relaxed);
+}
+
+pub fn tearing_supported() -> bool {
+    TEARING_SUPPORTED.load(Ordering::Relaxed)
+}
+
+/// Record whether the compositor supports `hdr` (sway 1.11 and later)
+pub fn set_hdr_supported(supported: bool) {
+    HDR_SUPPORTED.store(supported, Ordering::Relaxed);
+}
+
+pub fn hdr_supported() -> bool {
+    HDR_SUPPORTED.load(Ordering::Relaxed)
+}
+
+/// Record whether the compositor supports `render_bit_depth` (sway 1.7 and
+/// later)
+pub fn set_render_bit_depth_supported(supported: bool) {
+    RENDER_BIT_DEPTH_SUPPORTED.store(supported, Ordering::Relaxed);
+}
+
+pub fn render_bit_depth_supported() -> bool {
+    RENDER_BIT_DEPTH_SUPPORTED.load(Ordering::Relaxed)
+}
+
+/// Forget the color modes, tearing settings, bit depths and render times
+/// applied so far, which sway drops when it reloads its config
+pub fn forget_applied() {
+    COLOR_MODES.lock().unwrap().clear();
+    TEARING.lock().unwrap().clear();
+    BIT_DEPTHS.lock().unwrap().clear();
+    RENDER_TIMES.lock().unwrap().clear();
+}
+
+/// Take the `max_render_time` and tearing setting of `output` from the
+/// compositor where it reports them, so they survive a restart of the daemon
+pub fn remember_reported(output: &Output) {
+    if let Some(milliseconds) = output.max_render_time {
+        remember_max_render_time(&output.name, milliseconds);
+    }
+    if let Some(allowed) = output.allow_tearing {
+        remember_tearing(&output.name, allowed);
+    }
+}
+
+/// `max_render_time` last set on `connector` in milliseconds, 0 for off
+pub fn max_render_time(connector: &str) -> u32 {
+    RENDER_TIMES.lock().unwrap().get(connector).copied().unwrap_or(0)
+}
+
+pub fn remember_max_render_time(connector: &str, milliseconds: u32) {
+    RENDER_TIMES
+        .lock()
+        .unwrap()
+        .insert(connector.to_string(), milliseconds);
+}
+
+/// Value of sway's `output max_render_time` for `milliseconds`, 0 being off
+pub fn max_render_time_value(milliseconds: u32) -> String {
+    match milliseconds {
+        0 => String::from("off"),
+        milliseconds => milliseconds.to_string(),
+    }
+}
+
+/// Render bit depth last applied to `connector`, `None` if the daemon never
+/// set one
+pub fn render_bit_depth(connector: &str) -> Option<u32> {
+    BIT_DEPTHS.lock().unwrap().get(connector).copied()
+}
+
+fn color_mode(connector: &str) -> ColorMode {
+    COLOR_MODES
+        .lock()
+        .unwrap()
+        .get(connector)
+        .copied()
+        .unwrap_or(ColorMode::Default)
+}
+
+/// Whether tearing page flips were last allowed on `connector`
+pub fn allow_tearing(connector: &str) -> bool {
+    TEARING.lock().unwrap().get(connector).copied().unwrap_or(false)
+}
+
+/// Remember that tearing page flips were allowed or disallowed on `connector`
+pub fn remember_tearing(connector: &str, allowed: bool) {
+    TEARING.lock().unwrap().insert(connector.to_string(), allowed);
+}
+
+fn supported_color_modes(edid: &Option<Edid>) -> Vec<u32> {
+    let mut modes = vec![ColorMode::Default as u32];
+    if hdr_supported() && edid.as_ref().is_some_and(|edid| edid.hdr) {
+        modes.push(ColorMode::Bt2100 as u32);
+    }
+    modes
+}
+
+impl ColorMode {
+    pub fn from_u32(mode: u32) -> Option<ColorMode> {
+        num::FromPrimitive::from_u32(mode)
+    }
+}
+
 impl MonitorTransform {
     pub fn from_u32(transform: u32) -> Option<MonitorTransform> {
         num::FromPrimitive::from_u32(transform)
@@ -221,8 +616,8 @@ impl LogicalMonitor {
             monitors: monitor.to_vec(),
             primary: output.primary,
             transform,
-            x_pos: output.rect.x,
-            y_pos: output.rect.y,
+            x_pos: output.rect.0,
+            y_pos: output.rect.1,
             properties: LogicalMonitorProperties {
                 // Dummy data to emulate a{sv}
                 dummy: None,
@@ -230,22 +625,113 @@ impl LogicalMonitor {
             },
         }
     }
+
+    /// Report the monitor under `connector`, see [`Monitor::set_connector`]
+    pub(crate) fn set_connector(&mut self, connector: &str) {
+        for monitor in &mut self.monitors {
+            monitor.0 = connector.to_string();
+        }
+    }
+
     pub fn get_dpy_name(&self) -> String {
         let desc = &self.monitors[0];
         format!("{} {} {}", desc.1, desc.2, desc.3)
     }
+
+    pub fn get_connector(&self) -> &str {
+        &self.monitors[0].0
+    }
+
+    /// Criteria identifying the logical monitor's output in kanshi profiles,
+    /// see [`Monitor::kanshi_name`]
+    pub fn kanshi_name(&self, monitors: &[Monitor]) -> String {
+        match monitors
+            .iter()
+            .find(|monitor| monitor.get_connector() == self.get_connector())
+        {
+            Some(monitor) => monitor.kanshi_name(monitors),
+            None => self.get_dpy_name(),
+        }
+    }
+
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    pub fn transform(&self) -> u32 {
+        self.transform
+    }
+
+    pub fn is_primary(&self) -> bool {
+        self.primary
+    }
+
+    pub fn position(&self) -> (i32, i32) {
+        (self.x_pos, self.y_pos)
+    }
+
+    pub fn set_position(&mut self, x_pos: i32, y_pos: i32) {
+        self.x_pos = x_pos;
+        self.y_pos = y_pos;
+    }
+
+    /// Size of the monitor in physical pixels, taking rotation into account
+    pub fn physical_size(&self, monitors: &[Monitor]) -> Option<(i32, i32)> {
+        let monitor = monitors
+            .iter()
+            .find(|mon| mon.get_connector() == self.get_connector())?;
+        let (width, height) = monitor.get_current_size()?;
+        // Odd transforms rotate the monitor by 90 or 270 degrees
+        if self.transform % 2 == 1 {
+            Some((height, width))
+        } else {
+            Some((width, height))
+        }
+    }
+
+    /// Size of the monitor in sway's logical layout space
+    pub fn logical_size(&self, monitors: &[Monitor]) -> Option<(i32, i32)> {
+        let (width, height) = self.physical_size(monitors)?;
+        Some((
+            (width as f64 / self.scale).round() as i32,
+            (height as f64 / self.scale).round() as i32,
+        ))
+    }
 }
 
 impl MonitorApply {
+    /// Request keeping `logical_monitor` on `monitor` as it is, for writing
+    /// the current layout as a profile. Returns `None` if sway reports no
+    /// current mode.
+    pub fn current(logical_monitor: &LogicalMonitor, monitor: &Monitor) -> Option<MonitorApply> {
+        let mode = monitor.current_mode()?;
+        Some(MonitorApply {
+            x_pos: logical_monitor.x_pos,
+            y_pos: logical_monitor.y_pos,
+            scale: logical_monitor.scale,
+            transform: logical_monitor.transform,
+            primary: logical_monitor.primary,
+            monitors: vec![(
+                monitor.get_connector().to_string(),
+                mode.get_id().to_string(),
+                MonitorProperties::default(),
+            )],
+        })
+    }
+
     fn get_modestr(&self, monitor: &Monitor) -> Option<String> {
         let modestr = &self.monitors[0].1;
-        match monitor.search_modes(&modestr) {
-            Some(x) => Some(x.get_modestr().to_string()),
-            None => None,
-        }
+        monitor
+            .search_modes(modestr)
+            .map(|x| x.get_modestr().to_string())
+    }
+
+    /// Whether the client asked for this monitor to be the primary one
+    pub fn is_primary(&self) -> bool {
+        self.primary
     }
 
-    pub fn search_monitor<'a>(&self, monitors: &'a Vec<Monitor>) -> Option<&'a Monitor> {
+    pub fn search_monitor<'a>(&self, monitors: &'a [Monitor]) -> Option<&'a Monitor> {
         monitors
             .iter()
             .find(|mon| mon.description.0 == self.monitors[0].0)
@@ -253,22 +739,98 @@ impl MonitorApply {
 
     pub fn search_logical_monitor<'a>(
         &self,
-        logical_monitors: &'a Vec<LogicalMonitor>,
+        logical_monitors: &'a [LogicalMonitor],
     ) -> Option<&'a LogicalMonitor> {
         logical_monitors
             .iter()
             .find(|mon| mon.monitors[0].0 == self.monitors[0].0)
     }
 
-    pub fn save_kanshi(&self, kanshi_file: &mut Vec<u8>, monitor: &Monitor) {
-        let dpy_name = monitor.get_dpy_name();
-        let mode = match self.get_modestr(&monitor) {
+    /// Differences between this request and what sway applied, given as
+    /// the `monitors` and `logical_monitors` read back afterwards
+    pub fn check_applied(&self, monitors: &[Monitor], logical_monitors: &[LogicalMonitor]) -> Vec<String> {
+        let connector = &self.monitors[0].0;
+        let mode_id = &self.monitors[0].1;
+        let Some(logical_monitor) = self.search_logical_monitor(logical_monitors) else {
+            return vec![format!("{connector} was not enabled")];
+        };
+        let mut problems = Vec::new();
+        let monitor = self.search_monitor(monitors);
+        match monitor.and_then(Monitor::current_mode) {
+            Some(mode) if mode.has_id(mode_id) => {}
+            Some(mode) => problems.push(format!("{connector}: mode is {} instead of {mode_id}", mode.get_modestr())),
+            None => problems.push(format!("{connector}: no current mode")),
+        }
+        if (logical_monitor.scale() - self.scale).abs() > 0.001 {
+            problems.push(format!("{connector}: scale is {} instead of {}", logical_monitor.scale(), self.scale));
+        }
+        if logical_monitor.transform() != self.transform {
+            problems.push(format!("{connector}: transform is {} instead of {}", logical_monitor.transform(), self.transform));
+        }
+        let requested = self.monitors[0].2.adaptive_sync;
+        let applied = monitor.and_then(|monitor| monitor.properties.adaptive_sync);
+        if let Some((requested, applied)) = requested.zip(applied).filter(|(requested, applied)| requested != applied) {
+            let state = |on| if on { "on" } else { "off" };
+            problems.push(format!("{connector}: adaptive sync is {} instead of {}", state(applied), state(requested)));
+        }
+        problems
+    }
+
+    pub fn position(&self) -> (i32, i32) {
+        (self.x_pos, self.y_pos)
+    }
+
+    pub fn set_position(&mut self, x_pos: i32, y_pos: i32) {
+        self.x_pos = x_pos;
+        self.y_pos = y_pos;
+    }
+
+    /// Requested position and size as (x, y, width, height) in the coordinate
+    /// space of `layout`, `None` if the requested mode doesn't exist
+    pub fn rect(&self, monitors: &[Monitor], layout: LayoutMode) -> Option<Rect> {
+        let (width, height) = self
+            .search_monitor(monitors)?
+            .search_modes(&self.monitors[0].1)?
+            .get_size();
+        // Odd transforms rotate the monitor by 90 or 270 degrees
+        let (width, height) = if self.transform % 2 == 1 {
+            (height, width)
+        } else {
+            (width, height)
+        };
+        let (width, height) = match layout {
+            LayoutMode::Physical => (width, height),
+            LayoutMode::Logical => (
+                (width as f64 / self.scale).round() as i32,
+                (height as f64 / self.scale).round() as i32,
+            ),
+        };
+        Some((self.x_pos, self.y_pos, width, height))
+    }
+
+    /// Requested transform, rejecting values outside the mutter enum
+    pub fn get_transform(&self) -> zbus::fdo::Result<MonitorTransform> {
+        MonitorTransform::from_u32(self.transform)
+            .ok_or(ZError::InvalidArgs(String::from("Invalid tranform")))
+    }
+
+    pub fn save_kanshi(
+        &self,
+        kanshi_file: &mut Vec<u8>,
+        monitor: &Monitor,
+        monitors: &[Monitor],
+    ) -> zbus::fdo::Result<()> {
+        let dpy_name = monitor.kanshi_name(monitors);
+        let mode = match self.get_modestr(monitor) {
             Some(x) => x,
-            _ => return,
+            _ => return Ok(()),
         };
-        let transform =
-            MonitorTransform::from_u32(self.transform).unwrap_or(MonitorTransform::Normal);
-        let config = format!(
+        let transform = self.get_transform()?;
+        let color_mode = self.get_color_mode(monitor)?;
+        let requested_bit_depth = self.get_render_bit_depth()?;
+        let defaults = config::current();
+        let requested = &self.monitors[0].2;
+        let mut config = format!(
             "output \"{}\" mode {} position {},{} transform {} scale {} enable",
             dpy_name,
             mode,
@@ -277,20 +839,139 @@ impl MonitorApply {
             transform.to_sway(),
             self.scale
         );
+        if let Some(adaptive_sync) = requested.adaptive_sync.or(defaults.adaptive_sync) {
+            config.push_str(if adaptive_sync {
+                " adaptive_sync on"
+            } else {
+                " adaptive_sync off"
+            });
+        }
         writeln!(kanshi_file, "\t{config}").unwrap();
+        // kanshi has no tearing or scale filter options, so sway is told directly
+        // once the profile applies. Other compositors would fail the execs or,
+        // with sway also running, have them reach the wrong one.
+        if !matches!(backend::current(), Some(Backend::Sway(_))) {
+            return Ok(());
+        }
+        // Tearing allowed earlier, e.g. with `SetAllowTearing`, is kept when
+        // the request doesn't mention it.
+        let connector = monitor.get_connector();
+        let allow_tearing = requested
+            .allow_tearing
+            .or_else(|| allow_tearing(connector).then_some(true));
+        if let Some(allow_tearing) = allow_tearing {
+            let value = if allow_tearing { "yes" } else { "no" };
+            writeln!(
+                kanshi_file,
+                "\texec swaymsg output {} allow_tearing {value}",
+                monitor.get_connector()
+            )
+            .unwrap();
+        }
+        if let Some(scale_filter) = requested
+            .scale_filter
+            .as_ref()
+            .or(defaults.scale_filter.as_ref())
+        {
+            writeln!(
+                kanshi_file,
+                "\texec swaymsg output {} scale_filter {scale_filter}",
+                monitor.get_connector()
+            )
+            .unwrap();
+        }
+        let render_bit_depth = requested_bit_depth.or_else(|| render_bit_depth(connector));
+        if let Some(render_bit_depth) = render_bit_depth {
+            writeln!(
+                kanshi_file,
+                "\texec swaymsg output {} render_bit_depth {render_bit_depth}",
+                monitor.get_connector()
+            )
+            .unwrap();
+        }
+        let max_render_time = max_render_time(connector);
+        if max_render_time != 0 {
+            writeln!(
+                kanshi_file,
+                "\texec swaymsg output {} max_render_time {max_render_time}",
+                monitor.get_connector()
+            )
+            .unwrap();
+        }
+        if let Some(color_mode) = color_mode {
+            let value = if color_mode == ColorMode::Bt2100 { "on" } else { "off" };
+            writeln!(
+                kanshi_file,
+                "\texec swaymsg output {} hdr {value}",
+                monitor.get_connector()
+            )
+            .unwrap();
+        }
+        Ok(())
+    }
+
+    /// Requested color mode, rejecting unknown modes and modes `monitor`
+    /// doesn't support
+    pub fn get_color_mode(&self, monitor: &Monitor) -> zbus::fdo::Result<Option<ColorMode>> {
+        let Some(mode) = self.monitors[0].2.color_mode else {
+            return Ok(None);
+        };
+        let color_mode = ColorMode::from_u32(mode)
+            .ok_or_else(|| ZError::InvalidArgs(format!("Invalid color mode {mode}")))?;
+        if !monitor.supports_color_mode(color_mode) {
+            return Err(ZError::NotSupported(format!(
+                "{} doesn't support color mode {mode}",
+                monitor.get_connector()
+            )));
+        }
+        Ok(Some(color_mode))
+    }
+
+    /// Requested render bit depth, rejecting depths sway doesn't take
+    pub fn get_render_bit_depth(&self) -> zbus::fdo::Result<Option<u32>> {
+        let Some(depth) = self.monitors[0].2.render_bit_depth else {
+            return Ok(None);
+        };
+        if !render_bit_depth_supported() {
+            return Err(ZError::NotSupported(String::from(
+                "Render bit depths are not supported by the compositor",
+            )));
+        }
+        if !RENDER_BIT_DEPTHS.contains(&depth) {
+            return Err(ZError::InvalidArgs(format!("Invalid render bit depth {depth}")));
+        }
+        Ok(Some(depth))
+    }
+
+    /// Remember the requested color mode, tearing setting and render bit depth
+    /// once applied, to report them
+    pub fn remember_applied(&self) {
+        let (connector, _, properties) = &self.monitors[0];
+        if let Some(color_mode) = properties.color_mode.and_then(ColorMode::from_u32) {
+            COLOR_MODES
+                .lock()
+                .unwrap()
+                .insert(connector.clone(), color_mode);
+        }
+        if let Some(allowed) = properties.allow_tearing {
+            remember_tearing(connector, allowed);
+        }
+        if let Some(depth) = properties.render_bit_depth {
+            BIT_DEPTHS.lock().unwrap().insert(connector.clone(), depth);
+        }
     }
 
     pub fn verify(
         &self,
-        _sway_connect: &Arc<Mutex<Connection>>,
-        monitors: &Vec<Monitor>,
+        _backend: &impl DisplayBackend,
+        monitors: &[Monitor],
     ) -> zbus::fdo::Result<()> {
         let monitor = self
             .search_monitor(monitors)
             .ok_or(Failed(String::from("Monitor not found")))?;
 
         // Check if position is valid
-        if self.get_modestr(monitor) == None {
+        if self.get_modestr(monitor).is_none() {
             return Err(ZError::InvalidArgs(String::from("Invalid position")));
         }
 
@@ -305,9 +986,98 @@ impl MonitorApply {
             return Err(ZError::InvalidArgs(String::from("Invalid scale")));
         }
 
-        if MonitorTransform::from_u32(self.transform) == None {
-            return Err(ZError::InvalidArgs(String::from("Invalid tranform")));
+        self.get_transform()?;
+        self.get_color_mode(monitor)?;
+        self.get_render_bit_depth()?;
+
+        if let Some(scale_filter) = &self.monitors[0].2.scale_filter {
+            if !SCALE_FILTERS.contains(&scale_filter.as_str()) {
+                return Err(ZError::InvalidArgs(format!(
+                    "Invalid scale filter {scale_filter}"
+                )));
+            }
+        }
+
+        if self.monitors[0].2.allow_tearing.is_some() && !tearing_supported() {
+            return Err(ZError::NotSupported(String::from(
+                "Tearing page flips are not supported by the compositor",
+            )));
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, mode};
+
+    fn output(name: &str, serial: &str) -> Output {
+        Output {
+            make: String::from("Dell Inc."),
+            model: String::from("DELL U2720Q"),
+            serial: String::from(serial),
+            ..testing::output(name, Vec::new(), 0)
+        }
+    }
+
+    #[test]
+    fn tells_identical_monitors_apart_by_connector() {
+        let outputs = [output("DP-1", "1234"), output("DP-2", "1234")];
+        let monitors: Vec<Monitor> = outputs.iter().map(Monitor::new).collect();
+        assert_eq!(monitors[0].kanshi_name(&monitors), "DP-1");
+        assert_eq!(monitors[1].kanshi_name(&monitors), "DP-2");
+        let logical_monitor = LogicalMonitor::new(&outputs[1]);
+        assert_eq!(logical_monitor.kanshi_name(&monitors), "DP-2");
+    }
+
+    #[test]
+    fn names_distinct_monitors_by_description() {
+        let monitors: Vec<Monitor> = [output("DP-1", "1234"), output("DP-2", "5678")]
+            .iter()
+            .map(Monitor::new)
+            .collect();
+        assert_eq!(monitors[0].kanshi_name(&monitors), "Dell Inc. DELL U2720Q 1234");
+        assert_eq!(monitors[1].kanshi_name(&monitors), "Dell Inc. DELL U2720Q 5678");
+        assert_eq!(monitors[0].legacy_profile_key(), None);
+    }
+
+    #[test]
+    fn keeps_the_description_of_monitors_without_a_serial() {
+        let monitors = [Monitor::new(&output("DP-1", "Unknown"))];
+        assert!(!monitors[0].has_full_description());
+        assert_eq!(monitors[0].kanshi_name(&monitors), "Dell Inc. DELL U2720Q Unknown");
+        // Profiles saved under the connector are still found
+        assert_eq!(monitors[0].legacy_profile_key().as_deref(), Some("DP-1"));
+    }
+
+    #[test]
+    fn falls_back_to_the_output_for_a_truncated_edid() {
+        let output = Output {
+            adaptive_sync: Some(true),
+            ..output("DP-1", "1234")
+        };
+        let truncated = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x10, 0xac];
+        let properties = MonitorProperties::with_edid(&output, Edid::parse(&truncated));
+        assert_eq!(properties.name.as_deref(), Some("Dell Inc. DELL U2720Q 1234"));
+        assert_eq!(properties.builtin, Some(false));
+        assert_eq!(properties.virtual_output, Some(false));
+        assert_eq!(properties.adaptive_sync, Some(true));
+        assert_eq!(properties.edid, None);
+        assert_eq!(properties.supports_adaptive_sync, None);
+    }
+
+    #[test]
+    fn searches_modes_by_either_id() {
+        let output = Output {
+            modes: vec![mode(1920, 1080, 60000, true), mode(1920, 1080, 59940, false)],
+            ..output("DP-1", "1234")
+        };
+        let monitor = Monitor::new(&output);
+        let by_id = monitor.search_modes("1920x1080@60Hz").unwrap();
+        let by_sway_id = monitor.search_modes("1920x1080@60.000Hz").unwrap();
+        assert_eq!(by_id, by_sway_id);
+        assert_eq!(monitor.search_modes("1920x1080@59.94Hz").unwrap().sway_id(), Some("1920x1080@59.940Hz"));
+        assert!(monitor.search_modes("1920x1080@50Hz").is_none());
+    }
+}
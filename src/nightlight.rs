@@ -0,0 +1,268 @@
+//! Night light, following gnome-settings-daemon's color settings.
+//!
+//! The display panel's Night Light toggle only edits the settings of gsd's
+//! color plugin, which tints the screens through mutter's gamma calls. sway
+//! has none of those, so the daemon watches the settings with `gsettings
+//! monitor` and applies the temperature itself through [`gamma`].
+//!
+//! The manual schedule is followed. The automatic one (sunset to sunrise)
+//! needs the location, which the daemon doesn't have, so it uses the manual
+//! hours too. The temperature changes at once instead of fading.
+use crate::{config, gamma};
+use lazy_static::lazy_static;
+use log::{debug, error, info};
+use std::{
+    error::Error,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::Command,
+};
+
+const SCHEMA: &str = "org.gnome.settings-daemon.plugins.color";
+const KEYS: [&str; 4] = [
+    "night-light-enabled",
+    "night-light-temperature",
+    "night-light-schedule-from",
+    "night-light-schedule-to",
+];
+/// How often the schedule is checked
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(60);
+
+static SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// Temperature currently applied, `None` when the outputs are neutral
+    static ref APPLIED: Mutex<Option<u32>> = Mutex::new(None);
+}
+
+/// The color plugin's night light settings, with gsd's defaults
+#[derive(Debug, Clone, PartialEq)]
+struct Settings {
+    enabled: bool,
+    /// In Kelvin
+    temperature: u32,
+    /// Hours of the day, e.g. 20.5 for 20:30
+    from: f64,
+    to: f64,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            enabled: false,
+            temperature: 2700,
+            from: 20.0,
+            to: 6.0,
+        }
+    }
+}
+
+impl Settings {
+    /// Update `key` from its value as printed by `gsettings`, e.g. `true` or
+    /// `uint32 2700`. Unknown keys and malformed values are ignored.
+    fn set(&mut self, key: &str, value: &str) {
+        // Numbers can be printed with their type
+        let value = value.rsplit(' ').next().unwrap_or(value);
+        match key {
+            "night-light-enabled" => self.enabled = value == "true",
+            "night-light-temperature" => {
+                if let Ok(temperature) = value.parse() {
+                    self.temperature = temperature;
+                }
+            }
+            "night-light-schedule-from" => {
+                if let Ok(from) = value.parse() {
+                    self.from = from;
+                }
+            }
+            "night-light-schedule-to" => {
+                if let Ok(to) = value.parse() {
+                    self.to = to;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Temperature to apply at `hour`, `None` outside the schedule
+    fn temperature_at(&self, hour: f64) -> Option<u32> {
+        let scheduled = if self.from <= self.to {
+            hour >= self.from && hour < self.to
+        } else {
+            hour >= self.from || hour < self.to
+        };
+        (self.enabled && scheduled).then_some(self.temperature)
+    }
+}
+
+/// Whether night light is being followed, for the `NightLightSupported`
+/// property
+pub fn supported() -> bool {
+    config::current().night_light && SUPPORTED.load(Ordering::Relaxed)
+}
+
+/// Current local time as hours of the day
+fn local_hour() -> f64 {
+    // SAFETY: `time` accepts a null pointer and `localtime_r` only writes to `tm`
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            return 0.0;
+        }
+        tm.tm_hour as f64 + tm.tm_min as f64 / 60.0
+    }
+}
+
+async fn read(settings: &mut Settings) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for key in KEYS {
+        let output = Command::new("gsettings")
+            .args(["get", SCHEMA, key])
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(format!(
+                "cannot read {SCHEMA} {key}: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        settings.set(key, String::from_utf8_lossy(&output.stdout).trim());
+    }
+    Ok(())
+}
+
+/// Apply `temperature` unless it already is
+async fn apply(temperature: Option<u32>) {
+    if *APPLIED.lock().unwrap() == temperature {
+        return;
+    }
+    match tokio::task::spawn_blocking(move || gamma::set_temperature(temperature)).await {
+        Ok(Ok(())) => {
+            match temperature {
+                Some(kelvin) => info!("Night light on, {kelvin}K"),
+                None => info!("Night light off"),
+            }
+            *APPLIED.lock().unwrap() = temperature;
+        }
+        Ok(Err(e)) => error!("Cannot apply night light: {e}"),
+        Err(e) => error!("Cannot apply night light: {e}"),
+    }
+}
+
+/// Apply the current temperature again, so outputs connected or turned on
+/// since get it
+pub async fn refresh() {
+    let Some(temperature) = *APPLIED.lock().unwrap() else {
+        return;
+    };
+    let applied = tokio::task::spawn_blocking(move || gamma::set_temperature(Some(temperature))).await;
+    if let Ok(Err(e)) = applied {
+        error!("Cannot apply night light to new outputs: {e}");
+    }
+}
+
+/// Follow the night light settings until `gsettings monitor` exits, leaving
+/// the outputs neutral while `displayd.night_light` is off. Fails right away
+/// if the color plugin's schema isn't installed.
+pub async fn watch() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut settings = Settings::default();
+    read(&mut settings).await?;
+    let mut monitor = Command::new("gsettings")
+        .args(["monitor", SCHEMA])
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdout = monitor.stdout.take().ok_or("no output from gsettings monitor")?;
+    let mut lines = BufReader::new(stdout).lines();
+    SUPPORTED.store(true, Ordering::Relaxed);
+    let mut schedule = tokio::time::interval(SCHEDULE_INTERVAL);
+    loop {
+        let temperature = match config::current().night_light {
+            true => settings.temperature_at(local_hour()),
+            false => None,
+        };
+        apply(temperature).await;
+        tokio::select! {
+            line = lines.next_line() => {
+                let Some(line) = line? else {
+                    SUPPORTED.store(false, Ordering::Relaxed);
+                    return Err("gsettings monitor exited".into());
+                };
+                if let Some((key, value)) = line.split_once(':') {
+                    debug!("Color setting changed: {line}");
+                    settings.set(key.trim(), value.trim());
+                }
+            }
+            _ = schedule.tick() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_settings_as_gsettings_prints_them() {
+        let mut settings = Settings::default();
+        let cases = [
+            ("night-light-enabled", "true"),
+            ("night-light-temperature", "uint32 3500"),
+            ("night-light-schedule-from", "21.5"),
+            ("night-light-schedule-to", "7.0"),
+            // Malformed values and unknown keys are ignored
+            ("night-light-temperature", "uint32 warm"),
+            ("night-light-schedule-automatic", "false"),
+        ];
+        for (key, value) in cases {
+            settings.set(key, value);
+        }
+        assert_eq!(
+            settings,
+            Settings {
+                enabled: true,
+                temperature: 3500,
+                from: 21.5,
+                to: 7.0,
+            }
+        );
+        settings.set("night-light-enabled", "false");
+        assert!(!settings.enabled);
+    }
+
+    #[test]
+    fn follows_the_schedule() {
+        let overnight = Settings {
+            enabled: true,
+            ..Settings::default()
+        };
+        let daytime = Settings {
+            from: 8.0,
+            to: 17.0,
+            ..overnight.clone()
+        };
+        let cases = [
+            (&overnight, 20.0, Some(2700)),
+            (&overnight, 23.9, Some(2700)),
+            (&overnight, 5.9, Some(2700)),
+            (&overnight, 6.0, None),
+            (&overnight, 12.0, None),
+            (&daytime, 8.0, Some(2700)),
+            (&daytime, 16.9, Some(2700)),
+            (&daytime, 17.0, None),
+            (&daytime, 3.0, None),
+            (&Settings::default(), 22.0, None),
+        ];
+        for (settings, hour, expected) in cases {
+            assert_eq!(settings.temperature_at(hour), expected, "{settings:?} at {hour}");
+        }
+    }
+}
@@ -0,0 +1,558 @@
+//! The niri compositor, through its IPC socket at `$NIRI_SOCKET`.
+//!
+//! niri takes a request as a line of JSON and answers it with another,
+//! `{"Ok": ...}` or `{"Err": "..."}`, one request per connection. Its outputs
+//! are described in much the same terms as sway's, so they map onto
+//! [`Output`], and every option of a sway output command becomes an `Output`
+//! request of its own.
+//!
+//! Changes made over IPC last until niri reloads its config. With
+//! `displayd.niri_output_config`, the saved profile for the connected outputs
+//! is written to a section of niri's config instead of being left to kanshi,
+//! see [`write_config`].
+use crate::{
+    backend::{
+        Backend, BackendError, BackendEvents, DisplayBackend, Output, OutputCommand, OutputMode, MAX_RECONNECT_DELAY,
+        RECONNECT_DELAY,
+    },
+    get_kanshi_paths,
+    write_atomic,
+    kanshi::{self, OutputDirective},
+};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fs, io,
+    path::PathBuf,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+};
+use zbus::fdo;
+
+/// sway's names for the transforms, as in kanshi profiles and niri's config,
+/// with the names niri's IPC uses for them
+const TRANSFORMS: [(&str, &str); 8] = [
+    ("normal", "Normal"),
+    ("90", "90"),
+    ("180", "180"),
+    ("270", "270"),
+    ("flipped", "Flipped"),
+    ("flipped-90", "Flipped90"),
+    ("flipped-180", "Flipped180"),
+    ("flipped-270", "Flipped270"),
+];
+
+const OUTPUTS_BEGIN: &str = "// BEGIN regolith-displayd outputs";
+const OUTPUTS_END: &str = "// END regolith-displayd outputs";
+
+pub struct NiriBackend {
+    socket: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+struct NiriOutput {
+    name: String,
+    make: String,
+    model: String,
+    serial: Option<String>,
+    modes: Vec<NiriMode>,
+    current_mode: Option<usize>,
+    vrr_enabled: bool,
+    /// `None` if the output is off
+    logical: Option<LogicalOutput>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NiriMode {
+    width: u16,
+    height: u16,
+    /// mHz
+    refresh_rate: u32,
+    #[serde(default)]
+    is_preferred: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct LogicalOutput {
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+    scale: f64,
+    transform: String,
+}
+
+impl NiriBackend {
+    /// Connect to the niri at `$NIRI_SOCKET`
+    pub async fn connect() -> Result<NiriBackend, BackendError> {
+        let socket = std::env::var_os("NIRI_SOCKET")
+            .map(PathBuf::from)
+            .ok_or_else(|| BackendError::from(String::from("NIRI_SOCKET isn't set")))?;
+        let backend = NiriBackend { socket };
+        backend.request(json!("Version")).await?;
+        Ok(backend)
+    }
+
+    /// Send `request` and return what niri answered it with
+    async fn request(&self, request: Value) -> Result<Value, String> {
+        let stream = UnixStream::connect(&self.socket)
+            .await
+            .map_err(|e| format!("Cannot connect to niri: {e}"))?;
+        let (read, mut write) = stream.into_split();
+        let line = format!("{request}\n");
+        write
+            .write_all(line.as_bytes())
+            .await
+            .map_err(|e| format!("niri IPC error: {e}"))?;
+        let mut reply = String::new();
+        BufReader::new(read)
+            .read_line(&mut reply)
+            .await
+            .map_err(|e| format!("niri IPC error: {e}"))?;
+        let mut reply: Value = serde_json::from_str(&reply).map_err(|e| format!("Invalid reply from niri: {e}"))?;
+        if let Some(error) = reply.get("Err") {
+            return Err(error.as_str().unwrap_or("unknown error").to_string());
+        }
+        reply
+            .get_mut("Ok")
+            .map(Value::take)
+            .ok_or_else(|| format!("Invalid reply from niri: {reply}"))
+    }
+
+    async fn niri_outputs(&self) -> Result<Vec<NiriOutput>, String> {
+        let mut reply = self.request(json!("Outputs")).await?;
+        let outputs: HashMap<String, NiriOutput> = serde_json::from_value(reply["Outputs"].take())
+            .map_err(|e| format!("Invalid outputs from niri: {e}"))?;
+        let mut outputs: Vec<NiriOutput> = outputs.into_values().collect();
+        outputs.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(outputs)
+    }
+
+    async fn configure(&self, output: &str, action: Value) -> Result<(), String> {
+        debug!("Configuring {output} on niri: {action}");
+        let reply = self
+            .request(json!({ "Output": { "output": output, "action": action } }))
+            .await?;
+        match reply["OutputConfigChanged"].as_str() {
+            Some("Applied") => Ok(()),
+            Some("OutputWasMissing") => Err(format!("niri has no output {output}")),
+            _ => Err(format!("Unexpected reply from niri: {reply}")),
+        }
+    }
+}
+
+impl DisplayBackend for NiriBackend {
+    async fn detect(&self) -> Backend {
+        match self.request(json!("Version")).await {
+            Ok(reply) => Backend::Niri(reply["Version"].as_str().map(str::to_string)),
+            Err(e) => {
+                warn!("Could not query the niri version: {e}");
+                Backend::Niri(None)
+            }
+        }
+    }
+
+    async fn outputs(&self) -> Result<Vec<Output>, BackendError> {
+        Ok(self.niri_outputs().await?.iter().map(output).collect())
+    }
+
+    async fn apply(&self, commands: &[String]) -> fdo::Result<()> {
+        let outputs = self.niri_outputs().await.map_err(fdo::Error::Failed)?;
+        for command in commands {
+            debug!("Running output command on niri: {command}");
+            let actions = actions(&outputs, command)
+                .map_err(|e| fdo::Error::Failed(format!("Cannot run '{command}' on niri: {e}")))?;
+            for (output, action) in actions {
+                match output {
+                    Some(output) => self.configure(&output, action).await,
+                    None => self.request(json!({ "Action": action })).await.map(|_| ()),
+                }
+                .map_err(|e| fdo::Error::Failed(format!("niri rejected '{command}': {e}")))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// niri's event stream doesn't report output changes, so they are polled
+    async fn subscribe(&self) -> Option<BackendEvents> {
+        None
+    }
+
+    async fn reconnect(&self) {
+        let mut delay = RECONNECT_DELAY;
+        loop {
+            tokio::time::sleep(delay).await;
+            match self.request(json!("Version")).await {
+                Ok(_) => {
+                    info!("Reconnected to niri");
+                    return;
+                }
+                Err(e) => {
+                    debug!("Cannot reconnect to niri yet: {e}");
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                }
+            }
+        }
+    }
+}
+
+/// `output` as the daemon describes outputs
+fn output(output: &NiriOutput) -> Output {
+    let mode = |mode: &NiriMode| OutputMode {
+        width: mode.width.into(),
+        height: mode.height.into(),
+        refresh: mode.refresh_rate as i32,
+        preferred: mode.is_preferred,
+    };
+    let on = output.logical.is_some();
+    Output {
+        name: output.name.clone(),
+        make: output.make.clone(),
+        model: output.model.clone(),
+        serial: output
+            .serial
+            .clone()
+            .filter(|serial| !serial.is_empty())
+            .unwrap_or_else(|| String::from("Unknown")),
+        active: on,
+        power: on,
+        primary: false,
+        non_desktop: false,
+        modes: output.modes.iter().map(mode).collect(),
+        current_mode: output
+            .current_mode
+            .and_then(|index| output.modes.get(index))
+            .filter(|_| on)
+            .map(mode),
+        rect: output
+            .logical
+            .as_ref()
+            .map_or((0, 0, 0, 0), |logical| (logical.x, logical.y, logical.width as i32, logical.height as i32)),
+        scale: output.logical.as_ref().map(|logical| logical.scale),
+        transform: output.logical.as_ref().map(|logical| {
+            TRANSFORMS
+                .iter()
+                .find(|(_, niri)| *niri == logical.transform)
+                .map_or("normal", |(sway, _)| sway)
+                .to_string()
+        }),
+        adaptive_sync: Some(output.vrr_enabled),
+        max_render_time: None,
+        allow_tearing: None,
+    }
+}
+
+/// `output`'s description, as sway would give it
+fn description(output: &NiriOutput) -> String {
+    let serial = output.serial.as_deref().unwrap_or("Unknown");
+    format!("{} {} {serial}", output.make, output.model)
+}
+
+/// The requests with the effect of `command`, each for an output, or an
+/// action for all of them if the output is `None`
+fn actions(outputs: &[NiriOutput], command: &str) -> Result<Vec<(Option<String>, Value)>, String> {
+    let Some(parsed) = OutputCommand::parse(command)? else {
+        return Ok(Vec::new());
+    };
+    let names: Vec<&str> = outputs
+        .iter()
+        .filter(|output| parsed.selects(&output.name, &description(output)))
+        .map(|output| output.name.as_str())
+        .collect();
+    if names.is_empty() {
+        debug!("No output matches {}, ignoring '{command}'", parsed.criteria);
+        return Ok(Vec::new());
+    }
+    let change = &parsed.change;
+    let mut output_actions = Vec::new();
+    if let Some(enabled) = change.enabled {
+        output_actions.push(json!(if enabled { "On" } else { "Off" }));
+    }
+    if let Some(mode) = change.mode {
+        if mode.custom {
+            return Err(String::from("Custom modes aren't supported on niri"));
+        }
+        let refresh = mode.refresh.map(|refresh| f64::from(refresh) / 1000.0);
+        output_actions.push(
+            json!({ "Mode": { "mode": { "Specific": { "width": mode.width, "height": mode.height, "refresh": refresh } } } }),
+        );
+    }
+    if let Some((x, y)) = change.position {
+        output_actions.push(json!({ "Position": { "position": { "Specific": { "x": x, "y": y } } } }));
+    }
+    if let Some(scale) = change.scale {
+        output_actions.push(json!({ "Scale": { "scale": { "Specific": scale } } }));
+    }
+    if let Some(transform) = change.transform {
+        let niri = TRANSFORMS
+            .iter()
+            .find(|(sway, _)| *sway == transform)
+            .map_or("Normal", |(_, niri)| niri);
+        output_actions.push(json!({ "Transform": { "transform": niri } }));
+    }
+    if let Some(on) = change.adaptive_sync {
+        output_actions.push(json!({ "Vrr": { "vrr": { "vrr": on, "on_demand": false } } }));
+    }
+    let mut actions = Vec::new();
+    for action in output_actions {
+        actions.extend(names.iter().map(|name| (Some(name.to_string()), action.clone())));
+    }
+    if let Some(on) = change.power {
+        // niri only powers the outputs off together
+        if names.len() != outputs.len() {
+            return Err(String::from("niri can only power all outputs off and on"));
+        }
+        let action = if on { "PowerOnMonitors" } else { "PowerOffMonitors" };
+        actions.push((None, json!({ action: {} })));
+    }
+    Ok(actions)
+}
+
+/// niri's config: `$NIRI_CONFIG`, or `niri/config.kdl` in the user's config
+/// directory
+pub fn config_path() -> PathBuf {
+    if let Some(path) = std::env::var_os("NIRI_CONFIG").filter(|path| !path.is_empty()) {
+        return PathBuf::from(path);
+    }
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(".config"));
+    config_home.join("niri/config.kdl")
+}
+
+/// The `output` node of niri's config for `directive`. Outputs are named by
+/// their description if it has a serial, as niri matches either.
+fn output_node(directive: &OutputDirective, output: &NiriOutput) -> (String, String) {
+    let name = match output.serial.as_deref().filter(|serial| !serial.is_empty() && *serial != "Unknown") {
+        Some(serial) => format!("{} {} {serial}", output.make, output.model),
+        None => output.name.clone(),
+    };
+    let mut node = format!("output \"{}\" {{\n", name.replace('\\', "\\\\").replace('"', "\\\""));
+    let mut options = directive.options.iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "disable" => node.push_str("    off\n"),
+            "mode" => {
+                if let Some(mode) = options.next() {
+                    node.push_str(&format!("    mode \"{}\"\n", mode.trim_end_matches("Hz")));
+                }
+            }
+            "position" => {
+                if let Some((x, y)) = options.next().and_then(|position| position.split_once(',')) {
+                    node.push_str(&format!("    position x={x} y={y}\n"));
+                }
+            }
+            "scale" => {
+                if let Some(scale) = options.next() {
+                    node.push_str(&format!("    scale {scale}\n"));
+                }
+            }
+            "transform" => {
+                if let Some(transform) = options.next() {
+                    node.push_str(&format!("    transform \"{transform}\"\n"));
+                }
+            }
+            "adaptive_sync" if options.next().is_some_and(|value| value == "on") => {
+                node.push_str("    variable-refresh-rate\n");
+            }
+            "alias" => {
+                options.next();
+            }
+            _ => {}
+        }
+    }
+    node.push_str("}\n");
+    (name, node)
+}
+
+/// The `output` nodes of a section written before, by output name
+fn read_nodes(lines: &[&str]) -> BTreeMap<String, String> {
+    let mut nodes = BTreeMap::new();
+    let mut current: Option<(String, String)> = None;
+    for line in lines {
+        if let Some((name, node)) = &mut current {
+            node.push_str(line);
+            if line.trim() == "}" {
+                nodes.insert(std::mem::take(name), std::mem::take(node));
+                current = None;
+            }
+        } else if let Some(rest) = line.trim().strip_prefix("output \"") {
+            if let Some((name, _)) = rest.rsplit_once("\" {") {
+                current = Some((name.replace("\\\"", "\"").replace("\\\\", "\\"), line.to_string()));
+            }
+        }
+    }
+    nodes
+}
+
+/// Write the saved profile for the connected outputs to a section of niri's
+/// config, which niri reloads by itself. Outputs of other profiles keep the
+/// nodes written for them before, as niri has no profiles. Returns whether
+/// the config changed.
+pub async fn write_config() -> Result<bool, Box<dyn Error>> {
+    let backend = NiriBackend::connect().await?;
+    let outputs = backend.niri_outputs().await?;
+    let profiles_dir = get_kanshi_paths().await?.profiles;
+    let descriptions: Vec<(&str, String)> = outputs
+        .iter()
+        .map(|output| (output.name.as_str(), description(output)))
+        .collect();
+    let Some((_, profile, selected)) = kanshi::matching_profile(&profiles_dir, &descriptions)? else {
+        debug!("No saved profile for the connected outputs, leaving niri's config alone");
+        return Ok(false);
+    };
+    let path = config_path();
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("{}: {e}", path.display()).into()),
+    };
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let (before, previous, after) = match kanshi::find_section(&lines, OUTPUTS_BEGIN, OUTPUTS_END) {
+        Some((first, last)) => (&lines[..first], &lines[first + 1..last], &lines[last + 1..]),
+        None => (&lines[..], &[][..], &[][..]),
+    };
+    let mut nodes = read_nodes(previous);
+    for (directive, index) in profile.outputs.iter().zip(selected) {
+        let output = &outputs[index];
+        // Outputs without directives are left to the rest of niri's config
+        if !directive.options.is_empty() {
+            let (name, node) = output_node(directive, output);
+            nodes.insert(name, node);
+        }
+    }
+    let mut updated = before.concat();
+    if !updated.is_empty() && !updated.ends_with('\n') {
+        updated.push('\n');
+    }
+    updated.push_str(&format!("{OUTPUTS_BEGIN}\n"));
+    for node in nodes.values() {
+        updated.push_str(node);
+    }
+    updated.push_str(&format!("{OUTPUTS_END}\n"));
+    updated.push_str(&after.concat());
+    if updated == text {
+        return Ok(false);
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    write_atomic(&path, updated.as_bytes())?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The outputs of `niri msg --json outputs`: a monitor at 1.5 turned and
+    /// mirrored, and a panel that is off
+    const OUTPUTS: &str = r#"{
+        "DP-1": {
+            "name": "DP-1", "make": "Dell Inc.", "model": "DELL U2720Q", "serial": "ABC123",
+            "physical_size": [600, 340],
+            "modes": [
+                {"width": 3840, "height": 2160, "refresh_rate": 59997, "is_preferred": true},
+                {"width": 1920, "height": 1080, "refresh_rate": 60000, "is_preferred": false}
+            ],
+            "current_mode": 0, "vrr_supported": true, "vrr_enabled": false,
+            "logical": {"x": 0, "y": 0, "width": 1440, "height": 2560, "scale": 1.5, "transform": "Flipped90"}
+        },
+        "eDP-1": {
+            "name": "eDP-1", "make": "BOE", "model": "0x0BCA", "serial": null,
+            "physical_size": [290, 190],
+            "modes": [{"width": 2256, "height": 1504, "refresh_rate": 59999, "is_preferred": true}],
+            "current_mode": null, "vrr_supported": false, "vrr_enabled": false,
+            "logical": null
+        }
+    }"#;
+
+    fn outputs() -> Vec<NiriOutput> {
+        let outputs: HashMap<String, NiriOutput> = serde_json::from_str(OUTPUTS).unwrap();
+        let mut outputs: Vec<NiriOutput> = outputs.into_values().collect();
+        outputs.sort_by(|a, b| a.name.cmp(&b.name));
+        outputs
+    }
+
+    #[test]
+    fn describes_outputs_like_sway() {
+        let outputs = outputs();
+        let monitor = output(&outputs[0]);
+        assert_eq!(monitor.rect, (0, 0, 1440, 2560));
+        assert_eq!(monitor.scale, Some(1.5));
+        assert_eq!(monitor.transform.as_deref(), Some("flipped-90"));
+        assert_eq!(monitor.current_mode.map(|mode| mode.refresh), Some(59997));
+        let panel = output(&outputs[1]);
+        assert!(!panel.active);
+        assert_eq!(panel.serial, "Unknown");
+        assert_eq!(panel.current_mode, None);
+        assert_eq!(panel.transform, None);
+        assert_eq!(description(&outputs[1]), "BOE 0x0BCA Unknown");
+    }
+
+    #[test]
+    fn translates_output_commands() {
+        let cases = [
+            (
+                "output DP-1 mode 1920x1080@60Hz position 0,0",
+                vec![
+                    (
+                        Some("DP-1"),
+                        json!({ "Mode": { "mode": { "Specific": { "width": 1920, "height": 1080, "refresh": 60.0 } } } }),
+                    ),
+                    (Some("DP-1"), json!({ "Position": { "position": { "Specific": { "x": 0, "y": 0 } } } })),
+                ],
+            ),
+            (
+                r#"output "Dell Inc. DELL U2720Q ABC123" mode 3840x2160 transform flipped-270 scale 2"#,
+                vec![
+                    (
+                        Some("DP-1"),
+                        json!({ "Mode": { "mode": { "Specific": { "width": 3840, "height": 2160, "refresh": null } } } }),
+                    ),
+                    (Some("DP-1"), json!({ "Scale": { "scale": { "Specific": 2.0 } } })),
+                    (Some("DP-1"), json!({ "Transform": { "transform": "Flipped270" } })),
+                ],
+            ),
+            (
+                "output * enable adaptive_sync on",
+                vec![
+                    (Some("DP-1"), json!("On")),
+                    (Some("eDP-1"), json!("On")),
+                    (Some("DP-1"), json!({ "Vrr": { "vrr": { "vrr": true, "on_demand": false } } })),
+                    (Some("eDP-1"), json!({ "Vrr": { "vrr": { "vrr": true, "on_demand": false } } })),
+                ],
+            ),
+            ("output * power off", vec![(None, json!({ "PowerOffMonitors": {} }))]),
+            ("output DP-1 max_render_time 5", Vec::new()),
+            ("output HDMI-A-1 disable", Vec::new()),
+        ];
+        let outputs = outputs();
+        for (command, expected) in cases {
+            let expected: Vec<(Option<String>, Value)> = expected
+                .into_iter()
+                .map(|(output, action)| (output.map(str::to_string), action))
+                .collect();
+            assert_eq!(actions(&outputs, command).unwrap(), expected, "{command}");
+        }
+    }
+
+    #[test]
+    fn rejects_what_niri_cannot_do() {
+        let outputs = outputs();
+        for command in [
+            "output DP-1 mode --custom 1920x1080@60Hz",
+            "output DP-1 power off",
+            "output DP-1 transform 45",
+            "workspace 1",
+        ] {
+            assert!(actions(&outputs, command).is_err(), "{command}");
+        }
+    }
+}
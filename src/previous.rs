@@ -0,0 +1,47 @@
+//! The profile replaced by the last persistent apply, kept in
+//! `$XDG_STATE_HOME/regolith-displayd/previous-profile.json` so that
+//! `RevertLastApply` can put it back, across restarts too
+use crate::layout;
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path::PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Previous {
+    /// File name of the profile in the profiles directory
+    pub name: String,
+    /// Contents before the apply, `None` if the apply created the profile
+    pub contents: Option<String>,
+}
+
+fn path() -> Option<PathBuf> {
+    Some(layout::state_dir()?.join("previous-profile.json"))
+}
+
+/// Remember `previous`, replacing what an earlier apply left
+pub fn save(previous: &Previous) -> io::Result<()> {
+    let path = path().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "$HOME not defined"))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_string(previous)?)
+}
+
+/// The remembered profile, if any
+pub fn load() -> io::Result<Option<Previous>> {
+    let Some(path) = path() else {
+        return Ok(None);
+    };
+    match fs::read_to_string(path) {
+        Ok(text) => Ok(Some(serde_json::from_str(&text)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Forget the remembered profile once it was restored
+pub fn clear() -> io::Result<()> {
+    match path().map(fs::remove_file) {
+        Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,103 @@
+//! Privacy screens of built-in panels.
+//!
+//! The kernel only exposes the privacy screen as a KMS connector property,
+//! which belongs to sway, so the daemon goes through the platform driver
+//! instead. Only the ThinkPad ACPI driver has a control, so far, and it is
+//! only writable by root unless a udev rule or tmpfiles.d entry opens it up.
+//!
+//! As in GNOME, the setting is kept in `org.gnome.desktop.privacy
+//! privacy-screen`, which the display panel toggles too. The daemon watches
+//! it with `gsettings monitor`, like the night light settings (see
+//! [`nightlight`](crate::nightlight)), and applies it to the panel.
+use log::{debug, info, warn};
+use std::{error::Error, fs, io, path::Path, process::Stdio};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::Command,
+};
+
+/// ThinkPad ePrivacy control, only present on models with a privacy screen
+const THINKPAD_LCDSHADOW: &str = "/proc/acpi/ibm/lcdshadow";
+
+const SCHEMA: &str = "org.gnome.desktop.privacy";
+const KEY: &str = "privacy-screen";
+
+/// Whether the privacy screen is on, `None` if there is no privacy screen
+pub fn state() -> Option<bool> {
+    let contents = fs::read_to_string(THINKPAD_LCDSHADOW).ok()?;
+    // `status:\t\t1`, followed by the accepted commands
+    let status = contents
+        .lines()
+        .find_map(|line| line.strip_prefix("status:"))?
+        .trim();
+    match status {
+        "0" => Some(false),
+        "1" => Some(true),
+        _ => None,
+    }
+}
+
+/// Turn the privacy screen on or off: save the setting and apply it
+pub async fn set(enabled: bool) -> Result<(), String> {
+    if state().is_none() {
+        return Err(String::from("No privacy screen found"));
+    }
+    let output = Command::new("gsettings")
+        .args(["set", SCHEMA, KEY, if enabled { "true" } else { "false" }])
+        .output()
+        .await
+        .map_err(|e| format!("Cannot run gsettings: {e}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Cannot save {SCHEMA} {KEY}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    apply(enabled)
+}
+
+/// Switch the panel's privacy screen, unless it already is
+fn apply(enabled: bool) -> Result<(), String> {
+    if state() == Some(enabled) {
+        return Ok(());
+    }
+    let path = Path::new(THINKPAD_LCDSHADOW);
+    match fs::write(path, if enabled { "1" } else { "0" }) {
+        Ok(()) => {
+            info!("Privacy screen {}", if enabled { "on" } else { "off" });
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Err(format!(
+            "Saved the privacy screen setting, but {} is only writable by root",
+            path.display()
+        )),
+        Err(e) => Err(format!("Cannot write {}: {e}", path.display())),
+    }
+}
+
+/// Apply `org.gnome.desktop.privacy privacy-screen` whenever it changes,
+/// until `gsettings monitor` exits. Returns right away without a privacy
+/// screen.
+pub async fn watch() -> Result<(), Box<dyn Error + Send + Sync>> {
+    if state().is_none() {
+        debug!("No privacy screen, not following {SCHEMA} {KEY}");
+        return Ok(());
+    }
+    let mut monitor = Command::new("gsettings")
+        .args(["monitor", SCHEMA, KEY])
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdout = monitor.stdout.take().ok_or("no output from gsettings monitor")?;
+    let mut lines = BufReader::new(stdout).lines();
+    while let Some(line) = lines.next_line().await? {
+        let Some((_, value)) = line.split_once(':') else {
+            continue;
+        };
+        debug!("Privacy setting changed: {line}");
+        if let Err(e) = apply(value.trim() == "true") {
+            warn!("{e}");
+        }
+    }
+    Err("gsettings monitor exited".into())
+}
@@ -0,0 +1,872 @@
+//! `org.regolith.DisplayConfig`: managing the saved kanshi profiles over DBus,
+//! for pickers and scripts that shouldn't have to edit kanshi files. The
+//! daemon's other methods and signals that mutter doesn't have live here too,
+//! so `org.gnome.Mutter.DisplayConfig` stays as mutter defines it.
+use crate::{
+    backend::{DisplayBackend, Compositor},
+    backup, config, export, get_kanshi_paths,
+    kanshi::{self, OutputDirective, Profile},
+    layout,
+    lock::Mutex,
+    monitor::{self, Monitor},
+    previous, privacy, check_unique_connectors, reload_kanshi, write_profile, DisplayManager, RESCAN,
+};
+use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
+use std::{
+    collections::HashMap,
+    fs, io, iter,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use zbus::{dbus_interface, fdo, SignalContext};
+
+lazy_static! {
+    /// Files in the profiles directory holding a single profile, or variants
+    /// of one, with the outputs it lists, see [`load_existing`]
+    static ref EXISTING: StdMutex<Vec<(String, Vec<OutputDirective>)>> = StdMutex::new(Vec::new());
+    /// Names given to the profiles with `SetProfileName`, by file name, see
+    /// [`NAME_PREFIX`]
+    static ref NAMES: StdMutex<HashMap<String, String>> = StdMutex::new(HashMap::new());
+}
+
+/// File of the profile written by `SaveAnyExternalProfile`. kanshi applies the
+/// first profile matching the outputs, and this sorts after the profiles for
+/// specific monitors.
+pub const ANY_EXTERNAL_PROFILE: &str = "zz-any-external";
+
+/// Days after which `PruneProfiles` removes profiles whose outputs haven't
+/// been connected, unless `displayd.profile_retention_days` says otherwise
+pub const DEFAULT_PROFILE_RETENTION_DAYS: u32 = 90;
+
+pub struct ProfileServer {
+    manager: Arc<Mutex<DisplayManager>>,
+    backend: Arc<Compositor>,
+}
+
+impl ProfileServer {
+    pub fn new(manager: Arc<Mutex<DisplayManager>>, backend: Arc<Compositor>) -> ProfileServer {
+        ProfileServer {
+            manager,
+            backend,
+        }
+    }
+
+    /// Apply `commands`, those of profile `name`, and tell clients about the
+    /// resulting state
+    async fn apply_commands(&self, name: &str, commands: &[String]) -> fdo::Result<()> {
+        check_unique_connectors()?;
+        let mut manager = self.manager.lock().await;
+        if let Err(e) = self.backend.apply(commands).await {
+            DisplayManager::emit_profile_applied(name, false, &e.to_string()).await?;
+            return Err(e);
+        }
+        DisplayManager::emit_profile_applied(name, true, "").await?;
+        match DisplayManager::get_monitor_info(self.backend.as_ref()).await {
+            Ok(display_info) => manager.set_monitor_info(display_info),
+            Err(e) => error!("Error getting output information from sway: {e}"),
+        }
+        manager.publish_xwayland_scale().await;
+        manager.emit_monitors_changed().await?;
+        Ok(())
+    }
+
+    /// The profile file of the connected monitors and the variants it holds,
+    /// names and text, the one kanshi applies first
+    async fn variants(&self) -> fdo::Result<(PathBuf, Vec<(Option<String>, String)>)> {
+        let file = self.manager.lock().await.profile_name();
+        let path = profile_path(&file).await?;
+        let variants = match fs::read_to_string(&path) {
+            Ok(text) => section_variants(&text)
+                .map_err(|e| fdo::Error::Failed(format!("Invalid profile {file}: {e}")))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(fdo::Error::IOError(format!("{}: {e}", path.display()))),
+        };
+        Ok((path, variants))
+    }
+
+    /// Replace the variants in `path`, keeping the user's lines around them
+    /// (see [`splice_variants`]), and remove it if nothing is left
+    async fn write_variants(&self, path: PathBuf, variants: Vec<(Option<String>, String)>) -> fdo::Result<()> {
+        let existing = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(fdo::Error::IOError(format!("{}: {e}", path.display()))),
+        };
+        let variants: String = variants.into_iter().map(|(_, text)| text).collect();
+        let text = splice_variants(&existing, &variants);
+        if with_name(&text, None).trim().is_empty() {
+            fs::remove_file(&path).map_err(|e| fdo::Error::IOError(format!("{}: {e}", path.display())))?;
+        } else {
+            write_profile(path, text.into_bytes()).await?;
+        }
+        load_existing().await;
+        reload_kanshi().await?;
+        Ok(())
+    }
+}
+
+/// Read the profiles already in the profiles directory, so that a profile
+/// written by hand or by another tool for the connected outputs is updated
+/// by `ApplyMonitorsConfig` instead of joined by one under the daemon's name
+pub async fn load_existing() {
+    let dir = match get_kanshi_paths().await {
+        Ok(paths) => paths.profiles,
+        Err(e) => {
+            warn!("Cannot find the kanshi profiles: {e}");
+            return;
+        }
+    };
+    let mut existing = Vec::new();
+    let mut names = HashMap::new();
+    for entry in fs::read_dir(&dir).into_iter().flatten().filter_map(|entry| entry.ok()) {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let Ok(text) = fs::read_to_string(entry.path()) else {
+            continue;
+        };
+        if let Some(display_name) = name_of(&text) {
+            names.insert(name.clone(), display_name);
+        }
+        match kanshi::parse(&text) {
+            Ok(config) if is_variants(&config.profiles) => {
+                let profile = config.profiles.into_iter().next().unwrap();
+                existing.push((name, profile.outputs));
+            }
+            Ok(_) => debug!("Not reusing {name}, its profiles are for different outputs"),
+            Err(e) => warn!("Skipping the kanshi profile {name}: {e}"),
+        }
+    }
+    debug!("Found {} kanshi profiles in {}", existing.len(), dir.display());
+    *EXISTING.lock().unwrap() = existing;
+    *NAMES.lock().unwrap() = names;
+}
+
+/// Whether `profiles` are variants for the same outputs, which a single
+/// profile is too
+fn is_variants(profiles: &[Profile]) -> bool {
+    match profiles.split_first() {
+        Some((first, rest)) => rest.iter().all(|profile| profile.criteria() == first.criteria()),
+        None => false,
+    }
+}
+
+/// Lines around the part of a profile file the daemon writes. Lines outside
+/// them are the user's and kept when the profile is written again.
+const BEGIN_MARKER: &str = "# BEGIN regolith-displayd";
+const END_MARKER: &str = "# END regolith-displayd";
+
+/// Start of the comment line holding the name given to a profile file with
+/// `SetProfileName`. It is kept apart from kanshi's profile names, which name
+/// the variants.
+const NAME_PREFIX: &str = "# regolith-displayd name: ";
+
+/// `profile`, generated to be written to `path`, merged with what `path`
+/// holds. It replaces the section between the markers, or the first of
+/// several variants (see [`keep_variants`]) in files without markers, or
+/// else the whole file, keeping the name of the file in any case.
+pub fn merge_generated(path: &Path, profile: Vec<u8>) -> Vec<u8> {
+    let managed = format!("{BEGIN_MARKER}\n{}{END_MARKER}\n", String::from_utf8_lossy(&profile));
+    let Ok(existing) = fs::read_to_string(path) else {
+        return managed.into_bytes();
+    };
+    let merged = splice(&existing, &managed)
+        .or_else(|| keep_variants(&existing, &managed))
+        .unwrap_or(managed);
+    with_name(&merged, name_of(&existing).as_deref()).into_bytes()
+}
+
+/// The name in the [`NAME_PREFIX`] line of `text`
+fn name_of(text: &str) -> Option<String> {
+    text.lines()
+        .find_map(|line| line.strip_prefix(NAME_PREFIX))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+}
+
+/// `text` with its [`NAME_PREFIX`] line replaced by one for `name`, first, or
+/// removed if `name` is `None`
+fn with_name(text: &str, name: Option<&str>) -> String {
+    let rest = text.split_inclusive('\n').filter(|line| !line.starts_with(NAME_PREFIX));
+    match name {
+        Some(name) => iter::once(format!("{NAME_PREFIX}{name}\n").as_str()).chain(rest).collect(),
+        None => rest.collect(),
+    }
+}
+
+/// `text` with the lines between the markers, included, replaced by
+/// `managed`, which keeps the name of the profile it replaces. `None` if
+/// `text` has no markers.
+fn splice(text: &str, managed: &str) -> Option<String> {
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let (begin, end) = kanshi::find_section(&lines, BEGIN_MARKER, END_MARKER)?;
+    let name = kanshi::split_profiles(&lines[begin + 1..end].concat())
+        .ok()
+        .and_then(|profiles| profiles.into_iter().next())
+        .and_then(|(name, _)| name);
+    let managed = match name {
+        Some(name) => kanshi::rename_profile(managed, Some(&name)).ok()?,
+        None => managed.to_string(),
+    };
+    Some(lines[..begin].concat() + &managed + &lines[end + 1..].concat())
+}
+
+/// `profile` followed by the variants in `text` but the first, which it
+/// replaces as the one kanshi applies and whose name it gets. `None` if
+/// `text` doesn't hold several variants.
+fn keep_variants(text: &str, profile: &str) -> Option<String> {
+    let variants = kanshi::split_profiles(text).ok()?;
+    if variants.len() < 2 {
+        return None;
+    }
+    let active = kanshi::rename_profile(profile, variants[0].0.as_deref()).ok()?;
+    let others = variants[1..].iter().map(|(_, text)| text.as_str());
+    Some(iter::once(active.as_str()).chain(others).collect())
+}
+
+/// The variants in `text`, those between the markers if it has them
+fn section_variants(text: &str) -> Result<Vec<(Option<String>, String)>, kanshi::ParseError> {
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    match kanshi::find_section(&lines, BEGIN_MARKER, END_MARKER) {
+        Some((begin, end)) => kanshi::split_profiles(&lines[begin + 1..end].concat()),
+        None => kanshi::split_profiles(text),
+    }
+}
+
+/// `text` with its variants replaced by `variants` between the markers.
+/// Without markers, the lines from the first profile to the last are
+/// replaced. Lines outside are the user's and kept, and the markers are
+/// left out with the last variant.
+fn splice_variants(text: &str, variants: &str) -> String {
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let section = kanshi::find_section(&lines, BEGIN_MARKER, END_MARKER).or_else(|| {
+        let profiles = kanshi::parse(text).ok()?.profiles;
+        Some((profiles.first()?.lines.0 - 1, profiles.last()?.lines.1 - 1))
+    });
+    let (before, after) = match section {
+        Some((begin, end)) => (lines[..begin].concat(), lines[end + 1..].concat()),
+        None => (String::new(), String::new()),
+    };
+    let managed = if variants.is_empty() {
+        String::new()
+    } else {
+        format!("{BEGIN_MARKER}\n{variants}{END_MARKER}\n")
+    };
+    before + &managed + &after
+}
+
+/// The name given to the profile in file `file` with `SetProfileName`
+pub fn display_name(file: &str) -> Option<String> {
+    NAMES.lock().unwrap().get(file).cloned()
+}
+
+/// Name the saved profile `profile`, a file or profile name, `name`, or
+/// remove its name if `name` is empty, see `SetProfileName`. The names must
+/// have been read with [`load_existing`].
+pub async fn set_name(profile: &str, name: &str) -> fdo::Result<()> {
+    let file = resolve(profile);
+    let path = profile_path(&file).await?;
+    let name = name.trim();
+    check_name(name)?;
+    if NAMES.lock().unwrap().iter().any(|(other, display_name)| *other != file && display_name == name) {
+        return Err(fdo::Error::InvalidArgs(format!("Another profile is named {name}")));
+    }
+    let text = fs::read_to_string(&path)
+        .map_err(|e| fdo::Error::FileNotFound(format!("{}: {e}", path.display())))?;
+    let renamed = with_name(&text, (!name.is_empty()).then_some(name));
+    write_profile(path, renamed.into_bytes()).await?;
+    load_existing().await;
+    reload_kanshi().await?;
+    Ok(())
+}
+
+/// File of the profile named `name`, or `name` itself if no profile has that
+/// name, so profiles can be referred to by either
+fn resolve(name: &str) -> String {
+    NAMES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(_, display_name)| *display_name == name)
+        .map_or_else(|| name.to_string(), |(file, _)| file.clone())
+}
+
+/// Whether the profile of `SaveAnyExternalProfile` applies to `monitors`,
+/// the built-in panel and one other output
+pub fn any_external_matches(monitors: &[Monitor]) -> bool {
+    let existing = EXISTING.lock().unwrap();
+    let Some((_, outputs)) = existing.iter().find(|(name, _)| name == ANY_EXTERNAL_PROFILE) else {
+        return false;
+    };
+    outputs.len() == monitors.len()
+        && outputs
+            .iter()
+            .filter(|output| output.criteria != "*")
+            .all(|output| monitors.iter().any(|monitor| output.matches(monitor)))
+}
+
+/// Name of the existing profile listing exactly `monitors`, unless there is a
+/// profile under the `generated` name already. A profile under the `legacy`
+/// name an earlier version gave it is taken first.
+pub fn existing_name(monitors: &[Monitor], generated: &str, legacy: &str) -> Option<String> {
+    let existing = EXISTING.lock().unwrap();
+    if existing.iter().any(|(name, _)| name == generated) {
+        return None;
+    }
+    if existing.iter().any(|(name, _)| name == legacy) {
+        return Some(legacy.to_string());
+    }
+    existing
+        .iter()
+        .find(|(_, outputs)| lists_exactly(outputs, monitors))
+        .map(|(name, _)| name.clone())
+}
+
+/// Whether `outputs` name every one of `monitors` and nothing else. Profiles
+/// with `*` match any outputs, so they never list exactly the monitors.
+fn lists_exactly(outputs: &[OutputDirective], monitors: &[Monitor]) -> bool {
+    outputs.len() == monitors.len()
+        && outputs.iter().all(|output| output.criteria != "*")
+        && monitors
+            .iter()
+            .all(|monitor| outputs.iter().any(|output| output.matches(monitor)))
+}
+
+/// File names of the saved profiles in `dir`, sorted
+pub fn list(dir: &Path) -> io::Result<Vec<String>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// `$XDG_STATE_HOME/regolith-displayd/profiles-seen`: when the outputs of
+/// each profile were last connected, as lines of `SECONDS NAME`
+fn seen_path() -> Option<PathBuf> {
+    Some(layout::state_dir()?.join("profiles-seen"))
+}
+
+fn read_seen() -> HashMap<String, u64> {
+    let Some(text) = seen_path().and_then(|path| fs::read_to_string(path).ok()) else {
+        return HashMap::new();
+    };
+    text.lines()
+        .filter_map(|line| {
+            let (seconds, name) = line.split_once(' ')?;
+            Some((name.to_string(), seconds.parse().ok()?))
+        })
+        .collect()
+}
+
+fn write_seen(seen: &HashMap<String, u64>) -> io::Result<()> {
+    let path = seen_path()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "$HOME not defined"))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut lines: Vec<String> = seen
+        .iter()
+        .map(|(name, seconds)| format!("{seconds} {name}\n"))
+        .collect();
+    lines.sort();
+    fs::write(path, lines.concat())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+/// Record that the outputs of the profile named `generated`, and of every
+/// existing profile listing exactly `monitors`, are connected now
+pub fn mark_seen(monitors: &[Monitor], generated: &str) {
+    let mut seen = read_seen();
+    let now = now();
+    seen.insert(generated.to_string(), now);
+    for (name, outputs) in EXISTING.lock().unwrap().iter() {
+        if lists_exactly(outputs, monitors) {
+            seen.insert(name.clone(), now);
+        }
+    }
+    if let Err(e) = write_seen(&seen) {
+        warn!("Cannot record the connected profile: {e}");
+    }
+}
+
+/// Remove the profiles whose outputs haven't been connected for `max_age`,
+/// returning their names. Only files holding a single profile or variants of
+/// one, without `*`, are considered, and those seen for the first time are
+/// kept for `max_age` from now.
+pub async fn prune(max_age: Duration) -> io::Result<Vec<String>> {
+    let dir = get_kanshi_paths()
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::NotFound, e.to_string()))?
+        .profiles;
+    load_existing().await;
+    let mut seen = read_seen();
+    let now = now();
+    let mut removed = Vec::new();
+    let candidates: Vec<String> = EXISTING
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, outputs)| outputs.iter().all(|output| output.criteria != "*"))
+        .map(|(name, _)| name.clone())
+        .collect();
+    for name in candidates {
+        let last_seen = *seen.entry(name.clone()).or_insert(now);
+        if now.saturating_sub(last_seen) > max_age.as_secs() {
+            fs::remove_file(dir.join(&name))?;
+            info!("Removed the kanshi profile {name}, unused for {} days", (now - last_seen) / 86400);
+            seen.remove(&name);
+            removed.push(name);
+        }
+    }
+    // Forget monitor sets that went unused without ever getting a profile
+    seen.retain(|name, last_seen| {
+        now.saturating_sub(*last_seen) <= max_age.as_secs() || dir.join(name).exists()
+    });
+    write_seen(&seen)?;
+    if !removed.is_empty() {
+        load_existing().await;
+    }
+    removed.sort();
+    Ok(removed)
+}
+
+/// Days of `displayd.profile_retention_days` as a duration, `None` for 0
+pub fn retention(days: u32) -> Option<Duration> {
+    (days > 0).then(|| Duration::from_secs(u64::from(days) * 86400))
+}
+
+/// Refuse names kanshi can't take as a profile name
+fn check_name(name: &str) -> fdo::Result<()> {
+    if name.contains('"') || name.contains(char::is_control) {
+        return Err(fdo::Error::InvalidArgs(format!("Invalid profile name {name:?}")));
+    }
+    Ok(())
+}
+
+/// Path of the saved profile `name`, refusing names that would leave the
+/// profiles directory
+async fn profile_path(name: &str) -> fdo::Result<PathBuf> {
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        return Err(fdo::Error::InvalidArgs(format!("Invalid profile name {name:?}")));
+    }
+    Ok(get_kanshi_paths().await?.profiles.join(name))
+}
+
+/// Path of a bundle for `ExportProfiles` and `ImportProfiles`. It has to be
+/// absolute, as the daemon's working directory isn't the caller's.
+fn bundle_path(path: &str) -> fdo::Result<PathBuf> {
+    let path = PathBuf::from(path);
+    if !path.is_absolute() {
+        return Err(fdo::Error::InvalidArgs(format!("{} is not an absolute path", path.display())));
+    }
+    Ok(path)
+}
+
+#[dbus_interface(name = "org.regolith.DisplayConfig")]
+impl ProfileServer {
+    /// Names of the saved profiles, sorted
+    async fn list_profiles(&self) -> fdo::Result<Vec<String>> {
+        let dir = get_kanshi_paths().await?.profiles;
+        list(&dir).map_err(|e| fdo::Error::IOError(format!("{}: {e}", dir.display())))
+    }
+
+    /// Names of the saved profiles that have one, by file name
+    async fn profile_names(&self) -> HashMap<String, String> {
+        NAMES.lock().unwrap().clone()
+    }
+
+    /// Name the saved profile `profile` `name`, e.g. `Home dock`, replacing
+    /// the name it had. The name is kept in a comment of the profile file
+    /// (see [`NAME_PREFIX`]), so it doesn't change the names of its variants.
+    /// An empty name removes it.
+    async fn set_profile_name(&self, profile: &str, name: &str) -> fdo::Result<()> {
+        info!("SetProfileName {profile} {name}");
+        set_name(profile, name).await
+    }
+
+    /// Apply the saved profile `name` through sway, whatever outputs it lists
+    async fn apply_profile(&self, name: &str) -> fdo::Result<()> {
+        info!("ApplyProfile {name}");
+        let path = profile_path(&resolve(name)).await?;
+        let text = fs::read_to_string(&path)
+            .map_err(|e| fdo::Error::FileNotFound(format!("{}: {e}", path.display())))?;
+        let commands = kanshi::sway_commands(&text)
+            .map_err(|e| fdo::Error::Failed(format!("Invalid profile {name}: {e}")))?;
+        self.apply_commands(name, &commands).await
+    }
+
+    /// Save a profile applying to the built-in panel with any single other
+    /// output, placed `right` of or `below` the panel as it is configured now,
+    /// or as the `only` active output. Other outputs get their preferred mode.
+    async fn save_any_external_profile(&self, placement: &str) -> fdo::Result<()> {
+        info!("SaveAnyExternalProfile {placement}");
+        check_unique_connectors()?;
+        let profile = {
+            let manager = self.manager.lock().await;
+            export::any_external_profile(&manager.monitors, &manager.logical_monitors, placement)
+                .map_err(fdo::Error::InvalidArgs)?
+        };
+        let path = profile_path(ANY_EXTERNAL_PROFILE).await?;
+        let profile = merge_generated(&path, profile.into_bytes());
+        write_profile(path, profile).await?;
+        load_existing().await;
+        reload_kanshi().await?;
+        Ok(())
+    }
+
+    /// Names of the variants saved for the connected monitors, the one kanshi
+    /// applies first. An unnamed variant is listed as an empty string.
+    async fn list_variants(&self) -> fdo::Result<Vec<String>> {
+        let (_, variants) = self.variants().await?;
+        Ok(variants.into_iter().map(|(name, _)| name.unwrap_or_default()).collect())
+    }
+
+    /// Save the current layout as variant `variant` of the connected monitors'
+    /// profile, e.g. `mirrored`, replacing the variant of that name. It becomes
+    /// the variant kanshi applies.
+    async fn save_variant(&self, variant: &str) -> fdo::Result<()> {
+        info!("SaveVariant {variant}");
+        check_unique_connectors()?;
+        let variant = variant.trim();
+        check_name(variant)?;
+        if variant.is_empty() {
+            return Err(fdo::Error::InvalidArgs(String::from("Variants need a name")));
+        }
+        let (path, mut variants) = self.variants().await?;
+        let profile = {
+            let manager = self.manager.lock().await;
+            manager.current_profile(&manager.profile_name())?
+        };
+        let profile = kanshi::rename_profile(&profile, Some(variant))
+            .map_err(|e| fdo::Error::Failed(format!("Invalid generated profile: {e}")))?;
+        variants.retain(|(name, _)| name.as_deref() != Some(variant));
+        variants.insert(0, (Some(variant.to_string()), profile));
+        self.write_variants(path, variants).await
+    }
+
+    /// Apply variant `variant` of the connected monitors' profile and make it
+    /// the one kanshi applies
+    async fn switch_variant(&self, variant: &str) -> fdo::Result<()> {
+        info!("SwitchVariant {variant}");
+        let (path, mut variants) = self.variants().await?;
+        let index = variants
+            .iter()
+            .position(|(name, _)| name.as_deref().unwrap_or_default() == variant)
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("No variant {variant:?} for the connected monitors")))?;
+        let active = variants.remove(index);
+        let commands = kanshi::sway_commands(&active.1)
+            .map_err(|e| fdo::Error::Failed(format!("Invalid variant {variant}: {e}")))?;
+        self.apply_commands(variant, &commands).await?;
+        variants.insert(0, active);
+        self.write_variants(path, variants).await
+    }
+
+    /// Remove variant `variant` of the connected monitors' profile, and the
+    /// profile with the last one
+    async fn delete_variant(&self, variant: &str) -> fdo::Result<()> {
+        info!("DeleteVariant {variant}");
+        let (path, mut variants) = self.variants().await?;
+        let count = variants.len();
+        variants.retain(|(name, _)| name.as_deref().unwrap_or_default() != variant);
+        if variants.len() == count {
+            return Err(fdo::Error::InvalidArgs(format!("No variant {variant:?} for the connected monitors")));
+        }
+        self.write_variants(path, variants).await
+    }
+
+    /// Remove the profiles whose outputs haven't been connected for
+    /// `displayd.profile_retention_days` (see [`prune`]) and return their names
+    async fn prune_profiles(&self) -> fdo::Result<Vec<String>> {
+        info!("PruneProfiles");
+        let days = config::current()
+            .profile_retention_days
+            .unwrap_or(DEFAULT_PROFILE_RETENTION_DAYS);
+        let Some(max_age) = retention(days) else {
+            return Ok(Vec::new());
+        };
+        {
+            // Profiles in use are never stale, however long they were connected
+            let manager = self.manager.lock().await;
+            mark_seen(&manager.monitors, &manager.profile_name());
+        }
+        let removed = prune(max_age)
+            .await
+            .map_err(|e| fdo::Error::IOError(format!("Cannot prune the kanshi profiles: {e}")))?;
+        if !removed.is_empty() {
+            reload_kanshi().await?;
+        }
+        Ok(removed)
+    }
+
+    /// Remove the saved profile `name`
+    async fn delete_profile(&self, name: &str) -> fdo::Result<()> {
+        info!("DeleteProfile {name}");
+        let path = profile_path(&resolve(name)).await?;
+        fs::remove_file(&path).map_err(|e| fdo::Error::FileNotFound(format!("{}: {e}", path.display())))?;
+        load_existing().await;
+        reload_kanshi().await?;
+        Ok(())
+    }
+
+    /// Save the current layout as profile `name`, replacing it if it exists
+    /// but keeping its name
+    async fn save_current_as_profile(&self, name: &str) -> fdo::Result<()> {
+        info!("SaveCurrentAsProfile {name}");
+        check_unique_connectors()?;
+        let file = resolve(name);
+        let path = profile_path(&file).await?;
+        // The profile keeps its name, see `generate_profile`
+        let profile = {
+            let manager = self.manager.lock().await;
+            manager.current_profile(&file)?
+        };
+        let profile = merge_generated(&path, profile.into_bytes());
+        write_profile(path, profile).await?;
+        load_existing().await;
+        reload_kanshi().await?;
+        Ok(())
+    }
+
+    /// Write the saved profiles, JSON layouts and layout mode to the bundle
+    /// `path` read by `ImportProfiles` and `import-state`, returning the names
+    /// of the profiles
+    async fn export_profiles(&self, path: &str) -> fdo::Result<Vec<String>> {
+        info!("ExportProfiles {path}");
+        let path = bundle_path(path)?;
+        let bundle = backup::export()
+            .await
+            .map_err(|e| fdo::Error::Failed(format!("Cannot export the profiles: {e}")))?;
+        let json = serde_json::to_string_pretty(&bundle)
+            .map_err(|e| fdo::Error::Failed(format!("Cannot export the profiles: {e}")))?;
+        fs::write(&path, json + "\n").map_err(|e| fdo::Error::IOError(format!("{}: {e}", path.display())))?;
+        Ok(bundle.profiles.into_keys().collect())
+    }
+
+    /// Restore the bundle `path` written by `ExportProfiles` or `export-state`,
+    /// replacing files of the same name, and return the names of the profiles
+    /// it had. Nothing is written if any of its files is invalid.
+    async fn import_profiles(&self, path: &str) -> fdo::Result<Vec<String>> {
+        info!("ImportProfiles {path}");
+        let path = bundle_path(path)?;
+        let text = fs::read_to_string(&path)
+            .map_err(|e| fdo::Error::FileNotFound(format!("{}: {e}", path.display())))?;
+        let bundle = backup::parse(&text)
+            .map_err(|e| fdo::Error::InvalidArgs(format!("Cannot import {}: {e}", path.display())))?;
+        bundle
+            .import()
+            .await
+            .map_err(|e| fdo::Error::Failed(format!("Cannot import {}: {e}", path.display())))?;
+        if let Some(mode) = bundle.layout_mode {
+            let mut manager = self.manager.lock().await;
+            match manager.set_layout_mode(mode) {
+                Ok(true) => manager.emit_monitors_changed().await?,
+                Ok(false) => {}
+                Err(e) => warn!("Not switching to the imported layout mode: {e}"),
+            }
+        }
+        load_existing().await;
+        reload_kanshi().await?;
+        Ok(bundle.profiles.into_keys().collect())
+    }
+
+    /// Turn the privacy screen of the built-in panel on `connector` on or off.
+    /// See [`privacy`](crate::privacy).
+    async fn set_privacy_screen(&self, connector: &str, enabled: bool) -> fdo::Result<()> {
+        info!("SetPrivacyScreen {connector} {enabled}");
+        check_unique_connectors()?;
+        let mut manager_obj = self.manager.lock().await;
+        let Some(monitor) = manager_obj.monitors.iter().find(|monitor| monitor.get_connector() == connector) else {
+            return Err(fdo::Error::InvalidArgs(format!("No monitor connected to {connector}")));
+        };
+        if !monitor::is_builtin_connector(connector) || monitor.is_virtual() || privacy::state().is_none() {
+            return Err(fdo::Error::NotSupported(format!("{connector} has no privacy screen")));
+        }
+        if !config::current().manages(connector, &monitor.get_dpy_name()) {
+            return Err(fdo::Error::AccessDenied(format!("{connector} is not managed")));
+        }
+        privacy::set(enabled).await.map_err(fdo::Error::Failed)?;
+        match DisplayManager::get_monitor_info(self.backend.as_ref()).await {
+            Ok(display_info) => manager_obj.set_monitor_info(display_info),
+            Err(e) => error!("Error getting output information from sway: {e}"),
+        }
+        manager_obj.emit_monitors_changed().await?;
+        Ok(())
+    }
+
+    /// Allow or disallow tearing page flips on the output on `connector`. The
+    /// setting is kept in the profiles written afterwards, see
+    /// [`MonitorApply::save_kanshi`].
+    async fn set_allow_tearing(&self, connector: &str, allowed: bool) -> fdo::Result<()> {
+        info!("SetAllowTearing {connector} {allowed}");
+        check_unique_connectors()?;
+        let mut manager_obj = self.manager.lock().await;
+        let Some(monitor) = manager_obj.monitors.iter().find(|monitor| monitor.get_connector() == connector) else {
+            return Err(fdo::Error::InvalidArgs(format!("No monitor connected to {connector}")));
+        };
+        if !monitor::tearing_supported() {
+            return Err(fdo::Error::NotSupported(String::from("Tearing page flips are not supported by the compositor")));
+        }
+        if !config::current().manages(connector, &monitor.get_dpy_name()) {
+            return Err(fdo::Error::AccessDenied(format!("{connector} is not managed")));
+        }
+        let value = if allowed { "yes" } else { "no" };
+        self.backend.apply(&[format!("output {connector} allow_tearing {value}")]).await?;
+        monitor::remember_tearing(connector, allowed);
+        match DisplayManager::get_monitor_info(self.backend.as_ref()).await {
+            Ok(display_info) => manager_obj.set_monitor_info(display_info),
+            Err(e) => error!("Error getting output information from sway: {e}"),
+        }
+        manager_obj.emit_monitors_changed().await?;
+        Ok(())
+    }
+
+    /// Set sway's `max_render_time` of the output on `connector` to
+    /// `milliseconds`, or turn it off with 0. The value is kept in the profiles
+    /// written afterwards.
+    async fn set_max_render_time(&self, connector: &str, milliseconds: u32) -> fdo::Result<()> {
+        info!("SetMaxRenderTime {connector} {milliseconds}");
+        check_unique_connectors()?;
+        let mut manager_obj = self.manager.lock().await;
+        let Some(monitor) = manager_obj.monitors.iter().find(|monitor| monitor.get_connector() == connector) else {
+            return Err(fdo::Error::InvalidArgs(format!("No monitor connected to {connector}")));
+        };
+        if !config::current().manages(connector, &monitor.get_dpy_name()) {
+            return Err(fdo::Error::AccessDenied(format!("{connector} is not managed")));
+        }
+        let value = monitor::max_render_time_value(milliseconds);
+        self.backend.apply(&[format!("output {connector} max_render_time {value}")]).await?;
+        monitor::remember_max_render_time(connector, milliseconds);
+        match DisplayManager::get_monitor_info(self.backend.as_ref()).await {
+            Ok(display_info) => manager_obj.set_monitor_info(display_info),
+            Err(e) => error!("Error getting output information from sway: {e}"),
+        }
+        manager_obj.emit_monitors_changed().await?;
+        Ok(())
+    }
+
+    /// Put back the profile the last persistent `ApplyMonitorsConfig`
+    /// replaced, or remove it if that apply created it, and reload kanshi.
+    /// The restored layout is applied through sway right away as well. Only
+    /// the last apply can be reverted.
+    async fn revert_last_apply(&self) -> fdo::Result<String> {
+        info!("RevertLastApply");
+        let mut manager_obj = self.manager.lock().await;
+        let previous = previous::load()
+            .map_err(|e| fdo::Error::IOError(format!("Cannot read the previous profile: {e}")))?
+            .ok_or_else(|| fdo::Error::Failed(String::from("No apply to revert")))?;
+        let path = get_kanshi_paths().await?.profiles.join(&previous.name);
+        match &previous.contents {
+            Some(contents) => {
+                let commands = kanshi::sway_commands(contents)
+                    .map_err(|e| fdo::Error::Failed(format!("Invalid previous profile {}: {e}", previous.name)))?;
+                write_profile(path, contents.clone().into_bytes()).await?;
+                if let Err(e) = self.backend.apply(&commands).await {
+                    warn!("Cannot apply the previous profile {} directly: {e}", previous.name);
+                }
+            }
+            None => {
+                fs::remove_file(&path)
+                    .map_err(|e| fdo::Error::IOError(format!("{}: {e}", path.display())))?;
+            }
+        }
+        if let Err(e) = previous::clear() {
+            warn!("Cannot forget the previous profile: {e}");
+        }
+        load_existing().await;
+        if let Err(e) = reload_kanshi().await {
+            DisplayManager::emit_profile_applied(&previous.name, false, &format!("kanshi was not reloaded: {e}")).await?;
+            return Err(e.into());
+        }
+        DisplayManager::emit_profile_applied(&previous.name, true, "").await?;
+        match DisplayManager::get_monitor_info(self.backend.as_ref()).await {
+            Ok(display_info) => manager_obj.set_monitor_info(display_info),
+            Err(e) => error!("Error getting output information from sway: {e}"),
+        }
+        manager_obj.emit_monitors_changed().await?;
+        Ok(previous.name)
+    }
+
+    /// Poll sway for output changes now instead of waiting for the next poll,
+    /// e.g. after running `swaymsg output` by hand. `MonitorsChanged` follows if
+    /// anything changed.
+    async fn rescan(&self) {
+        info!("Rescan");
+        RESCAN.notify_one();
+    }
+
+    /// Emitted after a profile was written and kanshi reloaded, or applied
+    /// with `ApplyProfile`. `message` says what went wrong when `success` is
+    /// false and is empty otherwise.
+    #[dbus_interface(signal)]
+    pub async fn profile_applied(
+        &self,
+        ctxt: &SignalContext<'_>,
+        name: &str,
+        success: bool,
+        message: &str
+    ) -> zbus::Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOCKED: &str = "profile docked {\n\toutput eDP-1 disable\n\toutput DP-1 enable\n}\n";
+    const MIRRORED: &str = "profile mirrored {\n\toutput eDP-1 enable\n\toutput DP-1 enable\n}\n";
+
+    #[test]
+    fn splices_variants_between_the_markers() {
+        let text = format!("# kept\n{BEGIN_MARKER}\n{DOCKED}{END_MARKER}\n\n# also kept\n");
+        assert_eq!(section_variants(&text).unwrap().len(), 1);
+        let spliced = splice_variants(&text, &format!("{MIRRORED}{DOCKED}"));
+        assert_eq!(spliced, format!("# kept\n{BEGIN_MARKER}\n{MIRRORED}{DOCKED}{END_MARKER}\n\n# also kept\n"));
+        let variants = section_variants(&spliced).unwrap();
+        assert_eq!(variants[0].0.as_deref(), Some("mirrored"));
+        assert_eq!(variants[1].0.as_deref(), Some("docked"));
+        // With the last variant gone only the user's lines are left
+        assert_eq!(splice_variants(&text, ""), "# kept\n\n# also kept\n");
+    }
+
+    #[test]
+    fn puts_variants_of_files_without_markers_between_markers() {
+        let text = format!("# written by hand\n{DOCKED}{MIRRORED}# trailing\n");
+        let spliced = splice_variants(&text, MIRRORED);
+        assert_eq!(spliced, format!("# written by hand\n{BEGIN_MARKER}\n{MIRRORED}{END_MARKER}\n# trailing\n"));
+        assert_eq!(splice_variants("", DOCKED), format!("{BEGIN_MARKER}\n{DOCKED}{END_MARKER}\n"));
+    }
+
+    #[test]
+    fn keeps_the_name_apart_from_the_variants() {
+        let text = format!("{NAME_PREFIX}Home dock\n{DOCKED}{MIRRORED}");
+        assert_eq!(name_of(&text).as_deref(), Some("Home dock"));
+        // Replacing the active variant leaves the name line out, merge_generated puts it back
+        let replaced = keep_variants(&text, MIRRORED).unwrap();
+        assert_eq!(name_of(&replaced), None);
+        let named = with_name(&replaced, name_of(&text).as_deref());
+        assert!(named.starts_with(&format!("{NAME_PREFIX}Home dock\n")));
+        let variants: Vec<Option<String>> = section_variants(&named).unwrap().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(variants, [Some(String::from("docked")), Some(String::from("mirrored"))]);
+        let renamed = with_name(&named, Some("Office"));
+        assert_eq!(name_of(&renamed).as_deref(), Some("Office"));
+        assert_eq!(renamed.matches(NAME_PREFIX).count(), 1);
+        assert_eq!(with_name(&renamed, None), replaced);
+        assert_eq!(name_of(&format!("{NAME_PREFIX} \n{DOCKED}")), None);
+    }
+}
@@ -0,0 +1,225 @@
+//! Layouts restored from a directory of JSON files.
+//!
+//! Each file describes the layout of one set of outputs, keyed like kanshi
+//! profiles by connector or output description (`Make Model Serial`):
+//!
+//! ```json
+//! {
+//!     "outputs": {
+//!         "eDP-1": { "mode": "1920x1080@60Hz", "position": [0, 0], "scale": 1.25 },
+//!         "Dell Inc. DELL U2720Q 1234": { "position": [1536, 0], "transform": "90" },
+//!         "HDMI-A-1": { "enabled": false }
+//!     }
+//! }
+//! ```
+//!
+//! When the connected outputs change and a file lists exactly those outputs,
+//! the watch loop applies it through sway directly, without going through
+//! kanshi. Settings a file leaves out are left to sway.
+use crate::{
+    config,
+    kanshi::{self, TRANSFORMS},
+    monitor::Monitor,
+};
+use log::{debug, warn};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Layout {
+    pub outputs: BTreeMap<String, OutputLayout>,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OutputLayout {
+    #[serde(default = "enabled_default")]
+    pub enabled: bool,
+    /// `WIDTHxHEIGHT`, optionally followed by `@REFRESH` or `@REFRESHHz`
+    pub mode: Option<String>,
+    pub position: Option<(i32, i32)>,
+    pub scale: Option<f64>,
+    /// One of the sway transforms, e.g. `normal` or `flipped-90`
+    pub transform: Option<String>,
+}
+
+fn enabled_default() -> bool {
+    true
+}
+
+impl Layout {
+    fn validate(&self) -> Result<(), String> {
+        if self.outputs.is_empty() {
+            return Err(String::from("no outputs"));
+        }
+        if !self.outputs.values().any(|output| output.enabled) {
+            return Err(String::from("every output is disabled"));
+        }
+        for (name, output) in &self.outputs {
+            if let Some(mode) = &output.mode {
+                if !kanshi::valid_mode(mode) {
+                    return Err(format!("{name}: invalid mode {mode}"));
+                }
+            }
+            if let Some(scale) = output.scale {
+                if !(scale.is_finite() && scale > 0.0) {
+                    return Err(format!("{name}: invalid scale {scale}"));
+                }
+            }
+            if let Some(transform) = &output.transform {
+                if !TRANSFORMS.contains(&transform.as_str()) {
+                    return Err(format!("{name}: invalid transform {transform}"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The layout of `monitor`, found by connector or description
+    fn output(&self, monitor: &Monitor) -> Option<&OutputLayout> {
+        self.outputs
+            .get(monitor.get_connector())
+            .or_else(|| self.outputs.get(&monitor.get_dpy_name()))
+    }
+
+    /// Whether the layout lists exactly the connected `monitors`
+    pub fn matches(&self, monitors: &[Monitor]) -> bool {
+        self.outputs.len() == monitors.len()
+            && monitors.iter().all(|monitor| self.output(monitor).is_some())
+    }
+
+    /// sway commands applying the layout to the connected `monitors`,
+    /// leaving out the outputs the daemon doesn't manage
+    pub fn sway_commands(&self, monitors: &[Monitor]) -> Vec<String> {
+        let config = config::current();
+        let mut commands = Vec::new();
+        for monitor in monitors {
+            let Some(output) = self.output(monitor) else {
+                continue;
+            };
+            let connector = monitor.get_connector();
+            if !config.manages(connector, &monitor.get_dpy_name()) {
+                continue;
+            }
+            if !output.enabled {
+                commands.push(format!("output {connector} disable"));
+                continue;
+            }
+            let mut command = format!("output {connector} enable");
+            if let Some(mode) = &output.mode {
+                command.push_str(&format!(" mode {mode}"));
+            }
+            if let Some((x, y)) = output.position {
+                command.push_str(&format!(" position {x} {y}"));
+            }
+            if let Some(scale) = output.scale {
+                command.push_str(&format!(" scale {scale}"));
+            }
+            if let Some(transform) = &output.transform {
+                command.push_str(&format!(" transform {transform}"));
+            }
+            commands.push(command);
+        }
+        // sway refuses to disable its last enabled output, so enabling goes first
+        commands.sort_by_key(|command| command.ends_with(" disable"));
+        commands
+    }
+}
+
+/// Directory the layouts are read from when `displayd.layouts_dir` is unset
+pub fn default_dir() -> Option<PathBuf> {
+    Some(PathBuf::from(std::env::var("HOME").ok()?).join(".config/regolith3/displayd/layouts"))
+}
+
+/// Parse and validate the contents of a layout file
+pub fn parse(text: &str) -> Result<Layout, String> {
+    let layout: Layout = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    layout.validate()?;
+    Ok(layout)
+}
+
+/// Every valid layout in `dir`, skipping the malformed ones with a warning
+pub fn load(dir: &Path) -> Vec<(PathBuf, Layout)> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("Not reading layouts from {}: {e}", dir.display());
+            return Vec::new();
+        }
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let layout = fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|contents| parse(&contents));
+            match layout {
+                Ok(layout) => Some((path, layout)),
+                Err(e) => {
+                    warn!("Skipping layout {}: {e}", path.display());
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// The first layout in `dir` for exactly the connected `monitors`
+pub fn find(dir: &Path, monitors: &[Monitor]) -> Option<(PathBuf, Layout)> {
+    let mut layouts: Vec<_> = load(dir)
+        .into_iter()
+        .filter(|(_, layout)| layout.matches(monitors))
+        .collect();
+    if layouts.len() > 1 {
+        warn!(
+            "Several layouts match the connected outputs, using {}",
+            layouts[0].0.display()
+        );
+    }
+    (!layouts.is_empty()).then(|| layouts.remove(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_valid_layouts() {
+        let layout = parse(
+            r#"{"outputs": {
+                "eDP-1": {"enabled": false},
+                "Dell Inc. DELL U2720Q ABC123": {"mode": "3840x2160@60Hz", "position": [0, 0], "scale": 1.5, "transform": "90"}
+            }}"#,
+        )
+        .unwrap();
+        assert_eq!(layout.outputs.len(), 2);
+        assert!(!layout.outputs["eDP-1"].enabled);
+        assert_eq!(layout.outputs["Dell Inc. DELL U2720Q ABC123"].position, Some((0, 0)));
+    }
+
+    #[test]
+    fn rejects_invalid_layouts() {
+        let cases = [
+            (r#"{"outputs": {}}"#, "no outputs"),
+            (r#"{"outputs": {"DP-1": {"enabled": false}}}"#, "every output is disabled"),
+            (r#"{"outputs": {"DP-1": {"mode": "big"}}}"#, "DP-1: invalid mode big"),
+            (r#"{"outputs": {"DP-1": {"scale": -1.0}}}"#, "DP-1: invalid scale -1"),
+            (r#"{"outputs": {"DP-1": {"transform": "45"}}}"#, "DP-1: invalid transform 45"),
+        ];
+        for (text, error) in cases {
+            assert_eq!(parse(text), Err(String::from(error)), "{text}");
+        }
+        // Unknown fields are typos rather than settings to ignore
+        assert!(parse(r#"{"outputs": {"DP-1": {"sclae": 2.0}}}"#).is_err());
+    }
+}
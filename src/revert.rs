@@ -0,0 +1,242 @@
+//! Reverting temporary configurations that were never confirmed
+use crate::{
+    backend::{DisplayBackend, Compositor},
+    lock::{Mutex, Rank, Ranked},
+    reload_kanshi, DisplayManager,
+};
+use log::{error, info};
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::task::JoinHandle;
+
+/// Time a client has to confirm a temporary configuration
+pub const REVERT_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Temporary configuration applied to sway
+pub struct PendingConfig {
+    pub profile_name: String,
+    /// Where the profile goes once confirmed, and its contents
+    pub profile_path: PathBuf,
+    pub profile: Vec<u8>,
+    /// sway commands restoring the layout from before the configuration
+    pub previous: Vec<String>,
+    /// Layout mode from before the configuration, which may have switched it
+    pub previous_layout: Option<u32>,
+}
+
+/// Temporary configuration waiting for confirmation
+struct PendingRevert {
+    config: PendingConfig,
+    timer: JoinHandle<()>,
+}
+
+#[derive(Clone, Default)]
+pub struct RevertState {
+    pending: Arc<Mutex<Option<PendingRevert>>>,
+}
+
+impl Ranked for Option<PendingRevert> {
+    const RANK: Rank = Rank::Revert;
+}
+
+impl RevertState {
+    /// (Re)start the revert timer for a temporary configuration, kept until
+    /// confirmed.
+    ///
+    /// Reverting goes back to `config.previous`, or to the layout before the
+    /// first of several temporary configurations applied in a row. kanshi is
+    /// reloaded instead if sway refuses it.
+    pub async fn arm(
+        &self,
+        mut config: PendingConfig,
+        manager: Arc<Mutex<DisplayManager>>,
+        backend: Arc<Compositor>,
+    ) {
+        let mut pending = self.pending.lock().await;
+        if let Some(old) = pending.take() {
+            old.timer.abort();
+            config.previous = old.config.previous;
+            config.previous_layout = old.config.previous_layout;
+        }
+        let state = self.clone();
+        let timer = tokio::spawn(async move {
+            tokio::time::sleep(REVERT_TIMEOUT).await;
+            state.fire(&manager, backend.as_ref()).await;
+        });
+        *pending = Some(PendingRevert { config, timer });
+    }
+
+    /// Stop the revert timer, keeping the current configuration. Returns
+    /// `false` if nothing was pending.
+    pub async fn cancel(&self) -> bool {
+        match self.pending.lock().await.take() {
+            Some(pending) => {
+                pending.timer.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stop the revert timer and hand back the temporary configuration as
+    /// (profile name, path, contents) so it can be saved. `None` if nothing
+    /// was pending.
+    pub async fn confirm(&self) -> Option<(String, PathBuf, Vec<u8>)> {
+        let pending = self.pending.lock().await.take()?;
+        pending.timer.abort();
+        let config = pending.config;
+        Some((config.profile_name, config.profile_path, config.profile))
+    }
+
+    /// Go back to the layout and layout mode from before the pending
+    /// configuration and tell clients with MonitorsChanged, then with
+    /// `ConfigurationReverted` carrying the new serial. Returns `false` if nothing was pending.
+    async fn fire(&self, manager: &Mutex<DisplayManager>, backend: &impl DisplayBackend) -> bool {
+        // Held through the revert, so it can't interleave with an apply that
+        // starts meanwhile
+        let mut manager = manager.lock().await;
+        // Taking the pending revert guarantees a single revert per timer, even
+        // if a confirmation races with the timeout
+        let Some(pending) = self.pending.lock().await.take() else {
+            return false;
+        };
+        info!("Configuration not confirmed in time, reverting");
+        if let Err(e) = backend.apply(&pending.config.previous).await {
+            error!("Error restoring the previous layout, reloading kanshi instead: {e}");
+            if let Err(e) = reload_kanshi().await {
+                error!("Error reloading kanshi configuration: {e}");
+            }
+        }
+        if let Some(mode) = pending.config.previous_layout {
+            if let Err(e) = manager.set_layout_mode(mode) {
+                error!("Error restoring the previous layout mode: {e}");
+            }
+        }
+        match DisplayManager::get_monitor_info(backend).await {
+            Ok(display_info) => manager.set_monitor_info(display_info),
+            Err(e) => error!("Error getting output information after reverting: {e}"),
+        }
+        manager.publish_xwayland_scale().await;
+        if let Err(e) = manager.emit_monitors_changed().await {
+            error!("Error emitting MonitorsChanged: {e}");
+        }
+        if let Err(e) =
+            DisplayManager::emit_configuration_reverted(manager.get_serial(), &pending.config.profile_name).await
+        {
+            error!("Error emitting ConfigurationReverted: {e}");
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Backend, BackendError, BackendEvents, Output};
+    use crate::layout::LayoutMode;
+    use std::sync::Mutex as StdMutex;
+    use zbus::fdo;
+
+    /// Backend recording the commands it is given
+    #[derive(Default)]
+    struct RecordingBackend {
+        applied: StdMutex<Vec<String>>,
+    }
+
+    impl DisplayBackend for RecordingBackend {
+        async fn detect(&self) -> Backend {
+            Backend::Sway(None)
+        }
+
+        async fn outputs(&self) -> Result<Vec<Output>, BackendError> {
+            Ok(Vec::new())
+        }
+
+        async fn apply(&self, commands: &[String]) -> fdo::Result<()> {
+            self.applied.lock().unwrap().extend_from_slice(commands);
+            Ok(())
+        }
+
+        async fn subscribe(&self) -> Option<BackendEvents> {
+            None
+        }
+
+        async fn reconnect(&self) {}
+    }
+
+    /// Make a temporary configuration pending, without its timer
+    async fn make_pending(state: &RevertState) {
+        let config = PendingConfig {
+            profile_name: String::from("docked"),
+            profile_path: PathBuf::from("docked"),
+            profile: Vec::new(),
+            previous: vec![String::from("output DP-1 disable")],
+            previous_layout: Some(LayoutMode::Logical as u32),
+        };
+        let timer = tokio::spawn(async {});
+        *state.pending.lock().await = Some(PendingRevert { config, timer });
+    }
+
+    #[tokio::test]
+    async fn reverts_once_with_a_new_serial() {
+        let state = RevertState::default();
+        let manager = Mutex::new(DisplayManager::new().await);
+        let backend = RecordingBackend::default();
+        make_pending(&state).await;
+        let (first, second) = tokio::join!(state.fire(&manager, &backend), state.fire(&manager, &backend));
+        assert!(first != second);
+        assert_eq!(manager.lock().await.get_serial(), 1);
+        assert_eq!(*backend.applied.lock().unwrap(), ["output DP-1 disable"]);
+        assert!(!state.fire(&manager, &backend).await);
+        assert_eq!(manager.lock().await.get_serial(), 1);
+    }
+
+    #[tokio::test]
+    async fn reverts_the_layout_mode() {
+        // Not to touch the layout mode of the user running the tests
+        let state_home = std::env::temp_dir().join(format!("regolith-displayd-{}", std::process::id()));
+        std::env::set_var("XDG_STATE_HOME", &state_home);
+        let state = RevertState::default();
+        let mut manager = DisplayManager::new().await;
+        manager.properties.support_layout_change = Some(true);
+        manager.properties.layout = Some(LayoutMode::Physical as u32);
+        let manager = Mutex::new(manager);
+        make_pending(&state).await;
+        assert!(state.fire(&manager, &RecordingBackend::default()).await);
+        assert_eq!(manager.lock().await.properties.layout, Some(LayoutMode::Logical as u32));
+        assert_eq!(LayoutMode::load(), Some(LayoutMode::Logical));
+        let _ = std::fs::remove_dir_all(state_home);
+    }
+
+    #[tokio::test]
+    async fn confirming_without_a_pending_configuration() {
+        let state = RevertState::default();
+        assert!(state.confirm().await.is_none());
+        assert!(!state.cancel().await);
+    }
+
+    #[tokio::test]
+    async fn confirming_stops_the_revert() {
+        let state = RevertState::default();
+        let manager = Mutex::new(DisplayManager::new().await);
+        let backend = RecordingBackend::default();
+        make_pending(&state).await;
+        let (profile_name, _, _) = state.confirm().await.unwrap();
+        assert_eq!(profile_name, "docked");
+        assert!(!state.fire(&manager, &backend).await);
+        assert!(backend.applied.lock().unwrap().is_empty());
+        assert_eq!(manager.lock().await.get_serial(), 0);
+    }
+
+    #[tokio::test]
+    async fn confirming_while_the_timeout_fires() {
+        let state = RevertState::default();
+        let manager = Mutex::new(DisplayManager::new().await);
+        let backend = RecordingBackend::default();
+        make_pending(&state).await;
+        let (confirmed, reverted) = tokio::join!(state.confirm(), state.fire(&manager, &backend));
+        // Either the configuration is kept or it's reverted, never both
+        assert!(confirmed.is_some() != reverted);
+        assert_eq!(backend.applied.lock().unwrap().len(), usize::from(reverted));
+        assert!(state.confirm().await.is_none());
+    }
+}
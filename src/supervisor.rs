@@ -0,0 +1,155 @@
+//! Running kanshi as a child of the daemon, with `displayd.supervise_kanshi`.
+//!
+//! kanshi is started once the outputs have settled at startup rather than
+//! racing sway at session start, restarted when it exits and told to reload
+//! with SIGHUP instead of being replaced. Its failures to apply a profile are
+//! reported with `ProfileApplied` and retried, as the outputs are often still
+//! busy when the session starts.
+use crate::{get_kanshi_paths, kanshi_applies_profiles, DisplayManager};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use std::{
+    io,
+    path::Path,
+    process::{ExitStatus, Stdio},
+    sync::atomic::{AtomicI32, AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::Command,
+    sync::Notify,
+};
+
+/// Delay before restarting kanshi after it exited, doubled every time it
+/// exits again before running for [`STABLE_RUN`], up to [`MAX_RESTART_DELAY`]
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+/// Delay before the first retry of a profile kanshi failed to apply, doubled
+/// for every further failure, for up to [`MAX_RETRIES`] retries
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRIES: u32 = 6;
+
+/// Process id of the running kanshi, 0 if there is none
+static PID: AtomicI32 = AtomicI32::new(0);
+/// Failures to apply a profile since kanshi last applied one or was asked to
+/// reload by the daemon
+static FAILURES: AtomicU32 = AtomicU32::new(0);
+
+lazy_static! {
+    /// Signalled by the watch loop once the outputs settled after startup
+    static ref SETTLED: Notify = Notify::new();
+}
+
+/// Let kanshi start, see [`supervise`]
+pub fn outputs_settled() {
+    SETTLED.notify_one();
+}
+
+/// Ask the supervised kanshi to reread its config. Returns false if it isn't
+/// running, in which case it reads the config when it starts.
+pub fn reload() -> bool {
+    FAILURES.store(0, Ordering::Relaxed);
+    signal_reload()
+}
+
+fn signal_reload() -> bool {
+    let pid = PID.load(Ordering::Relaxed);
+    // SAFETY: `kill` has no memory safety requirements
+    pid > 0 && unsafe { libc::kill(pid, libc::SIGHUP) } == 0
+}
+
+/// Run kanshi with the daemon's config for as long as the daemon runs.
+/// Instances started by someone else are stopped first.
+pub async fn supervise() {
+    SETTLED.notified().await;
+    if !kanshi_applies_profiles() {
+        return;
+    }
+    if let Err(e) = Command::new("killall").arg("kanshi").status().await {
+        debug!("Cannot stop other kanshi instances: {e}");
+    }
+    let mut delay = RESTART_DELAY;
+    loop {
+        let config = match get_kanshi_paths().await {
+            Ok(paths) => paths.config,
+            Err(e) => {
+                warn!("Not running kanshi, cannot find its config: {e}");
+                return;
+            }
+        };
+        info!("Starting kanshi -c {}", config.display());
+        let started = Instant::now();
+        match run(&config).await {
+            Ok(status) => warn!("kanshi exited ({status})"),
+            Err(e) => warn!("Cannot run kanshi: {e}"),
+        }
+        PID.store(0, Ordering::Relaxed);
+        if started.elapsed() >= STABLE_RUN {
+            delay = RESTART_DELAY;
+        }
+        info!("Restarting kanshi in {}s", delay.as_secs());
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+    }
+}
+
+/// Run kanshi until it exits, following its log
+async fn run(config: &Path) -> io::Result<ExitStatus> {
+    let mut command = Command::new("kanshi");
+    command
+        .arg("-c")
+        .arg(config)
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    // SAFETY: only calls `prctl`, which is async-signal-safe. kanshi then goes
+    // away with the daemon, so an instance replacing it doesn't find two.
+    unsafe {
+        command.pre_exec(|| {
+            libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    PID.store(child.id().map_or(0, |id| id as i32), Ordering::Relaxed);
+    if let Some(stderr) = child.stderr.take() {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Some(line) = lines.next_line().await? {
+            follow(&line).await;
+        }
+    }
+    child.wait().await
+}
+
+/// Handle a line of kanshi's log, such as
+/// `failed to apply configuration for profile 'docked'` or
+/// `configuration for profile 'docked' applied`
+async fn follow(line: &str) {
+    if line.contains("configuration for profile") && line.ends_with("applied") {
+        FAILURES.store(0, Ordering::Relaxed);
+    }
+    if !line.contains("failed to apply") {
+        debug!("kanshi: {line}");
+        return;
+    }
+    let profile = line.split('\'').nth(1).unwrap_or_default();
+    if let Err(e) = DisplayManager::emit_profile_applied(profile, false, line).await {
+        warn!("Cannot emit ProfileApplied: {e}");
+    }
+    let failures = FAILURES.fetch_add(1, Ordering::Relaxed) + 1;
+    if failures > MAX_RETRIES {
+        warn!("kanshi failed to apply {profile} {failures} times, giving up");
+        return;
+    }
+    let delay = RETRY_DELAY * 2u32.pow(failures - 1);
+    info!("Retrying {profile} in {}s", delay.as_secs());
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        // A reload asked for meanwhile already retries
+        if FAILURES.load(Ordering::Relaxed) == failures {
+            signal_reload();
+        }
+    });
+}
@@ -0,0 +1,27 @@
+//! Outputs and modes shared by the unit tests
+use crate::backend::{Output, OutputMode};
+
+/// A `width`x`height` mode at `refresh` mHz
+pub fn mode(width: i32, height: i32, refresh: i32, preferred: bool) -> OutputMode {
+    OutputMode {
+        width,
+        height,
+        refresh,
+        preferred,
+    }
+}
+
+/// An enabled output on `name` at (`x`, 0) and scale 1, in the last of
+/// `modes`
+pub fn output(name: &str, modes: Vec<OutputMode>, x: i32) -> Output {
+    Output {
+        name: String::from(name),
+        active: true,
+        power: true,
+        current_mode: modes.last().copied(),
+        rect: (x, 0, modes.last().map_or(0, |mode| mode.width), modes.last().map_or(0, |mode| mode.height)),
+        scale: Some(1.0),
+        modes,
+        ..Output::default()
+    }
+}
@@ -0,0 +1,140 @@
+//! Turning single outputs off and on with `SetOutputEnabled`.
+//!
+//! The settings an output had when it was turned off are kept, so turning it
+//! back on restores its mode, position, scale and transform instead of sway's
+//! defaults. They are keyed by the output description (`Make Model Serial`,
+//! read from the EDID), so they follow the monitor to another connector, or by
+//! the connector for identical monitors sharing a description, see
+//! [`Monitor::kanshi_name`].
+use crate::{
+    lock::{Mutex, Rank, Ranked},
+    monitor::{LogicalMonitor, Monitor, MonitorTransform},
+};
+use std::{collections::HashMap, sync::Arc};
+
+/// Settings of an output at the moment it was turned off
+#[derive(Debug, Clone, PartialEq)]
+struct OutputSettings {
+    mode: String,
+    position: (i32, i32),
+    scale: f64,
+    /// Transform as reported to clients, see [`MonitorTransform`]
+    transform: u32,
+}
+
+#[derive(Clone, Default)]
+pub struct OutputMemory {
+    settings: Arc<Mutex<HashMap<String, OutputSettings>>>,
+}
+
+impl Ranked for HashMap<String, OutputSettings> {
+    const RANK: Rank = Rank::Outputs;
+}
+
+impl OutputMemory {
+    /// Remember the settings of `monitor`, one of `monitors`, shown as
+    /// `logical_monitor`, and return the sway command turning it off
+    pub async fn disable(&self, monitor: &Monitor, monitors: &[Monitor], logical_monitor: &LogicalMonitor) -> String {
+        if let Some(mode) = monitor.current_mode() {
+            let settings = OutputSettings {
+                mode: mode.sway_id().unwrap_or(mode.get_modestr()).to_string(),
+                position: logical_monitor.position(),
+                scale: logical_monitor.scale(),
+                transform: logical_monitor.transform(),
+            };
+            self.settings.lock().await.insert(monitor.kanshi_name(monitors), settings);
+        }
+        format!("output {} disable", monitor.get_connector())
+    }
+
+    /// The sway command turning `monitor`, one of `monitors`, back on with the
+    /// settings it had when it was turned off, or its preferred mode if they
+    /// aren't known
+    pub async fn enable(&self, monitor: &Monitor, monitors: &[Monitor]) -> String {
+        let connector = monitor.get_connector();
+        match self.settings.lock().await.get(&monitor.kanshi_name(monitors)) {
+            Some(settings) => format!(
+                "output {connector} enable mode {} position {} {} scale {} transform {}",
+                settings.mode,
+                settings.position.0,
+                settings.position.1,
+                settings.scale,
+                MonitorTransform::from_u32(settings.transform)
+                    .unwrap_or(MonitorTransform::Normal)
+                    .to_sway()
+            ),
+            None => match monitor.preferred_mode() {
+                Some(mode) => format!(
+                    "output {connector} enable mode {}",
+                    mode.sway_id().unwrap_or(mode.get_modestr())
+                ),
+                None => format!("output {connector} enable"),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Output;
+    use crate::testing::{self, mode};
+
+    fn output(transform: &str) -> Output {
+        let modes = vec![mode(2560, 1440, 59951, true), mode(1920, 1080, 60000, false)];
+        Output {
+            make: String::from("Dell Inc."),
+            model: String::from("DELL U2720Q"),
+            serial: String::from("1234"),
+            rect: (1920, 0, 1280, 720),
+            scale: Some(1.5),
+            transform: Some(String::from(transform)),
+            ..testing::output("DP-1", modes, 1920)
+        }
+    }
+
+    #[tokio::test]
+    async fn restores_the_settings_an_output_was_turned_off_with() {
+        let memory = OutputMemory::default();
+        // sway reports transforms counter-clockwise, as wl_output does, but its
+        // transform command turns clockwise
+        for (transform, command) in [("normal", "normal"), ("90", "270"), ("flipped-270", "flipped-90")] {
+            let output = output(transform);
+            let monitors = vec![Monitor::new(&output)];
+            let logical_monitor = LogicalMonitor::new(&output);
+            let disable = memory.disable(&monitors[0], &monitors, &logical_monitor).await;
+            assert_eq!(disable, "output DP-1 disable");
+            let enable = memory.enable(&monitors[0], &monitors).await;
+            assert_eq!(
+                enable,
+                format!("output DP-1 enable mode 1920x1080@60.000Hz position 1920 0 scale 1.5 transform {command}")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn enables_unknown_outputs_with_their_preferred_mode() {
+        let output = Output {
+            active: false,
+            current_mode: None,
+            ..output("normal")
+        };
+        let monitors = vec![Monitor::new(&output)];
+        let enable = OutputMemory::default().enable(&monitors[0], &monitors).await;
+        assert_eq!(enable, "output DP-1 enable mode 2560x1440@59.951Hz");
+    }
+
+    #[tokio::test]
+    async fn keeps_nothing_for_outputs_without_a_mode() {
+        let memory = OutputMemory::default();
+        let output = Output {
+            current_mode: None,
+            ..output("normal")
+        };
+        let monitors = vec![Monitor::new(&output)];
+        let logical_monitor = LogicalMonitor::new(&output);
+        memory.disable(&monitors[0], &monitors, &logical_monitor).await;
+        let enable = memory.enable(&monitors[0], &monitors).await;
+        assert_eq!(enable, "output DP-1 enable mode 2560x1440@59.951Hz");
+    }
+}
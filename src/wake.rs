@@ -0,0 +1,45 @@
+//! Detecting outputs waking up from DPMS, so the saved profile can be
+//! reapplied when sway resets their mode or scale on wake
+use crate::backend::Output;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Shortest time between two reapplies, in case outputs flap on and off
+pub const MIN_REAPPLY_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+pub struct WakeTracker {
+    powered: HashMap<String, bool>,
+    last_reapply: Option<Instant>,
+}
+
+impl WakeTracker {
+    /// Record the power state of `outputs`, returning the connectors that
+    /// turned on since the previous call. Newly connected outputs don't count.
+    pub fn update(&mut self, outputs: &[Output]) -> Vec<String> {
+        let mut woken = Vec::new();
+        let mut powered = HashMap::new();
+        for output in outputs {
+            if output.power && self.powered.get(&output.name) == Some(&false) {
+                woken.push(output.name.clone());
+            }
+            powered.insert(output.name.clone(), output.power);
+        }
+        self.powered = powered;
+        woken
+    }
+
+    /// Whether a reapply is allowed now, starting a new rate limit window if so
+    pub fn should_reapply(&mut self) -> bool {
+        let now = Instant::now();
+        match self.last_reapply {
+            Some(last) if now.duration_since(last) < MIN_REAPPLY_INTERVAL => false,
+            _ => {
+                self.last_reapply = Some(now);
+                true
+            }
+        }
+    }
+}
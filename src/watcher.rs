@@ -0,0 +1,174 @@
+//! Watching kanshi's profiles directory and config with inotify, so profiles
+//! edited by hand are picked up without `Reload`.
+//!
+//! Changed files are parsed first, and kanshi is only reloaded if they all
+//! parse, as it would reject the whole config otherwise. Changes the daemon
+//! made itself are followed by a reload of kanshi already, see [`reloaded`],
+//! and left alone.
+use crate::{get_kanshi_paths, kanshi, lock::Mutex, profiles, reload_kanshi, DisplayManager};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use std::{
+    collections::BTreeSet,
+    ffi::{CString, OsStr},
+    fs, io, mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
+use tokio::io::unix::AsyncFd;
+
+/// Changes are handled once no more have come for this long, as editors
+/// often write a file in several steps
+const SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+const EVENTS: u32 = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_MOVED_FROM | libc::IN_DELETE;
+
+lazy_static! {
+    /// When kanshi was last reloaded by the daemon
+    static ref LAST_RELOAD: StdMutex<Option<Instant>> = StdMutex::new(None);
+}
+
+/// Record that kanshi was told to reread its config, which covers the
+/// changes made before
+pub fn reloaded() {
+    *LAST_RELOAD.lock().unwrap() = Some(Instant::now());
+}
+
+/// Follow the changes to the profiles and the config for as long as the
+/// daemon runs
+pub async fn watch(manager: Arc<Mutex<DisplayManager>>) -> io::Result<()> {
+    let paths = get_kanshi_paths()
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::NotFound, e.to_string()))?;
+    fs::create_dir_all(&paths.profiles)?;
+    // SAFETY: plain system call, the descriptor is owned from here on
+    let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = AsyncFd::new(unsafe { OwnedFd::from_raw_fd(fd) })?;
+    let profiles_watch = add_watch(&fd, &paths.profiles)?;
+    // The directory is watched rather than the file, which editors replace
+    let config_dir = paths.config.parent().unwrap_or(Path::new("/"));
+    let config_name = paths.config.file_name().map(|name| name.to_os_string());
+    let config_watch = add_watch(&fd, config_dir)?;
+    info!("Watching {} for changes", paths.profiles.display());
+    loop {
+        let mut changed = BTreeSet::new();
+        let mut first = None;
+        loop {
+            let events = if first.is_none() {
+                next_events(&fd).await?
+            } else {
+                match tokio::time::timeout(SETTLE_DELAY, next_events(&fd)).await {
+                    Ok(events) => events?,
+                    Err(_) => break,
+                }
+            };
+            for (watch, name) in events {
+                let path = if watch == profiles_watch && !is_temporary(&name) {
+                    paths.profiles.join(&name)
+                } else if watch == config_watch && config_name.as_deref() == Some(OsStr::new(&name)) {
+                    paths.config.clone()
+                } else {
+                    continue;
+                };
+                first.get_or_insert_with(Instant::now);
+                changed.insert(path);
+            }
+        }
+        let Some(first) = first else {
+            continue;
+        };
+        handle(&manager, &changed, first).await;
+    }
+}
+
+/// Update the daemon and kanshi after `changed` were edited, the first of
+/// them at `first`
+async fn handle(manager: &Mutex<DisplayManager>, changed: &BTreeSet<PathBuf>, first: Instant) {
+    profiles::load_existing().await;
+    // Changes are only noticed once read, possibly after the reload following them
+    if LAST_RELOAD.lock().unwrap().is_some_and(|reload| reload + SETTLE_DELAY >= first) {
+        debug!("kanshi was reloaded since the profiles changed");
+        return;
+    }
+    let mut valid = true;
+    for path in changed {
+        let Ok(text) = fs::read_to_string(path) else {
+            // Removed
+            continue;
+        };
+        if let Err(e) = kanshi::parse(&text) {
+            warn!("Not reloading kanshi, {} doesn't parse: {e}", path.display());
+            valid = false;
+        }
+    }
+    if !valid {
+        return;
+    }
+    let names: Vec<String> = changed.iter().map(|path| path.display().to_string()).collect();
+    info!("{} changed, reloading kanshi", names.join(", "));
+    if let Err(e) = reload_kanshi().await {
+        warn!("Error reloading kanshi: {e}");
+    }
+    if let Err(e) = manager.lock().await.emit_monitors_changed().await {
+        warn!("Cannot emit MonitorsChanged: {e}");
+    }
+}
+
+fn add_watch(fd: &AsyncFd<OwnedFd>, dir: &Path) -> io::Result<i32> {
+    let dir = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `dir` is a valid C string for the duration of the call
+    let watch = unsafe { libc::inotify_add_watch(fd.as_raw_fd(), dir.as_ptr(), EVENTS) };
+    if watch < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(watch)
+}
+
+/// Editor swap and backup files, which kanshi's include glob may match but
+/// which are no profiles anyone finished editing
+pub(crate) fn is_temporary(name: &str) -> bool {
+    name.starts_with('.') || name.ends_with('~')
+}
+
+/// Wait for inotify events and return them as watch descriptors and file names
+async fn next_events(fd: &AsyncFd<OwnedFd>) -> io::Result<Vec<(i32, String)>> {
+    loop {
+        let mut guard = fd.readable().await?;
+        match guard.try_io(|fd| read_events(fd.get_ref())) {
+            Ok(result) => return result,
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+fn read_events(fd: &OwnedFd) -> io::Result<Vec<(i32, String)>> {
+    const HEADER: usize = mem::size_of::<libc::inotify_event>();
+    let mut buf = [0u8; 4096];
+    // SAFETY: `buf` is valid for writes of its length
+    let read = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+    if read < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let buf = &buf[..read as usize];
+    let mut events = Vec::new();
+    let mut offset = 0;
+    while offset + HEADER <= buf.len() {
+        let field = |at: usize| <[u8; 4]>::try_from(&buf[offset + at..offset + at + 4]).unwrap();
+        let watch = i32::from_ne_bytes(field(0));
+        let len = u32::from_ne_bytes(field(12)) as usize;
+        let name = buf.get(offset + HEADER..offset + HEADER + len).unwrap_or_default();
+        let name = name.split(|&b| b == 0).next().unwrap_or_default();
+        events.push((watch, String::from_utf8_lossy(name).into_owned()));
+        offset += HEADER + len;
+    }
+    Ok(events)
+}
@@ -0,0 +1,880 @@
+//! Any wlroots compositor, such as river, which has no IPC for outputs, or
+//! sway without its IPC, driven through the wlr output-management and
+//! output-power-management protocols.
+//!
+//! Like sway's IPC connection, the Wayland connection is owned by a dedicated
+//! thread that answers the requests sent to it. The sway output commands the
+//! daemon issues (see [`DisplayBackend`]) are translated into a single output
+//! configuration covering every head, as the protocol requires, so a batch of
+//! commands is applied or rejected as a whole.
+use crate::backend::{
+    Backend, BackendError, BackendEvent, BackendEvents, DisplayBackend, ModeRequest, Output,
+    OutputChange, OutputCommand, OutputMode, MAX_RECONNECT_DELAY, RECONNECT_DELAY,
+};
+use futures_util::{stream, StreamExt};
+use log::{debug, info, warn};
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Mutex,
+    },
+    thread,
+};
+use tokio::sync::{mpsc as async_mpsc, oneshot};
+use wayland_client::{
+    backend::ObjectId,
+    delegate_noop, event_created_child,
+    protocol::{
+        wl_output::{self, Transform, WlOutput},
+        wl_registry::WlRegistry,
+    },
+    Connection, Dispatch, EventQueue, Proxy, QueueHandle, WEnum,
+};
+use wayland_protocols_wlr::{
+    output_management::v1::client::{
+        zwlr_output_configuration_head_v1::ZwlrOutputConfigurationHeadV1,
+        zwlr_output_configuration_v1::{self, ZwlrOutputConfigurationV1},
+        zwlr_output_head_v1::{self, AdaptiveSyncState, ZwlrOutputHeadV1},
+        zwlr_output_manager_v1::{self, ZwlrOutputManagerV1},
+        zwlr_output_mode_v1::{self, ZwlrOutputModeV1},
+    },
+    output_power_management::v1::client::{
+        zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1,
+        zwlr_output_power_v1::{self, ZwlrOutputPowerV1},
+    },
+};
+
+/// sway's names for the transforms
+const TRANSFORMS: [(&str, Transform); 8] = [
+    ("normal", Transform::Normal),
+    ("90", Transform::_90),
+    ("180", Transform::_180),
+    ("270", Transform::_270),
+    ("flipped", Transform::Flipped),
+    ("flipped-90", Transform::Flipped90),
+    ("flipped-180", Transform::Flipped180),
+    ("flipped-270", Transform::Flipped270),
+];
+
+/// Largest difference, in mHz, between a requested refresh rate and the mode
+/// picked for it
+const REFRESH_TOLERANCE: i32 = 500;
+
+enum Request {
+    Outputs(oneshot::Sender<Result<Vec<Output>, String>>),
+    Apply(Vec<String>, oneshot::Sender<Result<(), String>>),
+}
+
+pub struct WlrBackend {
+    requests: Mutex<Sender<Request>>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ModeInfo {
+    width: i32,
+    height: i32,
+    /// mHz, 0 if unknown
+    refresh: i32,
+    preferred: bool,
+}
+
+struct Head {
+    proxy: ZwlrOutputHeadV1,
+    name: String,
+    make: String,
+    model: String,
+    serial: String,
+    enabled: bool,
+    modes: Vec<ZwlrOutputModeV1>,
+    current_mode: Option<ZwlrOutputModeV1>,
+    position: (i32, i32),
+    transform: Transform,
+    scale: f64,
+    /// `None` before version 4 of the protocol
+    adaptive_sync: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Default)]
+struct State {
+    manager: Option<ZwlrOutputManagerV1>,
+    power_manager: Option<ZwlrOutputPowerManagerV1>,
+    heads: Vec<Head>,
+    modes: HashMap<ObjectId, ModeInfo>,
+    /// Serial of the last complete description of the heads
+    serial: Option<u32>,
+    /// `wl_output`s by registry name, with their names once known
+    outputs: HashMap<u32, (WlOutput, Option<String>)>,
+    /// Power controls by connector, with the last mode they reported
+    powers: HashMap<String, (ZwlrOutputPowerV1, Option<bool>, bool)>,
+    outcome: Option<Outcome>,
+    /// A `done` event came since this was last reset
+    changed: bool,
+}
+
+/// The mode a head is configured with: one of its modes, or a custom mode
+/// of a width, height and refresh rate in mHz
+#[derive(Debug, PartialEq)]
+enum PlannedMode<M = ZwlrOutputModeV1> {
+    Mode(M),
+    Custom(i32, i32, i32),
+}
+
+impl WlrBackend {
+    /// Connect to the compositor at `$WAYLAND_DISPLAY`, which has to offer
+    /// wlr-output-management
+    pub async fn connect() -> Result<WlrBackend, BackendError> {
+        let (ready, started) = oneshot::channel();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || run(receiver, ready));
+        started
+            .await
+            .map_err(|_| BackendError::from(String::from("Output management worker stopped")))?
+            .map_err(BackendError::from)?;
+        Ok(WlrBackend {
+            requests: Mutex::new(sender),
+        })
+    }
+
+    fn send(&self, request: Request) -> Result<(), BackendError> {
+        self.requests
+            .lock()
+            .unwrap()
+            .send(request)
+            .map_err(|_| BackendError::from(String::from("Lost the connection to the compositor")))
+    }
+}
+
+impl DisplayBackend for WlrBackend {
+    async fn detect(&self) -> Backend {
+        Backend::Wlr(std::env::var("XDG_CURRENT_DESKTOP").ok().filter(|desktop| !desktop.is_empty()))
+    }
+
+    async fn outputs(&self) -> Result<Vec<Output>, BackendError> {
+        let (reply, response) = oneshot::channel();
+        self.send(Request::Outputs(reply))?;
+        response
+            .await
+            .map_err(|_| BackendError::from(String::from("Lost the connection to the compositor")))?
+            .map_err(BackendError::from)
+    }
+
+    async fn apply(&self, commands: &[String]) -> zbus::fdo::Result<()> {
+        let (reply, response) = oneshot::channel();
+        self.send(Request::Apply(commands.to_vec(), reply))
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+        response
+            .await
+            .map_err(|_| zbus::fdo::Error::Failed(String::from("Lost the connection to the compositor")))?
+            .map_err(zbus::fdo::Error::Failed)
+    }
+
+    /// A `done` event of the output manager for every change
+    async fn subscribe(&self) -> Option<BackendEvents> {
+        let (sender, receiver) = async_mpsc::unbounded_channel();
+        let (ready, started) = oneshot::channel();
+        thread::spawn(move || follow(sender, ready));
+        match started.await {
+            Ok(Ok(())) => Some(
+                stream::unfold(receiver, |mut receiver| async move {
+                    receiver.recv().await.map(|event| (event, receiver))
+                })
+                .boxed(),
+            ),
+            Ok(Err(e)) => {
+                warn!("Cannot follow the outputs, polling instead: {e}");
+                None
+            }
+            Err(_) => None,
+        }
+    }
+
+    async fn reconnect(&self) {
+        let mut delay = RECONNECT_DELAY;
+        loop {
+            tokio::time::sleep(delay).await;
+            match WlrBackend::connect().await {
+                Ok(backend) => {
+                    info!("Reconnected to the compositor");
+                    *self.requests.lock().unwrap() = backend.requests.into_inner().unwrap();
+                    return;
+                }
+                Err(e) => {
+                    debug!("Cannot reconnect to the compositor yet: {e}");
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                }
+            }
+        }
+    }
+}
+
+/// Connect and fetch the heads, the start of both threads
+fn setup() -> Result<(EventQueue<State>, State), String> {
+    let connection = Connection::connect_to_env().map_err(|e| format!("Cannot connect to the compositor: {e}"))?;
+    let mut queue = connection.new_event_queue();
+    let qh = queue.handle();
+    connection.display().get_registry(&qh, ());
+    let mut state = State::default();
+    // The globals, then the heads and their modes
+    queue.roundtrip(&mut state).map_err(|e| e.to_string())?;
+    if state.manager.is_none() {
+        return Err(String::from("The compositor doesn't support wlr-output-management"));
+    }
+    queue.roundtrip(&mut state).map_err(|e| e.to_string())?;
+    Ok((queue, state))
+}
+
+fn run(receiver: Receiver<Request>, ready: oneshot::Sender<Result<(), String>>) {
+    let (mut queue, mut state) = match setup() {
+        Ok(setup) => setup,
+        Err(e) => {
+            let _ = ready.send(Err(e));
+            return;
+        }
+    };
+    let _ = ready.send(Ok(()));
+    let qh = queue.handle();
+    for request in receiver {
+        // Catch up on hotplugs and changes made by others
+        let synced = queue.roundtrip(&mut state).map(|_| ()).map_err(|e| e.to_string());
+        match request {
+            Request::Outputs(reply) => {
+                let _ = reply.send(synced.map(|()| outputs(&state)));
+            }
+            Request::Apply(commands, reply) => {
+                let result = synced.and_then(|()| apply(&mut queue, &qh, &mut state, &commands));
+                let _ = reply.send(result);
+            }
+        }
+    }
+}
+
+/// Send an event for every change of the outputs until the receiver goes away
+fn follow(
+    sender: async_mpsc::UnboundedSender<Result<BackendEvent, BackendError>>,
+    ready: oneshot::Sender<Result<(), String>>,
+) {
+    let (mut queue, mut state) = match setup() {
+        Ok(setup) => setup,
+        Err(e) => {
+            let _ = ready.send(Err(e));
+            return;
+        }
+    };
+    let _ = ready.send(Ok(()));
+    state.changed = false;
+    loop {
+        if let Err(e) = queue.blocking_dispatch(&mut state) {
+            let _ = sender.send(Err(BackendError::from(e.to_string())));
+            return;
+        }
+        if std::mem::take(&mut state.changed) && sender.send(Ok(BackendEvent::OutputsChanged)).is_err() {
+            return;
+        }
+    }
+}
+
+fn transform_name(transform: Transform) -> &'static str {
+    TRANSFORMS
+        .iter()
+        .find(|(_, other)| *other == transform)
+        .map_or("normal", |(name, _)| name)
+}
+
+/// The heads as the daemon describes outputs
+fn outputs(state: &State) -> Vec<Output> {
+    let mode = |mode: &ZwlrOutputModeV1| {
+        let info = state.modes.get(&mode.id()).cloned().unwrap_or_default();
+        OutputMode {
+            width: info.width,
+            height: info.height,
+            refresh: info.refresh,
+            preferred: info.preferred,
+        }
+    };
+    let mut outputs: Vec<Output> = state
+        .heads
+        .iter()
+        .map(|head| {
+            let current_mode = head.current_mode.as_ref().filter(|_| head.enabled).map(mode);
+            let rect = match &current_mode {
+                Some(current) => {
+                    let (width, height) = match head.transform {
+                        Transform::_90 | Transform::_270 | Transform::Flipped90 | Transform::Flipped270 => {
+                            (current.height, current.width)
+                        }
+                        _ => (current.width, current.height),
+                    };
+                    (
+                        head.position.0,
+                        head.position.1,
+                        (width as f64 / head.scale).round() as i32,
+                        (height as f64 / head.scale).round() as i32,
+                    )
+                }
+                None => (0, 0, 0, 0),
+            };
+            Output {
+                name: head.name.clone(),
+                make: head.make.clone(),
+                model: head.model.clone(),
+                serial: head.serial.clone(),
+                active: head.enabled,
+                power: state
+                    .powers
+                    .get(&head.name)
+                    .and_then(|(_, on, _)| *on)
+                    .unwrap_or(true),
+                primary: false,
+                non_desktop: false,
+                modes: head.modes.iter().map(mode).collect(),
+                current_mode,
+                rect,
+                scale: head.enabled.then_some(head.scale),
+                transform: head.enabled.then(|| transform_name(head.transform).to_string()),
+                adaptive_sync: head.adaptive_sync,
+                max_render_time: None,
+                allow_tearing: None,
+            }
+        })
+        .collect();
+    outputs.sort_by(|a, b| a.name.cmp(&b.name));
+    outputs
+}
+
+/// Changes and power requests of `command`, for the heads it names
+fn parse_command(
+    state: &State,
+    command: &str,
+    changes: &mut HashMap<usize, OutputChange>,
+    power: &mut Vec<(String, bool)>,
+) -> Result<(), String> {
+    let Some(parsed) = OutputCommand::parse(command)? else {
+        return Ok(());
+    };
+    let heads: Vec<usize> = state
+        .heads
+        .iter()
+        .enumerate()
+        .filter(|(_, head)| parsed.selects(&head.name, &format!("{} {} {}", head.make, head.model, head.serial)))
+        .map(|(index, _)| index)
+        .collect();
+    if heads.is_empty() {
+        // sway keeps settings for outputs that aren't connected
+        debug!("No output matches {}, ignoring '{command}'", parsed.criteria);
+        return Ok(());
+    }
+    if let Some(on) = parsed.change.power {
+        power.extend(heads.iter().map(|&index| (state.heads[index].name.clone(), on)));
+    }
+    for index in heads {
+        changes.entry(index).or_default().merge(&parsed.change);
+    }
+    Ok(())
+}
+
+/// The protocol's transform for one of sway's
+fn transform(name: &str) -> Transform {
+    TRANSFORMS
+        .iter()
+        .find(|(other, _)| *other == name)
+        .map_or(Transform::Normal, |(_, transform)| *transform)
+}
+
+/// The mode `head` gets, see [`pick_mode`]
+fn plan_mode(state: &State, head: &Head, request: Option<ModeRequest>) -> Result<PlannedMode, String> {
+    let modes: Vec<ModeInfo> = head
+        .modes
+        .iter()
+        .map(|mode| state.modes.get(&mode.id()).cloned().unwrap_or_default())
+        .collect();
+    let current = head
+        .current_mode
+        .as_ref()
+        .and_then(|current| head.modes.iter().position(|mode| mode == current));
+    Ok(match pick_mode(&head.name, &modes, current, request)? {
+        PlannedMode::Mode(index) => PlannedMode::Mode(head.modes[index].clone()),
+        PlannedMode::Custom(width, height, refresh) => PlannedMode::Custom(width, height, refresh),
+    })
+}
+
+/// The index into `modes` of the mode the head `name` gets, `current` being
+/// that of its current mode: the one asked for, its current one, or its
+/// preferred one when it is turned on. A custom mode is planned if asked for,
+/// or if the head lists no modes.
+fn pick_mode(
+    name: &str,
+    modes: &[ModeInfo],
+    current: Option<usize>,
+    request: Option<ModeRequest>,
+) -> Result<PlannedMode<usize>, String> {
+    let Some(request) = request else {
+        return current
+            .or_else(|| modes.iter().position(|mode| mode.preferred))
+            .or_else(|| (!modes.is_empty()).then_some(0))
+            .map(PlannedMode::Mode)
+            .ok_or(format!("{name} has no modes"));
+    };
+    let sized = modes
+        .iter()
+        .enumerate()
+        .filter(|(_, mode)| mode.width == request.width && mode.height == request.height);
+    let found = match request.refresh {
+        Some(refresh) => sized
+            .filter(|(_, mode)| (mode.refresh - refresh).abs() <= REFRESH_TOLERANCE)
+            .min_by_key(|(_, mode)| (mode.refresh - refresh).abs()),
+        None => {
+            let sized: Vec<_> = sized.collect();
+            sized
+                .iter()
+                .find(|(_, mode)| mode.preferred)
+                .or_else(|| sized.iter().max_by_key(|(_, mode)| mode.refresh))
+                .copied()
+        }
+    };
+    match found {
+        Some((index, _)) if !request.custom => Ok(PlannedMode::Mode(index)),
+        _ if request.custom || modes.is_empty() => Ok(PlannedMode::Custom(
+            request.width,
+            request.height,
+            request.refresh.unwrap_or(0),
+        )),
+        _ => Err(format!("{name} has no mode {}x{}", request.width, request.height)),
+    }
+}
+
+fn apply(
+    queue: &mut EventQueue<State>,
+    qh: &QueueHandle<State>,
+    state: &mut State,
+    commands: &[String],
+) -> Result<(), String> {
+    let mut changes = HashMap::new();
+    let mut power = Vec::new();
+    for command in commands {
+        debug!("Running output command: {command}");
+        parse_command(state, command, &mut changes, &mut power)
+            .map_err(|e| format!("Cannot run '{command}' over wlr-output-management: {e}"))?;
+    }
+    if !changes.is_empty() {
+        configure(queue, qh, state, &changes)?;
+    }
+    for (connector, on) in power {
+        set_power(queue, qh, state, &connector, on)?;
+    }
+    Ok(())
+}
+
+/// Apply `changes` to the heads they are for, and the current state to the
+/// others. The configuration is retried once if the compositor cancels it because
+/// the heads changed meanwhile.
+fn configure(
+    queue: &mut EventQueue<State>,
+    qh: &QueueHandle<State>,
+    state: &mut State,
+    changes: &HashMap<usize, OutputChange>,
+) -> Result<(), String> {
+    let manager = state.manager.clone().ok_or("No output manager")?;
+    for _ in 0..2 {
+        let serial = state.serial.ok_or("The compositor hasn't described its outputs")?;
+        let mut plans = Vec::new();
+        for (index, head) in state.heads.iter().enumerate() {
+            let change = changes.get(&index).cloned().unwrap_or_default();
+            if !change.enabled.unwrap_or(head.enabled) {
+                plans.push((head.proxy.clone(), None));
+                continue;
+            }
+            if change.adaptive_sync.is_some() && manager.version() < 4 {
+                return Err(String::from("The compositor doesn't support setting adaptive sync"));
+            }
+            let mode = plan_mode(state, head, change.mode)?;
+            plans.push((head.proxy.clone(), Some((mode, change, head))));
+        }
+        let configuration = manager.create_configuration(serial, qh, ());
+        for (proxy, plan) in plans {
+            let Some((mode, change, head)) = plan else {
+                configuration.disable_head(&proxy);
+                continue;
+            };
+            let configured = configuration.enable_head(&proxy, qh, ());
+            match mode {
+                PlannedMode::Mode(mode) => configured.set_mode(&mode),
+                PlannedMode::Custom(width, height, refresh) => configured.set_custom_mode(width, height, refresh),
+            }
+            let (x, y) = change.position.unwrap_or(head.position);
+            configured.set_position(x, y);
+            configured.set_transform(change.transform.map_or(head.transform, transform));
+            configured.set_scale(change.scale.unwrap_or(head.scale));
+            if let Some(on) = change.adaptive_sync {
+                configured.set_adaptive_sync(if on {
+                    AdaptiveSyncState::Enabled
+                } else {
+                    AdaptiveSyncState::Disabled
+                });
+            }
+        }
+        configuration.apply();
+        state.outcome = None;
+        while state.outcome.is_none() {
+            queue.blocking_dispatch(state).map_err(|e| e.to_string())?;
+        }
+        configuration.destroy();
+        match state.outcome {
+            Some(Outcome::Succeeded) => {
+                // The new state of the heads
+                queue.roundtrip(state).map_err(|e| e.to_string())?;
+                return Ok(());
+            }
+            Some(Outcome::Failed) => return Err(String::from("The compositor rejected the output configuration")),
+            _ => {
+                debug!("The compositor cancelled the output configuration, retrying");
+                queue.roundtrip(state).map_err(|e| e.to_string())?;
+            }
+        }
+    }
+    Err(String::from("The outputs kept changing while configuring them"))
+}
+
+fn set_power(
+    queue: &mut EventQueue<State>,
+    qh: &QueueHandle<State>,
+    state: &mut State,
+    connector: &str,
+    on: bool,
+) -> Result<(), String> {
+    let manager = state
+        .power_manager
+        .clone()
+        .ok_or("The compositor doesn't support wlr-output-power-management")?;
+    if !state.powers.contains_key(connector) {
+        let output = state
+            .outputs
+            .values()
+            .find(|(_, name)| name.as_deref() == Some(connector))
+            .map(|(output, _)| output.clone())
+            .ok_or(format!("No wayland output named {connector}"))?;
+        let control = manager.get_output_power(&output, qh, connector.to_string());
+        state.powers.insert(connector.to_string(), (control, None, false));
+    }
+    let (control, _, failed) = state.powers.get_mut(connector).ok_or("Power control disappeared")?;
+    *failed = false;
+    control.set_mode(if on {
+        zwlr_output_power_v1::Mode::On
+    } else {
+        zwlr_output_power_v1::Mode::Off
+    });
+    queue.roundtrip(state).map_err(|e| e.to_string())?;
+    match state.powers.get(connector) {
+        Some((_, _, true)) => {
+            if let Some((control, _, _)) = state.powers.remove(connector) {
+                control.destroy();
+            }
+            Err(format!("Cannot change the power of {connector}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+impl Dispatch<WlRegistry, ()> for State {
+    fn event(
+        state: &mut Self,
+        registry: &WlRegistry,
+        event: <WlRegistry as Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        use wayland_client::protocol::wl_registry::Event;
+        match event {
+            Event::Global {
+                name,
+                interface,
+                version,
+            } => match interface.as_str() {
+                "wl_output" if version >= 4 => {
+                    let output = registry.bind::<WlOutput, _, _>(name, 4, qh, name);
+                    state.outputs.insert(name, (output, None));
+                }
+                "zwlr_output_manager_v1" => {
+                    state.manager = Some(registry.bind(name, version.min(4), qh, ()));
+                }
+                "zwlr_output_power_manager_v1" => {
+                    state.power_manager = Some(registry.bind(name, 1, qh, ()));
+                }
+                _ => {}
+            },
+            Event::GlobalRemove { name } => {
+                if let Some((_, Some(connector))) = state.outputs.remove(&name) {
+                    if let Some((control, _, _)) = state.powers.remove(&connector) {
+                        control.destroy();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<WlOutput, u32> for State {
+    fn event(
+        state: &mut Self,
+        _: &WlOutput,
+        event: wl_output::Event,
+        id: &u32,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let wl_output::Event::Name { name } = event {
+            if let Some((_, output_name)) = state.outputs.get_mut(id) {
+                *output_name = Some(name);
+            }
+        }
+    }
+}
+
+impl Dispatch<ZwlrOutputManagerV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _: &ZwlrOutputManagerV1,
+        event: zwlr_output_manager_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_output_manager_v1::Event::Head { head } => state.heads.push(Head {
+                proxy: head,
+                name: String::new(),
+                make: String::new(),
+                model: String::new(),
+                serial: String::new(),
+                enabled: false,
+                modes: Vec::new(),
+                current_mode: None,
+                position: (0, 0),
+                transform: Transform::Normal,
+                scale: 1.0,
+                adaptive_sync: None,
+            }),
+            zwlr_output_manager_v1::Event::Done { serial } => {
+                state.serial = Some(serial);
+                state.changed = true;
+            }
+            zwlr_output_manager_v1::Event::Finished => {
+                warn!("The compositor stopped managing outputs");
+                state.manager = None;
+            }
+            _ => {}
+        }
+    }
+
+    event_created_child!(State, ZwlrOutputManagerV1, [
+        zwlr_output_manager_v1::EVT_HEAD_OPCODE => (ZwlrOutputHeadV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrOutputHeadV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrOutputHeadV1,
+        event: zwlr_output_head_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        use zwlr_output_head_v1::Event;
+        if let Event::Finished = event {
+            if let Some(index) = state.heads.iter().position(|head| head.proxy == *proxy) {
+                let head = state.heads.remove(index);
+                if proxy.version() >= 3 {
+                    head.proxy.release();
+                }
+            }
+            return;
+        }
+        let Some(head) = state.heads.iter_mut().find(|head| head.proxy == *proxy) else {
+            return;
+        };
+        match event {
+            Event::Name { name } => head.name = name,
+            Event::Make { make } => head.make = make,
+            Event::Model { model } => head.model = model,
+            Event::SerialNumber { serial_number } => head.serial = serial_number,
+            Event::Mode { mode } => {
+                state.modes.insert(mode.id(), ModeInfo::default());
+                head.modes.push(mode);
+            }
+            Event::Enabled { enabled } => head.enabled = enabled != 0,
+            Event::CurrentMode { mode } => head.current_mode = Some(mode),
+            Event::Position { x, y } => head.position = (x, y),
+            Event::Transform {
+                transform: WEnum::Value(transform),
+            } => head.transform = transform,
+            Event::Scale { scale } => head.scale = scale,
+            Event::AdaptiveSync {
+                state: WEnum::Value(adaptive_sync),
+            } => head.adaptive_sync = Some(adaptive_sync == AdaptiveSyncState::Enabled),
+            _ => {}
+        }
+    }
+
+    event_created_child!(State, ZwlrOutputHeadV1, [
+        zwlr_output_head_v1::EVT_MODE_OPCODE => (ZwlrOutputModeV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrOutputModeV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrOutputModeV1,
+        event: zwlr_output_mode_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        use zwlr_output_mode_v1::Event;
+        if let Event::Finished = event {
+            state.modes.remove(&proxy.id());
+            for head in &mut state.heads {
+                head.modes.retain(|mode| mode != proxy);
+                if head.current_mode.as_ref() == Some(proxy) {
+                    head.current_mode = None;
+                }
+            }
+            if proxy.version() >= 3 {
+                proxy.release();
+            }
+            return;
+        }
+        let Some(info) = state.modes.get_mut(&proxy.id()) else {
+            return;
+        };
+        match event {
+            Event::Size { width, height } => {
+                info.width = width;
+                info.height = height;
+            }
+            Event::Refresh { refresh } => info.refresh = refresh,
+            Event::Preferred => info.preferred = true,
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ZwlrOutputConfigurationV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _: &ZwlrOutputConfigurationV1,
+        event: zwlr_output_configuration_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        state.outcome = match event {
+            zwlr_output_configuration_v1::Event::Succeeded => Some(Outcome::Succeeded),
+            zwlr_output_configuration_v1::Event::Failed => Some(Outcome::Failed),
+            zwlr_output_configuration_v1::Event::Cancelled => Some(Outcome::Cancelled),
+            _ => state.outcome,
+        };
+    }
+}
+
+impl Dispatch<ZwlrOutputPowerV1, String> for State {
+    fn event(
+        state: &mut Self,
+        _: &ZwlrOutputPowerV1,
+        event: zwlr_output_power_v1::Event,
+        connector: &String,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        let Some((_, on, failed)) = state.powers.get_mut(connector) else {
+            return;
+        };
+        match event {
+            zwlr_output_power_v1::Event::Mode { mode } => {
+                *on = Some(mode == WEnum::Value(zwlr_output_power_v1::Mode::On));
+            }
+            zwlr_output_power_v1::Event::Failed => *failed = true,
+            _ => {}
+        }
+    }
+}
+
+delegate_noop!(State: ignore ZwlrOutputConfigurationHeadV1);
+delegate_noop!(State: ignore ZwlrOutputPowerManagerV1);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode(width: i32, height: i32, refresh: i32, preferred: bool) -> ModeInfo {
+        ModeInfo {
+            width,
+            height,
+            refresh,
+            preferred,
+        }
+    }
+
+    fn request(width: i32, height: i32, refresh: Option<i32>, custom: bool) -> Option<ModeRequest> {
+        Some(ModeRequest {
+            width,
+            height,
+            refresh,
+            custom,
+        })
+    }
+
+    #[test]
+    fn plans_modes() {
+        let modes = [
+            mode(3840, 2160, 59997, true),
+            mode(3840, 2160, 30000, false),
+            mode(1920, 1080, 60000, false),
+            mode(1920, 1080, 119880, false),
+            mode(1920, 1080, 50000, false),
+        ];
+        let cases = [
+            (None, Some(2), PlannedMode::Mode(2)),
+            (None, None, PlannedMode::Mode(0)),
+            (request(3840, 2160, Some(30000), false), Some(0), PlannedMode::Mode(1)),
+            (request(1920, 1080, Some(119900), false), Some(0), PlannedMode::Mode(3)),
+            // Without a rate, the preferred mode, or else the fastest one
+            (request(3840, 2160, None, false), Some(2), PlannedMode::Mode(0)),
+            (request(1920, 1080, None, false), Some(0), PlannedMode::Mode(3)),
+            (
+                request(1920, 1080, Some(60000), true),
+                Some(0),
+                PlannedMode::Custom(1920, 1080, 60000),
+            ),
+        ];
+        for (request, current, expected) in cases {
+            assert_eq!(pick_mode("DP-1", &modes, current, request), Ok(expected), "{request:?}");
+        }
+    }
+
+    #[test]
+    fn plans_custom_modes_for_heads_without_modes() {
+        assert_eq!(
+            pick_mode("HEADLESS-1", &[], None, request(1280, 720, None, false)),
+            Ok(PlannedMode::Custom(1280, 720, 0))
+        );
+        assert!(pick_mode("HEADLESS-1", &[], None, None).is_err());
+    }
+
+    #[test]
+    fn refuses_modes_the_head_lacks() {
+        let modes = [mode(1920, 1080, 60000, true)];
+        for request in [request(2560, 1440, None, false), request(1920, 1080, Some(75000), false)] {
+            assert!(pick_mode("DP-1", &modes, Some(0), request).is_err(), "{request:?}");
+        }
+    }
+}
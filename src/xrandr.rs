@@ -0,0 +1,667 @@
+//! X11, for the Regolith i3 session, through the `xrandr` command.
+//!
+//! `xrandr --query --prop` describes the outputs, EDIDs included, and the
+//! sway output commands of a batch are translated into a single `xrandr` run.
+//! X11 has no scale per output, so only a scale of 1 is accepted. The primary
+//! output, which sway doesn't have, comes as an extra `primary` option, see
+//! [`primary_command`](crate::backend::primary_command). kanshi
+//! needs Wayland, so the saved profiles are written for autorandr instead,
+//! see [`write_autorandr_profile`].
+use crate::{
+    backend::{
+        Backend, BackendError, BackendEvents, DisplayBackend, ModeRequest, Output, OutputChange, OutputCommand,
+        OutputMode, MAX_RECONNECT_DELAY, RECONNECT_DELAY,
+    },
+    edid::Edid,
+    kanshi::{self, OutputDirective, TRANSFORMS},
+};
+use log::{debug, info, warn};
+use std::{collections::BTreeMap, error::Error, fs, io, path::PathBuf};
+use tokio::process::Command;
+use zbus::fdo;
+
+/// xrandr's rotations, in the order of [`TRANSFORMS`]
+const ROTATIONS: [&str; 4] = ["normal", "left", "inverted", "right"];
+
+/// Largest difference, in mHz, between a requested refresh rate and the rate
+/// passed to xrandr for it
+const REFRESH_TOLERANCE: i32 = 500;
+
+pub struct X11Backend;
+
+#[derive(Debug, Clone, Default)]
+struct XOutput {
+    name: String,
+    primary: bool,
+    /// Position and size in pixels, `None` if the output is off
+    geometry: Option<(i32, i32, i32, i32)>,
+    /// Index into [`TRANSFORMS`]
+    transform: usize,
+    modes: Vec<XMode>,
+    edid: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct XMode {
+    width: i32,
+    height: i32,
+    /// mHz
+    refresh: i32,
+    current: bool,
+    preferred: bool,
+}
+
+impl X11Backend {
+    /// Check that `xrandr` can reach the X server at `$DISPLAY`
+    pub async fn connect() -> Result<X11Backend, BackendError> {
+        if std::env::var_os("DISPLAY").is_none() {
+            return Err(BackendError::from(String::from("DISPLAY isn't set")));
+        }
+        xrandr(&["--version"]).await?;
+        Ok(X11Backend)
+    }
+}
+
+impl DisplayBackend for X11Backend {
+    async fn detect(&self) -> Backend {
+        match xrandr(&["--version"]).await {
+            Ok(text) => Backend::X11(
+                text.lines()
+                    .find_map(|line| line.strip_prefix("Server reports RandR version "))
+                    .map(|version| version.trim().to_string()),
+            ),
+            Err(e) => {
+                warn!("Could not query the RandR version: {e}");
+                Backend::X11(None)
+            }
+        }
+    }
+
+    async fn outputs(&self) -> Result<Vec<Output>, BackendError> {
+        Ok(query().await?.iter().map(output).collect())
+    }
+
+    async fn apply(&self, commands: &[String]) -> fdo::Result<()> {
+        let outputs = query().await.map_err(fdo::Error::Failed)?;
+        let mut changes = BTreeMap::new();
+        let mut power = None;
+        for command in commands {
+            debug!("Running output command on X11: {command}");
+            parse_command(&outputs, command, &mut changes, &mut power)
+                .map_err(|e| fdo::Error::Failed(format!("Cannot run '{command}' on X11: {e}")))?;
+        }
+        if !changes.is_empty() {
+            let args = xrandr_args(&outputs, &changes).map_err(fdo::Error::Failed)?;
+            info!("Running xrandr {}", args.join(" "));
+            let args: Vec<&str> = args.iter().map(String::as_str).collect();
+            xrandr(&args).await.map_err(fdo::Error::Failed)?;
+        }
+        if let Some(on) = power {
+            let status = Command::new("xset")
+                .args(["dpms", "force", if on { "on" } else { "off" }])
+                .status()
+                .await
+                .map_err(|e| fdo::Error::Failed(format!("Cannot run xset: {e}")))?;
+            if !status.success() {
+                return Err(fdo::Error::Failed(format!("xset dpms failed ({status})")));
+            }
+        }
+        Ok(())
+    }
+
+    /// `xrandr` can't wait for changes, so the outputs are polled
+    async fn subscribe(&self) -> Option<BackendEvents> {
+        None
+    }
+
+    async fn reconnect(&self) {
+        let mut delay = RECONNECT_DELAY;
+        loop {
+            tokio::time::sleep(delay).await;
+            match xrandr(&["--version"]).await {
+                Ok(_) => {
+                    info!("Reconnected to the X server");
+                    return;
+                }
+                Err(e) => {
+                    debug!("Cannot reach the X server yet: {e}");
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                }
+            }
+        }
+    }
+}
+
+/// Run `xrandr` with `args` and return what it printed
+async fn xrandr(args: &[&str]) -> Result<String, String> {
+    let output = Command::new("xrandr")
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| format!("Cannot run xrandr: {e}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "xrandr failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The connected outputs, sorted by name
+async fn query() -> Result<Vec<XOutput>, String> {
+    let mut outputs = parse_query(&xrandr(&["--query", "--prop"]).await?);
+    outputs.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(outputs)
+}
+
+/// Parse the output of `xrandr --query --prop`: a line for each output,
+/// its properties indented with tabs and its modes with spaces
+fn parse_query(text: &str) -> Vec<XOutput> {
+    let mut outputs = Vec::new();
+    let mut current: Option<XOutput> = None;
+    let mut in_edid = false;
+    for line in text.lines() {
+        if !line.starts_with(char::is_whitespace) {
+            outputs.extend(current.take());
+            current = parse_output_line(line);
+            in_edid = false;
+            continue;
+        }
+        // Lines of disconnected outputs are skipped along with them
+        let Some(output) = current.as_mut() else {
+            continue;
+        };
+        if line.starts_with('\t') {
+            let trimmed = line.trim();
+            if trimmed == "EDID:" {
+                in_edid = true;
+            } else if in_edid && line.starts_with("\t\t") && trimmed.bytes().all(|b| b.is_ascii_hexdigit()) {
+                output.edid.extend(
+                    trimmed
+                        .as_bytes()
+                        .chunks_exact(2)
+                        .filter_map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()),
+                );
+            } else {
+                in_edid = false;
+            }
+            continue;
+        }
+        in_edid = false;
+        output.modes.extend(parse_mode_line(line));
+    }
+    outputs.extend(current);
+    outputs
+}
+
+/// `eDP-1 connected primary 1920x1080+0+0 left X axis (normal left ...) 344mm x 194mm`,
+/// `None` unless the output is connected
+fn parse_output_line(line: &str) -> Option<XOutput> {
+    // The supported rotations and the size in mm follow the parenthesis
+    let head = line.split('(').next()?;
+    let mut words = head.split_whitespace();
+    let name = words.next()?;
+    if words.next()? != "connected" {
+        return None;
+    }
+    let mut output = XOutput {
+        name: name.to_string(),
+        ..XOutput::default()
+    };
+    let mut rotation = 0;
+    let (mut reflect_x, mut reflect_y) = (false, false);
+    for word in words {
+        match word {
+            "primary" => output.primary = true,
+            "X" => reflect_x = true,
+            "Y" => reflect_y = true,
+            "and" | "axis" => {}
+            word => match ROTATIONS.iter().position(|rotation| *rotation == word) {
+                Some(index) => rotation = index,
+                None => output.geometry = parse_geometry(word),
+            },
+        }
+    }
+    // Reflecting along Y is reflecting along X and turning by 180°
+    if reflect_y {
+        rotation = (rotation + 2) % 4;
+    }
+    output.transform = rotation + if reflect_x != reflect_y { 4 } else { 0 };
+    Some(output)
+}
+
+/// `WIDTHxHEIGHT+X+Y` as (x, y, width, height)
+fn parse_geometry(word: &str) -> Option<(i32, i32, i32, i32)> {
+    let (width, rest) = word.split_once('x')?;
+    let mut rest = rest.split('+');
+    let height = rest.next()?.parse().ok()?;
+    let x = rest.next()?.parse().ok()?;
+    let y = rest.next()?.parse().ok()?;
+    Some((x, y, width.parse().ok()?, height))
+}
+
+/// `   1920x1080     60.02*+  59.93`: a size and its rates, `*` marking the
+/// current one and `+` the preferred one
+fn parse_mode_line(line: &str) -> Vec<XMode> {
+    let mut words = line.split_whitespace();
+    let Some((width, height)) = words.next().and_then(|size| size.split_once('x')) else {
+        return Vec::new();
+    };
+    let (Ok(width), Ok(height)) = (width.parse(), height.trim_end_matches('i').parse()) else {
+        return Vec::new();
+    };
+    let mut modes: Vec<XMode> = Vec::new();
+    for word in words {
+        let rate = word.trim_end_matches(['*', '+']);
+        if rate.is_empty() {
+            // A marker on its own belongs to the rate before it
+            if let Some(mode) = modes.last_mut() {
+                mode.current |= word.contains('*');
+                mode.preferred |= word.contains('+');
+            }
+            continue;
+        }
+        let Ok(rate) = rate.parse::<f64>() else {
+            continue;
+        };
+        modes.push(XMode {
+            width,
+            height,
+            refresh: (rate * 1000.0).round() as i32,
+            current: word.contains('*'),
+            preferred: word.contains('+'),
+        });
+    }
+    modes
+}
+
+/// Make, model and serial as sway takes them from the EDID
+fn identity(output: &XOutput) -> (String, String, String) {
+    match Edid::parse(&output.edid) {
+        Ok(edid) => (
+            edid.manufacturer,
+            edid.name.unwrap_or_else(|| format!("0x{:04X}", edid.product)),
+            edid.serial_string.unwrap_or_else(|| format!("0x{:08X}", edid.serial)),
+        ),
+        Err(_) => (String::from("Unknown"), String::from("Unknown"), String::from("Unknown")),
+    }
+}
+
+/// `output`'s description, as sway would give it
+fn description(output: &XOutput) -> String {
+    let (make, model, serial) = identity(output);
+    format!("{make} {model} {serial}")
+}
+
+/// `output` as the daemon describes outputs
+fn output(output: &XOutput) -> Output {
+    let (make, model, serial) = identity(output);
+    let mode = |mode: &XMode| OutputMode {
+        width: mode.width,
+        height: mode.height,
+        refresh: mode.refresh,
+        preferred: mode.preferred,
+    };
+    let on = output.geometry.is_some();
+    Output {
+        name: output.name.clone(),
+        make,
+        model,
+        serial,
+        active: on,
+        power: on,
+        primary: output.primary,
+        non_desktop: false,
+        modes: output.modes.iter().map(mode).collect(),
+        current_mode: output.modes.iter().find(|mode| mode.current).filter(|_| on).map(mode),
+        rect: output.geometry.unwrap_or((0, 0, 0, 0)),
+        scale: on.then_some(1.0),
+        transform: on.then(|| TRANSFORMS[output.transform].to_string()),
+        adaptive_sync: Some(false),
+        max_render_time: None,
+        allow_tearing: None,
+    }
+}
+
+/// Changes and power requests of `command`, for the outputs it names
+fn parse_command(
+    outputs: &[XOutput],
+    command: &str,
+    changes: &mut BTreeMap<String, OutputChange>,
+    power: &mut Option<bool>,
+) -> Result<(), String> {
+    let Some(parsed) = OutputCommand::parse(command)? else {
+        return Ok(());
+    };
+    let names: Vec<&str> = outputs
+        .iter()
+        .filter(|output| parsed.selects(&output.name, &description(output)))
+        .map(|output| output.name.as_str())
+        .collect();
+    if names.is_empty() {
+        debug!("No output matches {}, ignoring '{command}'", parsed.criteria);
+        return Ok(());
+    }
+    let change = &parsed.change;
+    if change.mode.is_some_and(|mode| mode.custom) {
+        return Err(String::from("Custom modes aren't supported on X11"));
+    }
+    if let Some(scale) = change.scale.filter(|scale| (scale - 1.0).abs() > 0.001) {
+        return Err(format!("X11 has no scale per output, cannot scale by {scale}"));
+    }
+    if change.adaptive_sync == Some(true) {
+        return Err(String::from("Adaptive sync isn't supported on X11"));
+    }
+    if change.primary && names.len() > 1 {
+        return Err(String::from("Only one output can be primary"));
+    }
+    if let Some(on) = change.power {
+        // DPMS applies to the whole screen
+        if names.len() != outputs.len() {
+            return Err(String::from("X11 can only power all outputs off and on"));
+        }
+        *power = Some(on);
+    }
+    for name in names {
+        changes.entry(name.to_string()).or_default().merge(change);
+    }
+    Ok(())
+}
+
+/// Index into [`TRANSFORMS`] of one of sway's transforms
+fn transform_index(name: &str) -> usize {
+    TRANSFORMS.iter().position(|other| *other == name).unwrap_or(0)
+}
+
+/// The mode of `output` closest to `request`, preferring its preferred rate
+/// when `request` has none
+fn find_mode(output: &XOutput, request: &ModeRequest) -> Option<XMode> {
+    let sized = output
+        .modes
+        .iter()
+        .filter(|mode| mode.width == request.width && mode.height == request.height);
+    match request.refresh {
+        Some(refresh) => sized
+            .filter(|mode| (mode.refresh - refresh).abs() <= REFRESH_TOLERANCE)
+            .min_by_key(|mode| (mode.refresh - refresh).abs())
+            .copied(),
+        None => {
+            let sized: Vec<&XMode> = sized.collect();
+            sized
+                .iter()
+                .find(|mode| mode.preferred)
+                .or_else(|| sized.iter().max_by_key(|mode| mode.refresh))
+                .map(|mode| **mode)
+        }
+    }
+}
+
+/// The arguments of a single `xrandr` run applying `changes`
+fn xrandr_args(outputs: &[XOutput], changes: &BTreeMap<String, OutputChange>) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    for (name, change) in changes {
+        let output = outputs
+            .iter()
+            .find(|output| output.name == *name)
+            .ok_or(format!("No output named {name}"))?;
+        args.extend([String::from("--output"), name.clone()]);
+        if change.enabled == Some(false) {
+            args.push(String::from("--off"));
+            continue;
+        }
+        match &change.mode {
+            Some(request) => {
+                let mode = find_mode(output, request).ok_or(format!(
+                    "{name} has no mode {}x{}",
+                    request.width, request.height
+                ))?;
+                args.extend([
+                    String::from("--mode"),
+                    format!("{}x{}", mode.width, mode.height),
+                    String::from("--rate"),
+                    format!("{:.2}", f64::from(mode.refresh) / 1000.0),
+                ]);
+            }
+            None if output.geometry.is_none() => args.push(String::from("--auto")),
+            None => {}
+        }
+        if let Some((x, y)) = change.position {
+            args.extend([String::from("--pos"), format!("{x}x{y}")]);
+        }
+        if let Some(transform) = change.transform.map(transform_index) {
+            args.extend([
+                String::from("--rotate"),
+                ROTATIONS[transform % 4].to_string(),
+                String::from("--reflect"),
+                String::from(if transform >= 4 { "x" } else { "normal" }),
+            ]);
+        }
+        if change.primary {
+            args.push(String::from("--primary"));
+        }
+    }
+    Ok(args)
+}
+
+/// autorandr's profiles: `autorandr` in the user's config directory
+pub fn autorandr_dir() -> PathBuf {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(".config"));
+    config_home.join("autorandr")
+}
+
+/// The lines of autorandr's `config` for `output` as `directive` sets it up,
+/// with its current state for what the directive leaves out
+fn autorandr_output(directive: &OutputDirective, output: &XOutput) -> String {
+    let change = OutputChange::parse(&directive.options).unwrap_or_else(|e| {
+        warn!("Cannot read the directive for {}, keeping its state: {e}", output.name);
+        OutputChange::default()
+    });
+    let mut lines = format!("output {}\n", output.name);
+    let mode = match &change.mode {
+        Some(request) => find_mode(output, request),
+        None => output.modes.iter().find(|mode| mode.current).copied(),
+    };
+    let position = change.position.or(output.geometry.map(|(x, y, _, _)| (x, y)));
+    let (Some(mode), Some((x, y)), false) = (mode, position, change.enabled == Some(false)) else {
+        lines.push_str("off\n");
+        return lines;
+    };
+    let transform = change.transform.map_or(output.transform, transform_index);
+    lines.push_str(&format!("mode {}x{}\n", mode.width, mode.height));
+    lines.push_str(&format!("pos {x}x{y}\n"));
+    if output.primary {
+        lines.push_str("primary\n");
+    }
+    lines.push_str(&format!("rate {:.2}\n", f64::from(mode.refresh) / 1000.0));
+    lines.push_str(&format!("rotate {}\n", ROTATIONS[transform % 4]));
+    if transform >= 4 {
+        lines.push_str("reflect x\n");
+    }
+    lines
+}
+
+/// Write `profile`, the kanshi profile file `name` being saved, as the
+/// autorandr profile of the same name, with the EDIDs autorandr recognizes
+/// the outputs by in its `setup`. Only the profile for the connected outputs
+/// can be written, as autorandr needs their EDIDs. Returns whether a file
+/// changed.
+pub async fn write_autorandr_profile(name: &str, profile: &str) -> Result<bool, Box<dyn Error>> {
+    let outputs = query().await?;
+    let descriptions: Vec<(&str, String)> = outputs
+        .iter()
+        .map(|output| (output.name.as_str(), description(output)))
+        .collect();
+    let config = kanshi::parse(profile).map_err(|e| e.to_string())?;
+    let Some((profile, selected)) = config
+        .profiles
+        .iter()
+        .find_map(|profile| Some((profile, kanshi::select_outputs(profile, &descriptions)?)))
+    else {
+        debug!("{name} isn't for the connected outputs, not writing an autorandr profile");
+        return Ok(false);
+    };
+    let mut config = String::new();
+    for (directive, index) in profile.outputs.iter().zip(selected) {
+        config.push_str(&autorandr_output(directive, &outputs[index]));
+    }
+    let mut setup = String::new();
+    for output in outputs.iter().filter(|output| !output.edid.is_empty()) {
+        let edid: String = output.edid.iter().map(|byte| format!("{byte:02x}")).collect();
+        setup.push_str(&format!("{} {edid}\n", output.name));
+    }
+    let dir = autorandr_dir().join(name);
+    fs::create_dir_all(&dir)?;
+    let mut changed = false;
+    for (file, contents) in [("config", config), ("setup", setup)] {
+        let path = dir.join(file);
+        match fs::read_to_string(&path) {
+            Ok(text) if text == contents => continue,
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("{}: {e}", path.display()).into()),
+        }
+        fs::write(&path, contents)?;
+        changed = true;
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `xrandr --query --prop` with a laptop panel, an HDMI monitor turned to
+    /// the left and mirrored, and a disconnected DisplayPort, some properties
+    /// left out
+    const QUERY: &str = "\
+Screen 0: minimum 320 x 200, current 3000 x 1920, maximum 16384 x 16384
+eDP-1 connected primary 1920x1080+0+0 (normal left inverted right x axis y axis) 344mm x 194mm
+\tEDID: 
+\t\t00ffffffffffff0009e5d90800000000
+\t\t011d0104a5221378077de5955c58922a
+\tscaling mode: Full aspect 
+\t\tsupported: Full, Center, Full aspect
+\tnon-desktop: 0 
+\t\trange: (0, 1)
+   1920x1080     60.02*+  59.93    48.02  
+   1680x1050     59.95  
+DP-1 disconnected (normal left inverted right x axis y axis)
+\tnon-desktop: 0 
+\t\trange: (0, 1)
+HDMI-1 connected 1080x1920+1920+0 left X axis (normal left inverted right x axis y axis) 527mm x 296mm
+\tEDID: 
+\t\t00ffffffffffff0010acb5a04c323530
+\tnon-desktop: 0 
+\t\trange: (0, 1)
+   1920x1080     60.00 +  50.00    59.94*
+   1920x1080i    60.00    50.00  
+   1280x720      60.00  
+";
+
+    fn mode(width: i32, height: i32, refresh: i32) -> XMode {
+        XMode {
+            width,
+            height,
+            refresh,
+            ..XMode::default()
+        }
+    }
+
+    #[test]
+    fn parses_the_connected_outputs() {
+        let outputs = parse_query(QUERY);
+        let names: Vec<&str> = outputs.iter().map(|output| output.name.as_str()).collect();
+        assert_eq!(names, ["eDP-1", "HDMI-1"]);
+
+        let panel = &outputs[0];
+        assert!(panel.primary);
+        assert_eq!(panel.geometry, Some((0, 0, 1920, 1080)));
+        assert_eq!(panel.edid.len(), 32);
+        assert_eq!(panel.edid[..3], [0x00, 0xff, 0xff]);
+        assert_eq!(panel.modes.len(), 4);
+        assert!(panel.modes[0].current && panel.modes[0].preferred);
+
+        let hdmi = &outputs[1];
+        assert!(!hdmi.primary);
+        assert_eq!(hdmi.geometry, Some((1920, 0, 1080, 1920)));
+        assert_eq!(TRANSFORMS[hdmi.transform], "flipped-90");
+        assert_eq!(hdmi.edid.len(), 16);
+        assert_eq!(hdmi.modes.len(), 6);
+        let current: Vec<XMode> = hdmi.modes.iter().filter(|mode| mode.current).copied().collect();
+        assert_eq!(
+            current,
+            [XMode {
+                current: true,
+                ..mode(1920, 1080, 59940)
+            }]
+        );
+        assert!(hdmi.modes[0].preferred);
+    }
+
+    #[test]
+    fn parses_output_lines() {
+        let cases = [
+            (
+                "eDP-1 connected primary 1920x1080+0+0 (normal left inverted right x axis y axis) 344mm x 194mm",
+                Some((true, Some((0, 0, 1920, 1080)), "normal")),
+            ),
+            (
+                "DP-2 connected 2560x1440+1920+0 inverted (normal left inverted right x axis y axis) 597mm x 336mm",
+                Some((false, Some((1920, 0, 2560, 1440)), "180")),
+            ),
+            (
+                "DP-3 connected 1080x1920+0+0 right Y axis (normal left inverted right x axis y axis) 527mm x 296mm",
+                Some((false, Some((0, 0, 1080, 1920)), "flipped-90")),
+            ),
+            (
+                "HDMI-1 connected 1920x1080+0+0 X and Y axis (normal left inverted right x axis y axis) 527mm x 296mm",
+                Some((false, Some((0, 0, 1920, 1080)), "180")),
+            ),
+            (
+                "HDMI-2 connected (normal left inverted right x axis y axis)",
+                Some((false, None, "normal")),
+            ),
+            ("DP-1 disconnected (normal left inverted right x axis y axis)", None),
+            ("Screen 0: minimum 320 x 200, current 1920 x 1080, maximum 16384 x 16384", None),
+        ];
+        for (line, expected) in cases {
+            let parsed = parse_output_line(line)
+                .map(|output| (output.primary, output.geometry, TRANSFORMS[output.transform]));
+            assert_eq!(parsed, expected, "{line}");
+        }
+    }
+
+    #[test]
+    fn parses_mode_lines() {
+        let current = |mode: XMode| XMode { current: true, ..mode };
+        let preferred = |mode: XMode| XMode { preferred: true, ..mode };
+        let cases = [
+            (
+                "   1920x1080     60.02*+  59.93    48.02  ",
+                vec![
+                    current(preferred(mode(1920, 1080, 60020))),
+                    mode(1920, 1080, 59930),
+                    mode(1920, 1080, 48020),
+                ],
+            ),
+            (
+                "   1920x1080     60.00 +  50.00    59.94*",
+                vec![
+                    preferred(mode(1920, 1080, 60000)),
+                    mode(1920, 1080, 50000),
+                    current(mode(1920, 1080, 59940)),
+                ],
+            ),
+            ("   1920x1080i    60.00    50.00  ", vec![mode(1920, 1080, 60000), mode(1920, 1080, 50000)]),
+            ("   1280x720      60.00  ", vec![mode(1280, 720, 60000)]),
+            ("\tnon-desktop: 0 ", Vec::new()),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(parse_mode_line(line), expected, "{line}");
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! The integer scale of X11 clients, which XWayland can't scale per output.
+//!
+//! It is reported as `legacy-ui-scaling-factor` and published to X11
+//! toolkits as `Xft.dpi` through `xrdb` when a configuration is applied, if
+//! `displayd.xft_dpi` is on.
+use crate::config;
+use log::{debug, info, warn};
+use std::{
+    process::Stdio,
+    sync::atomic::{AtomicI32, Ordering},
+};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+/// `Xft.dpi` at scale 1
+pub const BASE_DPI: i32 = 96;
+
+/// Factor last published, 0 before the first one
+static PUBLISHED: AtomicI32 = AtomicI32::new(0);
+
+/// The largest of the enabled outputs' `scales`, rounded down so fractional
+/// scales don't make X11 clients bigger than their Wayland neighbours
+pub fn scale_factor(scales: impl IntoIterator<Item = f64>) -> i32 {
+    scales
+        .into_iter()
+        .map(|scale| scale.floor() as i32)
+        .max()
+        .unwrap_or(1)
+        .max(1)
+}
+
+/// Merge `Xft.dpi` for `factor` into the X resources, unless it already was
+/// or `displayd.xft_dpi` is off
+pub async fn publish(factor: i32) {
+    if !config::current().xft_dpi || PUBLISHED.load(Ordering::Relaxed) == factor {
+        return;
+    }
+    let dpi = BASE_DPI * factor;
+    match merge_resource(&format!("Xft.dpi: {dpi}\n")).await {
+        Ok(()) => {
+            info!("Xft.dpi set to {dpi} for XWayland");
+            PUBLISHED.store(factor, Ordering::Relaxed);
+        }
+        Err(e) => warn!("Cannot set Xft.dpi for XWayland: {e}"),
+    }
+}
+
+async fn merge_resource(resource: &str) -> std::io::Result<()> {
+    let mut xrdb = Command::new("xrdb")
+        .arg("-merge")
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = xrdb.stdin.take() {
+        stdin.write_all(resource.as_bytes()).await?;
+    }
+    let output = xrdb.wait_with_output().await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        debug!("xrdb failed: {stderr}");
+        return Err(std::io::Error::other(stderr.trim().to_string()));
+    }
+    Ok(())
+}